@@ -1,6 +1,6 @@
 use alloc::string::String;
-use parity_scale_codec::{Decode, Encode};
 use core::time::Duration;
+use parity_scale_codec::{Decode, Encode};
 
 #[derive(Debug, Encode, Decode, Default, Clone)]
 pub struct InitArgs {
@@ -48,6 +48,10 @@ pub struct InitArgs {
 
     /// The max retry times of getting the attestation report.
     pub ra_max_retries: u32,
+
+    /// The max total bytes of the headers returned to the contracts with an HTTP response.
+    /// Runtime default if None.
+    pub max_http_response_header_bytes: Option<u64>,
 }
 
 pub use phala_git_revision::git_revision;
@@ -15,15 +15,15 @@ use pink::{
     capi::v1::{
         ecall::{ECalls, ECallsRo},
         ocall::{
-            BatchHttpResult, ExecContext, HttpRequest, HttpRequestError, HttpResponse, OCalls,
-            StorageChanges,
+            BatchHttpResult, ExecContext, HttpRequest, HttpRequestError, HttpResponse,
+            HttpRetryPolicy, OCalls, StorageChanges,
         },
     },
     local_cache::{self, StorageQuotaExceeded},
     runtimes::v1::{get_runtime, using_ocalls},
     types::{BlockNumber, ExecutionMode},
 };
-use pink_extension::chain_extension::{JsCode, JsValue};
+use pink_extension::chain_extension::{JsBudget, JsCode, JsValue};
 use serde::{Deserialize, Serialize};
 use sidevm::{
     service::{Command as SidevmCommand, CommandSender, Metric, SystemMessage},
@@ -44,6 +44,7 @@ pub use phala_types::contract::InkCommand;
 use super::ContractsKeeper;
 
 pub(crate) mod http_counters;
+mod js_modules;
 
 #[derive(Serialize, Deserialize, Default, Clone, ::scale_info::TypeInfo)]
 pub struct ClusterConfig {
@@ -418,6 +419,20 @@ impl OCalls for RuntimeHandle<'_> {
         local_cache::set(&contract, &key, &value)
     }
 
+    fn cache_get_batch(&self, contract: Vec<u8>, keys: Vec<Vec<u8>>) -> Vec<Option<Vec<u8>>> {
+        if !context::get().mode.is_query() {
+            return vec![None; keys.len()];
+        }
+        local_cache::get_batch(&contract, &keys)
+    }
+
+    fn cache_set_batch(&self, contract: Vec<u8>, pairs: Vec<(Vec<u8>, Vec<u8>)>) -> Vec<bool> {
+        if context::get().mode.is_estimating() {
+            return vec![true; pairs.len()];
+        }
+        local_cache::set_batch(&contract, &pairs)
+    }
+
     fn cache_set_expiration(&self, contract: Vec<u8>, key: Vec<u8>, expiration: u64) {
         if context::get().mode.is_estimating() {
             return;
@@ -488,19 +503,45 @@ impl OCalls for RuntimeHandle<'_> {
             requests,
             context::time_remaining().min(timeout_ms),
         )?;
-        for result in &results {
-            match result {
-                Ok(r) => {
-                    http_counters::add(contract.clone(), r.status_code);
-                }
-                Err(_) => {
-                    http_counters::add(contract.clone(), 0);
-                }
-            }
-        }
+        count_batch_results(&contract, &results);
+        Ok(results)
+    }
+
+    fn batch_http_request_with_timeouts(
+        &self,
+        contract: AccountId,
+        requests: Vec<HttpRequest>,
+        timeout_ms: u64,
+        request_timeout_ms: Option<u64>,
+    ) -> BatchHttpResult {
+        let results = pink_extension_runtime::batch_http_request_with_timeouts(
+            requests,
+            context::time_remaining().min(timeout_ms),
+            request_timeout_ms,
+        )?;
+        count_batch_results(&contract, &results);
         Ok(results)
     }
 
+    fn http_request_with_retry(
+        &self,
+        contract: AccountId,
+        request: HttpRequest,
+        policy: HttpRetryPolicy,
+    ) -> Result<HttpResponse, HttpRequestError> {
+        // Nothing transient to retry in a query to a local sidevm.
+        if request.url.starts_with("sidevm://") {
+            return self.http_request(contract, request);
+        }
+        let result = pink_extension_runtime::http_request_with_retry(
+            request,
+            &policy,
+            context::time_remaining(),
+        );
+        count_batch_results(&contract, core::slice::from_ref(&result));
+        result
+    }
+
     fn emit_system_event_block(&self, _number: u64, _encoded_block: Vec<u8>) {
         error!("emit_system_event_block called on readonly calls");
     }
@@ -514,11 +555,33 @@ impl OCalls for RuntimeHandle<'_> {
     }
 
     fn js_eval(&self, caller: AccountId, codes: Vec<JsCode>, js_args: Vec<String>) -> JsValue {
-        info!("evaluating js from {caller:?}");
+        // The contracts calling the old extension don't know about `JsValue::Timeout`.
+        match self.js_eval_with_budget(caller, codes, js_args, JsBudget::default()) {
+            JsValue::Timeout(err) => JsValue::Exception(err),
+            value => value,
+        }
+    }
+
+    fn js_eval_with_budget(
+        &self,
+        caller: AccountId,
+        codes: Vec<JsCode>,
+        js_args: Vec<String>,
+        budget: JsBudget,
+    ) -> JsValue {
+        info!("evaluating js from {caller:?}, budget={budget:?}");
         let Some(js_runtime) = self.cluster.config.js_runtime else {
             return JsValue::Exception("No js runtime".into());
         };
-        let timeout = Duration::from_millis(context::time_remaining());
+        let timeout_ms = match budget.timeout_ms {
+            Some(budget_ms) => context::time_remaining().min(budget_ms),
+            None => context::time_remaining(),
+        };
+        let timeout = Duration::from_millis(timeout_ms);
+        let codes = match js_modules::bundle(codes) {
+            Ok(codes) => codes,
+            Err(err) => return JsValue::Exception(format!("SyntaxError: {err}")),
+        };
         let mut args = vec!["phatjs".into()];
         for code in codes {
             match code {
@@ -530,6 +593,8 @@ impl OCalls for RuntimeHandle<'_> {
                     args.push("-b".into());
                     args.push(hex::encode(code));
                 }
+                // Bundled into the sources above.
+                JsCode::Modules(_) => {}
             }
         }
         args.push("--".into());
@@ -547,12 +612,19 @@ impl OCalls for RuntimeHandle<'_> {
             }
             Ok(module) => module,
         };
+        let exec_context = context::get();
+        let chain_head = sidevm::ChainHead {
+            block_number: exec_context.block_number,
+            now_ms: exec_context.now_ms,
+        };
         let result = block_on_run_module(
             caller.into(),
             &module,
             args,
             timeout,
+            budget.max_instructions,
             context::sidevm_event_tx(),
+            chain_head,
             |vmid, level, message| self.log_to_server(vmid.into(), level, message),
         );
         match result {
@@ -570,6 +642,19 @@ impl OCalls for RuntimeHandle<'_> {
     }
 }
 
+fn count_batch_results(contract: &AccountId, results: &[Result<HttpResponse, HttpRequestError>]) {
+    for result in results {
+        match result {
+            Ok(r) => {
+                http_counters::add(contract.clone(), r.status_code);
+            }
+            Err(_) => {
+                http_counters::add(contract.clone(), 0);
+            }
+        }
+    }
+}
+
 pub fn load_module(code_hash: &Hash, init: impl FnOnce() -> Option<Vec<u8>>) -> Result<WasmModule> {
     struct Cache {
         engine: WasmEngine,
@@ -577,7 +662,8 @@ pub fn load_module(code_hash: &Hash, init: impl FnOnce() -> Option<Vec<u8>>) ->
     }
     static WASM_CACHE: Lazy<Mutex<Cache>> = Lazy::new(|| {
         Mutex::new(Cache {
-            engine: WasmEngine::new(),
+            // Counting the instructions lets the evaluations be bounded by their number.
+            engine: WasmEngine::with_instruction_counter(),
             cached_module: None,
         })
     });
@@ -638,6 +724,14 @@ impl OCalls for RuntimeHandleMut<'_> {
         self.readonly().cache_set(contract, key, value)
     }
 
+    fn cache_get_batch(&self, contract: Vec<u8>, keys: Vec<Vec<u8>>) -> Vec<Option<Vec<u8>>> {
+        self.readonly().cache_get_batch(contract, keys)
+    }
+
+    fn cache_set_batch(&self, contract: Vec<u8>, pairs: Vec<(Vec<u8>, Vec<u8>)>) -> Vec<bool> {
+        self.readonly().cache_set_batch(contract, pairs)
+    }
+
     fn cache_set_expiration(&self, contract: Vec<u8>, key: Vec<u8>, expiration: u64) {
         self.readonly()
             .cache_set_expiration(contract, key, expiration)
@@ -669,6 +763,21 @@ impl OCalls for RuntimeHandleMut<'_> {
             .batch_http_request(contract, requests, timeout_ms)
     }
 
+    fn batch_http_request_with_timeouts(
+        &self,
+        contract: AccountId,
+        requests: Vec<HttpRequest>,
+        timeout_ms: u64,
+        request_timeout_ms: Option<u64>,
+    ) -> BatchHttpResult {
+        self.readonly().batch_http_request_with_timeouts(
+            contract,
+            requests,
+            timeout_ms,
+            request_timeout_ms,
+        )
+    }
+
     fn emit_system_event_block(&self, number: u64, encoded_block: Vec<u8>) {
         if !tracing::enabled!(target: "phactory::event_chain", tracing::Level::INFO) {
             return;
@@ -697,6 +806,27 @@ impl OCalls for RuntimeHandleMut<'_> {
         self.readonly().js_eval(caller, codes, args)
     }
 
+    fn js_eval_with_budget(
+        &self,
+        caller: AccountId,
+        codes: Vec<JsCode>,
+        args: Vec<String>,
+        budget: JsBudget,
+    ) -> JsValue {
+        self.readonly()
+            .js_eval_with_budget(caller, codes, args, budget)
+    }
+
+    fn http_request_with_retry(
+        &self,
+        contract: AccountId,
+        request: HttpRequest,
+        policy: HttpRetryPolicy,
+    ) -> Result<HttpResponse, HttpRequestError> {
+        self.readonly()
+            .http_request_with_retry(contract, request, policy)
+    }
+
     fn origin(&self) -> Option<AccountId> {
         self.readonly().origin()
     }
@@ -785,6 +915,15 @@ impl Cluster {
         self.default_runtime().code_hash(address.clone())
     }
 
+    /// Read the raw storage of a contract. Returns None if unsupported by the runtime.
+    pub fn contract_storage_get(&self, address: &AccountId, key: Vec<u8>) -> Option<Vec<u8>> {
+        if !pink::types::ECallsAvailable::contract_storage_get(self.config.runtime_version) {
+            return None;
+        }
+        self.default_runtime()
+            .contract_storage_get(address.clone(), key)
+    }
+
     pub fn upload_resource(
         &mut self,
         origin: &AccountId,
@@ -0,0 +1,717 @@
+//! ES module imports for js evaluations.
+//!
+//! The js runtime evaluates plain scripts and has no module loader. So the modules given along
+//! with the codes ([`JsCode::Modules`]) are bundled into a prelude script, where each module is a
+//! function filling its exports on the first `__pinkRequire(name)`. The top level `import` and
+//! `export` statements of the modules and the codes are rewritten to use them. Importing a name
+//! missing from the bundle fails: nothing is loaded from the filesystem or the network.
+//!
+//! Imports bind the values exported when the import runs, rather than live bindings.
+
+use pink_extension::chain_extension::JsCode;
+
+const REQUIRE: &str = "__pinkRequire";
+const EXPORTS: &str = "__pinkExports";
+
+/// Bundles the modules among `codes` into a prelude code and rewrites the imports of the sources.
+///
+/// The codes are returned as is if there is no module.
+pub(super) fn bundle(codes: Vec<JsCode>) -> Result<Vec<JsCode>, String> {
+    let (modules, codes): (Vec<_>, Vec<_>) = codes
+        .into_iter()
+        .partition(|code| matches!(code, JsCode::Modules(_)));
+    let modules: Vec<_> = modules
+        .into_iter()
+        .flat_map(|code| match code {
+            JsCode::Modules(modules) => modules,
+            _ => vec![],
+        })
+        .collect();
+    if modules.is_empty() {
+        return Ok(codes);
+    }
+    let mut bundled = vec![JsCode::Source(prelude(&modules)?)];
+    for code in codes {
+        bundled.push(match code {
+            JsCode::Source(src) => JsCode::Source(rewrite(&src, false)?.0),
+            code => code,
+        });
+    }
+    Ok(bundled)
+}
+
+fn prelude(modules: &[(String, String)]) -> Result<String, String> {
+    let mut factories = String::new();
+    for (i, (name, source)) in modules.iter().enumerate() {
+        if modules[..i].iter().any(|(other, _)| other == name) {
+            return Err(format!("duplicate module '{name}'"));
+        }
+        let (body, exports) =
+            rewrite(source, true).map_err(|err| format!("in module '{name}': {err}"))?;
+        factories += &format!(
+            "[{}, function ({EXPORTS}) {{ \"use strict\"; {exports}\n{body}\n}}],\n",
+            quote(name)
+        );
+    }
+    Ok(format!(
+        r#"(() => {{
+const factories = new Map([
+{factories}]);
+const cache = new Map();
+const {REQUIRE} = (name) => {{
+  if (cache.has(name)) return cache.get(name);
+  if (!factories.has(name)) throw new Error(`could not load module '${{name}}'`);
+  const exports = Object.create(null);
+  // Set before running the module, so that cyclic imports see the partial exports.
+  cache.set(name, exports);
+  factories.get(name)(exports);
+  return exports;
+}};
+Object.defineProperty(globalThis, "{REQUIRE}", {{ value: {REQUIRE} }});
+}})();
+"#
+    ))
+}
+
+fn quote(s: &str) -> String {
+    // JSON strings are valid js string literals.
+    serde_json::to_string(s).expect("Strings always serialize")
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Kind {
+    Ident,
+    Str,
+    Punct(u8),
+    /// Numbers, templates and regexps.
+    Other,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Token {
+    kind: Kind,
+    start: usize,
+    end: usize,
+}
+
+/// Just enough of a js lexer to find the top level statements: strings, templates, regexps and
+/// comments are skipped over as a whole, so that the braces in them are not counted.
+struct Lexer<'a> {
+    src: &'a [u8],
+    pos: usize,
+    prev: Option<Token>,
+}
+
+impl<'a> Lexer<'a> {
+    fn tokenize(src: &'a str) -> Vec<Token> {
+        let mut lexer = Lexer {
+            src: src.as_bytes(),
+            pos: 0,
+            prev: None,
+        };
+        core::iter::from_fn(|| lexer.next_token()).collect()
+    }
+
+    fn peek(&self, offset: usize) -> u8 {
+        self.src.get(self.pos + offset).copied().unwrap_or(0)
+    }
+
+    fn skip_trivia(&mut self) {
+        while self.pos < self.src.len() {
+            match (self.peek(0), self.peek(1)) {
+                (b'/', b'/') => {
+                    while self.pos < self.src.len() && self.peek(0) != b'\n' {
+                        self.pos += 1;
+                    }
+                }
+                (b'/', b'*') => {
+                    self.pos += 2;
+                    while self.pos < self.src.len() && (self.peek(0), self.peek(1)) != (b'*', b'/')
+                    {
+                        self.pos += 1;
+                    }
+                    self.pos = (self.pos + 2).min(self.src.len());
+                }
+                (c, _) if c.is_ascii_whitespace() => self.pos += 1,
+                _ => break,
+            }
+        }
+    }
+
+    fn next_token(&mut self) -> Option<Token> {
+        self.skip_trivia();
+        if self.pos >= self.src.len() {
+            return None;
+        }
+        let start = self.pos;
+        let c = self.peek(0);
+        let kind = if is_ident_char(c) && !c.is_ascii_digit() {
+            self.skip_while(is_ident_char);
+            Kind::Ident
+        } else if c.is_ascii_digit() || (c == b'.' && self.peek(1).is_ascii_digit()) {
+            self.skip_while(|c| c.is_ascii_alphanumeric() || c == b'.' || c == b'_');
+            Kind::Other
+        } else if c == b'"' || c == b'\'' {
+            self.pos += 1;
+            self.skip_quoted(c);
+            Kind::Str
+        } else if c == b'`' {
+            self.pos += 1;
+            self.skip_template();
+            Kind::Other
+        } else if c == b'/' && self.regex_allowed() {
+            self.pos += 1;
+            self.skip_regex();
+            self.skip_while(is_ident_char);
+            Kind::Other
+        } else {
+            self.pos += 1;
+            Kind::Punct(c)
+        };
+        let token = Token {
+            kind,
+            start,
+            end: self.pos,
+        };
+        self.prev = Some(token);
+        Some(token)
+    }
+
+    fn skip_while(&mut self, f: impl Fn(u8) -> bool) {
+        while self.pos < self.src.len() && f(self.peek(0)) {
+            self.pos += 1;
+        }
+    }
+
+    fn skip_quoted(&mut self, quote: u8) {
+        while self.pos < self.src.len() {
+            let c = self.peek(0);
+            self.pos += if c == b'\\' { 2 } else { 1 };
+            if c == quote || c == b'\n' {
+                break;
+            }
+        }
+        self.pos = self.pos.min(self.src.len());
+    }
+
+    fn skip_template(&mut self) {
+        while self.pos < self.src.len() {
+            match self.peek(0) {
+                b'\\' => self.pos += 2,
+                b'`' => {
+                    self.pos += 1;
+                    break;
+                }
+                b'$' if self.peek(1) == b'{' => {
+                    self.pos += 2;
+                    let mut depth = 0;
+                    while let Some(token) = self.next_token() {
+                        match token.kind {
+                            Kind::Punct(b'{') => depth += 1,
+                            Kind::Punct(b'}') if depth == 0 => break,
+                            Kind::Punct(b'}') => depth -= 1,
+                            _ => {}
+                        }
+                    }
+                }
+                _ => self.pos += 1,
+            }
+        }
+        self.pos = self.pos.min(self.src.len());
+    }
+
+    fn skip_regex(&mut self) {
+        let mut in_class = false;
+        while self.pos < self.src.len() {
+            let c = self.peek(0);
+            self.pos += if c == b'\\' { 2 } else { 1 };
+            match c {
+                b'[' => in_class = true,
+                b']' => in_class = false,
+                b'/' if !in_class => break,
+                b'\n' => break,
+                _ => {}
+            }
+        }
+        self.pos = self.pos.min(self.src.len());
+    }
+
+    /// Whether a `/` starts a regexp rather than being a division, guessed from the token before.
+    fn regex_allowed(&self) -> bool {
+        let Some(prev) = self.prev else {
+            return true;
+        };
+        match prev.kind {
+            Kind::Punct(c) => !matches!(c, b')' | b']'),
+            Kind::Ident => matches!(
+                &self.src[prev.start..prev.end],
+                b"return"
+                    | b"typeof"
+                    | b"case"
+                    | b"do"
+                    | b"else"
+                    | b"in"
+                    | b"instanceof"
+                    | b"new"
+                    | b"delete"
+                    | b"void"
+                    | b"throw"
+                    | b"yield"
+                    | b"await"
+                    | b"of"
+            ),
+            Kind::Str | Kind::Other => false,
+        }
+    }
+}
+
+fn is_ident_char(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, b'_' | b'$' | b'\\') || c >= 0x80
+}
+
+/// Rewrites the top level imports and, for a module, exports of `src`.
+///
+/// Returns the rewritten source and, for a module, the statements defining its exports, to be run
+/// before the module body. The line numbers of the source are kept.
+fn rewrite(src: &str, is_module: bool) -> Result<(String, String), String> {
+    let mut rewriter = Rewriter {
+        src,
+        tokens: Lexer::tokenize(src),
+        exports: String::new(),
+    };
+    let mut output = String::new();
+    let mut copied = 0;
+    let mut depth = 0usize;
+    let mut i = 0;
+    while i < rewriter.tokens.len() {
+        let token = rewriter.tokens[i];
+        let statement = match token.kind {
+            Kind::Punct(b'{' | b'(' | b'[') => {
+                depth += 1;
+                None
+            }
+            Kind::Punct(b'}' | b')' | b']') => {
+                depth = depth.saturating_sub(1);
+                None
+            }
+            _ if depth > 0 || (i > 0 && rewriter.is_punct(i - 1, b'.')) => None,
+            // `import(...)` and `import.meta` are expressions.
+            _ if rewriter.is_word(i, "import")
+                && !rewriter.is_punct(i + 1, b'(')
+                && !rewriter.is_punct(i + 1, b'.') =>
+            {
+                Some(rewriter.import(i)?)
+            }
+            _ if rewriter.is_word(i, "export") => {
+                if !is_module {
+                    return Err(rewriter.error(i, "export is only valid in modules"));
+                }
+                Some(rewriter.export(i)?)
+            }
+            _ => None,
+        };
+        match statement {
+            Some((end, replacement)) => {
+                let span = token.start..rewriter.tokens[end - 1].end;
+                output += &src[copied..span.start];
+                output += &replacement;
+                output.extend(src[span.clone()].matches('\n'));
+                copied = span.end;
+                i = end;
+            }
+            None => i += 1,
+        }
+    }
+    output += &src[copied..];
+    Ok((output, rewriter.exports))
+}
+
+struct Rewriter<'a> {
+    src: &'a str,
+    tokens: Vec<Token>,
+    exports: String,
+}
+
+/// A top level statement to replace: the index of the token after it, and its replacement.
+type Statement = (usize, String);
+
+impl Rewriter<'_> {
+    fn text(&self, i: usize) -> &str {
+        self.tokens
+            .get(i)
+            .map(|token| &self.src[token.start..token.end])
+            .unwrap_or("")
+    }
+
+    fn kind(&self, i: usize) -> Option<Kind> {
+        self.tokens.get(i).map(|token| token.kind)
+    }
+
+    fn is_punct(&self, i: usize, c: u8) -> bool {
+        self.kind(i) == Some(Kind::Punct(c))
+    }
+
+    fn is_word(&self, i: usize, word: &str) -> bool {
+        self.kind(i) == Some(Kind::Ident) && self.text(i) == word
+    }
+
+    fn error(&self, i: usize, msg: &str) -> String {
+        let pos = self
+            .tokens
+            .get(i)
+            .map_or(self.src.len(), |token| token.start);
+        let line = self.src[..pos].matches('\n').count() + 1;
+        format!("line {line}: {msg}")
+    }
+
+    fn expect_word(&self, i: usize, word: &str) -> Result<(), String> {
+        if self.is_word(i, word) {
+            Ok(())
+        } else {
+            Err(self.error(i, &format!("expected `{word}`")))
+        }
+    }
+
+    fn expect(&self, i: usize, kind: Kind, what: &str) -> Result<&str, String> {
+        if self.kind(i) == Some(kind) {
+            Ok(self.text(i))
+        } else {
+            Err(self.error(i, &format!("expected {what}")))
+        }
+    }
+
+    /// `from "<module>"`, returning the expression importing the module.
+    fn from(&self, i: &mut usize) -> Result<String, String> {
+        self.expect_word(*i, "from")?;
+        let name = self.expect(*i + 1, Kind::Str, "a module name")?;
+        *i += 2;
+        Ok(format!("{REQUIRE}({name})"))
+    }
+
+    fn end(&self, mut i: usize, replacement: String) -> Statement {
+        if self.is_punct(i, b';') {
+            i += 1;
+        }
+        (i, replacement)
+    }
+
+    /// `{ a, b as c, "d" as e }`, returning the (name, alias) pairs.
+    fn specifiers(&self, i: &mut usize) -> Result<Vec<(String, String)>, String> {
+        let mut specifiers = vec![];
+        *i += 1;
+        while !self.is_punct(*i, b'}') {
+            if !matches!(self.kind(*i), Some(Kind::Ident | Kind::Str)) {
+                return Err(self.error(*i, "expected a name"));
+            }
+            let name = self.text(*i).to_string();
+            *i += 1;
+            let mut alias = name.clone();
+            if self.is_word(*i, "as") {
+                if !matches!(self.kind(*i + 1), Some(Kind::Ident | Kind::Str)) {
+                    return Err(self.error(*i + 1, "expected a name"));
+                }
+                alias = self.text(*i + 1).to_string();
+                *i += 2;
+            }
+            specifiers.push((name, alias));
+            if self.is_punct(*i, b',') {
+                *i += 1;
+            } else if !self.is_punct(*i, b'}') {
+                return Err(self.error(*i, "expected `,` or `}`"));
+            }
+        }
+        *i += 1;
+        Ok(specifiers)
+    }
+
+    fn import(&self, start: usize) -> Result<Statement, String> {
+        let mut i = start + 1;
+        if self.kind(i) == Some(Kind::Str) {
+            let replacement = format!("{REQUIRE}({});", self.text(i));
+            return Ok(self.end(i + 1, replacement));
+        }
+        let mut bindings = vec![];
+        let mut namespace = None;
+        let mut more = true;
+        if self.kind(i) == Some(Kind::Ident) {
+            bindings.push(format!("default: {}", self.text(i)));
+            i += 1;
+            more = self.is_punct(i, b',');
+            if more {
+                i += 1;
+            }
+        }
+        if more && self.is_punct(i, b'*') {
+            self.expect_word(i + 1, "as")?;
+            namespace = Some(self.expect(i + 2, Kind::Ident, "a name")?);
+            i += 3;
+        } else if more && self.is_punct(i, b'{') {
+            let start = i;
+            for (name, alias) in self.specifiers(&mut i)? {
+                if alias.starts_with(['"', '\'']) {
+                    return Err(self.error(start, &format!("{alias} is not a valid binding")));
+                }
+                bindings.push(if name == alias {
+                    name
+                } else {
+                    format!("{name}: {alias}")
+                });
+            }
+        } else if more {
+            return Err(self.error(i, "expected `*` or `{`"));
+        }
+        let module = self.from(&mut i)?;
+        let bindings = bindings.join(", ");
+        let replacement = match namespace {
+            Some(ns) if bindings.is_empty() => format!("const {ns} = {module};"),
+            Some(ns) => format!("const {ns} = {module}, {{ {bindings} }} = {ns};"),
+            None => format!("const {{ {bindings} }} = {module};"),
+        };
+        Ok(self.end(i, replacement))
+    }
+
+    fn export_getter(&mut self, name: &str, value: &str) {
+        let key = if name.starts_with(['"', '\'']) {
+            name.to_string()
+        } else {
+            quote(name)
+        };
+        self.exports += &format!(
+            "Object.defineProperty({EXPORTS}, {key}, {{ enumerable: true, get: () => {value} }}); "
+        );
+    }
+
+    fn export(&mut self, start: usize) -> Result<Statement, String> {
+        let mut i = start + 1;
+        match self.kind(i) {
+            _ if self.is_word(i, "default") => {
+                let mut decl = i + 1;
+                if self.is_word(decl, "async") && self.is_word(decl + 1, "function") {
+                    decl += 1;
+                }
+                if self.is_word(decl, "function") || self.is_word(decl, "class") {
+                    let mut name = decl + 1;
+                    if self.is_punct(name, b'*') {
+                        name += 1;
+                    }
+                    if self.kind(name) == Some(Kind::Ident) && !self.is_word(name, "extends") {
+                        let name = self.text(name).to_string();
+                        self.export_getter("default", &name);
+                        return Ok((i + 1, String::new()));
+                    }
+                }
+                Ok((i + 1, format!("{EXPORTS}.default =")))
+            }
+            Some(Kind::Punct(b'*')) => {
+                i += 1;
+                let namespace = if self.is_word(i, "as") {
+                    if !matches!(self.kind(i + 1), Some(Kind::Ident | Kind::Str)) {
+                        return Err(self.error(i + 1, "expected a name"));
+                    }
+                    i += 2;
+                    Some(self.text(i - 1).to_string())
+                } else {
+                    None
+                };
+                let module = self.from(&mut i)?;
+                let replacement = match namespace {
+                    Some(ns) => {
+                        self.export_getter(&ns, &module);
+                        format!("{module};")
+                    }
+                    None => format!(
+                        "((m) => {{ for (const k of Object.keys(m)) {{ if (k !== \"default\" && \
+                         !(k in {EXPORTS})) Object.defineProperty({EXPORTS}, k, \
+                         {{ enumerable: true, get: () => m[k] }}); }} }})({module});"
+                    ),
+                };
+                Ok(self.end(i, replacement))
+            }
+            Some(Kind::Punct(b'{')) => {
+                let specifiers = self.specifiers(&mut i)?;
+                if self.is_word(i, "from") {
+                    let module = self.from(&mut i)?;
+                    for (name, alias) in specifiers {
+                        let key = if name.starts_with(['"', '\'']) {
+                            name
+                        } else {
+                            quote(&name)
+                        };
+                        self.export_getter(&alias, &format!("{module}[{key}]"));
+                    }
+                    Ok(self.end(i, format!("{module};")))
+                } else {
+                    for (name, alias) in specifiers {
+                        if name.starts_with(['"', '\'']) {
+                            return Err(self.error(start, &format!("{name} is not a local name")));
+                        }
+                        self.export_getter(&alias, &name);
+                    }
+                    Ok(self.end(i, String::new()))
+                }
+            }
+            _ if self.is_word(i, "function")
+                || self.is_word(i, "class")
+                || (self.is_word(i, "async") && self.is_word(i + 1, "function")) =>
+            {
+                let mut name = i + 1 + usize::from(self.is_word(i, "async"));
+                if self.is_punct(name, b'*') {
+                    name += 1;
+                }
+                let name = self.expect(name, Kind::Ident, "a name")?.to_string();
+                self.export_getter(&name, &name);
+                // Only `export` goes, the declaration stays in place.
+                Ok((i, String::new()))
+            }
+            _ if self.is_word(i, "const") || self.is_word(i, "let") || self.is_word(i, "var") => {
+                for name in self.declared_names(i + 1)? {
+                    self.export_getter(&name, &name);
+                }
+                Ok((i, String::new()))
+            }
+            _ => Err(self.error(i, "unsupported export")),
+        }
+    }
+
+    /// The names declared by `a = 1, b = f(x, y)`, up to the end of the statement.
+    fn declared_names(&self, mut i: usize) -> Result<Vec<String>, String> {
+        let mut names = vec![];
+        let mut depth = 0usize;
+        let mut expect_name = true;
+        while let Some(kind) = self.kind(i) {
+            if expect_name {
+                if kind != Kind::Ident {
+                    return Err(self.error(i, "only plain names can be exported, not patterns"));
+                }
+                names.push(self.text(i).to_string());
+                expect_name = false;
+                i += 1;
+                continue;
+            }
+            let ends_line = self.src[self.tokens[i - 1].end..self.tokens[i].start].contains('\n');
+            match kind {
+                Kind::Punct(b'{' | b'(' | b'[') => depth += 1,
+                Kind::Punct(b'}' | b')' | b']') if depth == 0 => break,
+                Kind::Punct(b'}' | b')' | b']') => depth -= 1,
+                Kind::Punct(b';') if depth == 0 => break,
+                Kind::Punct(b',') if depth == 0 => expect_name = true,
+                _ if depth == 0
+                    && ends_line
+                    && !matches!(self.kind(i - 1), Some(Kind::Punct(_))) =>
+                {
+                    break
+                }
+                _ => {}
+            }
+            i += 1;
+        }
+        Ok(names)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn imports_and_exports_are_rewritten_in_place() {
+        let (main, _) = rewrite(
+            "import a, { b as c, d } from \"m\";\nimport * as ns from 'n'\nimport \"side\";\n\
+             const x = import(\"dyn\"); // import { no } from \"comment\"\n`${ f({ import: 1 }) }`",
+            false,
+        )
+        .unwrap();
+        assert_eq!(
+            main,
+            "const { default: a, b: c, d } = __pinkRequire(\"m\");\n\
+             const ns = __pinkRequire('n');\n__pinkRequire(\"side\");\n\
+             const x = import(\"dyn\"); // import { no } from \"comment\"\n`${ f({ import: 1 }) }`"
+        );
+
+        let (body, exports) = rewrite(
+            "export function f() {}\nexport const a = 1, b = g(1, 2);\nexport { a as c };\n\
+             export default 42;",
+            true,
+        )
+        .unwrap();
+        assert_eq!(
+            body,
+            " function f() {}\n const a = 1, b = g(1, 2);\n\n__pinkExports.default = 42;"
+        );
+        for name in ["\"f\"", "\"a\"", "\"b\"", "\"c\""] {
+            assert!(
+                exports.contains(&format!("__pinkExports, {name}")),
+                "{exports}"
+            );
+        }
+
+        assert!(rewrite("export const a = 1;", false).is_err());
+        assert!(rewrite("export const { a } = o;", true).is_err());
+    }
+
+    #[test]
+    fn main_script_calls_a_function_imported_from_a_module() {
+        use crate::contracts::block_on_run_module;
+        use pink_extension::chain_extension::JsValue;
+        use sidevm::ChainHead;
+        use std::time::Duration;
+
+        let path = concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/../../e2e/res/prebuilt/phatjs.wasm"
+        );
+        let code = std::fs::read(path).unwrap();
+        let module = sidevm::WasmEngine::with_instruction_counter()
+            .compile(&code)
+            .unwrap();
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let _guard = rt.enter();
+        let (event_tx, _event_rx) = tokio::sync::mpsc::channel(1);
+        let chain_head = ChainHead {
+            block_number: 1,
+            now_ms: 0,
+        };
+        let run = |codes: Vec<JsCode>| {
+            let mut args = vec!["phatjs".to_string()];
+            for code in bundle(codes).unwrap() {
+                let JsCode::Source(src) = code else {
+                    unreachable!("only sources here");
+                };
+                args.extend(["-c".into(), src]);
+            }
+            args.push("--".into());
+            block_on_run_module(
+                [0; 32],
+                &module,
+                args,
+                Duration::from_secs(30),
+                None,
+                event_tx.clone(),
+                chain_head,
+                |_, _, _| {},
+            )
+            .unwrap()
+        };
+        let modules = JsCode::Modules(vec![
+            (
+                "math".into(),
+                "import { twice } from \"util\";\n\
+                 export function quad(x) { return twice(twice(x)); }"
+                    .into(),
+            ),
+            ("util".into(), "export const twice = (x) => x * 2;".into()),
+        ]);
+        let main = "import { quad } from \"math\";\nscriptOutput = quad(3);";
+        assert_eq!(
+            run(vec![modules, JsCode::Source(main.into())]),
+            JsValue::Other("12".into())
+        );
+
+        let modules = JsCode::Modules(vec![("util".into(), "export const a = 1;".into())]);
+        let main = "import { readFileSync } from \"fs\";";
+        let JsValue::Exception(err) = run(vec![modules, JsCode::Source(main.into())]) else {
+            panic!("importing a missing module should fail");
+        };
+        assert!(err.contains("could not load module 'fs'"), "{err}");
+    }
+}
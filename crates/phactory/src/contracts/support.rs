@@ -5,13 +5,13 @@ use pink_extension::{chain_extension::JsValue, SidevmConfig};
 use serde::{Deserialize, Serialize};
 use std::sync::{mpsc, Arc, Mutex};
 
-use parity_scale_codec::Decode;
+use parity_scale_codec::{Decode, Encode};
 use phala_mq::SignedMessageChannel;
 use phala_scheduler::RequestScheduler;
 use runtime::BlockNumber;
 use sidevm::{
     service::{Command as SidevmCommand, CommandSender, ExitReason},
-    OcallAborted, OutgoingRequestChannel, ShortId, VmId, WasmInstanceConfig, WasmModule,
+    ChainHead, OcallAborted, OutgoingRequestChannel, ShortId, VmId, WasmInstanceConfig, WasmModule,
 };
 
 use super::pink::Cluster;
@@ -128,6 +128,9 @@ pub struct SidevmInfo {
     handle: Arc<Mutex<SidevmHandle>>,
     #[serde(default)]
     pub config: SidevmConfig,
+    /// The contract storage keys granted to the instance when it was deployed.
+    #[serde(default)]
+    readable_keys: Vec<Vec<u8>>,
 }
 
 pub(crate) enum SidevmCode {
@@ -148,6 +151,9 @@ pub struct Contract {
     pub sidevm_info: Option<SidevmInfo>,
     weight: u32,
     on_block_end: Option<OnBlockEnd>,
+    /// The storage keys granted to the next deployed sidevm instance.
+    #[serde(default)]
+    sidevm_readable_keys: Vec<Vec<u8>>,
 }
 
 #[derive(Copy, Clone, Serialize, Deserialize, ::scale_info::TypeInfo)]
@@ -173,6 +179,7 @@ impl Contract {
             sidevm_info: None,
             weight: 0,
             on_block_end: None,
+            sidevm_readable_keys: vec![],
         }
     }
 
@@ -250,6 +257,31 @@ impl Contract {
         });
     }
 
+    pub(crate) fn set_sidevm_readable_keys(&mut self, keys: Vec<Vec<u8>>) {
+        self.sidevm_readable_keys = keys;
+    }
+
+    /// Whether the sidevm instance is allowed to read the given storage key of the contract.
+    pub(crate) fn sidevm_can_read(&self, key: &[u8]) -> bool {
+        self.sidevm_info
+            .as_ref()
+            .map(|info| info.readable_keys.iter().any(|k| k == key))
+            .unwrap_or(false)
+    }
+
+    /// Read a storage key of the contract for its sidevm instance with `read`, if the key is
+    /// granted to the instance.
+    pub(crate) fn sidevm_read_storage(
+        &self,
+        key: Vec<u8>,
+        read: impl FnOnce(Vec<u8>) -> Result<Option<Vec<u8>>, sidevm::QueryError>,
+    ) -> Result<Option<Vec<u8>>, sidevm::QueryError> {
+        if !self.sidevm_can_read(&key) {
+            return Err(sidevm::QueryError::PermissionDenied);
+        }
+        read(key)
+    }
+
     pub(crate) fn start_sidevm(
         &mut self,
         spawner: &sidevm::service::Spawner,
@@ -327,6 +359,7 @@ impl Contract {
             handle,
             auto_restart: true,
             config,
+            readable_keys: self.sidevm_readable_keys.clone(),
         });
         Ok(())
     }
@@ -349,10 +382,16 @@ impl Contract {
                     // system works or not is not clear ATM.
                     ExitReason::OcallAborted(OcallAborted::GasExhausted) => false,
                     ExitReason::OcallAborted(OcallAborted::Stifled) => true,
+                    ExitReason::OcallAborted(OcallAborted::CpuBudgetExceeded) => false,
+                    ExitReason::OcallAborted(OcallAborted::InstructionLimitExceeded) => false,
+                    ExitReason::OcallAborted(OcallAborted::MemoryCeilingReached) => false,
                     ExitReason::Restore => true,
                     ExitReason::WaitingForCode => false,
                     ExitReason::CodeTooLarge => false,
                     ExitReason::FailedToStart => false,
+                    ExitReason::WarmupTimeout => false,
+                    ExitReason::ShutdownTimedOut => false,
+                    ExitReason::Evicted => false,
                 };
                 if !need_restart {
                     return Ok(());
@@ -565,20 +604,89 @@ fn local_cache_ops() -> sidevm::DynCacheOps {
         fn remove(&self, contract: &[u8], key: &[u8]) -> OpResult<Option<Vec<u8>>> {
             Ok(cache::remove(contract, key))
         }
+
+        fn get_versioned(&self, contract: &[u8], key: &[u8]) -> OpResult<Option<(Vec<u8>, u64)>> {
+            Ok(cache::get_versioned(contract, key))
+        }
+
+        fn set_if_version(
+            &self,
+            contract: &[u8],
+            key: &[u8],
+            value: &[u8],
+            expected_version: u64,
+        ) -> OpResult<u64> {
+            cache::set_if_version(contract, key, value, expected_version).map_err(|err| match err {
+                cache::VersionedSetError::VersionConflict => sidevm::OcallError::VersionConflict,
+                cache::VersionedSetError::StorageQuotaExceeded => {
+                    sidevm::OcallError::ResourceLimited
+                }
+            })
+        }
+
+        fn set_with_ttl(
+            &self,
+            contract: &[u8],
+            key: &[u8],
+            value: &[u8],
+            ttl_secs: u64,
+        ) -> OpResult<()> {
+            cache::set_with_ttl(contract, key, value, ttl_secs)
+                .map_err(|_| sidevm::OcallError::ResourceLimited)
+        }
+
+        fn set_content(&self, contract: &[u8], key: &[u8], value: &[u8]) -> OpResult<[u8; 32]> {
+            cache::set_content(contract, key, value)
+                .map_err(|_| sidevm::OcallError::ResourceLimited)
+        }
+
+        fn set_by_hash(&self, contract: &[u8], hash: &[u8; 32], value: &[u8]) -> OpResult<()> {
+            cache::set_by_hash(contract, hash, value).map_err(|err| match err {
+                cache::ContentSetError::HashMismatch => sidevm::OcallError::InvalidParameter,
+                cache::ContentSetError::StorageQuotaExceeded => sidevm::OcallError::ResourceLimited,
+            })
+        }
+
+        fn get_by_hash(&self, contract: &[u8], hash: &[u8; 32]) -> OpResult<Option<Vec<u8>>> {
+            Ok(cache::get_by_hash(contract, hash))
+        }
+
+        fn get_batch(&self, contract: &[u8], keys: &[Vec<u8>]) -> OpResult<Vec<Option<Vec<u8>>>> {
+            Ok(cache::get_batch(contract, keys))
+        }
+
+        fn set_batch(&self, contract: &[u8], pairs: &[(Vec<u8>, Vec<u8>)]) -> OpResult<Vec<bool>> {
+            Ok(cache::set_batch(contract, pairs))
+        }
+
+        fn usage(&self, contract: &[u8]) -> Option<usize> {
+            cache::usage(contract)
+        }
     }
     &CacheOps
 }
 
+/// The gas a poll of the js runtime can burn in about 1 second, tested with md5 calculation.
+const GAS_PER_SECOND: u64 = 100_000_000_000;
+
+/// Run the module to its output, returning `JsValue::Timeout` if it goes over the timeout or the
+/// max number of instructions, in which case the module must count its instructions.
+#[allow(clippy::too_many_arguments)]
 #[instrument(skip_all, fields(id=%ShortId(id)), name = "run")]
 pub fn block_on_run_module(
     id: VmId,
     module: &WasmModule,
     args: Vec<String>,
     timeout: Duration,
+    max_instructions: Option<u64>,
     sidevm_event_tx: OutgoingRequestChannel,
+    chain_head: ChainHead,
     log_handler: impl Fn(VmId, u8, String),
 ) -> Result<JsValue> {
     info!("Run wasm module timeout={}ms", timeout.as_millis());
+    // The timer can't fire while a poll is running, so a poll isn't given more gas than it can
+    // burn within the timeout, which traps a busy loop in time.
+    let gas_per_breath = (timeout.as_millis().min(1000) as u64).max(1) * (GAS_PER_SECOND / 1000);
     enum OutMsg {
         Log(u8, String),
         Output(Vec<u8>),
@@ -592,8 +700,8 @@ pub fn block_on_run_module(
     let (event_tx, mut event_rx) = tokio::sync::mpsc::channel(1);
     let tx_for_logging = output_tx.clone();
     let config = WasmInstanceConfig {
-        max_memory_pages: 256,           // 16MB
-        gas_per_breath: 100_000_000_000, // About 1 second tested with md5 calculation
+        max_memory_pages: 256, // 16MB
+        gas_per_breath,
         cache_ops: local_cache_ops(),
         scheduler: None,
         weight: 0,
@@ -604,6 +712,28 @@ pub fn block_on_run_module(
                 error!("Failed to send log message to response channel: {}", err);
             }
         })),
+        log_sink: None,
+        // The result must be deterministic, so pin the chain head to the executing block.
+        pinned_chain_head: Some(chain_head),
+        fuel_policy: None,
+        log_limit: None,
+        pubsub_namespaces: vec![],
+        shared_cache: None,
+        on_fuel_exhausted: None,
+        identity: None,
+        soft_memory_pages: None,
+        tls_client_identity: None,
+        session_limits: Default::default(),
+        outbound_limits: Default::default(),
+        body_limits: Default::default(),
+        dns: Default::default(),
+        tls_roots: None,
+        clock: None,
+        cpu_budget: None,
+        snapshot: None,
+        max_instructions,
+        memory_pool: None,
+        memory_priority: 0,
     };
     let (mut wasm_run, _env) = module
         .run(args, config)
@@ -633,6 +763,13 @@ pub fn block_on_run_module(
                         }
                         true
                     }
+                    sidevm::OutgoingRequest::ReadStorage { reply_tx, .. } => {
+                        // The module is not bound to a contract, so there is nothing to read.
+                        let denied: Result<Option<Vec<u8>>, _> =
+                            Err(sidevm::QueryError::PermissionDenied);
+                        _ = reply_tx.send(denied.encode());
+                        false
+                    }
                 }
             }
             tokio::select! {
@@ -647,7 +784,14 @@ pub fn block_on_run_module(
                 rv = &mut wasm_run => {
                     if let Err(err) = rv {
                         error!(target: "sidevm", ?err, "Js runtime exited with error.");
-                        if let Err(err) = output_tx.send(OutMsg::Error(err.into())) {
+                        let msg = match err.downcast::<OcallAborted>() {
+                            Ok(OcallAborted::Stifled | OcallAborted::InstructionLimitExceeded) => {
+                                OutMsg::Timeout
+                            }
+                            Ok(aborted) => OutMsg::Error(anyhow!("{aborted}")),
+                            Err(err) => OutMsg::Error(err.into()),
+                        };
+                        if let Err(err) = output_tx.send(msg) {
                             error!("Failed to send error message to response channel: {}", err);
                         }
                     }
@@ -686,7 +830,7 @@ pub fn block_on_run_module(
                 return Err(anyhow!("Sidevm terminated without output"));
             }
             Ok(OutMsg::Timeout) => {
-                return Err(anyhow!("Sidevm execution timeout"));
+                return Ok(JsValue::Timeout("Sidevm execution timeout".into()));
             }
             Ok(OutMsg::Error(err)) => {
                 return Err(err);
@@ -700,3 +844,119 @@ pub fn block_on_run_module(
 
 pub use keeper::*;
 mod keeper;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    #[test]
+    fn endless_js_loop_times_out() {
+        let path = concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/../../e2e/res/prebuilt/phatjs.wasm"
+        );
+        let code = std::fs::read(path).unwrap();
+        let module = sidevm::WasmEngine::with_instruction_counter()
+            .compile(&code)
+            .unwrap();
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let _guard = rt.enter();
+        let (event_tx, _event_rx) = tokio::sync::mpsc::channel(1);
+        let chain_head = ChainHead {
+            block_number: 1,
+            now_ms: 0,
+        };
+        let run = |timeout, max_instructions| {
+            let args = ["phatjs", "-c", "while(true){}", "--"].map(String::from);
+            block_on_run_module(
+                [0; 32],
+                &module,
+                args.to_vec(),
+                timeout,
+                max_instructions,
+                event_tx.clone(),
+                chain_head,
+                |_, _, _| {},
+            )
+            .unwrap()
+        };
+
+        let budget = Duration::from_millis(500);
+        let start = Instant::now();
+        assert!(matches!(run(budget, None), JsValue::Timeout(_)));
+        // The gas of a poll is calibrated roughly, so leave some room for slow machines.
+        assert!(start.elapsed() < budget * 2, "took {:?}", start.elapsed());
+
+        let start = Instant::now();
+        let value = run(Duration::from_secs(30), Some(10_000_000));
+        assert!(matches!(value, JsValue::Timeout(_)));
+        assert!(start.elapsed() < Duration::from_secs(30));
+    }
+
+    fn new_contract() -> Contract {
+        use phala_crypto::sr25519::KDF;
+        use sp_core::Pair;
+
+        let key = sp_core::sr25519::Pair::from_seed(&[1; 32]);
+        let ecdh_key = key.derive_ecdh_key().unwrap();
+        let send_mq = phala_mq::MessageSendQueue::new();
+        let mut recv_mq = phala_mq::MessageDispatcher::new();
+        let sender = phala_mq::MessageOrigin::Contract(Default::default());
+        let cmd_mq = SecretReceiver::new_secret(
+            recv_mq.subscribe(b"commands".to_vec()).into(),
+            ecdh_key.clone(),
+        );
+        Contract::new(
+            send_mq.channel(sender, key.into()),
+            cmd_mq,
+            ecdh_key,
+            Default::default(),
+            AccountId::new([1; 32]),
+        )
+    }
+
+    #[test]
+    fn sidevm_reads_only_the_granted_keys() {
+        let storage: std::collections::BTreeMap<_, _> = [
+            (b"granted".to_vec(), b"shared".to_vec()),
+            (b"other".to_vec(), b"private".to_vec()),
+        ]
+        .into();
+        let read = |key: Vec<u8>| Ok(storage.get(&key).cloned());
+        let denied = |result: Result<Option<Vec<u8>>, sidevm::QueryError>| {
+            matches!(result, Err(sidevm::QueryError::PermissionDenied))
+        };
+
+        let mut contract = new_contract();
+        contract.set_sidevm_readable_keys(vec![b"granted".to_vec()]);
+        // The keys are granted to the instance deployed next, there is none yet.
+        assert!(denied(
+            contract.sidevm_read_storage(b"granted".to_vec(), read)
+        ));
+
+        let (out_tx, _out_rx) = tokio::sync::mpsc::channel(1);
+        let (_service, spawner) = sidevm::service::service(1, out_tx);
+        // Without the code the instance waits for it to be uploaded, which is enough here.
+        contract
+            .start_sidevm(
+                &spawner,
+                SidevmCode::Hash(H256::zero()),
+                false,
+                SidevmConfig::default(),
+            )
+            .unwrap();
+        assert_eq!(
+            contract
+                .sidevm_read_storage(b"granted".to_vec(), read)
+                .unwrap(),
+            Some(b"shared".to_vec())
+        );
+        assert!(denied(
+            contract.sidevm_read_storage(b"other".to_vec(), read)
+        ));
+        assert!(denied(
+            contract.sidevm_read_storage(b"missing".to_vec(), read)
+        ));
+    }
+}
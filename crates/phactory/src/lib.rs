@@ -420,11 +420,14 @@ impl<Platform: pal::Platform> Phactory<Platform> {
         }
 
         self.can_load_chain_state = !system::gk_master_key_exists(&args.sealing_path);
-        self.args = Arc::new(args);
+        self.set_args(args);
         self.query_scheduler = create_query_scheduler(self.args.cores);
     }
 
     pub fn set_args(&mut self, args: InitArgs) {
+        if let Some(max) = args.max_http_response_header_bytes {
+            pink_extension_runtime::set_max_response_header_bytes(max as usize);
+        }
         self.args = Arc::new(args);
         if let Some(system) = &mut self.system {
             system.sealing_path = self.args.sealing_path.clone();
@@ -541,6 +544,19 @@ impl<Platform: pal::Platform> Phactory<Platform> {
         reconfig_one("all_proxy", &config.all_proxy);
         reconfig_one("i2p_proxy", &config.i2p_proxy);
     }
+
+    /// Derive the identity keys of the sidevm instances from the identity key of the worker.
+    fn configure_sidevm_identity(&mut self) {
+        let Some(system) = &self.system else {
+            return;
+        };
+        let derived = system
+            .identity_key
+            .derive_sr25519_pair(&[b"sidevm_identity"])
+            .expect("should not fail with valid info");
+        let secret = blake2_256(&derived.dump_secret_key());
+        self.sidevm_spawner = self.sidevm_spawner.clone().with_identity_secret(secret);
+    }
 }
 
 impl<P: pal::Platform> Phactory<P> {
@@ -551,6 +567,7 @@ impl<P: pal::Platform> Phactory<P> {
         self.update_runtime_info(|_| {});
         self.trusted_sk =
             Self::load_runtime_data(&self.platform, &self.args.sealing_path)?.trusted_sk;
+        self.configure_sidevm_identity();
         if let Some(system) = &mut self.system {
             system.on_restored(self.args.safe_mode_level, &self.sidevm_spawner)?;
         }
@@ -620,13 +637,20 @@ impl<P: pal::Platform> Phactory<P> {
         request: sidevm::OutgoingRequest,
         weak_phactory: Weak<Mutex<Phactory<P>>>,
     ) -> Option<impl Future<Output = ()>> {
-        let sidevm::OutgoingRequest::Query {
-            contract_id,
-            payload,
-            reply_tx,
-        } = request
-        else {
-            return None;
+        let (contract_id, payload, reply_tx) = match request {
+            sidevm::OutgoingRequest::Query {
+                contract_id,
+                payload,
+                reply_tx,
+            } => (contract_id, payload, reply_tx),
+            sidevm::OutgoingRequest::ReadStorage { key, reply_tx } => {
+                let result = self.read_contract_storage_for_sidevm(from, key);
+                if reply_tx.send(result.encode()).is_err() {
+                    error!("Failed to send sidevm storage read reply");
+                }
+                return None;
+            }
+            sidevm::OutgoingRequest::Output(_) => return None,
         };
         let query_scheduler = self.query_scheduler.clone();
         let mut derived_from = from.to_vec();
@@ -694,6 +718,26 @@ impl<P: pal::Platform> Phactory<P> {
             }
         })
     }
+
+    fn read_contract_storage_for_sidevm(
+        &self,
+        from: [u8; 32],
+        key: Vec<u8>,
+    ) -> Result<Option<Vec<u8>>, SidevmQueryError> {
+        let system = self.system.as_ref().expect("system always exists here");
+        let address = AccountId::new(from);
+        let contract = system
+            .contracts
+            .get(&address)
+            .ok_or(SidevmQueryError::ContractNotFound)?;
+        contract.sidevm_read_storage(key, |key| {
+            let cluster = system
+                .contract_cluster
+                .as_ref()
+                .ok_or(SidevmQueryError::ContractNotFound)?;
+            Ok(cluster.contract_storage_get(&address, key))
+        })
+    }
 }
 
 fn opaque_to_sidevm_err(err: ContractQueryError) -> SidevmQueryError {
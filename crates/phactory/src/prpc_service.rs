@@ -512,6 +512,7 @@ impl<Platform: pal::Platform + Serialize + DeserializeOwned> Phactory<Platform>
         self.runtime_info = Some(resp.clone());
         self.runtime_state = Some(runtime_state);
         self.system = Some(system);
+        self.configure_sidevm_identity();
         Ok(resp)
     }
 
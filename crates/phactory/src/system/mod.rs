@@ -64,11 +64,14 @@ use pink::types::{HookPoint, PinkEvent};
 use pink_extension::{SidevmOperation, Workers};
 use std::convert::TryFrom;
 use std::future::Future;
+use std::time::Duration;
 use tracing::{error, info};
 
 pub type TransactionResult = Result<Option<pink::types::ExecSideEffects>, TransactionError>;
 
 pub(crate) const MAX_SUPPORTED_CONSENSUS_VERSION: u32 = 0;
+/// Longest time a contract can give its sidevm instance to exit before it is stopped forcibly.
+const MAX_SIDEVM_SHUTDOWN_TIMEOUT_MS: u64 = 60_000;
 
 #[derive(Encode, Decode, Debug, Clone, thiserror::Error)]
 #[error("TransactionError: {:?}", self)]
@@ -701,6 +704,10 @@ impl<Platform: pal::Platform> System<Platform> {
     }
 
     pub fn did_process_block(&mut self, block: &mut BlockInfo) {
+        sidevm::set_chain_head(sidevm::ChainHead {
+            block_number: block.block_number,
+            now_ms: block.now_ms,
+        });
         if let Some(gatekeeper) = &mut self.gatekeeper {
             gatekeeper.did_process_block(block);
         }
@@ -1815,6 +1822,17 @@ pub(crate) fn apply_pink_events(
                     error!(target: "sidevm", %vmid, ?err, "Push message to sidevm failed");
                 }
             }
+            PinkEvent::ShutdownSidevm { timeout_ms } => {
+                let vmid = sidevm::ShortId(&origin);
+                let contract = get_contract!(&origin);
+                let timeout_ms = timeout_ms.min(MAX_SIDEVM_SHUTDOWN_TIMEOUT_MS);
+                let timeout = Duration::from_millis(timeout_ms);
+                if let Err(err) =
+                    contract.push_message_to_sidevm(SidevmCommand::Shutdown { timeout })
+                {
+                    error!(target: "sidevm", %vmid, ?err, "Push message to sidevm failed");
+                }
+            }
             PinkEvent::ForceStopSidevm {
                 contract: target_contract,
             } => {
@@ -1885,6 +1903,21 @@ pub(crate) fn apply_pink_events(
                     }
                 }
             }
+            PinkEvent::SetSidevmReadableStorage(keys) => {
+                let vmid = sidevm::ShortId(&origin);
+                if keys.len() > pink_extension::MAX_SIDEVM_READABLE_KEYS {
+                    error!(
+                        target: "sidevm",
+                        %vmid,
+                        "Too many readable storage keys: {}",
+                        keys.len()
+                    );
+                    continue;
+                }
+                let contract = get_contract!(&origin);
+                info!(target: "sidevm", %vmid, "Granted {} readable storage keys", keys.len());
+                contract.set_sidevm_readable_keys(keys);
+            }
             PinkEvent::SetJsRuntime(code_hash) => {
                 ensure_system!();
                 info!("Set JsRuntime to 0x{}", hex_fmt::HexFmt(&code_hash));
@@ -1,9 +1,95 @@
+use std::fmt;
 use std::ptr::NonNull;
+use std::sync::Arc;
 
 use wasmer::{
     vm::{self, MemoryError, MemoryStyle, TableStyle, VMMemoryDefinition, VMTableDefinition},
     MemoryType, Pages, TableType, Tunables,
 };
+use wasmer_vm::LinearMemory;
+
+/// Decides whether the memories created by the tunables can take more pages, e.g. to cap the
+/// memory of many instances together.
+pub trait MemoryGrowGuard: Send + Sync + 'static {
+    /// Take `delta` more pages for a memory of `current` pages. The memory is not created or grown
+    /// if it returns false.
+    fn reserve(&self, current: Pages, delta: Pages) -> bool;
+    /// Give back the pages of a memory dropped or failed to grow.
+    fn release(&self, pages: Pages);
+}
+
+/// A memory whose pages are taken from a [`MemoryGrowGuard`].
+struct GuardedMemory {
+    inner: vm::VMMemory,
+    guard: Arc<dyn MemoryGrowGuard>,
+}
+
+impl GuardedMemory {
+    fn new(inner: vm::VMMemory, guard: Arc<dyn MemoryGrowGuard>) -> Result<Self, MemoryError> {
+        if !guard.reserve(Pages(0), inner.size()) {
+            return Err(MemoryError::Generic(
+                "Not enough memory left to the instances".to_string(),
+            ));
+        }
+        Ok(Self { inner, guard })
+    }
+}
+
+impl fmt::Debug for GuardedMemory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GuardedMemory")
+            .field("inner", &self.inner)
+            .finish()
+    }
+}
+
+impl LinearMemory for GuardedMemory {
+    fn ty(&self) -> MemoryType {
+        self.inner.ty()
+    }
+
+    fn size(&self) -> Pages {
+        self.inner.size()
+    }
+
+    fn style(&self) -> MemoryStyle {
+        self.inner.style()
+    }
+
+    fn grow(&mut self, delta: Pages) -> Result<Pages, MemoryError> {
+        if !self.guard.reserve(self.inner.size(), delta) {
+            return Err(MemoryError::CouldNotGrow {
+                current: self.inner.size(),
+                attempted_delta: delta,
+            });
+        }
+        let result = self.inner.grow(delta);
+        if result.is_err() {
+            self.guard.release(delta);
+        }
+        result
+    }
+
+    fn vmmemory(&self) -> NonNull<VMMemoryDefinition> {
+        self.inner.vmmemory()
+    }
+
+    fn try_clone(&self) -> Option<Box<dyn LinearMemory + 'static>> {
+        // A clone would share the pages, which are taken from the guard only once.
+        None
+    }
+
+    fn duplicate(&mut self) -> Result<Box<dyn LinearMemory + 'static>, MemoryError> {
+        let inner = vm::VMMemory(self.inner.duplicate()?);
+        Ok(Box::new(GuardedMemory::new(inner, self.guard.clone())?))
+    }
+}
+
+impl Drop for GuardedMemory {
+    fn drop(&mut self) {
+        self.guard.release(self.inner.size());
+    }
+}
 
 /// A custom tunables that allows you to set a memory limit.
 ///
@@ -16,11 +102,33 @@ pub struct LimitingTunables<T: Tunables> {
     limit: Pages,
     /// The base implementation we delegate all the logic to
     base: T,
+    /// Takes the pages of the memories created, if any.
+    grow_guard: Option<Arc<dyn MemoryGrowGuard>>,
 }
 
 impl<T: Tunables> LimitingTunables<T> {
     pub fn new(base: T, limit: Pages) -> Self {
-        Self { limit, base }
+        Self {
+            limit,
+            base,
+            grow_guard: None,
+        }
+    }
+
+    /// Take the pages of the memories created from the guard, on top of the limit of each.
+    pub fn with_grow_guard(mut self, guard: Arc<dyn MemoryGrowGuard>) -> Self {
+        self.grow_guard = Some(guard);
+        self
+    }
+
+    fn guard_memory(&self, memory: vm::VMMemory) -> Result<vm::VMMemory, MemoryError> {
+        match &self.grow_guard {
+            Some(guard) => {
+                let memory = GuardedMemory::new(memory, guard.clone())?;
+                Ok(vm::VMMemory(Box::new(memory)))
+            }
+            None => Ok(memory),
+        }
     }
 
     /// Takes an input memory type as requested by the guest and sets
@@ -84,12 +192,12 @@ impl<T: Tunables> Tunables for LimitingTunables<T> {
     ) -> Result<vm::VMMemory, MemoryError> {
         let adjusted = self.adjust_memory(ty);
         self.validate_memory(&adjusted)?;
-        self.base.create_host_memory(&adjusted, style)
+        self.guard_memory(self.base.create_host_memory(&adjusted, style)?)
     }
 
     /// Create a memory owned by the VM given a [`MemoryType`] and a [`MemoryStyle`].
     ///
-    /// Delegated to base.
+    /// Delegated to base, then guarded by the grow guard if any.
     unsafe fn create_vm_memory(
         &self,
         ty: &MemoryType,
@@ -98,8 +206,10 @@ impl<T: Tunables> Tunables for LimitingTunables<T> {
     ) -> Result<vm::VMMemory, MemoryError> {
         let adjusted = self.adjust_memory(ty);
         self.validate_memory(&adjusted)?;
-        self.base
-            .create_vm_memory(&adjusted, style, vm_definition_location)
+        let memory = self
+            .base
+            .create_vm_memory(&adjusted, style, vm_definition_location)?;
+        self.guard_memory(memory)
     }
 
     /// Create a table owned by the host given a [`TableType`] and a [`TableStyle`].
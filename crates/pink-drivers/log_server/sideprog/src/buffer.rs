@@ -193,6 +193,7 @@ impl From<SystemMessage> for SerMessage {
             SystemMessage::Metric(Metric::PinkQueryIn(user)) => {
                 Self::QueryIn { user: HexSer(user) }
             }
+            SystemMessage::Shutdown { .. } => unreachable!("the server exits on shutdown"),
         }
     }
 }
@@ -207,6 +208,7 @@ fn contract_id_of(sysmessage: &SystemMessage) -> String {
         SystemMessage::PinkEvent { contract, .. } => contract,
         SystemMessage::PinkMessageOutput { contract, .. } => contract,
         SystemMessage::Metric(_) => return "<metric>".into(),
+        SystemMessage::Shutdown { .. } => return "<shutdown>".into(),
     };
     hex(id)
 }
@@ -217,6 +219,7 @@ fn entry_of(sysmessage: &SystemMessage) -> String {
         SystemMessage::PinkEvent { contract, .. } => contract,
         SystemMessage::PinkMessageOutput { contract, .. } => contract,
         SystemMessage::Metric(_) => return "<metric>".into(),
+        SystemMessage::Shutdown { .. } => return "<shutdown>".into(),
     };
     hex(id)
 }
@@ -4,7 +4,7 @@ use std::rc::Rc;
 use log::{error, info};
 use scale::Decode;
 
-use sidevm::env::ocall_funcs_guest::local_cache_get;
+use sidevm::env::{messages::SystemMessage, ocall_funcs_guest::local_cache_get};
 
 use buffer::Buffer;
 mod buffer;
@@ -105,6 +105,10 @@ async fn main() {
                 info!("Input message channel closed");
                 break;
             }
+            Some(Ok(SystemMessage::Shutdown { .. })) => {
+                info!("Shutting down");
+                break;
+            }
             Some(Ok(message)) => message,
             Some(Err(e)) => {
                 error!("Decode system message failed: {}", e);
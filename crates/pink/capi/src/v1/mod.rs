@@ -180,18 +180,23 @@ pub mod ecall {
         /// Would be called once per block.
         #[xcall(id = 24, since = "1.2")]
         fn on_idle(&mut self, block_number: BlockNumber);
+
+        /// Returns the raw value of the given storage key of a contract.
+        #[xcall(id = 25, since = "1.2")]
+        fn contract_storage_get(&self, contract: AccountId, key: Vec<u8>) -> Option<Vec<u8>>;
     }
 }
 
 pub mod ocall {
     use super::{CrossCallMut, Executing, OCall};
     use crate::types::{AccountId, BlockNumber, ExecSideEffects, ExecutionMode, Hash};
-    pub use pink_extension::chain_extension::{JsCode, JsValue};
+    pub use pink_extension::chain_extension::{JsBudget, JsCode, JsValue};
     use pink_macro::cross_call;
     use scale::{Decode, Encode};
 
     pub use pink_extension::chain_extension::{
-        BatchHttpResult, HttpRequest, HttpRequestError, HttpResponse, StorageQuotaExceeded,
+        BatchHttpResult, HttpRequest, HttpRequestError, HttpResponse, HttpRetryPolicy,
+        StorageQuotaExceeded,
     };
     pub type StorageChanges = Vec<(Vec<u8>, (Vec<u8>, i32))>;
 
@@ -322,5 +327,46 @@ pub mod ocall {
         /// Get the origin of the transaction (if available).
         #[xcall(id = 20)]
         fn origin(&self) -> Option<AccountId>;
+
+        /// Performs a batch of HTTP(S) requests on behalf of the contract, giving up each request
+        /// after its own timeout (if any) and the pending ones at the deadline of the batch.
+        #[xcall(id = 21)]
+        fn batch_http_request_with_timeouts(
+            &self,
+            contract: AccountId,
+            requests: Vec<HttpRequest>,
+            timeout_ms: u64,
+            request_timeout_ms: Option<u64>,
+        ) -> BatchHttpResult;
+
+        /// Like `js_eval`, but aborts the evaluation with `JsValue::Timeout` once it goes over
+        /// the budget.
+        #[xcall(id = 22)]
+        fn js_eval_with_budget(
+            &self,
+            contract: AccountId,
+            codes: Vec<JsCode>,
+            args: Vec<String>,
+            budget: JsBudget,
+        ) -> JsValue;
+
+        /// Performs a HTTP(S) request on behalf of the contract, retrying it on the transient
+        /// failures as told by the policy. Returns the last response or error.
+        #[xcall(id = 23)]
+        fn http_request_with_retry(
+            &self,
+            contract: AccountId,
+            request: HttpRequest,
+            policy: HttpRetryPolicy,
+        ) -> Result<HttpResponse, HttpRequestError>;
+
+        /// Fetches the cache values associated with the specified contract and keys, in one call.
+        #[xcall(id = 24)]
+        fn cache_get_batch(&self, contract: Vec<u8>, keys: Vec<Vec<u8>>) -> Vec<Option<Vec<u8>>>;
+
+        /// Sets the cache values associated with the specified contract and keys, in one call.
+        /// Returns whether each of them was set, false if the storage quota is exceeded.
+        #[xcall(id = 25)]
+        fn cache_set_batch(&self, contract: Vec<u8>, pairs: Vec<(Vec<u8>, Vec<u8>)>) -> Vec<bool>;
     }
 }
@@ -0,0 +1,30 @@
+//! Compares reading N keys from the local cache through N `cache_get` calls against a single
+//! `cache_get_batch` call, both going through the mocked chain extension.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use pink_extension_runtime::mock_ext::mock_all_ext;
+
+fn bench_cache_get(c: &mut Criterion) {
+    mock_all_ext();
+    let mut group = c.benchmark_group("cache_get");
+    for n in [1usize, 16, 256] {
+        let keys: Vec<Vec<u8>> = (0..n).map(|i| format!("key-{i}").into_bytes()).collect();
+        for key in &keys {
+            let _ = pink_extension::ext().cache_set(key, &[0u8; 64]);
+        }
+        group.bench_with_input(BenchmarkId::new("individual", n), &keys, |b, keys| {
+            b.iter(|| {
+                keys.iter()
+                    .map(|key| pink_extension::ext().cache_get(key))
+                    .collect::<Vec<_>>()
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("batched", n), &keys, |b, keys| {
+            b.iter(|| pink_extension::ext().cache_get_batch(keys.clone()))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_cache_get);
+criterion_main!(benches);
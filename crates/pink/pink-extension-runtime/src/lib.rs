@@ -3,13 +3,14 @@ use std::io::Write;
 use std::{
     fmt::Display,
     str::FromStr,
+    sync::atomic::{AtomicUsize, Ordering},
     time::{Duration, SystemTime},
 };
 
 use pink_extension::{
     chain_extension::{
-        self as ext, HttpRequest, HttpRequestError, HttpResponse, JsCode, JsValue, PinkExtBackend,
-        SigType, StorageQuotaExceeded,
+        self as ext, HttpRequest, HttpRequestError, HttpResponse, HttpRetryPolicy, JsBudget,
+        JsCode, JsValue, PinkExtBackend, SigType, StorageQuotaExceeded,
     },
     Balance, EcdhPublicKey, EcdsaPublicKey, EcdsaSignature, Hash,
 };
@@ -43,6 +44,32 @@ impl<'a, T, E> DefaultPinkExtension<'a, T, E> {
     }
 }
 
+static MAX_RESPONSE_HEADER_BYTES: AtomicUsize = AtomicUsize::new(64 * 1024);
+
+/// Set the max total size of the names and values of the headers returned with an HTTP response.
+///
+/// The headers beyond it are dropped, and the response is marked with
+/// [`HEADERS_TRUNCATED`](ext::HEADERS_TRUNCATED).
+pub fn set_max_response_header_bytes(max: usize) {
+    MAX_RESPONSE_HEADER_BYTES.store(max, Ordering::Relaxed);
+}
+
+fn collect_headers(headers: &HeaderMap) -> Vec<(String, String)> {
+    let max_bytes = MAX_RESPONSE_HEADER_BYTES.load(Ordering::Relaxed);
+    let mut total = 0;
+    let mut collected = vec![];
+    for (k, v) in headers {
+        let value = v.to_str().unwrap_or_default();
+        total += k.as_str().len() + value.len();
+        if total > max_bytes {
+            collected.push((ext::HEADERS_TRUNCATED.into(), "1".into()));
+            break;
+        }
+        collected.push((k.to_string(), value.into()));
+    }
+    collected
+}
+
 fn block_on<F: core::future::Future>(f: F) -> F::Output {
     match tokio::runtime::Handle::try_current() {
         Ok(handle) => handle.block_on(f),
@@ -70,6 +97,40 @@ pub fn batch_http_request(requests: Vec<HttpRequest>, timeout_ms: u64) -> ext::B
     .or(Err(ext::HttpRequestError::Timeout))
 }
 
+/// Send the requests concurrently, giving up each one after `request_timeout_ms` if given, and
+/// the ones still pending after `timeout_ms` with `BatchDeadlineExceeded`.
+pub fn batch_http_request_with_timeouts(
+    requests: Vec<HttpRequest>,
+    timeout_ms: u64,
+    request_timeout_ms: Option<u64>,
+) -> ext::BatchHttpResult {
+    const MAX_CONCURRENT_REQUESTS: usize = 5;
+    if requests.len() > MAX_CONCURRENT_REQUESTS {
+        return Err(ext::HttpRequestError::TooManyRequests);
+    }
+    let request_timeout_ms = request_timeout_ms.map(|ms| ms.min(timeout_ms));
+    block_on(async move {
+        let deadline = tokio::time::Instant::now() + Duration::from_millis(timeout_ms);
+        let futs = requests.into_iter().map(|request| async move {
+            // The timeout of the client is only a backstop, the ones below fire first.
+            let client_timeout_ms = request_timeout_ms.unwrap_or(timeout_ms) + 200;
+            let response = async_http_request(request, client_timeout_ms);
+            let response = async move {
+                match request_timeout_ms {
+                    Some(ms) => tokio::time::timeout(Duration::from_millis(ms), response)
+                        .await
+                        .unwrap_or(Err(HttpRequestError::Timeout)),
+                    None => response.await,
+                }
+            };
+            tokio::time::timeout_at(deadline, response)
+                .await
+                .unwrap_or(Err(HttpRequestError::BatchDeadlineExceeded))
+        });
+        Ok(futures::future::join_all(futs).await)
+    })
+}
+
 pub fn http_request(
     request: HttpRequest,
     timeout_ms: u64,
@@ -96,6 +157,36 @@ pub fn http_request(
     }
 }
 
+/// Send the request until it succeeds, fails in a way not worth retrying, or is sent
+/// `policy.max_attempts` times, returning the last result.
+///
+/// The attempts and the backoffs between them all fit in `timeout_ms`, a retry that wouldn't is
+/// not made.
+pub fn http_request_with_retry(
+    request: HttpRequest,
+    policy: &HttpRetryPolicy,
+    timeout_ms: u64,
+) -> Result<HttpResponse, HttpRequestError> {
+    block_on(async move {
+        let deadline = tokio::time::Instant::now() + Duration::from_millis(timeout_ms);
+        let mut attempt = 1;
+        loop {
+            let remaining = deadline.saturating_duration_since(tokio::time::Instant::now());
+            let result = async_http_request(request.clone(), remaining.as_millis() as u64).await;
+            if attempt >= policy.max_attempts || !policy.should_retry(&result) {
+                return result;
+            }
+            let backoff = Duration::from_millis(policy.backoff_ms_before(attempt));
+            if tokio::time::Instant::now() + backoff >= deadline {
+                return result;
+            }
+            log::info!("chain_ext: retrying http request, attempt {attempt} failed");
+            tokio::time::sleep(backoff).await;
+            attempt += 1;
+        }
+    })
+}
+
 async fn async_http_request(
     request: HttpRequest,
     timeout_ms: u64,
@@ -143,11 +234,7 @@ async fn async_http_request(
         }
     };
 
-    let headers: Vec<_> = response
-        .headers()
-        .iter()
-        .map(|(k, v)| (k.to_string(), v.to_str().unwrap_or_default().into()))
-        .collect();
+    let headers = collect_headers(response.headers());
 
     const MAX_BODY_SIZE: usize = 1024 * 1024 * 2; // 2MB
 
@@ -191,6 +278,27 @@ impl<T: PinkRuntimeEnv, E: From<&'static str>> PinkExtBackend for DefaultPinkExt
         Ok(batch_http_request(requests, timeout_ms))
     }
 
+    fn batch_http_request_with_timeouts(
+        &self,
+        requests: Vec<HttpRequest>,
+        timeout_ms: u64,
+        request_timeout_ms: Option<u64>,
+    ) -> Result<ext::BatchHttpResult, Self::Error> {
+        Ok(batch_http_request_with_timeouts(
+            requests,
+            timeout_ms,
+            request_timeout_ms,
+        ))
+    }
+
+    fn http_request_with_retry(
+        &self,
+        request: HttpRequest,
+        policy: HttpRetryPolicy,
+    ) -> Result<Result<HttpResponse, HttpRequestError>, Self::Error> {
+        Ok(http_request_with_retry(request, &policy, 10 * 1000))
+    }
+
     fn sign(
         &self,
         sigtype: SigType,
@@ -375,6 +483,28 @@ impl<T: PinkRuntimeEnv, E: From<&'static str>> PinkExtBackend for DefaultPinkExt
     fn js_eval(&self, _codes: Vec<JsCode>, _args: Vec<String>) -> Result<JsValue, Self::Error> {
         Ok(JsValue::Exception("No Js Runtime".into()))
     }
+
+    fn js_eval_with_budget(
+        &self,
+        codes: Vec<JsCode>,
+        args: Vec<String>,
+        _budget: JsBudget,
+    ) -> Result<JsValue, Self::Error> {
+        self.js_eval(codes, args)
+    }
+
+    fn cache_get_batch(&self, keys: Vec<Vec<u8>>) -> Result<Vec<Option<Vec<u8>>>, Self::Error> {
+        keys.into_iter()
+            .map(|key| self.cache_get(key.into()))
+            .collect()
+    }
+
+    fn cache_set_batch(&self, pairs: Vec<(Vec<u8>, Vec<u8>)>) -> Result<Vec<bool>, Self::Error> {
+        pairs
+            .into_iter()
+            .map(|(key, value)| Ok(self.cache_set(key.into(), value.into())?.is_ok()))
+            .collect()
+    }
 }
 
 struct LimitedWriter<W> {
@@ -410,3 +540,117 @@ impl<W: std::io::Write> std::io::Write for LimitedWriter<W> {
         self.writer.flush()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::net::TcpListener;
+
+    /// Serve HTTP on a local port, answering every request if `respond`, or never otherwise.
+    fn serve(respond: bool) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let _ = stream.read(&mut [0u8; 1024]);
+                if respond {
+                    let _ = stream.write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\nok");
+                } else {
+                    std::thread::sleep(Duration::from_secs(5));
+                }
+            }
+        });
+        url
+    }
+
+    #[test]
+    fn slow_request_does_not_starve_the_batch() {
+        let fast = serve(true);
+        let slow = serve(false);
+        let get = |url: &str| HttpRequest::new(url, "GET", vec![], vec![]);
+
+        let requests = vec![get(&fast), get(&slow)];
+        let results = batch_http_request_with_timeouts(requests, 2_000, Some(300)).unwrap();
+        assert_eq!(results[0].as_ref().unwrap().status_code, 200);
+        assert!(matches!(results[1], Err(HttpRequestError::Timeout)));
+
+        let requests = vec![get(&fast), get(&slow)];
+        let results = batch_http_request_with_timeouts(requests, 300, None).unwrap();
+        assert_eq!(results[0].as_ref().unwrap().status_code, 200);
+        assert!(matches!(
+            results[1],
+            Err(HttpRequestError::BatchDeadlineExceeded)
+        ));
+    }
+
+    /// Serve HTTP on a local port, answering `503` to the first `failures` requests and `200`
+    /// to the next ones.
+    fn serve_flaky(failures: usize) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            for (i, stream) in listener.incoming().enumerate() {
+                let mut stream = stream.unwrap();
+                let _ = stream.read(&mut [0u8; 1024]);
+                let response: &[u8] = if i < failures {
+                    b"HTTP/1.1 503 Service Unavailable\r\ncontent-length: 0\r\n\r\n"
+                } else {
+                    b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\nok"
+                };
+                let _ = stream.write_all(response);
+            }
+        });
+        url
+    }
+
+    #[test]
+    fn transient_failures_are_retried() {
+        let get = |url: &str| HttpRequest::new(url, "GET", vec![], vec![]);
+        let policy = HttpRetryPolicy {
+            max_attempts: 3,
+            backoff_ms: 10,
+            ..Default::default()
+        };
+
+        let url = serve_flaky(2);
+        let response = http_request_with_retry(get(&url), &policy, 5_000).unwrap();
+        assert_eq!(response.status_code, 200);
+
+        let url = serve_flaky(3);
+        let response = http_request_with_retry(get(&url), &policy, 5_000).unwrap();
+        assert_eq!(response.status_code, 503);
+
+        let url = serve_flaky(1);
+        let response = http_request_with_retry(get(&url), &Default::default(), 5_000).unwrap();
+        assert_eq!(response.status_code, 503);
+    }
+
+    #[test]
+    fn oversized_headers_are_truncated() {
+        let mut headers = HeaderMap::new();
+        headers.insert("content-type", HeaderValue::from_static("application/json"));
+        headers.insert(
+            "x-padding",
+            HeaderValue::from_str(&"a".repeat(100)).unwrap(),
+        );
+
+        set_max_response_header_bytes(64);
+        let collected = collect_headers(&headers);
+        let expected = [
+            ("content-type", "application/json"),
+            (ext::HEADERS_TRUNCATED, "1"),
+        ];
+        assert_eq!(collected.len(), expected.len());
+        for ((key, value), (expected_key, expected_value)) in collected.iter().zip(expected) {
+            assert_eq!(
+                (key.as_str(), value.as_str()),
+                (expected_key, expected_value)
+            );
+        }
+
+        set_max_response_header_bytes(1024);
+        assert_eq!(collect_headers(&headers).len(), 2);
+    }
+}
@@ -15,6 +15,35 @@ use std::{
 
 pub use pink_extension::chain_extension::StorageQuotaExceeded;
 
+/// Hash of a content-addressed value.
+pub type ContentHash = [u8; CONTENT_HASH_LEN];
+
+/// Length of a [`ContentHash`].
+pub const CONTENT_HASH_LEN: usize = 32;
+
+/// Hash of a content-addressed value, which is the blake2b-256 of the content.
+pub fn content_hash(content: &[u8]) -> ContentHash {
+    sp_core::blake2_256(content)
+}
+
+/// Error returned by a write to the given content hash.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContentSetError {
+    /// The hash of the content does not match the given one.
+    HashMismatch,
+    /// The storage quota is exceeded.
+    StorageQuotaExceeded,
+}
+
+/// Error returned by a version checked write.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VersionedSetError {
+    /// The current version of the key does not match the expected one.
+    VersionConflict,
+    /// The storage quota is exceeded.
+    StorageQuotaExceeded,
+}
+
 static TEST_MODE: AtomicBool = AtomicBool::new(false);
 
 pub(crate) fn enable_test_mode() {
@@ -37,10 +66,14 @@ fn with_global_cache<T>(f: impl FnOnce(&mut LocalCache) -> T) -> T {
 }
 
 struct Storage {
-    // Sum of the size of all the keys and values.
+    // Sum of the size of all the keys and values, plus the contents with their hashes.
     size: usize,
     max_size: usize,
+    // The last version assigned to a value in this storage.
+    version: u64,
     kvs: BTreeMap<Vec<u8>, StorageValue>,
+    // The deduplicated contents referenced by the content-addressed values.
+    contents: BTreeMap<ContentHash, Content>,
 }
 
 impl Storage {
@@ -48,7 +81,9 @@ impl Storage {
         Self {
             size: 0,
             max_size,
+            version: 0,
             kvs: Default::default(),
+            contents: Default::default(),
         }
     }
 
@@ -59,69 +94,142 @@ impl Storage {
         if self.size <= self.max_size {
             return;
         }
-        let map = std::mem::take(&mut self.kvs);
-
-        let mut kvs: Vec<_> = map
-            .into_iter()
-            .map(|(k, v)| (v.expire_at, (k, v)))
-            .collect();
-        kvs.sort_by_key(|(expire, _)| *expire);
-        self.kvs = kvs
-            .into_iter()
-            .filter_map(|(_, (k, v))| {
-                if self.size <= self.max_size {
-                    return Some((k, v));
-                }
-                self.size -= k.len() + v.value.len();
-                None
-            })
+        let mut keys: Vec<_> = self
+            .kvs
+            .iter()
+            .map(|(k, v)| (v.expire_at, k.clone()))
             .collect();
+        keys.sort_by_key(|(expire, _)| *expire);
+        for (_, key) in keys {
+            if self.size <= self.max_size {
+                break;
+            }
+            _ = self.remove(&key);
+        }
     }
 
     fn clear_expired(&mut self, now: u64) {
-        self.kvs.retain(|k, v| {
-            if v.expire_at > now {
-                true
-            } else {
-                self.size -= v.value.len() + k.len();
-                false
-            }
-        });
+        let expired: Vec<_> = self
+            .kvs
+            .iter()
+            .filter(|(_, v)| v.expire_at <= now)
+            .map(|(k, _)| k.clone())
+            .collect();
+        for key in expired {
+            _ = self.remove(&key);
+        }
     }
 
     fn remove(&mut self, key: &[u8]) -> Option<Vec<u8>> {
-        let v = self.kvs.remove(key).map(|v| v.value);
-        if let Some(v) = &v {
-            self.size -= v.len() + key.len();
+        let v = self.kvs.remove(key)?;
+        self.size -= key.len() + v.value.size();
+        Some(match v.value {
+            Value::Inline(value) => value,
+            Value::Content(hash) => self.release(&hash),
+        })
+    }
+
+    /// Drops a reference to the content, freeing it with the last one.
+    fn release(&mut self, hash: &ContentHash) -> Vec<u8> {
+        let Some(content) = self.contents.get_mut(hash) else {
+            return vec![];
+        };
+        content.refs -= 1;
+        if content.refs > 0 {
+            return content.data.clone();
         }
-        v
+        self.size -= CONTENT_HASH_LEN + content.data.len();
+        self.contents
+            .remove(hash)
+            .map(|content| content.data)
+            .unwrap_or_default()
     }
 
-    fn set(
-        &mut self,
-        key: Cow<[u8]>,
-        value: Cow<[u8]>,
-        lifetime: u64,
-    ) -> Result<(), StorageQuotaExceeded> {
-        _ = self.remove(key.as_ref());
-        let data_len = key.len() + value.len();
-        let mut store_size = self.size + data_len;
+    fn resolve<'a>(&'a self, value: &'a Value) -> &'a [u8] {
+        match value {
+            Value::Inline(value) => value,
+            Value::Content(hash) => self
+                .contents
+                .get(hash)
+                .map(|content| &content.data[..])
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Returns the version of the key if it is present and not expired, or 0 otherwise.
+    fn version_of(&self, key: &[u8], now: u64) -> u64 {
+        match self.kvs.get(key) {
+            Some(v) if v.expire_at > now => v.version,
+            _ => 0,
+        }
+    }
+
+    /// Accounts for the bytes returned by `data_len`, clearing the expired values if they don't
+    /// fit.
+    fn reserve(&mut self, data_len: impl Fn(&Self) -> usize) -> Result<(), StorageQuotaExceeded> {
+        let mut store_size = self.size + data_len(self);
         if store_size > self.max_size {
             self.clear_expired(now());
-            store_size = self.size + data_len;
+            store_size = self.size + data_len(self);
             if store_size > self.max_size {
                 return Err(StorageQuotaExceeded);
             }
         }
         self.size = store_size;
+        Ok(())
+    }
+
+    fn insert(&mut self, key: Cow<[u8]>, value: Value, lifetime: u64) -> u64 {
+        self.version += 1;
         self.kvs.insert(
             key.into_owned(),
             StorageValue {
                 expire_at: now().saturating_add(lifetime),
-                value: value.into_owned(),
+                version: self.version,
+                value,
             },
         );
-        Ok(())
+        self.version
+    }
+
+    fn set(
+        &mut self,
+        key: Cow<[u8]>,
+        value: Cow<[u8]>,
+        lifetime: u64,
+    ) -> Result<u64, StorageQuotaExceeded> {
+        _ = self.remove(key.as_ref());
+        self.reserve(|_| key.len() + value.len())?;
+        Ok(self.insert(key, Value::Inline(value.into_owned()), lifetime))
+    }
+
+    /// Set the key to reference the content, which is stored only once however many keys
+    /// reference it.
+    fn set_content(
+        &mut self,
+        key: Cow<[u8]>,
+        content: Cow<[u8]>,
+        lifetime: u64,
+    ) -> Result<ContentHash, StorageQuotaExceeded> {
+        _ = self.remove(key.as_ref());
+        let hash = content_hash(&content);
+        self.reserve(|this| {
+            let content_len = if this.contents.contains_key(&hash) {
+                0
+            } else {
+                CONTENT_HASH_LEN + content.len()
+            };
+            key.len() + CONTENT_HASH_LEN + content_len
+        })?;
+        self.contents
+            .entry(hash)
+            .or_insert_with(|| Content {
+                refs: 0,
+                data: content.into_owned(),
+            })
+            .refs += 1;
+        self.insert(key, Value::Content(hash), lifetime);
+        Ok(hash)
     }
 
     #[cfg(test)]
@@ -133,7 +241,30 @@ impl Storage {
 struct StorageValue {
     /// Expiration time in seconds since the first call to `now`.
     expire_at: u64,
-    value: Vec<u8>,
+    /// Monotonic version of the value, bumped on every write.
+    version: u64,
+    value: Value,
+}
+
+enum Value {
+    Inline(Vec<u8>),
+    /// A reference to a deduplicated content.
+    Content(ContentHash),
+}
+
+impl Value {
+    fn size(&self) -> usize {
+        match self {
+            Value::Inline(value) => value.len(),
+            Value::Content(_) => CONTENT_HASH_LEN,
+        }
+    }
+}
+
+struct Content {
+    /// Number of keys referencing the content.
+    refs: usize,
+    data: Vec<u8>,
 }
 
 pub struct LocalCache {
@@ -174,17 +305,45 @@ impl LocalCache {
     }
 
     pub fn get(&self, id: &[u8], key: &[u8]) -> Option<Vec<u8>> {
-        let entry = self.storages.get(id)?.kvs.get(key)?;
+        let storage = self.storages.get(id)?;
+        let entry = storage.kvs.get(key)?;
+        if entry.expire_at <= now() {
+            None
+        } else {
+            Some(storage.resolve(&entry.value).to_owned())
+        }
+    }
+
+    pub fn get_versioned(&self, id: &[u8], key: &[u8]) -> Option<(Vec<u8>, u64)> {
+        let storage = self.storages.get(id)?;
+        let entry = storage.kvs.get(key)?;
         if entry.expire_at <= now() {
             None
         } else {
-            Some(entry.value.to_owned())
+            Some((storage.resolve(&entry.value).to_owned(), entry.version))
         }
     }
 
+    /// Get the values of the keys, in order.
+    pub fn get_batch(&self, id: &[u8], keys: &[Vec<u8>]) -> Vec<Option<Vec<u8>>> {
+        keys.iter().map(|key| self.get(id, key)).collect()
+    }
+
+    /// Get a content by its hash, as long as any key references it.
+    pub fn get_by_hash(&self, id: &[u8], hash: &ContentHash) -> Option<Vec<u8>> {
+        let content = self.storages.get(id)?.contents.get(hash)?;
+        Some(content.data.to_owned())
+    }
+
+    /// Number of bytes used by the given storage.
+    pub fn usage(&self, id: &[u8]) -> Option<usize> {
+        self.storages.get(id).map(|storage| storage.size)
+    }
+
     #[cfg(test)]
     fn get_include_expired(&self, id: &[u8], key: &[u8]) -> Option<Vec<u8>> {
-        Some(self.storages.get(id)?.kvs.get(key)?.value.to_owned())
+        let storage = self.storages.get(id)?;
+        Some(storage.resolve(&storage.kvs.get(key)?.value).to_owned())
     }
 
     pub fn set(
@@ -198,6 +357,92 @@ impl LocalCache {
             .get_mut(id.as_ref())
             .ok_or(StorageQuotaExceeded)?
             .set(key, value, self.default_value_lifetime)
+            .map(|_| ())
+    }
+
+    /// Set the pairs in order, returning whether each of them was set.
+    ///
+    /// A failed pair does not roll back the ones set before it.
+    pub fn set_batch(&mut self, id: &[u8], pairs: &[(Vec<u8>, Vec<u8>)]) -> Vec<bool> {
+        pairs
+            .iter()
+            .map(|(key, value)| self.set(id.into(), key.into(), value.into()).is_ok())
+            .collect()
+    }
+
+    /// Set the value expiring after `ttl` seconds. A TTL of 0 removes the key instead.
+    pub fn set_with_ttl(
+        &mut self,
+        id: Cow<[u8]>,
+        key: Cow<[u8]>,
+        value: Cow<[u8]>,
+        ttl: u64,
+    ) -> Result<(), StorageQuotaExceeded> {
+        if ttl == 0 {
+            let _ = self.remove(id.as_ref(), key.as_ref());
+            return Ok(());
+        }
+        self.maybe_clear_expired();
+        self.storages
+            .get_mut(id.as_ref())
+            .ok_or(StorageQuotaExceeded)?
+            .set(key, value, ttl)
+            .map(|_| ())
+    }
+
+    /// Store the value deduplicated by its content, with the key referencing it.
+    ///
+    /// Returns the hash of the content, which can be used to get it by [`Self::get_by_hash`].
+    pub fn set_content(
+        &mut self,
+        id: Cow<[u8]>,
+        key: Cow<[u8]>,
+        value: Cow<[u8]>,
+    ) -> Result<ContentHash, StorageQuotaExceeded> {
+        self.maybe_clear_expired();
+        self.storages
+            .get_mut(id.as_ref())
+            .ok_or(StorageQuotaExceeded)?
+            .set_content(key, value, self.default_value_lifetime)
+    }
+
+    /// Store the value deduplicated by its content, keyed by its hash, which must match `hash`.
+    pub fn set_by_hash(
+        &mut self,
+        id: Cow<[u8]>,
+        hash: &ContentHash,
+        value: Cow<[u8]>,
+    ) -> Result<(), ContentSetError> {
+        if content_hash(&value) != *hash {
+            return Err(ContentSetError::HashMismatch);
+        }
+        self.set_content(id, Cow::Borrowed(&hash[..]), value)
+            .map(|_| ())
+            .or(Err(ContentSetError::StorageQuotaExceeded))
+    }
+
+    /// Set the value only if the current version of the key equals `expected_version`.
+    ///
+    /// A version of 0 stands for an absent key. Returns the new version on success.
+    pub fn set_if_version(
+        &mut self,
+        id: Cow<[u8]>,
+        key: Cow<[u8]>,
+        value: Cow<[u8]>,
+        expected_version: u64,
+    ) -> Result<u64, VersionedSetError> {
+        self.maybe_clear_expired();
+        let lifetime = self.default_value_lifetime;
+        let storage = self
+            .storages
+            .get_mut(id.as_ref())
+            .ok_or(VersionedSetError::StorageQuotaExceeded)?;
+        if storage.version_of(key.as_ref(), now()) != expected_version {
+            return Err(VersionedSetError::VersionConflict);
+        }
+        storage
+            .set(key, value, lifetime)
+            .or(Err(VersionedSetError::StorageQuotaExceeded))
     }
 
     pub fn set_expire(&mut self, id: Cow<[u8]>, key: Cow<[u8]>, expire: u64) {
@@ -278,6 +523,62 @@ pub fn get(contract: &[u8], key: &[u8]) -> Option<Vec<u8>> {
     with_global_cache(|cache| cache.get(contract, key))
 }
 
+pub fn get_batch(contract: &[u8], keys: &[Vec<u8>]) -> Vec<Option<Vec<u8>>> {
+    with_global_cache(|cache| cache.get_batch(contract, keys))
+}
+
+pub fn set_batch(contract: &[u8], pairs: &[(Vec<u8>, Vec<u8>)]) -> Vec<bool> {
+    with_global_cache(|cache| cache.set_batch(contract, pairs))
+}
+
+pub fn usage(contract: &[u8]) -> Option<usize> {
+    with_global_cache(|cache| cache.usage(contract))
+}
+
+pub fn set_with_ttl(
+    contract: &[u8],
+    key: &[u8],
+    value: &[u8],
+    ttl: u64,
+) -> Result<(), StorageQuotaExceeded> {
+    with_global_cache(|cache| cache.set_with_ttl(contract.into(), key.into(), value.into(), ttl))
+}
+
+pub fn set_content(
+    contract: &[u8],
+    key: &[u8],
+    value: &[u8],
+) -> Result<ContentHash, StorageQuotaExceeded> {
+    with_global_cache(|cache| cache.set_content(contract.into(), key.into(), value.into()))
+}
+
+pub fn set_by_hash(
+    contract: &[u8],
+    hash: &ContentHash,
+    value: &[u8],
+) -> Result<(), ContentSetError> {
+    with_global_cache(|cache| cache.set_by_hash(contract.into(), hash, value.into()))
+}
+
+pub fn get_by_hash(contract: &[u8], hash: &ContentHash) -> Option<Vec<u8>> {
+    with_global_cache(|cache| cache.get_by_hash(contract, hash))
+}
+
+pub fn get_versioned(contract: &[u8], key: &[u8]) -> Option<(Vec<u8>, u64)> {
+    with_global_cache(|cache| cache.get_versioned(contract, key))
+}
+
+pub fn set_if_version(
+    contract: &[u8],
+    key: &[u8],
+    value: &[u8],
+    expected_version: u64,
+) -> Result<u64, VersionedSetError> {
+    with_global_cache(|cache| {
+        cache.set_if_version(contract.into(), key.into(), value.into(), expected_version)
+    })
+}
+
 pub fn set_expiration(contract: &[u8], key: &[u8], expiration: u64) {
     with_global_cache(|cache| cache.set_expire(contract.into(), key.into(), expiration))
 }
@@ -379,6 +680,197 @@ mod test {
         assert_eq!(get_size(&cache, b"id"), 0);
     }
 
+    #[test]
+    fn set_if_version_works() {
+        let mut cache = test_cache();
+        cache.apply_quotas([(&b"id"[..], 1000)]);
+
+        assert_eq!(
+            cache.set_if_version(cow(b"id"), cow(b"foo"), cow(b"v1"), 0),
+            Ok(1)
+        );
+        assert_eq!(
+            cache.get_versioned(b"id", b"foo"),
+            Some((b"v1".to_vec(), 1))
+        );
+
+        // Two writers both read version 1, only the first one wins.
+        assert_eq!(
+            cache.set_if_version(cow(b"id"), cow(b"foo"), cow(b"a"), 1),
+            Ok(2)
+        );
+        assert_eq!(
+            cache.set_if_version(cow(b"id"), cow(b"foo"), cow(b"b"), 1),
+            Err(VersionedSetError::VersionConflict)
+        );
+        assert_eq!(cache.get_versioned(b"id", b"foo"), Some((b"a".to_vec(), 2)));
+
+        // Plain writes bump the version as well.
+        assert!(cache.set(cow(b"id"), cow(b"foo"), cow(b"c")).is_ok());
+        assert_eq!(cache.get_versioned(b"id", b"foo"), Some((b"c".to_vec(), 3)));
+
+        // Versions keep growing after the key is removed.
+        assert!(cache.remove(b"id", b"foo").is_some());
+        assert_eq!(
+            cache.set_if_version(cow(b"id"), cow(b"foo"), cow(b"d"), 3),
+            Err(VersionedSetError::VersionConflict)
+        );
+        assert_eq!(
+            cache.set_if_version(cow(b"id"), cow(b"foo"), cow(b"d"), 0),
+            Ok(4)
+        );
+    }
+
+    #[test]
+    fn concurrent_versioned_writers_never_lose_updates() {
+        use std::sync::{Arc, Mutex};
+
+        let cache = Arc::new(Mutex::new(LocalCache {
+            default_value_lifetime: 3600,
+            ..test_cache()
+        }));
+        cache
+            .lock()
+            .unwrap()
+            .apply_quotas([(&b"id"[..], 1000), (&b"other"[..], 1000)]);
+
+        // Each VM increments the counter 100 times, retrying when another one got in between.
+        let vms: Vec<_> = (0..4)
+            .map(|_| {
+                let cache = cache.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..100 {
+                        loop {
+                            let (count, version) =
+                                match cache.lock().unwrap().get_versioned(b"id", b"n") {
+                                    Some((v, version)) => {
+                                        (u32::from_le_bytes(v.try_into().unwrap()), version)
+                                    }
+                                    None => (0, 0),
+                                };
+                            std::thread::yield_now();
+                            let value = (count + 1).to_le_bytes();
+                            let result = cache.lock().unwrap().set_if_version(
+                                cow(b"id"),
+                                cow(b"n"),
+                                cow(&value),
+                                version,
+                            );
+                            match result {
+                                Ok(_) => break,
+                                Err(VersionedSetError::VersionConflict) => continue,
+                                Err(err) => panic!("unexpected error: {err:?}"),
+                            }
+                        }
+                    }
+                })
+            })
+            .collect();
+        for vm in vms {
+            vm.join().unwrap();
+        }
+
+        let mut cache = cache.lock().unwrap();
+        let (count, version) = cache.get_versioned(b"id", b"n").unwrap();
+        assert_eq!(u32::from_le_bytes(count.try_into().unwrap()), 400);
+        assert_eq!(version, 400);
+
+        // A writer holding a stale version loses, the same key of another id is independent.
+        assert_eq!(
+            cache.set_if_version(cow(b"id"), cow(b"n"), cow(b"stale"), 399),
+            Err(VersionedSetError::VersionConflict)
+        );
+        assert_eq!(
+            cache.set_if_version(cow(b"other"), cow(b"n"), cow(b"fresh"), 0),
+            Ok(1)
+        );
+    }
+
+    #[test]
+    fn set_with_ttl_works() {
+        let mut cache = test_cache();
+        cache.apply_quotas([(&b"id"[..], 1000)]);
+
+        assert!(cache
+            .set_with_ttl(cow(b"id"), cow(b"foo"), cow(b"value"), 1)
+            .is_ok());
+        assert!(cache
+            .set_with_ttl(cow(b"id"), cow(b"bar"), cow(b"value"), 10)
+            .is_ok());
+        assert_eq!(cache.get(b"id", b"foo"), Some(b"value".to_vec()));
+
+        sleep(1);
+        assert_eq!(cache.get(b"id", b"foo"), None);
+        assert_eq!(cache.get(b"id", b"bar"), Some(b"value".to_vec()));
+
+        // A zero TTL deletes the key.
+        assert!(cache
+            .set_with_ttl(cow(b"id"), cow(b"bar"), cow(b"value"), 0)
+            .is_ok());
+        assert_eq!(cache.get_include_expired(b"id", b"bar"), None);
+    }
+
+    #[test]
+    fn batch_ops_apply_in_order() {
+        let mut cache = test_cache();
+        cache.apply_quotas([(&b"id"[..], 10)]);
+
+        let pairs = [
+            (b"a".to_vec(), b"12345".to_vec()),
+            (b"b".to_vec(), b"1234567890".to_vec()),
+            (b"a".to_vec(), b"x".to_vec()),
+        ];
+        assert_eq!(cache.set_batch(b"id", &pairs), [true, false, true]);
+        assert_eq!(
+            cache.get_batch(b"id", &[b"a".to_vec(), b"b".to_vec()]),
+            [Some(b"x".to_vec()), None]
+        );
+    }
+
+    #[test]
+    fn content_is_deduplicated() {
+        let mut cache = test_cache();
+        cache.apply_quotas([(&b"id"[..], 1000)]);
+        let blob = [7u8; 100];
+
+        let hash = cache
+            .set_content(cow(b"id"), cow(b"a"), cow(&blob))
+            .unwrap();
+        assert_eq!(hash, content_hash(&blob));
+        assert_eq!(get_size(&cache, b"id"), 1 + 32 + 32 + 100);
+        let second = cache
+            .set_content(cow(b"id"), cow(b"b"), cow(&blob))
+            .unwrap();
+        assert_eq!(second, hash);
+        // Only the second reference is accounted.
+        assert_eq!(get_size(&cache, b"id"), 2 * (1 + 32) + 32 + 100);
+
+        assert_eq!(cache.get(b"id", b"a"), Some(blob.to_vec()));
+        assert_eq!(cache.get(b"id", b"b"), Some(blob.to_vec()));
+        assert_eq!(cache.get_by_hash(b"id", &hash), Some(blob.to_vec()));
+
+        assert_eq!(cache.remove(b"id", b"a"), Some(blob.to_vec()));
+        assert_eq!(cache.get(b"id", b"b"), Some(blob.to_vec()));
+        assert_eq!(cache.remove(b"id", b"b"), Some(blob.to_vec()));
+        assert_eq!(cache.get_by_hash(b"id", &hash), None);
+        assert_eq!(get_size(&cache, b"id"), 0);
+    }
+
+    #[test]
+    fn set_by_hash_rejects_mismatch() {
+        let mut cache = test_cache();
+        cache.apply_quotas([(&b"id"[..], 1000)]);
+
+        let hash = content_hash(b"foo");
+        assert_eq!(
+            cache.set_by_hash(cow(b"id"), &hash, cow(b"bar")),
+            Err(ContentSetError::HashMismatch)
+        );
+        assert_eq!(cache.set_by_hash(cow(b"id"), &hash, cow(b"foo")), Ok(()));
+        assert_eq!(cache.get_by_hash(b"id", &hash), Some(b"foo".to_vec()));
+        assert_eq!(cache.get(b"id", &hash), Some(b"foo".to_vec()));
+    }
+
     #[test]
     fn fit_size_works() {
         let mut store = Storage::new(20);
@@ -1,6 +1,6 @@
 use std::borrow::Cow;
 
-use pink_extension::chain_extension::{mock::mock_all_with, JsCode, JsValue, SigType};
+use pink_extension::chain_extension::{mock::mock_all_with, JsBudget, JsCode, JsValue, SigType};
 use pink_extension::{chain_extension as ext, EcdsaPublicKey, EcdsaSignature, Hash};
 use sp_core::crypto::AccountId32;
 
@@ -33,6 +33,27 @@ impl ext::PinkExtBackend for MockExtension {
         super::DefaultPinkExtension::new(self).batch_http_request(requests, timeout_ms)
     }
 
+    fn batch_http_request_with_timeouts(
+        &self,
+        requests: Vec<ext::HttpRequest>,
+        timeout_ms: u64,
+        request_timeout_ms: Option<u64>,
+    ) -> Result<ext::BatchHttpResult, Self::Error> {
+        super::DefaultPinkExtension::new(self).batch_http_request_with_timeouts(
+            requests,
+            timeout_ms,
+            request_timeout_ms,
+        )
+    }
+
+    fn http_request_with_retry(
+        &self,
+        request: ext::HttpRequest,
+        policy: ext::HttpRetryPolicy,
+    ) -> Result<Result<ext::HttpResponse, ext::HttpRequestError>, Self::Error> {
+        super::DefaultPinkExtension::new(self).http_request_with_retry(request, policy)
+    }
+
     fn sign(
         &self,
         sigtype: SigType,
@@ -155,6 +176,23 @@ impl ext::PinkExtBackend for MockExtension {
     fn js_eval(&self, codes: Vec<JsCode>, args: Vec<String>) -> Result<JsValue, Self::Error> {
         super::DefaultPinkExtension::new(self).js_eval(codes, args)
     }
+
+    fn js_eval_with_budget(
+        &self,
+        codes: Vec<JsCode>,
+        args: Vec<String>,
+        budget: JsBudget,
+    ) -> Result<JsValue, Self::Error> {
+        super::DefaultPinkExtension::new(self).js_eval_with_budget(codes, args, budget)
+    }
+
+    fn cache_get_batch(&self, keys: Vec<Vec<u8>>) -> Result<Vec<Option<Vec<u8>>>, Self::Error> {
+        Ok(local_cache::get_batch(&[], &keys))
+    }
+
+    fn cache_set_batch(&self, pairs: Vec<(Vec<u8>, Vec<u8>)>) -> Result<Vec<bool>, Self::Error> {
+        Ok(local_cache::set_batch(&[], &pairs))
+    }
 }
 
 thread_local! {
@@ -3,12 +3,15 @@ use alloc::string::String;
 use alloc::vec::Vec;
 use ink::ChainExtensionInstance;
 
-pub use http_request::{HttpRequest, HttpRequestError, HttpResponse};
+pub use http_request::{
+    ByteRange, HttpRequest, HttpRequestError, HttpResponse, HttpRetryPolicy, RangeError,
+    HEADERS_TRUNCATED,
+};
 pub use ink::primitives::AccountId;
 pub use signing::SigType;
 
 use crate::{Balance, EcdsaPublicKey, EcdsaSignature, Hash};
-pub use pink_types::js::{JsCode, JsValue};
+pub use pink_types::js::{JsBudget, JsCode, JsValue};
 
 #[cfg(doc)]
 use crate::{debug, error, http_get, http_post, http_put, info, warn};
@@ -665,7 +668,7 @@ pub trait PinkExt {
     /// - [setTimeout/setInterval](https://developer.mozilla.org/en-US/docs/Web/API/setTimeout)
     /// - [Streams](https://developer.mozilla.org/en-US/docs/Web/API/Streams_API)
     /// - [URL](https://developer.mozilla.org/en-US/docs/Web/API/URL)
-    /// - [TextEncoder/Decoder](https://developer.mozilla.org/zh-CN/docs/Web/API/TextEncoder) - 
+    /// - [TextEncoder/Decoder](https://developer.mozilla.org/zh-CN/docs/Web/API/TextEncoder) -
     ///   Note that this implementation is incomplete. It only supports utf8 encoding/decoding.
     ///   Additional polyfills may be necessary for other requirements.
     /// - [fetch](https://developer.mozilla.org/en-US/docs/Web/API/Fetch_API/Using_Fetch)
@@ -700,6 +703,178 @@ pub trait PinkExt {
     /// 1.2
     #[ink(extension = 24, handle_status = false)]
     fn js_eval(codes: Vec<JsCode>, args: Vec<String>) -> JsValue;
+
+    /// Get multiple values from the local cache in one call.
+    ///
+    /// The values are looked up in the order of the keys, so the result is the same as calling
+    /// `cache_get` on each key in turn. Saves a chain extension round trip per key.
+    ///
+    /// # Arguments
+    ///
+    /// * `keys`: The keys to look up.
+    ///
+    /// # Returns
+    ///
+    /// The value of each key, or `None` if it is not in the cache. Always `None` if it is called
+    /// from a transaction context.
+    ///
+    /// # Example
+    ///
+    /// ```ignore
+    /// let values = pink::ext().cache_get_batch(vec![b"foo".to_vec(), b"bar".to_vec()]);
+    /// ```
+    ///
+    /// # Availability
+    /// any contract | query
+    ///
+    /// # Runtime version
+    /// 1.2
+    #[ink(extension = 25, handle_status = false)]
+    fn cache_get_batch(keys: Vec<Vec<u8>>) -> Vec<Option<Vec<u8>>>;
+
+    /// Set multiple values in the local cache in one call.
+    ///
+    /// The pairs are applied in order, so a later pair overwrites an earlier one with the same
+    /// key. The batch is not transactional: a pair that fails, e.g. for exceeding the storage
+    /// quota, does not roll back the pairs set before it.
+    ///
+    /// # Arguments
+    ///
+    /// * `pairs`: The key-value pairs to set, with the default expiration time of 7 days.
+    ///
+    /// # Returns
+    ///
+    /// Whether each pair was set. Always `true` if it is called from a transaction context.
+    ///
+    /// # Example
+    ///
+    /// ```ignore
+    /// let pairs = vec![(b"foo".to_vec(), b"1".to_vec()), (b"bar".to_vec(), b"2".to_vec())];
+    /// let results = pink::ext().cache_set_batch(pairs);
+    /// ```
+    ///
+    /// # Availability
+    /// any contract | query | transaction
+    ///
+    /// # Runtime version
+    /// 1.2
+    #[ink(extension = 26, handle_status = false)]
+    fn cache_set_batch(pairs: Vec<(Vec<u8>, Vec<u8>)>) -> Vec<bool>;
+
+    /// Batch HTTP request with a timeout for each request.
+    ///
+    /// Like `batch_http_request`, but a slow request can't take the whole budget of the batch:
+    /// each request is given up after `request_timeout_ms`, while the others keep going. The
+    /// requests finished by the deadline of the batch always return their results.
+    ///
+    /// # Arguments
+    ///
+    /// * `requests`: The HTTP requests to send, at most 5.
+    /// * `timeout_ms`: The deadline of the whole batch in milliseconds.
+    /// * `request_timeout_ms`: The timeout of each request in milliseconds. Only the deadline of
+    ///   the batch applies if None.
+    ///
+    /// # Returns
+    ///
+    /// The response to each request, or the error it failed with, which is
+    /// `HttpRequestError::Timeout` for a request over its own timeout, and
+    /// `HttpRequestError::BatchDeadlineExceeded` for a request still pending at the deadline of
+    /// the batch.
+    ///
+    /// # Example
+    ///
+    /// ```ignore
+    /// let requests = vec![
+    ///     HttpRequest::new("https://httpbin.org/get", "GET", vec![], vec![]),
+    ///     HttpRequest::new("https://httpbin.org/delay/10", "GET", vec![], vec![]),
+    /// ];
+    /// let result = pink::ext().batch_http_request_with_timeouts(requests, 5000, Some(2000));
+    /// ```
+    ///
+    /// # Availability
+    /// any contract | query
+    ///
+    /// # Runtime version
+    /// 1.2
+    #[ink(extension = 27, handle_status = true)]
+    fn batch_http_request_with_timeouts(
+        requests: Vec<HttpRequest>,
+        timeout_ms: u64,
+        request_timeout_ms: Option<u64>,
+    ) -> BatchHttpResult;
+
+    /// Execute JavaScript code within a budget.
+    ///
+    /// Like `js_eval`, but the evaluation is aborted once it goes over the budget, so a script
+    /// stuck in a loop can't hold the call until the worker gives up on it.
+    ///
+    /// # Arguments
+    ///
+    /// * `codes`: Vector of `JsCode` representing JavaScript code to be evaluated.
+    /// * `args`: Vector of `String`, passed into the script as `scriptArgs`.
+    /// * `budget`: The time and the number of instructions the evaluation can take. The time
+    ///   remaining to the contract call always applies.
+    ///
+    /// # Returns
+    ///
+    /// * `JsValue`: Result of the JavaScript expression evaluation, or `JsValue::Timeout` if it
+    ///   went over the budget.
+    ///
+    /// # Example
+    ///
+    /// ```ignore
+    /// let budget = JsBudget {
+    ///     timeout_ms: Some(500),
+    ///     max_instructions: None,
+    /// };
+    /// let codes = vec![JsCode::Source("while(true){}".into())];
+    /// let result = pink::ext().js_eval_with_budget(codes, vec![], budget);
+    /// assert!(matches!(result, JsValue::Timeout(_)));
+    /// ```
+    ///
+    /// # Availability
+    /// any contract | query | transaction
+    ///
+    /// # Runtime version
+    /// 1.2
+    #[ink(extension = 28, handle_status = false)]
+    fn js_eval_with_budget(codes: Vec<JsCode>, args: Vec<String>, budget: JsBudget) -> JsValue;
+
+    /// Make a HTTP request, retried by the runtime on transient failures.
+    ///
+    /// Like `http_request`, but the request is sent again after a backoff when it fails with a
+    /// network error or a timeout, or is answered with one of the status codes of the policy,
+    /// until it succeeds or `max_attempts` is reached. The retries and the waits between them
+    /// are bounded by the time remaining to the contract call.
+    ///
+    /// Only the requests that are safe to repeat should be retried.
+    ///
+    /// # Arguments
+    ///
+    /// * `request`: The HTTP request to send.
+    /// * `policy`: How the request is retried. It's sent once with `HttpRetryPolicy::default()`.
+    ///
+    /// # Returns
+    ///
+    /// The response to the last attempt, or the error it failed with.
+    ///
+    /// # Example
+    ///
+    /// ```ignore
+    /// let request = HttpRequest::new("https://httpbin.org/get", "GET", vec![], vec![]);
+    /// let response = pink::ext().http_request_with_retry(request, HttpRetryPolicy::new(3));
+    /// ```
+    ///
+    /// # Availability
+    /// any contract | query only
+    ///
+    /// # Runtime version
+    /// 1.2
+    #[ink(extension = 29, handle_status = true)]
+    fn http_request_with_retry(
+        request: HttpRequest,
+        policy: HttpRetryPolicy,
+    ) -> Result<HttpResponse, HttpRequestError>;
 }
 
 pub fn pink_extension_instance() -> <PinkExt as ChainExtensionInstance>::Instance {
@@ -3,7 +3,7 @@ use alloc::vec::Vec;
 use num_enum::{IntoPrimitive, TryFromPrimitive};
 
 use super::ErrorCode;
-#[derive(scale::Encode, scale::Decode)]
+#[derive(scale::Encode, scale::Decode, Clone)]
 #[cfg_attr(feature = "std", derive(scale_info::TypeInfo))]
 pub struct HttpRequest {
     pub url: String,
@@ -27,8 +27,107 @@ impl HttpRequest {
             body,
         }
     }
+
+    /// Only ask for the given bytes of the resource, by sending a `Range` header.
+    ///
+    /// Use [`HttpResponse::into_range_body`] to get the bytes out of the response.
+    pub fn with_range(mut self, range: ByteRange) -> Self {
+        let value = match range.end {
+            Some(end) => alloc::format!("bytes={}-{}", range.start, end),
+            None => alloc::format!("bytes={}-", range.start),
+        };
+        self.headers.push(("Range".into(), value));
+        self
+    }
+}
+
+/// How an HTTP request is retried by the runtime on transient failures.
+///
+/// The request is sent again as is, so retrying one that isn't idempotent is up to the caller.
+#[derive(scale::Encode, scale::Decode, Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "std", derive(scale_info::TypeInfo))]
+pub struct HttpRetryPolicy {
+    /// Max number of times the request is sent, the first one included.
+    pub max_attempts: u32,
+    /// Milliseconds to wait before the first retry, doubled before each of the next ones.
+    pub backoff_ms: u64,
+    /// Max milliseconds to wait before a retry.
+    pub max_backoff_ms: u64,
+    /// The status codes of the responses retried, on top of the network errors and timeouts.
+    pub retry_on_status: Vec<u16>,
+}
+
+impl Default for HttpRetryPolicy {
+    /// Send the request once, as `http_request` does.
+    fn default() -> Self {
+        Self {
+            max_attempts: 1,
+            backoff_ms: 100,
+            max_backoff_ms: 2000,
+            retry_on_status: alloc::vec![502, 503, 504],
+        }
+    }
+}
+
+impl HttpRetryPolicy {
+    /// Retry up to `max_attempts` in total, with the default backoff and status codes.
+    pub fn new(max_attempts: u32) -> Self {
+        Self {
+            max_attempts,
+            ..Default::default()
+        }
+    }
+
+    /// Milliseconds to wait before the given retry, counted from 1.
+    pub fn backoff_ms_before(&self, retry: u32) -> u64 {
+        let factor = 1u64
+            .checked_shl(retry.saturating_sub(1))
+            .unwrap_or(u64::MAX);
+        self.backoff_ms
+            .saturating_mul(factor)
+            .min(self.max_backoff_ms)
+    }
+
+    /// Whether a request ending with the result is worth sending again.
+    pub fn should_retry(&self, result: &Result<HttpResponse, HttpRequestError>) -> bool {
+        match result {
+            Ok(response) => {
+                // 523 is answered by the runtime when the server can't be reached.
+                response.status_code == 523 || self.retry_on_status.contains(&response.status_code)
+            }
+            Err(err) => matches!(
+                err,
+                HttpRequestError::Timeout | HttpRequestError::NetworkError
+            ),
+        }
+    }
 }
 
+/// A range of bytes of a remote resource.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteRange {
+    /// Offset of the first byte.
+    pub start: u64,
+    /// Offset of the last byte, inclusive. Up to the end of the resource if None.
+    pub end: Option<u64>,
+}
+
+/// Why the response to a ranged request doesn't carry the requested bytes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RangeError {
+    /// The server answered `416 Range Not Satisfiable`, the range is out of the resource.
+    NotSatisfiable,
+    /// The server doesn't support ranges and answered `200` with the whole resource, which is
+    /// given here.
+    Ignored(Vec<u8>),
+    /// The server answered with an unexpected status code.
+    Status(u16),
+}
+
+/// Header added by the runtime in place of the response headers dropped for exceeding the size
+/// limit of the headers.
+pub const HEADERS_TRUNCATED: &str = "x-pink-headers-truncated";
+
 #[derive(scale::Encode, scale::Decode)]
 #[cfg_attr(feature = "std", derive(scale_info::TypeInfo))]
 pub struct HttpResponse {
@@ -52,6 +151,8 @@ pub enum HttpRequestError {
     TooManyRequests,
     NetworkError,
     ResponseTooLarge,
+    /// The request was still pending when the deadline of its batch passed.
+    BatchDeadlineExceeded,
 }
 
 impl super::sealed::Sealed for HttpRequestError {}
@@ -97,6 +198,7 @@ impl HttpRequestError {
             Self::TooManyRequests => "Too many requests",
             Self::NetworkError => "Network error",
             Self::ResponseTooLarge => "Response too large",
+            Self::BatchDeadlineExceeded => "Batch deadline exceeded",
         }
     }
 }
@@ -119,6 +221,40 @@ impl HttpResponse {
             body: Default::default(),
         }
     }
+
+    /// Get the value of a response header, matching its name case-insensitively.
+    ///
+    /// # Examples
+    ///
+    /// ```ignore
+    /// let response = pink::http_get!("https://example.com/");
+    /// let is_json = response
+    ///     .header("Content-Type")
+    ///     .map_or(false, |value| value.starts_with("application/json"));
+    /// ```
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Whether some of the response headers were dropped for exceeding the size limit of the
+    /// runtime.
+    pub fn headers_truncated(&self) -> bool {
+        self.header(HEADERS_TRUNCATED).is_some()
+    }
+
+    /// Get the requested bytes out of the response to a request made with
+    /// [`HttpRequest::with_range`].
+    pub fn into_range_body(self) -> Result<Vec<u8>, RangeError> {
+        match self.status_code {
+            206 => Ok(self.body),
+            416 => Err(RangeError::NotSatisfiable),
+            200 => Err(RangeError::Ignored(self.body)),
+            code => Err(RangeError::Status(code)),
+        }
+    }
 }
 
 #[macro_export]
@@ -224,3 +360,53 @@ macro_rules! http_put {
         $crate::http_put!($url, $data, Default::default())
     }};
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    fn range_header(range: ByteRange) -> Option<String> {
+        let request = HttpRequest::new("https://example.com", "GET", vec![], vec![]);
+        let request = request.with_range(range);
+        request
+            .headers
+            .into_iter()
+            .find_map(|(name, value)| (name == "Range").then_some(value))
+    }
+
+    #[test]
+    fn ranged_requests_send_the_range_header() {
+        let closed = ByteRange {
+            start: 10,
+            end: Some(19),
+        };
+        assert_eq!(range_header(closed).as_deref(), Some("bytes=10-19"));
+        let open = ByteRange {
+            start: 10,
+            end: None,
+        };
+        assert_eq!(range_header(open).as_deref(), Some("bytes=10-"));
+    }
+
+    #[test]
+    fn range_body_depends_on_the_status() {
+        let response = |status_code| HttpResponse {
+            status_code,
+            ..HttpResponse::ok(b"bytes".to_vec())
+        };
+        assert_eq!(response(206).into_range_body(), Ok(b"bytes".to_vec()));
+        assert_eq!(
+            response(200).into_range_body(),
+            Err(RangeError::Ignored(b"bytes".to_vec()))
+        );
+        assert_eq!(
+            response(416).into_range_body(),
+            Err(RangeError::NotSatisfiable)
+        );
+        assert_eq!(
+            response(500).into_range_body(),
+            Err(RangeError::Status(500))
+        );
+    }
+}
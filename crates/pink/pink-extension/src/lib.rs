@@ -180,6 +180,26 @@ pub enum PinkEvent {
     /// System contract
     #[codec(index = 12)]
     SetJsRuntime(Hash),
+    /// Grant the SideVM instance of the caller contract read access to the given storage keys.
+    ///
+    /// Please do not use this event directly, use [`set_sidevm_readable_storage()`] instead.
+    ///
+    /// # Availability
+    /// Any contract
+    #[codec(index = 13)]
+    SetSidevmReadableStorage(Vec<Vec<u8>>),
+    /// Ask the side VM instance associated with the caller contract to exit, stopping it forcibly
+    /// if it is still running after the timeout.
+    ///
+    /// Please do not use this event directly, use [`stop_sidevm()`] instead.
+    ///
+    /// # Availability
+    /// Any contract
+    #[codec(index = 14)]
+    ShutdownSidevm {
+        /// Milliseconds the instance is given to exit by itself.
+        timeout_ms: u64,
+    },
 }
 
 #[derive(Encode, Decode, Debug, Clone)]
@@ -249,6 +269,8 @@ impl PinkEvent {
             PinkEvent::UpgradeRuntimeTo { .. } => false,
             PinkEvent::SidevmOperation(_) => true,
             PinkEvent::SetJsRuntime(_) => false,
+            PinkEvent::SetSidevmReadableStorage(_) => false,
+            PinkEvent::ShutdownSidevm { .. } => true,
         }
     }
 
@@ -265,6 +287,8 @@ impl PinkEvent {
             PinkEvent::UpgradeRuntimeTo { .. } => "UpgradeRuntimeTo",
             PinkEvent::SidevmOperation(_) => "SidevmOperation",
             PinkEvent::SetJsRuntime(_) => "SetJsRuntime",
+            PinkEvent::SetSidevmReadableStorage(_) => "SetSidevmReadableStorage",
+            PinkEvent::ShutdownSidevm { .. } => "ShutdownSidevm",
         }
     }
 
@@ -281,6 +305,8 @@ impl PinkEvent {
             PinkEvent::UpgradeRuntimeTo { .. } => false,
             PinkEvent::SidevmOperation(_) => false,
             PinkEvent::SetJsRuntime(_) => false,
+            PinkEvent::SetSidevmReadableStorage(_) => false,
+            PinkEvent::ShutdownSidevm { .. } => false,
         }
     }
 }
@@ -395,6 +421,15 @@ pub fn force_stop_sidevm() {
     emit_event::<PinkEnvironment, _>(PinkEvent::StopSidevm)
 }
 
+/// Ask the side VM instance to exit if it is running
+///
+/// The program receives a `SystemMessage::Shutdown`, and is given `timeout_ms` milliseconds to
+/// finish its current work and exit by itself before being stopped forcibly. The timeout is capped
+/// by the worker.
+pub fn stop_sidevm(timeout_ms: u64) {
+    emit_event::<PinkEnvironment, _>(PinkEvent::ShutdownSidevm { timeout_ms })
+}
+
 /// Pushes a message to the associated SideVM instance.
 ///
 /// Note: There is no guarantee that the message will be received by the SideVM instance.
@@ -411,6 +446,25 @@ pub fn push_sidevm_message(message: Vec<u8>) {
     emit_event::<PinkEnvironment, _>(PinkEvent::SidevmMessage(message))
 }
 
+/// Max number of storage keys that can be granted to the SideVM instance.
+pub const MAX_SIDEVM_READABLE_KEYS: usize = 64;
+
+/// Grants the SideVM instance of the calling contract read access to the given storage keys.
+///
+/// The grant takes effect when the SideVM instance is deployed, so it should be called before
+/// [`start_sidevm()`]. Calling it again replaces the previous grant for the next deployment. The
+/// SideVM can read the raw values of the granted keys, and is denied for any other key.
+///
+/// At most [`MAX_SIDEVM_READABLE_KEYS`] keys can be granted, and the grant is ignored otherwise.
+/// It only takes effect in transactions.
+///
+///# Arguments
+///
+///* `keys`: The raw contract storage keys readable by the SideVM instance.
+pub fn set_sidevm_readable_storage(keys: Vec<Vec<u8>>) {
+    emit_event::<PinkEnvironment, _>(PinkEvent::SetSidevmReadableStorage(keys))
+}
+
 /// Set the log handler contract of current cluster. (system only)
 pub fn set_log_handler(contract: AccountId) {
     emit_event::<PinkEnvironment, _>(PinkEvent::SetLogHandler(contract))
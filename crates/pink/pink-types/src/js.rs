@@ -6,6 +6,10 @@ use alloc::vec::Vec;
 pub enum JsCode {
     Source(String),
     Bytecode(Vec<u8>),
+    /// Modules, as (name, source) pairs, that the other codes can `import` by name.
+    ///
+    /// Imports only resolve to the modules given here, never to the filesystem or the network.
+    Modules(Vec<(String, String)>),
 }
 
 #[derive(scale::Encode, scale::Decode, Debug, PartialEq, Eq, Clone)]
@@ -17,4 +21,19 @@ pub enum JsValue {
     Bytes(Vec<u8>),
     Other(String),
     Exception(String),
+    /// The evaluation went over its [`JsBudget`].
+    Timeout(String),
+}
+
+/// Bounds of a js evaluation, on top of the time remaining to the contract call.
+///
+/// An evaluation going over any of them is aborted and returns [`JsValue::Timeout`]. No bound if
+/// None.
+#[derive(scale::Encode, scale::Decode, Debug, Default, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "std", derive(scale_info::TypeInfo))]
+pub struct JsBudget {
+    /// Max milliseconds the evaluation can take.
+    pub timeout_ms: Option<u64>,
+    /// Max number of wasm instructions the js runtime can retire.
+    pub max_instructions: Option<u64>,
 }
@@ -256,8 +256,8 @@ mod test_cluster {
     use pink_capi::v1::{
         ecall::ECalls,
         ocall::{
-            BatchHttpResult, ExecContext, HttpRequest, HttpRequestError, HttpResponse, JsCode,
-            JsValue, OCalls, StorageChanges,
+            BatchHttpResult, ExecContext, HttpRequest, HttpRequestError, HttpResponse,
+            HttpRetryPolicy, JsBudget, JsCode, JsValue, OCalls, StorageChanges,
         },
         CrossCall, CrossCallMut, ECall,
     };
@@ -464,6 +464,14 @@ mod test_cluster {
             local_cache::set(&contract, &key, &value)
         }
 
+        fn cache_get_batch(&self, contract: Vec<u8>, keys: Vec<Vec<u8>>) -> Vec<Option<Vec<u8>>> {
+            local_cache::get_batch(&contract, &keys)
+        }
+
+        fn cache_set_batch(&self, contract: Vec<u8>, pairs: Vec<(Vec<u8>, Vec<u8>)>) -> Vec<bool> {
+            local_cache::set_batch(&contract, &pairs)
+        }
+
         fn cache_set_expiration(&self, contract: Vec<u8>, key: Vec<u8>, expiration: u64) {
             local_cache::set_expiration(&contract, &key, expiration)
         }
@@ -493,6 +501,20 @@ mod test_cluster {
             pink_extension_runtime::batch_http_request(requests, timeout_ms)
         }
 
+        fn batch_http_request_with_timeouts(
+            &self,
+            _: AccountId,
+            requests: Vec<HttpRequest>,
+            timeout_ms: u64,
+            request_timeout_ms: Option<u64>,
+        ) -> BatchHttpResult {
+            pink_extension_runtime::batch_http_request_with_timeouts(
+                requests,
+                timeout_ms,
+                request_timeout_ms,
+            )
+        }
+
         fn emit_system_event_block(&self, number: u64, _encoded_block: Vec<u8>) {
             log::info!("emit_system_event_block: number={}", number,);
         }
@@ -514,6 +536,25 @@ mod test_cluster {
             JsValue::Exception("Not implemented".to_string())
         }
 
+        fn js_eval_with_budget(
+            &self,
+            _contract: AccountId,
+            _codes: Vec<JsCode>,
+            _args: Vec<String>,
+            _budget: JsBudget,
+        ) -> JsValue {
+            JsValue::Exception("Not implemented".to_string())
+        }
+
+        fn http_request_with_retry(
+            &self,
+            _contract: AccountId,
+            request: HttpRequest,
+            policy: HttpRetryPolicy,
+        ) -> Result<HttpResponse, HttpRequestError> {
+            pink_extension_runtime::http_request_with_retry(request, &policy, 10 * 1000)
+        }
+
         fn origin(&self) -> Option<AccountId> {
             None
         }
@@ -290,6 +290,10 @@ impl ecall::ECalls for ECallImpl {
     fn on_idle(&mut self, block_number: BlockNumber) {
         on_idle(block_number);
     }
+
+    fn contract_storage_get(&self, contract: AccountId, key: Vec<u8>) -> Option<Vec<u8>> {
+        PalletContracts::get_storage(contract, key).ok().flatten()
+    }
 }
 
 /// Clip gas limit to 0.5 second for tx, 10 seconds for query
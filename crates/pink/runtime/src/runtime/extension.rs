@@ -9,8 +9,8 @@ use phala_crypto::sr25519::{Persistence, KDF};
 use phala_types::contract::ConvertTo;
 use pink_extension::{
     chain_extension::{
-        self as ext, HttpRequest, HttpResponse, JsCode, JsValue, PinkExtBackend, SigType,
-        StorageQuotaExceeded,
+        self as ext, HttpRequest, HttpRequestError, HttpResponse, HttpRetryPolicy, JsBudget,
+        JsCode, JsValue, PinkExtBackend, SigType, StorageQuotaExceeded,
     },
     dispatch_ext_call, CacheOp, EcdhPublicKey, EcdsaPublicKey, EcdsaSignature, Hash, PinkEvent,
 };
@@ -169,6 +169,28 @@ impl PinkExtBackend for CallInQuery {
         Ok(OCallImpl.batch_http_request(self.address.clone(), requests, timeout_ms))
     }
 
+    fn batch_http_request_with_timeouts(
+        &self,
+        requests: Vec<ext::HttpRequest>,
+        timeout_ms: u64,
+        request_timeout_ms: Option<u64>,
+    ) -> Result<ext::BatchHttpResult, Self::Error> {
+        Ok(OCallImpl.batch_http_request_with_timeouts(
+            self.address.clone(),
+            requests,
+            timeout_ms,
+            request_timeout_ms,
+        ))
+    }
+
+    fn http_request_with_retry(
+        &self,
+        request: HttpRequest,
+        policy: HttpRetryPolicy,
+    ) -> Result<Result<HttpResponse, HttpRequestError>, Self::Error> {
+        Ok(OCallImpl.http_request_with_retry(self.address.clone(), request, policy))
+    }
+
     fn sign(
         &self,
         sigtype: SigType,
@@ -325,6 +347,23 @@ impl PinkExtBackend for CallInQuery {
     fn js_eval(&self, codes: Vec<JsCode>, args: Vec<String>) -> Result<JsValue, Self::Error> {
         Ok(OCallImpl.js_eval(self.address.clone(), codes, args))
     }
+
+    fn js_eval_with_budget(
+        &self,
+        codes: Vec<JsCode>,
+        args: Vec<String>,
+        budget: JsBudget,
+    ) -> Result<JsValue, Self::Error> {
+        Ok(OCallImpl.js_eval_with_budget(self.address.clone(), codes, args, budget))
+    }
+
+    fn cache_get_batch(&self, keys: Vec<Vec<u8>>) -> Result<Vec<Option<Vec<u8>>>, Self::Error> {
+        Ok(OCallImpl.cache_get_batch(self.address_bytes(), keys))
+    }
+
+    fn cache_set_batch(&self, pairs: Vec<(Vec<u8>, Vec<u8>)>) -> Result<Vec<bool>, Self::Error> {
+        Ok(OCallImpl.cache_set_batch(self.address_bytes(), pairs))
+    }
 }
 
 struct CallInCommand {
@@ -352,6 +391,21 @@ impl PinkExtBackend for CallInCommand {
     ) -> Result<ext::BatchHttpResult, Self::Error> {
         Ok(Err(ext::HttpRequestError::NotAllowed))
     }
+    fn batch_http_request_with_timeouts(
+        &self,
+        _requests: Vec<ext::HttpRequest>,
+        _timeout_ms: u64,
+        _request_timeout_ms: Option<u64>,
+    ) -> Result<ext::BatchHttpResult, Self::Error> {
+        Ok(Err(ext::HttpRequestError::NotAllowed))
+    }
+    fn http_request_with_retry(
+        &self,
+        _request: HttpRequest,
+        _policy: HttpRetryPolicy,
+    ) -> Result<Result<HttpResponse, HttpRequestError>, Self::Error> {
+        Ok(Err(HttpRequestError::NotAllowed))
+    }
     fn sign(
         &self,
         sigtype: SigType,
@@ -495,4 +549,24 @@ impl PinkExtBackend for CallInCommand {
             "Js evaluation is not supported in transaction".into(),
         ))
     }
+
+    fn js_eval_with_budget(
+        &self,
+        code: Vec<JsCode>,
+        args: Vec<String>,
+        _budget: JsBudget,
+    ) -> Result<JsValue, Self::Error> {
+        self.js_eval(code, args)
+    }
+
+    fn cache_get_batch(&self, keys: Vec<Vec<u8>>) -> Result<Vec<Option<Vec<u8>>>, Self::Error> {
+        Ok(vec![None; keys.len()])
+    }
+
+    fn cache_set_batch(&self, pairs: Vec<(Vec<u8>, Vec<u8>)>) -> Result<Vec<bool>, Self::Error> {
+        pairs
+            .into_iter()
+            .map(|(key, value)| Ok(self.cache_set(key.into(), value.into())?.is_ok()))
+            .collect()
+    }
 }
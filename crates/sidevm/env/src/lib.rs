@@ -37,7 +37,9 @@ cfg_if::cfg_if! {
 
 pub type IntRet = i64;
 
-#[derive(Clone, Copy, Debug, derive_more::Display, IntoPrimitive, TryFromPrimitive, Encode, Decode)]
+#[derive(
+    Clone, Copy, Debug, derive_more::Display, IntoPrimitive, TryFromPrimitive, Encode, Decode,
+)]
 #[repr(u8)]
 pub enum OcallError {
     Ok = 0,
@@ -69,20 +71,20 @@ pub enum OcallError {
     Stifled = 14,
     /// The create resource is already exists.
     AlreadyExists = 15,
-    /// Reserved for future use
-    Reserved16 = 16,
-    /// Reserved for future use
-    Reserved17 = 17,
-    /// Reserved for future use
-    Reserved18 = 18,
-    /// Reserved for future use
-    Reserved19 = 19,
-    /// Reserved for future use
-    Reserved20 = 20,
-    /// Reserved for future use
-    Reserved21 = 21,
-    /// Reserved for future use
-    Reserved22 = 22,
+    /// The version of the value does not match the expected one.
+    VersionConflict = 16,
+    /// The instance is not allowed to access the resource.
+    PermissionDenied = 17,
+    /// The TLS certificate or private key configured for the instance is malformed.
+    InvalidCertificate = 18,
+    /// The instance used up its CPU time budget.
+    CpuBudgetExceeded = 19,
+    /// The instance retired as many instructions as it is allowed to.
+    InstructionLimitExceeded = 20,
+    /// More bytes were read from the resource than the instance is allowed to receive.
+    BodyTooLarge = 21,
+    /// The memory of all the instances together reached the ceiling of the host.
+    MemoryCeilingReached = 22,
     /// Reserved for future use
     Reserved23 = 23,
     /// Reserved for future use
@@ -5,6 +5,28 @@ use crate::OcallError;
 pub type AccountId = [u8; 32];
 pub type H256 = [u8; 32];
 
+/// The chain head as seen by the host.
+#[derive(Encode, Decode, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ChainHead {
+    /// The number of the latest processed block.
+    pub block_number: u32,
+    /// The timestamp of the block in milliseconds.
+    pub now_ms: u64,
+}
+
+/// What the instance has left to run before reaching the limits of the metering.
+#[derive(Encode, Decode, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FuelStatus {
+    /// Gas left in the current poll. The instance is stifled if it runs out before yielding.
+    pub gas_left: u64,
+    /// Gas given to each poll.
+    pub gas_per_breath: u64,
+    /// Fuel left in the tank for the next polls. None if the fuel is unlimited.
+    pub fuel_left: Option<u64>,
+    /// Instructions the instance can still retire. None if they aren't limited.
+    pub instructions_left: Option<u64>,
+}
+
 #[derive(Encode, Decode)]
 pub struct QueryRequest {
     pub origin: Option<AccountId>,
@@ -29,6 +51,15 @@ impl HttpHead {
         }
         None
     }
+
+    /// Whether the request is a WebSocket handshake.
+    ///
+    /// The host accepts such requests on behalf of the guest, and the `io_stream` of them carries
+    /// the messages instead of the raw bytes, see the `ws_poll_*` ocalls.
+    pub fn is_websocket_upgrade(&self) -> bool {
+        let upgrade = self.get_header("upgrade").unwrap_or_default();
+        upgrade.eq_ignore_ascii_case("websocket") && self.get_header("sec-websocket-key").is_some()
+    }
 }
 
 #[derive(Encode, Decode, Debug)]
@@ -44,6 +75,13 @@ pub struct HttpResponseHead {
     pub headers: Vec<(String, String)>,
 }
 
+/// A message of a WebSocket connection.
+#[derive(Encode, Decode, Debug, Clone, PartialEq, Eq)]
+pub enum WsMessage {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
 #[derive(Encode, Decode)]
 pub enum SystemMessage {
     PinkLog {
@@ -69,6 +107,11 @@ pub enum SystemMessage {
         output: Vec<u8>,
     },
     Metric(Metric),
+    /// The host asks the program to exit. It is stopped forcibly if it is still running after
+    /// `timeout_ms`.
+    Shutdown {
+        timeout_ms: u64,
+    },
 }
 
 #[derive(Encode, Decode)]
@@ -107,6 +150,8 @@ pub enum QueryError {
     InvalidContractExecResult,
     /// Error reported by the pink runtime.
     DispatchError(String),
+    /// The access is not granted by the contract.
+    PermissionDenied,
 }
 
 impl From<OcallError> for QueryError {
@@ -126,3 +171,12 @@ pub enum QueryResponse {
     },
     SimpleOutput(Vec<u8>),
 }
+
+/// An item received from a pub/sub topic.
+#[derive(Encode, Decode, Debug, Clone, PartialEq, Eq)]
+pub enum TopicMessage {
+    /// A message published to the topic.
+    Message(Vec<u8>),
+    /// The given number of messages were dropped because the subscriber was too slow to keep up.
+    Dropped(u32),
+}
@@ -107,6 +107,57 @@ pub trait OcallFuncs {
     #[ocall(id = 233, encode_output)]
     fn local_cache_remove(key: &[u8]) -> Result<Option<Vec<u8>>>;
 
+    /// Get value from the local cache with its version.
+    #[ocall(id = 234, encode_output)]
+    fn local_cache_get_versioned(key: &[u8]) -> Result<Option<(Vec<u8>, u64)>>;
+
+    /// Set value to the local cache only if the current version of the key equals
+    /// `expected_version`. Version 0 means the key must not exist.
+    ///
+    /// Returns the new version, or `OcallError::VersionConflict` if the version mismatches.
+    #[ocall(id = 235, encode_input, encode_output)]
+    fn local_cache_set_if_version(
+        key: Cow<[u8]>,
+        value: Cow<[u8]>,
+        expected_version: u64,
+    ) -> Result<u64>;
+
+    /// Set value to the local cache, expiring after `ttl_secs`.
+    ///
+    /// A TTL of 0 removes the key. Expired keys read as absent.
+    #[ocall(id = 239, encode_input)]
+    fn local_cache_set_with_ttl(key: Cow<[u8]>, value: Cow<[u8]>, ttl_secs: u64) -> Result<()>;
+
+    /// Set value to the local cache, deduplicated by its content.
+    ///
+    /// Values of the same content share a single copy, and the quota accounts it only once.
+    /// Returns the blake2b-256 hash of the content, to get it by `local_cache_get_by_hash`.
+    #[ocall(id = 236, encode_output)]
+    fn local_cache_set_content(key: &[u8], value: &[u8]) -> Result<[u8; 32]>;
+
+    /// Set value to the local cache, deduplicated by its content and keyed by its blake2b-256
+    /// hash.
+    ///
+    /// Returns `OcallError::InvalidParameter` if the hash doesn't match the value.
+    #[ocall(id = 237)]
+    fn local_cache_set_by_hash(hash: &[u8], value: &[u8]) -> Result<()>;
+
+    /// Get a value set by `local_cache_set_content` or `local_cache_set_by_hash` by the hash of
+    /// its content.
+    #[ocall(id = 238, encode_output)]
+    fn local_cache_get_by_hash(hash: &[u8]) -> Result<Option<Vec<u8>>>;
+
+    /// Get multiple values from the local cache, in the order of the keys.
+    #[ocall(id = 257, encode_input, encode_output)]
+    fn local_cache_get_batch(keys: Vec<Vec<u8>>) -> Result<Vec<Option<Vec<u8>>>>;
+
+    /// Set multiple values to the local cache, in order.
+    ///
+    /// Returns whether each pair was set. Not transactional: a failed pair does not roll back
+    /// the ones set before it.
+    #[ocall(id = 258, encode_input, encode_output)]
+    fn local_cache_set_batch(pairs: Vec<(Vec<u8>, Vec<u8>)>) -> Result<Vec<bool>>;
+
     /// Create input channel
     #[ocall(id = 240, encode_output)]
     fn create_input_channel(ch: InputChannel) -> Result<i32>;
@@ -127,6 +178,93 @@ pub trait OcallFuncs {
     /// Emit program output.
     #[ocall(id = 243)]
     fn emit_program_output(output: &[u8]) -> Result<()>;
+
+    /// Returns the latest block processed by the host.
+    ///
+    /// For instances bound to a query, this is the block the query is executed at.
+    #[ocall(id = 244, encode_output)]
+    fn chain_head() -> Result<messages::ChainHead>;
+
+    /// Signal the host that the program has finished its initialization.
+    ///
+    /// If the instance is deployed with a warmup phase, incoming requests are held by the host
+    /// until this is called.
+    #[ocall(id = 245)]
+    fn signal_ready() -> Result<()>;
+
+    /// Hash the given message on the host.
+    ///
+    /// Charged in proportion to the message length.
+    #[ocall(id = 246, encode_output)]
+    fn hash(algorithm: HashAlgorithm, message: &[u8]) -> Result<[u8; 32]>;
+
+    /// Read a storage key of the contract this instance belongs to.
+    ///
+    /// Only the keys granted by the contract are readable. Returns a channel resource that yields
+    /// a SCALE encoded `Result<Option<Vec<u8>>, QueryError>`.
+    #[ocall(id = 247)]
+    fn read_contract_storage(key: &[u8]) -> Result<i32>;
+
+    /// Publish a message to the given topic, delivering it to every instance subscribed to it.
+    ///
+    /// Never blocks. Subscribers whose buffer is full miss the message, and get a
+    /// `TopicMessage::Dropped` marker before the next one they receive.
+    #[ocall(id = 248)]
+    fn publish(topic: &str, message: &[u8]) -> Result<()>;
+
+    /// Subscribe to the given topic.
+    ///
+    /// Returns a channel resource that yields SCALE encoded `TopicMessage`s.
+    #[ocall(id = 249)]
+    fn subscribe(topic: &str) -> Result<i32>;
+
+    /// Returns the sr25519 public key of the identity given to this instance by the host.
+    ///
+    /// The key is derived from a host secret and the vmid and code hash of the instance, so it
+    /// stays the same across restarts. Returns `OcallError::UnsupportedOperation` if the host
+    /// doesn't provide identities.
+    #[ocall(id = 250, encode_output)]
+    fn identity_public_key() -> Result<[u8; 32]>;
+
+    /// Sign the message with the identity key of this instance.
+    ///
+    /// The signature is made in the `substrate` signing context, so it can be verified with the
+    /// sr25519 primitives of the chain.
+    #[ocall(id = 251, encode_output)]
+    fn identity_sign(message: &[u8]) -> Result<[u8; 64]>;
+
+    /// Verify a signature made by the identity key of this instance.
+    #[ocall(id = 252, encode_output)]
+    fn identity_verify(message: &[u8], signature: &[u8]) -> Result<bool>;
+
+    /// Returns the protocol negotiated via ALPN on a TLS stream, if any.
+    #[ocall(id = 253, encode_output)]
+    fn tls_alpn_protocol(resource_id: i32) -> Result<Option<Vec<u8>>>;
+
+    /// Receive a message from a WebSocket accepted by the host.
+    ///
+    /// The ping, pong and close frames are handled by the host. Returns `OcallError::EndOfFile`
+    /// once the connection is closed.
+    #[ocall(id = 254, encode_output)]
+    fn ws_poll_recv(waker_id: i32, resource_id: i32) -> Result<messages::WsMessage>;
+
+    /// Queue a message to send to a WebSocket accepted by the host.
+    ///
+    /// Returns `OcallError::Pending` without taking the message if the connection isn't ready.
+    /// Use `ws_poll_flush` to wait for the queued messages to be sent.
+    #[ocall(id = 255, encode_input)]
+    fn ws_poll_send(waker_id: i32, resource_id: i32, message: messages::WsMessage) -> Result<()>;
+
+    /// Flush the messages queued to a WebSocket.
+    #[ocall(id = 256)]
+    fn ws_poll_flush(waker_id: i32, resource_id: i32) -> Result<()>;
+
+    /// Returns the gas, fuel and instructions this instance has left.
+    ///
+    /// The host only reads its counters, so this costs no more than any other ocall, and can be
+    /// called often, e.g. between the items of a batch.
+    #[ocall(id = 259, encode_output)]
+    fn fuel_status() -> Result<messages::FuelStatus>;
 }
 
 #[repr(u8)]
@@ -142,6 +280,26 @@ pub enum InputChannel {
     HttpRequest = 4,
 }
 
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HashAlgorithm {
+    Sha256 = 1,
+    Blake2b256 = 2,
+}
+
+impl I32Convertible for HashAlgorithm {
+    fn to_i32(&self) -> i32 {
+        *self as i32
+    }
+    fn from_i32(i: i32) -> Result<Self> {
+        match i {
+            1 => Ok(HashAlgorithm::Sha256),
+            2 => Ok(HashAlgorithm::Blake2b256),
+            _ => Err(OcallError::InvalidParameter),
+        }
+    }
+}
+
 impl I32Convertible for InputChannel {
     fn to_i32(&self) -> i32 {
         *self as i32
@@ -16,4 +16,9 @@ pub enum TlsServerConfig {
 pub enum TlsClientConfig {
     /// Nothing to be configured in this version.
     V0,
+    /// Offer the given protocols via ALPN, e.g. `h2` and `http/1.1`, in order of preference.
+    ///
+    /// The connection is made once the handshake is done, so that the negotiated protocol can be
+    /// queried with `tls_alpn_protocol` right away.
+    V1 { alpn_protocols: Vec<Vec<u8>> },
 }
@@ -0,0 +1,155 @@
+//! Caps of the bytes the guests read from the outside through the host.
+//!
+//! A server answering an outbound connection, or a client sending an incoming HTTP request, could
+//! otherwise stream without end into the memory of the guest. The bytes are counted as they are
+//! read, so the cap holds for the streamed bodies as well, and the read past it fails with
+//! `OcallError::BodyTooLarge` rather than being truncated silently.
+
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use sidevm_env::{OcallError, Result};
+
+/// Caps of the bodies each instance can receive.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BodyLimits {
+    /// Max number of bytes read from an outbound connection, the heads of the responses
+    /// included. Unlimited if None.
+    pub max_response_bytes: Option<u64>,
+    /// Max number of bytes of the body of an incoming HTTP request. Unlimited if None.
+    pub max_request_body_bytes: Option<u64>,
+}
+
+struct Cap {
+    max: u64,
+    read: u64,
+}
+
+/// The bytes read so far from the capped resources, by resource id.
+#[derive(Default)]
+pub(crate) struct ReadCaps {
+    limits: BodyLimits,
+    caps: HashMap<i32, Cap>,
+}
+
+impl ReadCaps {
+    pub(crate) fn set_limits(&mut self, limits: BodyLimits) {
+        self.limits = limits;
+    }
+
+    fn cap(&mut self, resource_id: i32, max: Option<u64>) {
+        if let Some(max) = max {
+            self.caps.insert(resource_id, Cap { max, read: 0 });
+        }
+    }
+
+    /// Cap the bytes read from a new outbound connection.
+    pub(crate) fn cap_response(&mut self, resource_id: i32) {
+        self.cap(resource_id, self.limits.max_response_bytes);
+    }
+
+    /// Cap the bytes read from the body stream of a new incoming HTTP request.
+    pub(crate) fn cap_request_body(&mut self, resource_id: i32) {
+        self.cap(resource_id, self.limits.max_request_body_bytes);
+    }
+
+    /// How much of a buffer of `len` bytes a read from the resource may fill.
+    ///
+    /// One byte more than what is left of the cap is allowed, to tell a body ending right at the
+    /// cap from one going past it.
+    pub(crate) fn window(&self, resource_id: i32, len: usize) -> usize {
+        match self.caps.get(&resource_id) {
+            Some(cap) => {
+                let left = cap.max.saturating_sub(cap.read).saturating_add(1);
+                len.min(usize::try_from(left).unwrap_or(usize::MAX))
+            }
+            None => len,
+        }
+    }
+
+    /// Count the bytes just read from the resource.
+    ///
+    /// Fails with `OcallError::BodyTooLarge` once more than the cap has been read.
+    pub(crate) fn consume(&mut self, resource_id: i32, len: u64) -> Result<()> {
+        let Some(cap) = self.caps.get_mut(&resource_id) else {
+            return Ok(());
+        };
+        cap.read = cap.read.saturating_add(len);
+        if cap.read > cap.max {
+            return Err(OcallError::BodyTooLarge);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn release(&mut self, resource_id: i32) {
+        self.caps.remove(&resource_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    /// Read the stream to the end the way the poll_read ocall does.
+    async fn read_capped(stream: &mut TcpStream, caps: &mut ReadCaps, id: i32) -> Result<u64> {
+        let mut buf = vec![0u8; 64 * 1024];
+        let mut total = 0;
+        loop {
+            let len = caps.window(id, buf.len());
+            let n = stream
+                .read(&mut buf[..len])
+                .await
+                .or(Err(OcallError::IoError))?;
+            caps.consume(id, n as u64)?;
+            if n == 0 {
+                return Ok(total);
+            }
+            total += n as u64;
+        }
+    }
+
+    #[tokio::test]
+    async fn over_cap_response_fails() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let chunk = vec![b'x'; 64 * 1024];
+            // An endless response, as a malicious server would send.
+            while socket.write_all(&chunk).await.is_ok() {}
+        });
+
+        let mut caps = ReadCaps::default();
+        caps.set_limits(BodyLimits {
+            max_response_bytes: Some(1024 * 1024),
+            ..Default::default()
+        });
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        caps.cap_response(0);
+        let result = read_capped(&mut stream, &mut caps, 0).await;
+        assert!(matches!(result, Err(OcallError::BodyTooLarge)));
+        assert!(caps.caps[&0].read <= 1024 * 1024 + 1);
+    }
+
+    #[tokio::test]
+    async fn response_at_cap_is_read() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            socket.write_all(&[b'x'; 1000]).await.unwrap();
+        });
+
+        let mut caps = ReadCaps::default();
+        caps.set_limits(BodyLimits {
+            max_response_bytes: Some(1000),
+            ..Default::default()
+        });
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        caps.cap_response(0);
+        assert_eq!(read_capped(&mut stream, &mut caps, 0).await.unwrap(), 1000);
+    }
+}
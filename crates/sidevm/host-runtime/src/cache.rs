@@ -0,0 +1,280 @@
+//! An in-memory [`CacheOps`] with a byte budget for each instance.
+//!
+//! A set that would exceed the budget of an instance evicts its least recently used entries to
+//! make room, so a program filling the cache only affects itself. Only a value that can't fit in
+//! the budget even with the cache emptied is rejected.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use blake2::{digest::consts::U32, Blake2b, Digest};
+use sidevm_env::{OcallError, Result};
+
+use crate::{CacheOps, VmId};
+
+/// Domain separator of the shared namespaces, keeping them apart from the ids of the instances.
+const SHARED_NAMESPACE_TAG: &[u8] = b"sidevm/shared-cache/";
+
+/// The cache namespace shared by the instances configured with the same `name`.
+pub(crate) fn shared_namespace(name: &str) -> VmId {
+    Blake2b::<U32>::new()
+        .chain_update(SHARED_NAMESPACE_TAG)
+        .chain_update(name)
+        .finalize()
+        .into()
+}
+
+struct Entry {
+    value: Vec<u8>,
+    last_used: u64,
+    expire_at: Option<Instant>,
+}
+
+#[derive(Default)]
+struct Storage {
+    budget: Option<usize>,
+    /// Sum of the size of all the keys and values.
+    usage: usize,
+    /// Bumped on every access, to order the entries by recency.
+    clock: u64,
+    entries: HashMap<Vec<u8>, Entry>,
+    /// The keys by the time they were last used.
+    recency: BTreeMap<u64, Vec<u8>>,
+}
+
+impl Storage {
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    fn get(&mut self, key: &[u8]) -> Option<Vec<u8>> {
+        let expired = self
+            .entries
+            .get(key)?
+            .expire_at
+            .map_or(false, |at| at <= Instant::now());
+        if expired {
+            self.remove(key);
+            return None;
+        }
+        let now = self.tick();
+        let entry = self.entries.get_mut(key)?;
+        let key = self
+            .recency
+            .remove(&entry.last_used)
+            .expect("BUG: entry missing in the recency index");
+        entry.last_used = now;
+        self.recency.insert(now, key);
+        Some(entry.value.clone())
+    }
+
+    fn remove(&mut self, key: &[u8]) -> Option<Vec<u8>> {
+        let entry = self.entries.remove(key)?;
+        self.recency.remove(&entry.last_used);
+        self.usage -= key.len() + entry.value.len();
+        Some(entry.value)
+    }
+
+    fn set(&mut self, key: &[u8], value: &[u8], budget: usize) -> Result<()> {
+        let size = key.len() + value.len();
+        if size > budget {
+            return Err(OcallError::ResourceLimited);
+        }
+        self.remove(key);
+        while self.usage + size > budget {
+            let Some((_, lru_key)) = self.recency.pop_first() else {
+                break;
+            };
+            if let Some(entry) = self.entries.remove(&lru_key) {
+                self.usage -= lru_key.len() + entry.value.len();
+            }
+        }
+        let now = self.tick();
+        self.entries.insert(
+            key.to_vec(),
+            Entry {
+                value: value.to_vec(),
+                last_used: now,
+                expire_at: None,
+            },
+        );
+        self.recency.insert(now, key.to_vec());
+        self.usage += size;
+        Ok(())
+    }
+}
+
+/// A cache bounding the bytes stored by each instance, evicting the least recently used entries.
+pub struct LruCache {
+    default_budget: usize,
+    storages: Mutex<HashMap<Vec<u8>, Storage>>,
+}
+
+impl LruCache {
+    /// Create a cache giving each instance `default_budget` bytes for its keys and values.
+    pub fn new(default_budget: usize) -> Self {
+        Self {
+            default_budget,
+            storages: Default::default(),
+        }
+    }
+
+    /// Override the budget of an instance, evicting its entries if it's now over the budget.
+    pub fn set_budget(&self, contract: &[u8], budget: usize) {
+        let mut storages = self.storages.lock().unwrap();
+        let storage = storages.entry(contract.to_vec()).or_default();
+        storage.budget = Some(budget);
+        while storage.usage > budget {
+            let Some((_, key)) = storage.recency.first_key_value() else {
+                break;
+            };
+            let key = key.clone();
+            storage.remove(&key);
+        }
+    }
+
+    /// Total bytes stored by all the instances.
+    pub fn total_usage(&self) -> usize {
+        let storages = self.storages.lock().unwrap();
+        storages.values().map(|storage| storage.usage).sum()
+    }
+}
+
+impl CacheOps for LruCache {
+    fn get(&self, contract: &[u8], key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let mut storages = self.storages.lock().unwrap();
+        Ok(storages
+            .get_mut(contract)
+            .and_then(|storage| storage.get(key)))
+    }
+
+    fn set(&self, contract: &[u8], key: &[u8], value: &[u8]) -> Result<()> {
+        let mut storages = self.storages.lock().unwrap();
+        let storage = storages.entry(contract.to_vec()).or_default();
+        let budget = storage.budget.unwrap_or(self.default_budget);
+        storage.set(key, value, budget)
+    }
+
+    fn set_expiration(&self, contract: &[u8], key: &[u8], expire_after_secs: u64) -> Result<()> {
+        let mut storages = self.storages.lock().unwrap();
+        let Some(storage) = storages.get_mut(contract) else {
+            return Ok(());
+        };
+        if expire_after_secs == 0 {
+            storage.remove(key);
+        } else if let Some(entry) = storage.entries.get_mut(key) {
+            entry.expire_at = Instant::now().checked_add(Duration::from_secs(expire_after_secs));
+        }
+        Ok(())
+    }
+
+    fn remove(&self, contract: &[u8], key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let mut storages = self.storages.lock().unwrap();
+        Ok(storages
+            .get_mut(contract)
+            .and_then(|storage| storage.remove(key)))
+    }
+
+    fn usage(&self, contract: &[u8]) -> Option<usize> {
+        let storages = self.storages.lock().unwrap();
+        storages.get(contract).map(|storage| storage.usage)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn namespaces_are_isolated_unless_shared() {
+        let cache = LruCache::new(1024);
+        let (vm_a, vm_b) = ([1u8; 32], [2u8; 32]);
+        cache.set(&vm_a, b"key", b"a").unwrap();
+        cache.set(&vm_b, b"key", b"b").unwrap();
+        assert_eq!(cache.get(&vm_a, b"key").unwrap(), Some(b"a".to_vec()));
+        assert_eq!(cache.get(&vm_b, b"key").unwrap(), Some(b"b".to_vec()));
+
+        let shared = shared_namespace("demo");
+        assert_eq!(shared, shared_namespace("demo"));
+        assert_ne!(shared, shared_namespace("other"));
+        assert_eq!(cache.get(&shared, b"key").unwrap(), None);
+        cache.set(&shared, b"key", b"shared").unwrap();
+        assert_eq!(cache.get(&vm_a, b"key").unwrap(), Some(b"a".to_vec()));
+    }
+
+    #[test]
+    fn sets_over_budget_evict_the_least_recently_used() {
+        let vm = [1u8; 32];
+        // Room for three entries of a one byte key and a three bytes value.
+        let cache = LruCache::new(12);
+        for key in [b"a", b"b", b"c"] {
+            cache.set(&vm, key, b"val").unwrap();
+        }
+        assert_eq!(cache.usage(&vm), Some(12));
+        // Refreshes `a`, leaving `b` the least recently used.
+        assert_eq!(cache.get(&vm, b"a").unwrap(), Some(b"val".to_vec()));
+        cache.set(&vm, b"d", b"val").unwrap();
+        assert_eq!(cache.get(&vm, b"b").unwrap(), None);
+        for key in [b"a", b"c", b"d"] {
+            assert_eq!(cache.get(&vm, key).unwrap(), Some(b"val".to_vec()));
+        }
+        assert_eq!(cache.usage(&vm), Some(12));
+
+        // A bigger value takes the room of as many entries as needed, `a` then `c`.
+        cache.set(&vm, b"e", b"value12").unwrap();
+        assert_eq!(cache.get(&vm, b"a").unwrap(), None);
+        assert_eq!(cache.get(&vm, b"c").unwrap(), None);
+        assert_eq!(cache.get(&vm, b"d").unwrap(), Some(b"val".to_vec()));
+        assert_eq!(cache.usage(&vm), Some(12));
+    }
+
+    #[test]
+    fn values_larger_than_the_budget_are_rejected() {
+        let vm = [1u8; 32];
+        let cache = LruCache::new(8);
+        cache.set(&vm, b"a", b"val").unwrap();
+        assert!(matches!(
+            cache.set(&vm, b"b", b"too large"),
+            Err(OcallError::ResourceLimited)
+        ));
+        // Nothing is evicted for a value that can't fit anyway.
+        assert_eq!(cache.get(&vm, b"a").unwrap(), Some(b"val".to_vec()));
+        assert_eq!(cache.get(&vm, b"b").unwrap(), None);
+        assert_eq!(cache.usage(&vm), Some(4));
+        // The key counts against the budget too.
+        assert!(matches!(
+            cache.set(&vm, b"long key", b"v"),
+            Err(OcallError::ResourceLimited)
+        ));
+    }
+
+    #[test]
+    fn shrinking_the_budget_evicts_down_to_it() {
+        let (vm, other) = ([1u8; 32], [2u8; 32]);
+        let cache = LruCache::new(100);
+        for key in [b"a", b"b", b"c", b"d"] {
+            cache.set(&vm, key, b"val").unwrap();
+        }
+        cache.set(&other, b"a", b"val").unwrap();
+        assert_eq!(cache.get(&vm, b"a").unwrap(), Some(b"val".to_vec()));
+        assert_eq!(cache.total_usage(), 20);
+
+        cache.set_budget(&vm, 9);
+        assert_eq!(cache.usage(&vm), Some(8));
+        assert_eq!(cache.get(&vm, b"b").unwrap(), None);
+        assert_eq!(cache.get(&vm, b"c").unwrap(), None);
+        assert_eq!(cache.get(&vm, b"a").unwrap(), Some(b"val".to_vec()));
+        assert_eq!(cache.get(&vm, b"d").unwrap(), Some(b"val".to_vec()));
+        // The new budget holds for the later sets, and leaves the other instances alone.
+        assert!(matches!(
+            cache.set(&vm, b"e", b"too large"),
+            Err(OcallError::ResourceLimited)
+        ));
+        assert_eq!(cache.usage(&other), Some(4));
+        assert_eq!(cache.total_usage(), 12);
+    }
+}
@@ -0,0 +1,165 @@
+//! The clock seen by the guests, through their timers and the WASI clocks.
+//!
+//! Instances use the system clock, unless the embedder installs a [`ManualClock`], which only
+//! moves when advanced by hand. Tests and replays then see the same timing on every run.
+
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+
+/// A timer created by a guest.
+pub(crate) type Timer = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// A clock advancing only when [`ManualClock::advance`] is called.
+///
+/// Clones share the same time, so the embedder keeps a clone to drive the instances with.
+#[derive(Clone, Default)]
+pub struct ManualClock {
+    state: Arc<Mutex<ManualClockState>>,
+}
+
+#[derive(Default)]
+struct ManualClockState {
+    since_unix_epoch: Duration,
+    next_timer_id: u64,
+    /// The wakers of the pending timers, with their deadlines.
+    timers: BTreeMap<u64, (Duration, Waker)>,
+}
+
+impl ManualClock {
+    /// Create a clock frozen at the given time since the unix epoch.
+    pub fn new(since_unix_epoch: Duration) -> Self {
+        let clock = Self::default();
+        clock.state.lock().unwrap().since_unix_epoch = since_unix_epoch;
+        clock
+    }
+
+    /// The current time of the clock since the unix epoch.
+    pub fn now(&self) -> Duration {
+        self.state.lock().unwrap().since_unix_epoch
+    }
+
+    /// Move the clock forward, waking up the timers due by then.
+    pub fn advance(&self, duration: Duration) {
+        let mut state = self.state.lock().unwrap();
+        state.since_unix_epoch += duration;
+        let now = state.since_unix_epoch;
+        let mut due = vec![];
+        state.timers.retain(|_, (deadline, waker)| {
+            if *deadline > now {
+                return true;
+            }
+            due.push(waker.clone());
+            false
+        });
+        drop(state);
+        for waker in due {
+            waker.wake();
+        }
+    }
+
+    fn sleep(&self, duration: Duration) -> ManualSleep {
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_timer_id;
+        state.next_timer_id += 1;
+        ManualSleep {
+            clock: self.clone(),
+            id,
+            deadline: state.since_unix_epoch + duration,
+        }
+    }
+}
+
+struct ManualSleep {
+    clock: ManualClock,
+    id: u64,
+    deadline: Duration,
+}
+
+impl Future for ManualSleep {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut state = self.clock.state.lock().unwrap();
+        if state.since_unix_epoch >= self.deadline {
+            return Poll::Ready(());
+        }
+        state
+            .timers
+            .insert(self.id, (self.deadline, cx.waker().clone()));
+        Poll::Pending
+    }
+}
+
+impl Drop for ManualSleep {
+    fn drop(&mut self) {
+        self.clock.state.lock().unwrap().timers.remove(&self.id);
+    }
+}
+
+/// The clock of an instance.
+#[derive(Clone, Default)]
+pub(crate) enum Clock {
+    #[default]
+    System,
+    Manual(ManualClock),
+}
+
+impl Clock {
+    pub(crate) fn sleep(&self, duration: Duration) -> Timer {
+        match self {
+            Clock::System => Box::pin(tokio::time::sleep(duration)),
+            Clock::Manual(clock) => Box::pin(clock.sleep(duration)),
+        }
+    }
+
+    /// The time since the unix epoch, or None to read the system clock.
+    pub(crate) fn manual_now(&self) -> Option<Duration> {
+        match self {
+            Clock::System => None,
+            Clock::Manual(clock) => Some(clock.now()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::task::{waker, ArcWake};
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    #[derive(Default)]
+    struct Flag(AtomicBool);
+
+    impl ArcWake for Flag {
+        fn wake_by_ref(arc_self: &Arc<Self>) {
+            arc_self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn frozen_timer_fires_only_when_advanced() {
+        let clock = ManualClock::new(Duration::from_secs(1_000));
+        let woken = Arc::new(Flag::default());
+        let waker = waker(woken.clone());
+        let mut cx = Context::from_waker(&waker);
+        let mut timer = Clock::Manual(clock.clone()).sleep(Duration::from_millis(10));
+
+        assert!(timer.as_mut().poll(&mut cx).is_pending());
+        // Way past the deadline in real time, but the clock is frozen.
+        std::thread::sleep(Duration::from_millis(50));
+        clock.advance(Duration::from_millis(9));
+        assert!(!woken.0.load(Ordering::SeqCst));
+        assert!(timer.as_mut().poll(&mut cx).is_pending());
+
+        clock.advance(Duration::from_millis(1));
+        assert!(woken.0.load(Ordering::SeqCst));
+        assert!(timer.as_mut().poll(&mut cx).is_ready());
+        assert_eq!(clock.now(), Duration::from_millis(1_000_010));
+    }
+}
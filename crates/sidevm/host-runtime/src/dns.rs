@@ -0,0 +1,247 @@
+//! Resolution of the hosts the guests connect to.
+//!
+//! The resolver is given to the instances when they are spawned. By default, it is a single
+//! caching resolver shared by all of them, so a host isn't looked up again until its records
+//! expire. An optional policy vets the resolved addresses before any connection is made, e.g. to
+//! keep the guests off the private network of the host.
+//!
+//! The connections tunneled through a proxy are resolved by the proxy, and aren't checked.
+
+use std::{
+    collections::HashMap,
+    io::{self, Error, ErrorKind},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    sync::Arc,
+};
+
+use futures::future::BoxFuture;
+use once_cell::sync::{Lazy, OnceCell};
+use sidevm_env::OcallError;
+use tokio::net::TcpStream;
+use trust_dns_resolver::TokioAsyncResolver;
+
+/// Resolves host names to IP addresses.
+pub trait Resolver: Send + Sync {
+    fn resolve<'a>(&'a self, host: &'a str) -> BoxFuture<'a, io::Result<Vec<IpAddr>>>;
+}
+
+/// A resolver using the DNS configuration of the system, caching the answers as long as their
+/// TTLs allow.
+#[derive(Default)]
+pub struct CachingResolver {
+    inner: OnceCell<TokioAsyncResolver>,
+}
+
+impl CachingResolver {
+    fn inner(&self) -> io::Result<&TokioAsyncResolver> {
+        // By default, tokio uses the blocking DNS resovler from libc and run them in a thread
+        // pool. That would cause problem such as run out of thread-pool in some poor network
+        // situation. So, we use trust-dns async resolver here.
+        self.inner.get_or_try_init(|| {
+            TokioAsyncResolver::tokio_from_system_conf()
+                .map_err(|e| Error::new(ErrorKind::Other, e))
+        })
+    }
+}
+
+impl Resolver for CachingResolver {
+    fn resolve<'a>(&'a self, host: &'a str) -> BoxFuture<'a, io::Result<Vec<IpAddr>>> {
+        Box::pin(async move {
+            let ips = self
+                .inner()?
+                .lookup_ip(host)
+                .await
+                .map_err(|e| Error::new(ErrorKind::Other, e))?;
+            Ok(ips.iter().collect())
+        })
+    }
+}
+
+/// A fixed map of host names to addresses, mostly for tests. Unknown hosts fail to resolve.
+#[derive(Debug, Clone, Default)]
+pub struct StaticHosts {
+    hosts: HashMap<String, Vec<IpAddr>>,
+}
+
+impl StaticHosts {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_host(
+        mut self,
+        host: impl Into<String>,
+        ips: impl IntoIterator<Item = IpAddr>,
+    ) -> Self {
+        self.hosts.insert(host.into(), ips.into_iter().collect());
+        self
+    }
+}
+
+impl Resolver for StaticHosts {
+    fn resolve<'a>(&'a self, host: &'a str) -> BoxFuture<'a, io::Result<Vec<IpAddr>>> {
+        let rv = self
+            .hosts
+            .get(host)
+            .cloned()
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("unknown host {host}")));
+        Box::pin(async move { rv })
+    }
+}
+
+/// Decides whether the guests may connect to a host, given the addresses it resolved to.
+pub type AddrPolicy = Arc<dyn Fn(&str, &[IpAddr]) -> bool + Send + Sync>;
+
+/// A policy allowing only the hosts all of whose addresses are publicly routable, rejecting the
+/// loopback, private (RFC 1918 and unique local), link-local and shared addresses.
+pub fn public_only(_host: &str, ips: &[IpAddr]) -> bool {
+    ips.iter().all(is_public)
+}
+
+fn is_public(ip: &IpAddr) -> bool {
+    fn is_public_v4(ip: &Ipv4Addr) -> bool {
+        let [a, b, ..] = ip.octets();
+        // 100.64.0.0/10 is the shared address space of the carrier-grade NATs.
+        let shared = a == 100 && (b & 0xc0) == 64;
+        !(ip.is_private()
+            || ip.is_loopback()
+            || ip.is_link_local()
+            || ip.is_unspecified()
+            || ip.is_broadcast()
+            || shared)
+    }
+    fn is_public_v6(ip: &Ipv6Addr) -> bool {
+        if let Some(ip) = ip.to_ipv4_mapped() {
+            return is_public_v4(&ip);
+        }
+        let first = ip.segments()[0];
+        let unique_local = (first & 0xfe00) == 0xfc00;
+        let link_local = (first & 0xffc0) == 0xfe80;
+        !(ip.is_loopback() || ip.is_unspecified() || unique_local || link_local)
+    }
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => is_public_v6(ip),
+    }
+}
+
+static DEFAULT_RESOLVER: Lazy<Arc<CachingResolver>> = Lazy::new(Default::default);
+
+/// The resolver and the address policy of an instance.
+#[derive(Clone)]
+pub struct Dns {
+    resolver: Arc<dyn Resolver>,
+    policy: Option<AddrPolicy>,
+}
+
+impl Default for Dns {
+    fn default() -> Self {
+        Self::new(DEFAULT_RESOLVER.clone())
+    }
+}
+
+impl Dns {
+    pub fn new(resolver: Arc<dyn Resolver>) -> Self {
+        Self {
+            resolver,
+            policy: None,
+        }
+    }
+
+    /// Check the resolved addresses with the policy before connecting.
+    ///
+    /// The addresses given literally by the guests are checked too.
+    pub fn with_policy(
+        mut self,
+        policy: impl Fn(&str, &[IpAddr]) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.policy = Some(Arc::new(policy));
+        self
+    }
+
+    /// Resolve the host, failing with `PermissionDenied` if the policy rejects its addresses.
+    pub(crate) async fn lookup(&self, host: &str) -> io::Result<Vec<IpAddr>> {
+        let ips = match host.parse::<IpAddr>() {
+            Ok(ip) => vec![ip],
+            Err(_) => self.resolver.resolve(host).await?,
+        };
+        if ips.is_empty() {
+            return Err(Error::new(ErrorKind::Other, "DNS: No address found"));
+        }
+        if let Some(policy) = &self.policy {
+            if !policy(host, &ips) {
+                return Err(Error::new(
+                    ErrorKind::PermissionDenied,
+                    format!("connecting to {host} is denied"),
+                ));
+            }
+        }
+        Ok(ips)
+    }
+
+    /// Connect to the first reachable address of the host.
+    pub(crate) async fn connect(&self, host: &str, port: u16) -> io::Result<TcpStream> {
+        let mut last_err = None;
+        for ip in self.lookup(host).await? {
+            match TcpStream::connect((ip, port)).await {
+                Ok(stream) => return Ok(stream),
+                Err(e) => last_err = Some(e),
+            }
+        }
+        Err(last_err.unwrap_or_else(|| Error::new(ErrorKind::Other, "DNS: No address found")))
+    }
+}
+
+/// The error given to the guest for a failed outbound connection.
+pub(crate) fn connect_error(err: &io::Error) -> OcallError {
+    match err.kind() {
+        ErrorKind::PermissionDenied => OcallError::PermissionDenied,
+        _ => OcallError::IoError,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn denied_private_ip_is_never_connected() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let hosts = StaticHosts::new().with_host("intranet.test", [Ipv4Addr::LOCALHOST.into()]);
+        let dns = Dns::new(Arc::new(hosts)).with_policy(public_only);
+
+        for host in ["intranet.test", "127.0.0.1"] {
+            let err = dns.connect(host, port).await.unwrap_err();
+            assert!(matches!(connect_error(&err), OcallError::PermissionDenied));
+        }
+        let accepted = tokio::time::timeout(Duration::from_millis(50), listener.accept()).await;
+        assert!(accepted.is_err(), "a connection was made");
+
+        let dns = Dns::new(Arc::new(StaticHosts::new())).with_policy(public_only);
+        let err = dns.connect("unknown.test", port).await.unwrap_err();
+        assert!(matches!(connect_error(&err), OcallError::IoError));
+    }
+
+    #[test]
+    fn public_only_rejects_private_ranges() {
+        for ip in [
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "100.64.0.1",
+            "::1",
+            "fd00::1",
+        ] {
+            assert!(!public_only("", &[ip.parse().unwrap()]), "{ip}");
+        }
+        assert!(!public_only("", &["::ffff:10.0.0.1".parse().unwrap()]));
+        assert!(public_only("", &["1.1.1.1".parse().unwrap()]));
+        assert!(!public_only(
+            "",
+            &["1.1.1.1".parse().unwrap(), "10.0.0.1".parse().unwrap()]
+        ));
+    }
+}
@@ -8,7 +8,7 @@ use std::{
     ops::{Deref, DerefMut},
     sync::{Arc, Mutex},
     task::Poll::{Pending, Ready},
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use anyhow::Context;
@@ -17,20 +17,23 @@ use tokio::{
     net::{TcpListener, TcpStream},
     sync::{
         mpsc::{error::TrySendError, Sender},
-        oneshot,
+        oneshot, watch,
     },
     sync::{oneshot::Sender as OneshotSender, Semaphore},
 };
 use tracing::{error, info, warn, Instrument, Span};
 use wasmer::{
-    self, imports, AsStoreMut, Function, FunctionEnv, FunctionEnvMut, Imports, Instance, Memory,
-    Store, StoreMut,
+    self, imports, AsStoreMut, Extern, Function, FunctionEnv, FunctionEnvMut, Imports, Instance,
+    Memory, Pages, Store, StoreMut, WASM_PAGE_SIZE,
 };
 
 use env::{
-    messages::{AccountId, HttpRequest, HttpResponseHead, QueryRequest, SystemMessage},
+    messages::{
+        AccountId, ChainHead, FuelStatus, HttpRequest, HttpResponseHead, QueryRequest,
+        SystemMessage, WsMessage,
+    },
     tls::{TlsClientConfig, TlsServerConfig},
-    IntPtr, IntRet, OcallError, Result, RetEncode,
+    HashAlgorithm, IntPtr, IntRet, OcallError, Result, RetEncode,
 };
 use scale::{Decode, Encode};
 use sidevm_env as env;
@@ -39,8 +42,21 @@ use wasmer_middlewares::metering;
 
 use crate::{
     async_context::{get_task_cx, set_task_env, GuestWaker},
-    resource::{Resource, ResourceKeeper, TcpListenerResource},
-    tls::{load_tls_config, TlsStream},
+    body_limit::{BodyLimits, ReadCaps},
+    cache,
+    clock::{Clock, ManualClock},
+    dns::Dns,
+    identity::VmIdentity,
+    metering::{instructions_exhausted, read_fuel_status, remaining_instructions, HelperCosts},
+    outbound::{OutboundGate, OutboundLimits},
+    pubsub,
+    resource::{Resource, ResourceInfo, ResourceKeeper, TcpListenerResource},
+    session::{SessionLimits, Sessions, TooManySessions},
+    snapshot::{
+        input_channel_from_u8, GlobalValue, MemorySnapshot, GLOBAL_EXPORT_PREFIX, SNAPSHOT_VERSION,
+    },
+    tls::{self, load_tls_config, TlsClientIdentity, TlsRoots, TlsStream},
+    websocket::{self, WebSocket},
     IncomingHttpRequest, VmId,
 };
 
@@ -126,7 +142,7 @@ pub(crate) struct TaskSet {
 }
 
 impl TaskSet {
-    fn with_task0() -> Self {
+    pub(crate) fn with_task0() -> Self {
         let awake_tasks = dashmap::DashSet::new();
         awake_tasks.insert(0);
         Self {
@@ -155,16 +171,88 @@ impl TaskSet {
     }
 }
 
+/// The storage behind the local cache ocalls.
+///
+/// `contract` is the namespace of the entries, which is the id of the calling instance unless it
+/// is configured to share a namespace with others. Entries of different namespaces must be kept
+/// apart.
 pub trait CacheOps {
     fn get(&self, contract: &[u8], key: &[u8]) -> Result<Option<Vec<u8>>>;
     fn set(&self, contract: &[u8], key: &[u8], value: &[u8]) -> Result<()>;
     fn set_expiration(&self, contract: &[u8], key: &[u8], expire_after_secs: u64) -> Result<()>;
     fn remove(&self, contract: &[u8], key: &[u8]) -> Result<Option<Vec<u8>>>;
+    /// Get the value along with its version. Versions of a key increase monotonically on writes.
+    fn get_versioned(&self, _contract: &[u8], _key: &[u8]) -> Result<Option<(Vec<u8>, u64)>> {
+        Err(OcallError::UnsupportedOperation)
+    }
+    /// Atomically set the value if the current version equals `expected_version`.
+    ///
+    /// Returns the new version or `OcallError::VersionConflict`.
+    fn set_if_version(
+        &self,
+        _contract: &[u8],
+        _key: &[u8],
+        _value: &[u8],
+        _expected_version: u64,
+    ) -> Result<u64> {
+        Err(OcallError::UnsupportedOperation)
+    }
+    /// Set the value expiring after `ttl_secs`. A TTL of 0 removes the key.
+    fn set_with_ttl(&self, contract: &[u8], key: &[u8], value: &[u8], ttl_secs: u64) -> Result<()> {
+        if ttl_secs == 0 {
+            return self.remove(contract, key).map(|_| ());
+        }
+        self.set(contract, key, value)?;
+        self.set_expiration(contract, key, ttl_secs)
+    }
+    /// Set the value deduplicated by its content, returning the blake2b-256 hash of it.
+    fn set_content(&self, _contract: &[u8], _key: &[u8], _value: &[u8]) -> Result<[u8; 32]> {
+        Err(OcallError::UnsupportedOperation)
+    }
+    /// Set the value deduplicated by its content, keyed by its hash.
+    ///
+    /// Returns `OcallError::InvalidParameter` if the hash mismatches.
+    fn set_by_hash(&self, _contract: &[u8], _hash: &[u8; 32], _value: &[u8]) -> Result<()> {
+        Err(OcallError::UnsupportedOperation)
+    }
+    /// Get a content by its hash.
+    fn get_by_hash(&self, _contract: &[u8], _hash: &[u8; 32]) -> Result<Option<Vec<u8>>> {
+        Err(OcallError::UnsupportedOperation)
+    }
+    /// Get the values of the keys, in order.
+    fn get_batch(&self, contract: &[u8], keys: &[Vec<u8>]) -> Result<Vec<Option<Vec<u8>>>> {
+        keys.iter().map(|key| self.get(contract, key)).collect()
+    }
+    /// Set the pairs in order, returning whether each of them was set.
+    ///
+    /// Not transactional: a failed pair does not roll back the ones set before it.
+    fn set_batch(&self, contract: &[u8], pairs: &[(Vec<u8>, Vec<u8>)]) -> Result<Vec<bool>> {
+        Ok(pairs
+            .iter()
+            .map(|(key, value)| self.set(contract, key, value).is_ok())
+            .collect())
+    }
+    /// Number of bytes used by the contract in the cache, if known.
+    fn usage(&self, _contract: &[u8]) -> Option<usize> {
+        None
+    }
 }
 
 pub type DynCacheOps = &'static (dyn CacheOps + Send + Sync);
 pub type LogHandler = Box<dyn Fn(VmId, u8, &str) + Send + Sync>;
 
+/// A line logged by a guest, through the log ocall or written to its stdout or stderr.
+#[derive(Debug, Clone)]
+pub struct LogRecord {
+    pub vm_id: VmId,
+    pub level: log::Level,
+    pub message: String,
+}
+
+/// Receives the logs of the guests instead of the global logger. The records are dropped while
+/// the channel is full, so a slow consumer never stalls a guest.
+pub type LogSink = Sender<LogRecord>;
+
 pub type OutgoingRequestChannel = Sender<(VmId, OutgoingRequest)>;
 
 pub enum OutgoingRequest {
@@ -175,10 +263,101 @@ pub enum OutgoingRequest {
     },
     // Used by Js Engine to send js eval result
     Output(Vec<u8>),
+    /// Read a storage key of the contract the VM belongs to.
+    ReadStorage {
+        key: Vec<u8>,
+        reply_tx: OneshotSender<Vec<u8>>,
+    },
 }
 
 struct VmMemory(Option<Memory>);
 
+/// Cap of the logs a VM can emit while serving a single query.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct LogLimit {
+    pub max_bytes: usize,
+    pub max_lines: usize,
+}
+
+enum LogAdmission {
+    Accept,
+    /// The cap has just been reached, emit the truncation marker instead.
+    Truncate,
+    Drop,
+}
+
+/// Log volume accounting of the current query. Reset each time a query is pushed.
+#[derive(Default)]
+struct LogBudget {
+    limit: Option<LogLimit>,
+    bytes: usize,
+    lines: usize,
+    truncated: bool,
+}
+
+impl LogBudget {
+    fn reset(&mut self) {
+        self.bytes = 0;
+        self.lines = 0;
+        self.truncated = false;
+    }
+
+    fn admit(&mut self, len: usize) -> LogAdmission {
+        let Some(limit) = self.limit else {
+            return LogAdmission::Accept;
+        };
+        if self.truncated {
+            return LogAdmission::Drop;
+        }
+        self.bytes = self.bytes.saturating_add(len);
+        self.lines += 1;
+        if self.bytes > limit.max_bytes || self.lines > limit.max_lines {
+            self.truncated = true;
+            return LogAdmission::Truncate;
+        }
+        LogAdmission::Accept
+    }
+}
+
+/// Runtime statistics of a VM, updated after each poll.
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct VmStats {
+    /// Number of times the VM has been polled.
+    pub polls: u64,
+    /// Total gas consumed since the VM started.
+    pub gas_used: u64,
+    /// Gas consumed by the last poll.
+    pub last_poll_gas_used: u64,
+    /// Current size of the linear memory in pages.
+    pub memory_pages: u32,
+    /// Largest size of the linear memory in pages seen so far.
+    pub peak_memory_pages: u32,
+    /// Number of resources held by the guest, such as the pending connections and timers of its
+    /// outstanding async ocalls, and the open streams.
+    pub open_resources: usize,
+    /// Time since the VM started, in milliseconds.
+    pub uptime_ms: u64,
+    /// Instructions retired since the VM started, as of the last poll. Only counted under an
+    /// instruction limit, 0 otherwise.
+    pub instructions: u64,
+}
+
+/// A snapshot of the state of a VM for diagnostics.
+#[derive(Debug, Clone, Serialize)]
+pub struct VmDump {
+    pub id: String,
+    pub weight: u32,
+    pub gas_per_breath: u64,
+    pub stats: VmStats,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cache_bytes: Option<usize>,
+    /// Number of outbound connections being established or open.
+    pub outbound_in_flight: usize,
+    /// Number of outbound connections waiting for a slot.
+    pub outbound_queued: usize,
+    pub resources: Vec<ResourceInfo>,
+}
+
 pub(crate) struct EnvInner {
     memory: VmMemory,
     id: VmId,
@@ -193,13 +372,37 @@ pub(crate) struct EnvInner {
     awake_tasks: Arc<TaskSet>,
     current_task: i32,
     cache_ops: DynCacheOps,
+    /// Namespace of the cache entries of the guest, its own id unless sharing one.
+    cache_namespace: VmId,
+    clock: Clock,
     weight: u32,
     instance: Option<Instance>,
     outgoing_query_guard: Arc<Semaphore>,
     outgoing_request_tx: OutgoingRequestChannel,
     log_handler: Option<LogHandler>,
+    log_sink: Option<LogSink>,
     _counter: vm_counter::Counter,
     args: Vec<String>,
+    stats: VmStats,
+    started_at: Instant,
+    pinned_chain_head: Option<ChainHead>,
+    ready_tx: watch::Sender<bool>,
+    log_budget: LogBudget,
+    helper_costs: HelperCosts,
+    pubsub_namespaces: Vec<String>,
+    identity: Option<VmIdentity>,
+    tls_client_config: Result<Arc<tokio_rustls::rustls::ClientConfig>>,
+    sessions: Sessions,
+    outbound: OutboundGate,
+    read_caps: ReadCaps,
+    dns: Dns,
+    /// The input channels opened by the guest, by resource id.
+    input_channels: Vec<(i32, env::InputChannel)>,
+    max_task_id: i32,
+    /// Total instructions the guest can retire, if counted.
+    instruction_limit: Option<u64>,
+    /// Fuel left in the tank as of the last poll, if the fuel is limited.
+    fuel_left: Option<u64>,
 }
 
 impl VmMemory {
@@ -279,17 +482,102 @@ impl Env {
                 awake_tasks: Arc::new(TaskSet::with_task0()),
                 current_task: 0,
                 cache_ops,
+                cache_namespace: id,
+                clock: Default::default(),
                 weight: 1,
                 instance: None,
                 outgoing_query_guard: Arc::new(Semaphore::new(1)),
                 outgoing_request_tx,
                 log_handler,
+                log_sink: None,
                 _counter: Default::default(),
                 args,
+                stats: Default::default(),
+                started_at: Instant::now(),
+                pinned_chain_head: None,
+                ready_tx: watch::channel(false).0,
+                log_budget: Default::default(),
+                helper_costs: Default::default(),
+                pubsub_namespaces: Default::default(),
+                identity: None,
+                tls_client_config: tls::client_config(None, None),
+                sessions: Default::default(),
+                outbound: Default::default(),
+                read_caps: Default::default(),
+                dns: Default::default(),
+                input_channels: Default::default(),
+                max_task_id: 0,
+                instruction_limit: None,
+                fuel_left: None,
             })),
         }
     }
 
+    /// Pin the chain head seen by the guest, typically to the block a query is executed at.
+    pub fn set_pinned_chain_head(&self, head: Option<ChainHead>) {
+        self.inner.lock().unwrap().pinned_chain_head = head;
+    }
+
+    pub fn set_log_limit(&self, limit: Option<LogLimit>) {
+        self.inner.lock().unwrap().log_budget.limit = limit;
+    }
+
+    pub fn set_log_sink(&self, sink: Option<LogSink>) {
+        self.inner.lock().unwrap().log_sink = sink;
+    }
+
+    /// Send a log line of the guest to its sink. Returns false if it has none.
+    pub(crate) fn send_to_log_sink(&self, level: log::Level, message: &str) -> bool {
+        self.inner.lock().unwrap().send_to_log_sink(level, message)
+    }
+
+    pub fn set_helper_costs(&self, costs: HelperCosts) {
+        self.inner.lock().unwrap().helper_costs = costs;
+    }
+
+    /// Set the namespaces of the pub/sub topics the guest can access.
+    pub fn set_pubsub_namespaces(&self, namespaces: Vec<String>) {
+        self.inner.lock().unwrap().pubsub_namespaces = namespaces;
+    }
+
+    /// Share the cache entries with the other instances given the same `name`, instead of
+    /// keeping them private to this one.
+    pub fn set_shared_cache(&self, name: Option<&str>) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.cache_namespace = match name {
+            Some(name) => cache::shared_namespace(name),
+            None => inner.id,
+        };
+    }
+
+    /// Drive the timers and the clocks of the guest by the given clock instead of the system one.
+    pub fn set_manual_clock(&self, clock: Option<ManualClock>) {
+        self.inner.lock().unwrap().clock = clock.map_or(Clock::System, Clock::Manual);
+    }
+
+    /// Set the certificate presented by the guest to the TLS servers requiring client auth, and
+    /// the roots it trusts. The public webpki roots are trusted if `roots` is None.
+    ///
+    /// A malformed identity doesn't prevent the instance from starting, but fails its TLS
+    /// connections with `OcallError::InvalidCertificate`.
+    pub fn set_tls_client(&self, identity: Option<&TlsClientIdentity>, roots: Option<&TlsRoots>) {
+        let config = tls::client_config(identity, roots);
+        if let Err(err) = &config {
+            warn!(target: "sidevm", ?err, "Invalid TLS client identity");
+        }
+        self.inner.lock().unwrap().tls_client_config = config;
+    }
+
+    /// Set the identity key the guest can sign with.
+    pub fn set_identity(&self, identity: Option<VmIdentity>) {
+        self.inner.lock().unwrap().identity = identity;
+    }
+
+    /// Subscribe to the readiness signaled by the guest.
+    pub fn subscribe_ready(&self) -> watch::Receiver<bool> {
+        self.inner.lock().unwrap().ready_tx.subscribe()
+    }
+
     pub fn set_memory(&self, memory: Memory) {
         self.inner.lock().unwrap().memory.0 = Some(memory);
     }
@@ -323,6 +611,7 @@ impl Env {
     ) -> Option<impl Future<Output = anyhow::Result<()>>> {
         let mut env_guard = self.inner.lock().unwrap();
         let tx = env_guard.query_tx.clone()?;
+        env_guard.log_budget.reset();
         let reply_tx = env_guard
             .resources
             .push(Resource::OneshotTx(Some(reply_tx)));
@@ -359,6 +648,134 @@ impl Env {
         metering::set_remaining_points(store, instance, guard.gas_per_breath);
     }
 
+    /// Update the statistics after a poll of the VM. Returns the gas used by the poll.
+    pub fn record_poll(&self, store: &mut impl AsStoreMut) -> u64 {
+        let mut inner = self.inner.lock().unwrap();
+        let used = inner
+            .gas_per_breath
+            .saturating_sub(inner.gas_to_breath(store));
+        let memory_pages = inner
+            .memory
+            .0
+            .as_ref()
+            .map(|memory| memory.view(&*store).size().0)
+            .unwrap_or_default();
+        let stats = &mut inner.stats;
+        stats.polls += 1;
+        stats.gas_used = stats.gas_used.saturating_add(used);
+        stats.last_poll_gas_used = used;
+        stats.memory_pages = memory_pages;
+        stats.peak_memory_pages = stats.peak_memory_pages.max(memory_pages);
+        if let Some(limit) = inner.instruction_limit {
+            let instance = inner.instance.as_ref().expect("BUG: instance is not set");
+            let remaining = remaining_instructions(store, instance).unwrap_or_default();
+            inner.stats.instructions = limit.saturating_sub(remaining);
+        }
+        used
+    }
+
+    /// Take a snapshot of the VM state. Addresses are masked if `redact` is true.
+    pub fn dump(&self, redact: bool) -> VmDump {
+        let inner = self.inner.lock().unwrap();
+        VmDump {
+            id: hex_fmt::HexFmt(inner.id).to_string(),
+            weight: inner.weight,
+            gas_per_breath: inner.gas_per_breath,
+            stats: inner.current_stats(),
+            cache_bytes: inner.cache_ops.usage(&inner.cache_namespace[..]),
+            outbound_in_flight: inner.outbound.in_flight(),
+            outbound_queued: inner.outbound.queued(),
+            resources: inner.resources.dump(redact),
+        }
+    }
+
+    /// Capture the state of the guest. Must be called between two polls.
+    pub(crate) fn snapshot(
+        &self,
+        store: &mut impl AsStoreMut,
+        module_hash: [u8; 32],
+    ) -> anyhow::Result<MemorySnapshot> {
+        let inner = self.inner.lock().unwrap();
+        if inner.resources.count() > inner.input_channels.len() {
+            anyhow::bail!("the guest holds resources other than its input channels");
+        }
+        let instance = inner
+            .instance
+            .as_ref()
+            .context("instance is not initialized")?;
+        let mut globals = vec![];
+        for (name, export) in instance.exports.iter() {
+            let (Some(index), Extern::Global(global)) =
+                (name.strip_prefix(GLOBAL_EXPORT_PREFIX), export)
+            else {
+                continue;
+            };
+            let value = GlobalValue::from_value(global.get(&mut *store))
+                .context("unsupported global type")?;
+            globals.push((index.parse()?, value));
+        }
+        let view = inner.memory.unwrap_ref().view(&*store);
+        let mut memory = vec![0; view.data_size() as usize];
+        view.read(0, &mut memory)?;
+        let ready = *inner.ready_tx.borrow();
+        Ok(MemorySnapshot {
+            version: SNAPSHOT_VERSION,
+            module_hash,
+            memory,
+            globals,
+            input_channels: inner
+                .input_channels
+                .iter()
+                .map(|(id, ch)| (*id, *ch as u8))
+                .collect(),
+            max_task_id: inner.max_task_id,
+            ready,
+        })
+    }
+
+    /// Bring the guest back to the state of the snapshot. Must be called before the first poll.
+    pub(crate) fn restore(
+        &self,
+        store: &mut impl AsStoreMut,
+        snapshot: &MemorySnapshot,
+    ) -> anyhow::Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        let memory = inner.memory.unwrap_ref().clone();
+        let pages = (snapshot.memory.len() / WASM_PAGE_SIZE) as u32;
+        let current = memory.view(&*store).size().0;
+        if pages > current {
+            memory.grow(&mut *store, Pages(pages - current))?;
+        }
+        memory.view(&*store).write(0, &snapshot.memory)?;
+        let instance = inner
+            .instance
+            .as_ref()
+            .context("instance is not initialized")?;
+        for (index, value) in &snapshot.globals {
+            let global = instance
+                .exports
+                .get_global(&format!("{GLOBAL_EXPORT_PREFIX}{index}"))?;
+            global.set(&mut *store, (*value).into())?;
+        }
+        for (id, ch) in &snapshot.input_channels {
+            let ch = input_channel_from_u8(*ch)?;
+            let (tx, rx) = tokio::sync::mpsc::channel(20);
+            inner.resources.put(*id, Resource::ChannelRx(rx))?;
+            *inner.input_tx(ch) = Some(tx);
+            inner.input_channels.push((*id, ch));
+        }
+        // The wakers of the guest were registered to the resources of the previous instance, so
+        // every task is polled again to register them anew.
+        for task_id in 0..=snapshot.max_task_id {
+            inner.awake_tasks.push_task(task_id);
+        }
+        inner.max_task_id = snapshot.max_task_id;
+        if snapshot.ready {
+            inner.ready_tx.send_replace(true);
+        }
+        Ok(())
+    }
+
     pub fn has_more_ready(&self) -> bool {
         !self.inner.lock().unwrap().awake_tasks.is_empty()
     }
@@ -381,6 +798,38 @@ impl Env {
         self.inner.lock().unwrap().is_stifled(store)
     }
 
+    pub(crate) fn set_instruction_limit(&self, limit: Option<u64>) {
+        self.inner.lock().unwrap().instruction_limit = limit;
+    }
+
+    pub(crate) fn set_fuel_left(&self, fuel_left: Option<u64>) {
+        self.inner.lock().unwrap().fuel_left = fuel_left;
+    }
+
+    /// Whether the guest has trapped for reaching its instruction limit.
+    pub fn instructions_exhausted(&self, store: &mut impl AsStoreMut) -> bool {
+        let inner = self.inner.lock().unwrap();
+        match (&inner.instruction_limit, &inner.instance) {
+            (Some(_), Some(instance)) => instructions_exhausted(store, instance),
+            _ => false,
+        }
+    }
+
+    /// The runtime statistics of the VM.
+    pub fn stats(&self) -> VmStats {
+        self.inner.lock().unwrap().current_stats()
+    }
+
+    /// Total gas consumed by the completed polls.
+    pub fn gas_used(&self) -> u64 {
+        self.inner.lock().unwrap().stats.gas_used
+    }
+
+    /// Size of the linear memory in pages, as of the last poll.
+    pub fn memory_pages(&self) -> u32 {
+        self.inner.lock().unwrap().stats.memory_pages
+    }
+
     pub fn memory(&self) -> Memory {
         self.inner
             .lock()
@@ -402,19 +851,32 @@ impl Env {
             body_stream,
             response_tx,
         } = request;
+        let websocket_key = websocket::handshake_key(&head);
         let mut env_guard = self.inner.lock().unwrap();
         let connect_tx = env_guard.http_connect_tx.clone()?;
+        if env_guard.sessions.is_full() {
+            env_guard.reap_sessions();
+            if env_guard.sessions.is_full() {
+                warn!(target: "sidevm", "Too many open sessions, http request rejected");
+                let _ = response_tx.send(Err(TooManySessions.into()));
+                return None;
+            }
+        }
         let (reply_tx, reply_rx) = oneshot::channel();
         let reply_tx = env_guard
             .resources
             .push(Resource::OneshotTx(Some(reply_tx)));
+        let is_websocket = websocket_key.is_some();
         tokio::spawn(
             async move {
                 let reply = reply_rx.await;
                 let reply = reply
                     .context("Failed to receive http response")
                     .and_then(|bytes| {
-                        let response = HttpResponseHead::decode(&mut &bytes[..])?;
+                        let mut response = HttpResponseHead::decode(&mut &bytes[..])?;
+                        if let (Some(key), 101) = (&websocket_key, response.status) {
+                            websocket::accept(&mut response, key);
+                        }
                         Ok(response)
                     });
                 if response_tx.send(reply).is_err() {
@@ -423,9 +885,19 @@ impl Env {
             }
             .instrument(Span::current()),
         );
-        let body_stream = env_guard
-            .resources
-            .push(Resource::DuplexStream(body_stream));
+        let body_stream = if is_websocket {
+            Resource::WebSocket(Box::new(WebSocket::new(body_stream)))
+        } else {
+            Resource::DuplexStream(body_stream)
+        };
+        let body_stream = env_guard.resources.push(body_stream);
+        if let (false, Ok(body_stream)) = (is_websocket, &body_stream) {
+            env_guard.read_caps.cap_request_body(*body_stream);
+        }
+        if let (Ok(reply_tx), Ok(body_stream)) = (&reply_tx, &body_stream) {
+            // Can't fail, there was room before the resources were pushed.
+            let _ = env_guard.sessions.open(vec![*reply_tx, *body_stream]);
+        }
         let inner = Arc::downgrade(&self.inner);
         Some(async move {
             let response_tx = reply_tx?;
@@ -451,6 +923,28 @@ impl Env {
     pub fn with_args<T>(&self, f: impl FnOnce(&[String]) -> T) -> T {
         f(&self.inner.lock().unwrap().args)
     }
+
+    pub fn set_session_limits(&self, limits: SessionLimits) {
+        self.inner.lock().unwrap().sessions.set_limits(limits);
+    }
+
+    pub fn set_outbound_limits(&self, limits: OutboundLimits) {
+        self.inner.lock().unwrap().outbound.set_limits(limits);
+    }
+
+    pub fn set_body_limits(&self, limits: BodyLimits) {
+        self.inner.lock().unwrap().read_caps.set_limits(limits);
+    }
+
+    /// Set the resolver and the address policy of the outbound connections.
+    pub fn set_dns(&self, dns: Dns) {
+        self.inner.lock().unwrap().dns = dns;
+    }
+
+    /// Free the resources of the sessions left idle for too long.
+    pub fn reap_sessions(&self) {
+        self.inner.lock().unwrap().reap_sessions();
+    }
 }
 
 impl<'a, 'b> env::OcallEnv for FnEnvMut<'a, &'b mut EnvInner> {
@@ -471,32 +965,44 @@ impl<'a, 'b> env::OcallFuncs for FnEnvMut<'a, &'b mut EnvInner> {
     }
 
     fn poll(&mut self, waker_id: i32, resource_id: i32) -> Result<Vec<u8>> {
+        self.sessions.touch(resource_id);
         self.resources.get_mut(resource_id)?.poll(waker_id)
     }
 
     fn poll_read(&mut self, waker_id: i32, resource_id: i32, data: &mut [u8]) -> Result<u32> {
-        self.resources
+        self.sessions.touch(resource_id);
+        let len = self.read_caps.window(resource_id, data.len());
+        let read = self
+            .resources
             .get_mut(resource_id)?
-            .poll_read(waker_id, data)
+            .poll_read(waker_id, &mut data[..len])?;
+        self.read_caps.consume(resource_id, read as u64)?;
+        Ok(read)
     }
 
     fn poll_write(&mut self, waker_id: i32, resource_id: i32, data: &[u8]) -> Result<u32> {
+        self.sessions.touch(resource_id);
         self.resources
             .get_mut(resource_id)?
             .poll_write(waker_id, data)
     }
 
     fn poll_shutdown(&mut self, waker_id: i32, resource_id: i32) -> Result<()> {
+        self.sessions.touch(resource_id);
         self.resources.get_mut(resource_id)?.poll_shutdown(waker_id)
     }
 
     fn poll_res(&mut self, waker_id: i32, resource_id: i32) -> Result<i32> {
-        let res = self.resources.get_mut(resource_id)?.poll_res(waker_id)?;
-        self.resources.push(res)
+        let (res, permit) = self.resources.get_mut(resource_id)?.poll_res(waker_id)?;
+        let id = self.resources.push(res)?;
+        self.outbound.hold(id, permit);
+        self.read_caps.cap_response(id);
+        Ok(id)
     }
 
     fn mark_task_ready(&mut self, task_id: i32) -> Result<()> {
         self.awake_tasks.push_task(task_id);
+        self.max_task_id = self.max_task_id.max(task_id);
         Ok(())
     }
 
@@ -505,8 +1011,8 @@ impl<'a, 'b> env::OcallFuncs for FnEnvMut<'a, &'b mut EnvInner> {
     }
 
     fn create_timer(&mut self, timeout: i32) -> Result<i32> {
-        let sleep = tokio::time::sleep(Duration::from_millis(timeout as u64));
-        self.resources.push(Resource::Sleep(Box::pin(sleep)))
+        let sleep = self.clock.sleep(Duration::from_millis(timeout as u64));
+        self.resources.push(Resource::Sleep(sleep))
     }
 
     fn enable_ocall_trace(&mut self, enable: bool) -> Result<()> {
@@ -563,7 +1069,12 @@ impl<'a, 'b> env::OcallFuncs for FnEnvMut<'a, &'b mut EnvInner> {
             return Err(OcallError::InvalidParameter);
         }
         let host = host.to_owned();
-        let fut = async move { tcp_connect(&host, port).await };
+        let dns = self.dns.clone();
+        let slot = self.outbound.acquire()?;
+        let fut = async move {
+            let permit = slot.await;
+            Ok((tcp_connect(&dns, &host, port).await?, permit))
+        };
         self.resources.push(Resource::TcpConnect(Box::pin(fut)))
     }
 
@@ -571,21 +1082,51 @@ impl<'a, 'b> env::OcallFuncs for FnEnvMut<'a, &'b mut EnvInner> {
         if host.len() > 253 {
             return Err(OcallError::InvalidParameter);
         }
-        let TlsClientConfig::V0 = config;
+        let client_config = self.tls_client_config.clone()?;
         let domain = host
             .as_str()
             .try_into()
             .or(Err(OcallError::InvalidParameter))?;
+        let dns = self.dns.clone();
+        let slot = self.outbound.acquire()?;
+        let alpn_protocols = match config {
+            TlsClientConfig::V0 => {
+                let fut = async move {
+                    let permit = slot.await;
+                    let stream = tcp_connect(&dns, &host, port).await?;
+                    Ok((TlsStream::connect(domain, stream, client_config), permit))
+                };
+                return self.resources.push(Resource::TlsConnect(Box::pin(fut)));
+            }
+            TlsClientConfig::V1 { alpn_protocols } => alpn_protocols,
+        };
+        const MAX_ALPN_PROTOCOLS: usize = 8;
+        if alpn_protocols.len() > MAX_ALPN_PROTOCOLS
+            || alpn_protocols.iter().any(|p| p.is_empty() || p.len() > 255)
+        {
+            return Err(OcallError::InvalidParameter);
+        }
+        let mut client_config = (*client_config).clone();
+        client_config.alpn_protocols = alpn_protocols;
+        let client_config = Arc::new(client_config);
         let fut = async move {
-            tcp_connect(&host, port)
-                .await
-                .map(move |stream| TlsStream::connect(domain, stream))
+            let permit = slot.await;
+            let stream = tcp_connect(&dns, &host, port).await?;
+            let stream = TlsStream::connect_handshaked(domain, stream, client_config).await?;
+            Ok((stream, permit))
         };
         self.resources.push(Resource::TlsConnect(Box::pin(fut)))
     }
 
     fn log(&mut self, level: log::Level, message: &str) -> Result<()> {
-        log::log!(target: "sidevm", level, "{message}");
+        let (level, message) = match self.log_budget.admit(message.len()) {
+            LogAdmission::Accept => (level, message),
+            LogAdmission::Truncate => (log::Level::Warn, "log truncated"),
+            LogAdmission::Drop => return Ok(()),
+        };
+        if !self.send_to_log_sink(level, message) {
+            log::log!(target: "sidevm", level, "{message}");
+        }
         if let Some(log_handler) = &self.log_handler {
             log_handler(self.id, level as u8, message);
         }
@@ -593,20 +1134,68 @@ impl<'a, 'b> env::OcallFuncs for FnEnvMut<'a, &'b mut EnvInner> {
     }
 
     fn local_cache_get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
-        self.cache_ops.get(&self.id[..], key)
+        self.cache_ops.get(&self.cache_namespace[..], key)
     }
 
     fn local_cache_set(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
-        self.cache_ops.set(&self.id[..], key, value)
+        self.cache_ops.set(&self.cache_namespace[..], key, value)
     }
 
     fn local_cache_set_expiration(&mut self, key: &[u8], expire_after_secs: u64) -> Result<()> {
         self.cache_ops
-            .set_expiration(&self.id[..], key, expire_after_secs)
+            .set_expiration(&self.cache_namespace[..], key, expire_after_secs)
     }
 
     fn local_cache_remove(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
-        self.cache_ops.remove(&self.id[..], key)
+        self.cache_ops.remove(&self.cache_namespace[..], key)
+    }
+
+    fn local_cache_get_versioned(&mut self, key: &[u8]) -> Result<Option<(Vec<u8>, u64)>> {
+        self.cache_ops.get_versioned(&self.cache_namespace[..], key)
+    }
+
+    fn local_cache_set_if_version(
+        &mut self,
+        key: Cow<[u8]>,
+        value: Cow<[u8]>,
+        expected_version: u64,
+    ) -> Result<u64> {
+        self.cache_ops
+            .set_if_version(&self.cache_namespace[..], &key, &value, expected_version)
+    }
+
+    fn local_cache_set_with_ttl(
+        &mut self,
+        key: Cow<[u8]>,
+        value: Cow<[u8]>,
+        ttl_secs: u64,
+    ) -> Result<()> {
+        self.cache_ops
+            .set_with_ttl(&self.cache_namespace[..], &key, &value, ttl_secs)
+    }
+
+    fn local_cache_set_content(&mut self, key: &[u8], value: &[u8]) -> Result<[u8; 32]> {
+        self.cache_ops
+            .set_content(&self.cache_namespace[..], key, value)
+    }
+
+    fn local_cache_set_by_hash(&mut self, hash: &[u8], value: &[u8]) -> Result<()> {
+        let hash = hash.try_into().or(Err(OcallError::InvalidParameter))?;
+        self.cache_ops
+            .set_by_hash(&self.cache_namespace[..], hash, value)
+    }
+
+    fn local_cache_get_by_hash(&mut self, hash: &[u8]) -> Result<Option<Vec<u8>>> {
+        let hash = hash.try_into().or(Err(OcallError::InvalidParameter))?;
+        self.cache_ops.get_by_hash(&self.cache_namespace[..], hash)
+    }
+
+    fn local_cache_get_batch(&mut self, keys: Vec<Vec<u8>>) -> Result<Vec<Option<Vec<u8>>>> {
+        self.cache_ops.get_batch(&self.cache_namespace[..], &keys)
+    }
+
+    fn local_cache_set_batch(&mut self, pairs: Vec<(Vec<u8>, Vec<u8>)>) -> Result<Vec<bool>> {
+        self.cache_ops.set_batch(&self.cache_namespace[..], &pairs)
     }
 
     fn awake_wakers(&mut self) -> Result<Vec<i32>> {
@@ -629,7 +1218,15 @@ impl<'a, 'b> env::OcallFuncs for FnEnvMut<'a, &'b mut EnvInner> {
         Ok(())
     }
 
+    fn hash(&mut self, algorithm: HashAlgorithm, message: &[u8]) -> Result<[u8; 32]> {
+        // Charge before doing the work, so that an underfunded guest gets nothing for free.
+        let cost = self.helper_costs.hash.cost(message.len());
+        self.inner.pay(&mut self.store, cost)?;
+        Ok(digest(algorithm, message))
+    }
+
     fn oneshot_send(&mut self, resource_id: i32, data: &[u8]) -> Result<()> {
+        self.sessions.touch(resource_id);
         let res = self.resources.get_mut(resource_id)?;
         match res {
             Resource::OneshotTx(sender) => match sender.take() {
@@ -642,24 +1239,14 @@ impl<'a, 'b> env::OcallFuncs for FnEnvMut<'a, &'b mut EnvInner> {
     }
 
     fn create_input_channel(&mut self, ch: env::InputChannel) -> Result<i32> {
-        use env::InputChannel::*;
-        macro_rules! create_channel {
-            ($field: expr) => {{
-                if $field.is_some() {
-                    return Err(OcallError::AlreadyExists);
-                }
-                let (tx, rx) = tokio::sync::mpsc::channel(20);
-                let res = self.resources.push(Resource::ChannelRx(rx))?;
-                $field = Some(tx);
-                Ok(res)
-            }};
-        }
-        match ch {
-            GeneralMessage => create_channel!(self.message_tx),
-            SystemMessage => create_channel!(self.sys_message_tx),
-            Query => create_channel!(self.query_tx),
-            HttpRequest => create_channel!(self.http_connect_tx),
+        if self.input_tx(ch).is_some() {
+            return Err(OcallError::AlreadyExists);
         }
+        let (tx, rx) = tokio::sync::mpsc::channel(20);
+        let res = self.resources.push(Resource::ChannelRx(rx))?;
+        *self.input_tx(ch) = Some(tx);
+        self.input_channels.push((res, ch));
+        Ok(res)
     }
 
     fn gas_remaining(&mut self) -> Result<u8> {
@@ -672,39 +1259,18 @@ impl<'a, 'b> env::OcallFuncs for FnEnvMut<'a, &'b mut EnvInner> {
     }
 
     fn query_local_contract(&mut self, contract_id: [u8; 32], payload: Vec<u8>) -> Result<i32> {
-        let sem = self
-            .inner
-            .outgoing_query_guard
-            .clone()
-            .try_acquire_owned()
-            .or(Err(OcallError::ResourceLimited))?;
-        let (res_tx, res_rx) = tokio::sync::mpsc::channel::<Vec<u8>>(1);
-        let res_id = self.resources.push(Resource::ChannelRx(res_rx))?;
-        let (reply_tx, reply_rx) = oneshot::channel();
-        let request = OutgoingRequest::Query {
-            contract_id,
-            payload,
-            reply_tx,
-        };
-        let from = self.inner.id;
         self.inner
-            .outgoing_request_tx
-            .try_send((from, request))
-            .or(Err(OcallError::IoError))?;
-        tokio::spawn(async move {
-            let _sem = sem;
-            let result = match reply_rx.await {
-                Ok(reply) => res_tx.send(reply).await,
-                Err(_) => {
-                    warn!(target: "sidevm", "Failed to receive query result");
-                    res_tx.send(Vec::new()).await
-                }
-            };
-            if result.is_err() {
-                error!(target: "sidevm", "Failed to send query result");
-            }
-        });
-        Ok(res_id)
+            .send_outgoing_request(|reply_tx| OutgoingRequest::Query {
+                contract_id,
+                payload,
+                reply_tx,
+            })
+    }
+
+    fn read_contract_storage(&mut self, key: &[u8]) -> Result<i32> {
+        let key = key.to_vec();
+        self.inner
+            .send_outgoing_request(|reply_tx| OutgoingRequest::ReadStorage { key, reply_tx })
     }
 
     /// Returns the vmid of the current instance.
@@ -720,9 +1286,129 @@ impl<'a, 'b> env::OcallFuncs for FnEnvMut<'a, &'b mut EnvInner> {
             .try_send((from, request))
             .or(Err(OcallError::IoError))
     }
+
+    fn chain_head(&mut self) -> Result<ChainHead> {
+        Ok(self.inner.chain_head())
+    }
+
+    fn signal_ready(&mut self) -> Result<()> {
+        self.ready_tx.send_replace(true);
+        Ok(())
+    }
+
+    fn fuel_status(&mut self) -> Result<FuelStatus> {
+        let inner = &*self.inner;
+        let instance = inner
+            .instance
+            .as_ref()
+            .ok_or(OcallError::UnsupportedOperation)?;
+        Ok(read_fuel_status(
+            &mut self.store,
+            instance,
+            inner.gas_per_breath,
+            inner.fuel_left,
+            inner.instruction_limit.is_some(),
+        ))
+    }
+
+    fn publish(&mut self, topic: &str, message: &[u8]) -> Result<()> {
+        pubsub::check_topic(topic, &self.pubsub_namespaces)?;
+        let cost = self.helper_costs.publish.cost(message.len());
+        self.inner.pay(&mut self.store, cost)?;
+        pubsub::publish(topic, message)
+    }
+
+    fn subscribe(&mut self, topic: &str) -> Result<i32> {
+        pubsub::check_topic(topic, &self.pubsub_namespaces)?;
+        let rx = pubsub::subscribe(topic)?;
+        self.resources.push(Resource::ChannelRx(rx))
+    }
+
+    fn tls_alpn_protocol(&mut self, resource_id: i32) -> Result<Option<Vec<u8>>> {
+        match self.resources.get_mut(resource_id)? {
+            Resource::TlsStream(stream) => Ok(stream.alpn_protocol().map(|p| p.to_vec())),
+            _ => Err(OcallError::UnsupportedOperation),
+        }
+    }
+
+    fn ws_poll_recv(&mut self, waker_id: i32, resource_id: i32) -> Result<WsMessage> {
+        self.sessions.touch(resource_id);
+        self.resources.get_mut(resource_id)?.ws_poll_recv(waker_id)
+    }
+
+    fn ws_poll_send(&mut self, waker_id: i32, resource_id: i32, message: WsMessage) -> Result<()> {
+        self.sessions.touch(resource_id);
+        self.resources
+            .get_mut(resource_id)?
+            .ws_poll_send(waker_id, message)
+    }
+
+    fn ws_poll_flush(&mut self, waker_id: i32, resource_id: i32) -> Result<()> {
+        self.sessions.touch(resource_id);
+        self.resources.get_mut(resource_id)?.ws_poll_flush(waker_id)
+    }
+
+    fn identity_public_key(&mut self) -> Result<[u8; 32]> {
+        let identity = self
+            .identity
+            .as_ref()
+            .ok_or(OcallError::UnsupportedOperation)?;
+        Ok(identity.public_key())
+    }
+
+    fn identity_sign(&mut self, message: &[u8]) -> Result<[u8; 64]> {
+        let cost = self.helper_costs.sign.cost(message.len());
+        self.inner.pay(&mut self.store, cost)?;
+        let identity = self
+            .identity
+            .as_ref()
+            .ok_or(OcallError::UnsupportedOperation)?;
+        Ok(identity.sign(message))
+    }
+
+    fn identity_verify(&mut self, message: &[u8], signature: &[u8]) -> Result<bool> {
+        let cost = self.helper_costs.sign.cost(message.len());
+        self.inner.pay(&mut self.store, cost)?;
+        let identity = self
+            .identity
+            .as_ref()
+            .ok_or(OcallError::UnsupportedOperation)?;
+        Ok(identity.verify(message, signature))
+    }
 }
 
 impl EnvInner {
+    fn input_tx(&mut self, ch: env::InputChannel) -> &mut Option<Sender<Vec<u8>>> {
+        use env::InputChannel::*;
+        match ch {
+            GeneralMessage => &mut self.message_tx,
+            SystemMessage => &mut self.sys_message_tx,
+            Query => &mut self.query_tx,
+            HttpRequest => &mut self.http_connect_tx,
+        }
+    }
+
+    fn current_stats(&self) -> VmStats {
+        VmStats {
+            open_resources: self.resources.count(),
+            uptime_ms: self.started_at.elapsed().as_millis() as u64,
+            ..self.stats
+        }
+    }
+
+    fn send_to_log_sink(&self, level: log::Level, message: &str) -> bool {
+        let Some(sink) = &self.log_sink else {
+            return false;
+        };
+        let record = LogRecord {
+            vm_id: self.id,
+            level,
+            message: message.into(),
+        };
+        let _ = sink.try_send(record);
+        true
+    }
+
     pub(crate) fn make_mut<'a, 'b>(
         &'a mut self,
         store: &'b mut impl AsStoreMut,
@@ -731,12 +1417,68 @@ impl EnvInner {
     }
 
     pub(crate) fn close(&mut self, resource_id: i32) -> Result<()> {
+        self.sessions.forget(resource_id);
+        self.outbound.release(resource_id);
+        self.read_caps.release(resource_id);
+        self.input_channels.retain(|(id, _)| *id != resource_id);
         match self.resources.take(resource_id) {
             None => Err(OcallError::NotFound),
+            Some(Resource::WebSocket(ws)) => {
+                ws.close();
+                Ok(())
+            }
             Some(_res) => Ok(()),
         }
     }
 
+    /// The pinned chain head if any, otherwise the one shared by all the instances.
+    fn chain_head(&self) -> ChainHead {
+        self.pinned_chain_head.unwrap_or_else(chain_head::current)
+    }
+
+    fn reap_sessions(&mut self) {
+        let expired = self.sessions.reap();
+        if !expired.is_empty() {
+            info!(target: "sidevm", resources = ?expired, "Reaping idle sessions");
+        }
+        for resource_id in expired {
+            let _ = self.resources.take(resource_id);
+        }
+    }
+
+    /// Send a request to the host, returning a channel resource to receive the reply.
+    fn send_outgoing_request(
+        &mut self,
+        make_request: impl FnOnce(OneshotSender<Vec<u8>>) -> OutgoingRequest,
+    ) -> Result<i32> {
+        let sem = self
+            .outgoing_query_guard
+            .clone()
+            .try_acquire_owned()
+            .or(Err(OcallError::ResourceLimited))?;
+        let (res_tx, res_rx) = tokio::sync::mpsc::channel::<Vec<u8>>(1);
+        let res_id = self.resources.push(Resource::ChannelRx(res_rx))?;
+        let (reply_tx, reply_rx) = oneshot::channel();
+        let request = make_request(reply_tx);
+        self.outgoing_request_tx
+            .try_send((self.id, request))
+            .or(Err(OcallError::IoError))?;
+        tokio::spawn(async move {
+            let _sem = sem;
+            let result = match reply_rx.await {
+                Ok(reply) => res_tx.send(reply).await,
+                Err(_) => {
+                    warn!(target: "sidevm", "Failed to receive query result");
+                    res_tx.send(Vec::new()).await
+                }
+            };
+            if result.is_err() {
+                error!(target: "sidevm", "Failed to send query result");
+            }
+        });
+        Ok(res_id)
+    }
+
     fn is_stifled(&mut self, store: &mut impl AsStoreMut) -> bool {
         let instance = self.instance.as_ref().expect("BUG: instance is not set");
         match metering::get_remaining_points(store, instance) {
@@ -768,11 +1510,7 @@ impl EnvInner {
     }
 }
 
-fn is_ip(host: &str) -> bool {
-    host.parse::<std::net::IpAddr>().is_ok()
-}
-
-async fn tcp_connect(host: &str, port: u16) -> io::Result<TcpStream> {
+async fn tcp_connect(dns: &Dns, host: &str, port: u16) -> io::Result<TcpStream> {
     fn get_proxy(key: &str) -> Option<String> {
         std::env::var(key).ok().and_then(|uri| {
             if uri.trim().is_empty() {
@@ -789,34 +1527,14 @@ async fn tcp_connect(host: &str, port: u16) -> io::Result<TcpStream> {
         None
     };
 
-    if let Some(proxy_url) = proxy_url.or_else(|| get_proxy("all_proxy")) {
+    if let Some(proxy_url) = proxy_url {
+        phala_tokio_proxy::connect((host, port), proxy_url).await
+    } else if let Some(proxy) = crate::proxy::outbound_proxy() {
+        proxy.connect(host, port).await
+    } else if let Some(proxy_url) = get_proxy("all_proxy") {
         phala_tokio_proxy::connect((host, port), proxy_url).await
-    } else if is_ip(host) {
-        TcpStream::connect((host, port)).await
     } else {
-        // By default, tokio uses the blocking DNS resovler from libc and run them in a thread pool.
-        // That would cause problem such as run out of thread-pool in some poor network situation.
-        // So, we use trust-dns async resolver here.
-        let resolver = trust_dns_resolver::TokioAsyncResolver::tokio_from_system_conf()
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
-        let ips = resolver
-            .lookup_ip(host)
-            .await
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
-        let mut last_err = None;
-        for ip in ips {
-            match TcpStream::connect((ip, port)).await {
-                Ok(stream) => return Ok(stream),
-                Err(e) => last_err = Some(e),
-            }
-        }
-        match last_err {
-            Some(e) => Err(e),
-            None => Err(io::Error::new(
-                io::ErrorKind::Other,
-                "DNS: No address found",
-            )),
-        }
+        dns.connect(host, port).await
     }
 }
 
@@ -863,6 +1581,16 @@ fn do_ocall(
     let env = &mut *guard;
 
     env.current_task = task_id;
+    #[cfg(feature = "failure-injection")]
+    if let Some(err) = crate::failure::injected_failure(func_id) {
+        if env.ocall_trace_enabled {
+            let func_name = env::ocall_id2name(func_id);
+            tracing::trace!(target: "sidevm", "{func_name}({p0}, {p1}, {p2}, {p3}) injected {err:?}");
+        }
+        return convert(Err(err));
+    }
+    #[cfg(feature = "ocall-trace")]
+    let started_at = Instant::now();
     let result = set_task_env(env.awake_tasks.clone(), task_id, || {
         let memory = env.memory.unwrap_ref().clone();
         let vm = MemoryView(memory.view(&func_env));
@@ -899,6 +1627,14 @@ fn do_ocall(
         let func_name = env::ocall_id2name(func_id);
         tracing::trace!(target: "sidevm", "{func_name}({p0}, {p1}, {p2}, {p3}) = {result:?}");
     }
+    #[cfg(feature = "ocall-trace")]
+    crate::ocall_trace::record(
+        env.id,
+        func_id,
+        [p0, p1, p2, p3],
+        started_at.elapsed(),
+        &result,
+    );
     convert(result)
 }
 
@@ -910,10 +1646,26 @@ fn convert(result: Result<i32, OcallError>) -> Result<IntRet, OcallAborted> {
     }
 }
 
+fn digest(algorithm: HashAlgorithm, message: &[u8]) -> [u8; 32] {
+    match algorithm {
+        HashAlgorithm::Sha256 => {
+            use sha2::Digest;
+            sha2::Sha256::digest(message).into()
+        }
+        HashAlgorithm::Blake2b256 => {
+            use blake2::Digest;
+            blake2::Blake2b::<blake2::digest::consts::U32>::digest(message).into()
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub enum OcallAborted {
     GasExhausted,
     Stifled,
+    CpuBudgetExceeded,
+    InstructionLimitExceeded,
+    MemoryCeilingReached,
 }
 
 impl From<OcallAborted> for OcallError {
@@ -921,6 +1673,9 @@ impl From<OcallAborted> for OcallError {
         match aborted {
             OcallAborted::GasExhausted => OcallError::GasExhausted,
             OcallAborted::Stifled => OcallError::Stifled,
+            OcallAborted::CpuBudgetExceeded => OcallError::CpuBudgetExceeded,
+            OcallAborted::InstructionLimitExceeded => OcallError::InstructionLimitExceeded,
+            OcallAborted::MemoryCeilingReached => OcallError::MemoryCeilingReached,
         }
     }
 }
@@ -930,12 +1685,35 @@ impl fmt::Display for OcallAborted {
         match self {
             OcallAborted::GasExhausted => write!(f, "Gas exhausted"),
             OcallAborted::Stifled => write!(f, "Stifled"),
+            OcallAborted::CpuBudgetExceeded => write!(f, "CPU budget exceeded"),
+            OcallAborted::InstructionLimitExceeded => write!(f, "Instruction limit exceeded"),
+            OcallAborted::MemoryCeilingReached => write!(f, "Memory ceiling reached"),
         }
     }
 }
 
 impl std::error::Error for OcallAborted {}
 
+pub use chain_head::set_chain_head;
+mod chain_head {
+    use super::ChainHead;
+    use std::sync::Mutex;
+
+    static CHAIN_HEAD: Mutex<ChainHead> = Mutex::new(ChainHead {
+        block_number: 0,
+        now_ms: 0,
+    });
+
+    /// Update the chain head exposed to all sidevm instances without a pinned head.
+    pub fn set_chain_head(head: ChainHead) {
+        *CHAIN_HEAD.lock().unwrap() = head;
+    }
+
+    pub(super) fn current() -> ChainHead {
+        *CHAIN_HEAD.lock().unwrap()
+    }
+}
+
 pub use vm_counter::vm_count;
 mod vm_counter {
     use std::sync::atomic::{AtomicUsize, Ordering};
@@ -963,3 +1741,108 @@ mod vm_counter {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::LruCache;
+
+    #[test]
+    fn pinned_chain_head_overrides_the_shared_one() {
+        let cache_ops: DynCacheOps = Box::leak(Box::new(LruCache::new(1024)));
+        let (tx, _rx) = tokio::sync::mpsc::channel(1);
+        let env = Env::new([0; 32], cache_ops, tx, None, vec![]);
+        let shared = ChainHead {
+            block_number: 100,
+            now_ms: 1000,
+        };
+        let pinned = ChainHead {
+            block_number: 42,
+            now_ms: 420,
+        };
+
+        set_chain_head(shared);
+        assert_eq!(env.inner.lock().unwrap().chain_head(), shared);
+
+        env.set_pinned_chain_head(Some(pinned));
+        set_chain_head(ChainHead {
+            block_number: 101,
+            now_ms: 1006,
+        });
+        assert_eq!(env.inner.lock().unwrap().chain_head(), pinned);
+
+        env.set_pinned_chain_head(None);
+        assert_eq!(env.inner.lock().unwrap().chain_head().block_number, 101);
+    }
+
+    #[tokio::test]
+    async fn storage_reads_are_relayed_to_the_host() {
+        let cache_ops: DynCacheOps = Box::leak(Box::new(LruCache::new(1024)));
+        let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+        let env = Env::new([7; 32], cache_ops, tx, None, vec![]);
+        let read = |key: &[u8]| {
+            let key = key.to_vec();
+            env.inner
+                .lock()
+                .unwrap()
+                .send_outgoing_request(|reply_tx| OutgoingRequest::ReadStorage { key, reply_tx })
+        };
+
+        let res_id = read(b"key").unwrap();
+        // Only one outgoing request can be in flight.
+        assert!(matches!(read(b"other"), Err(OcallError::ResourceLimited)));
+
+        let (from, request) = rx.recv().await.unwrap();
+        assert_eq!(from, [7; 32]);
+        let OutgoingRequest::ReadStorage { key, reply_tx } = request else {
+            panic!("unexpected request");
+        };
+        assert_eq!(key, b"key");
+        reply_tx.send(b"value".to_vec()).unwrap();
+
+        let res = env.inner.lock().unwrap().resources.take(res_id);
+        let Some(Resource::ChannelRx(mut reply_rx)) = res else {
+            panic!("no reply channel");
+        };
+        assert_eq!(reply_rx.recv().await.unwrap(), b"value");
+    }
+
+    #[test]
+    fn log_budget_truncates_once_per_query() {
+        let mut budget = LogBudget {
+            limit: Some(LogLimit {
+                max_bytes: 10,
+                max_lines: 3,
+            }),
+            ..Default::default()
+        };
+        assert!(matches!(budget.admit(4), LogAdmission::Accept));
+        assert!(matches!(budget.admit(6), LogAdmission::Accept));
+        assert!(matches!(budget.admit(1), LogAdmission::Truncate));
+        assert!(matches!(budget.admit(1), LogAdmission::Drop));
+
+        // The next query starts over, and the line count is capped too.
+        budget.reset();
+        for _ in 0..3 {
+            assert!(matches!(budget.admit(0), LogAdmission::Accept));
+        }
+        assert!(matches!(budget.admit(0), LogAdmission::Truncate));
+
+        let mut unlimited = LogBudget::default();
+        for _ in 0..1000 {
+            assert!(matches!(unlimited.admit(1 << 20), LogAdmission::Accept));
+        }
+    }
+
+    #[test]
+    fn digest_matches_the_reference_vectors() {
+        assert_eq!(
+            hex_fmt::HexFmt(digest(HashAlgorithm::Sha256, b"abc")).to_string(),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            hex_fmt::HexFmt(digest(HashAlgorithm::Blake2b256, b"abc")).to_string(),
+            "bddd813c634239723171ef3fee98579b94964e3bb1cb3e427262c8c068d52319"
+        );
+    }
+}
@@ -231,9 +231,14 @@ pub fn clock_time_get(
     let t_out = (timespec_out.tv_sec * 1_000_000_000).wrapping_add(timespec_out.tv_nsec);
 
     let guard = env.data().inner.lock().unwrap();
+    // A manual clock stands for both the realtime and the monotonic clocks.
+    let t_out = match guard.clock.manual_now() {
+        Some(now) => now.as_nanos() as wasi::Timestamp,
+        None => t_out as wasi::Timestamp,
+    };
     let memory = guard.memory.unwrap_ref().view(&env);
     let time = time.deref(&memory);
-    wasi_try!(time.write(t_out as wasi::Timestamp).ok(), Errno::Fault);
+    wasi_try!(time.write(t_out).ok(), Errno::Fault);
 
     Errno::Success
 }
@@ -461,6 +466,14 @@ fn fd_write_stdio(
         };
         let bytes = buf.as_ref();
         for line in String::from_utf8_lossy(&bytes[..bytes.len().min(1024)]).lines() {
+            let level = if fd == 2 {
+                log::Level::Error
+            } else {
+                log::Level::Info
+            };
+            if env.send_to_log_sink(level, line) {
+                continue;
+            }
             if fd == 2 {
                 error!(target: "sidevm", "{}", line);
             } else {
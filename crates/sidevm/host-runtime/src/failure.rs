@@ -0,0 +1,123 @@
+//! Injection of ocall failures, to test how the guests cope with a flaky host.
+//!
+//! Only compiled with the `failure-injection` feature, which must never be enabled in production.
+
+use std::sync::Mutex;
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sidevm_env::{ocall_id2name, OcallError};
+
+/// How an injected failure looks like to the guest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FailureKind {
+    /// The ocall never completes. The `poll*` ocalls keep returning `Pending` without ever waking
+    /// the guest up, so only its own timeout gets it out. The other ocalls fail with `IoError`.
+    Timeout,
+    /// The connection is reset. Fails with `IoError`, which is what the guest gets for a reset
+    /// from the peer.
+    ConnectionReset,
+    /// Fails with `IoError`.
+    IoError,
+    /// Fails with `ResourceLimited`, as a full cache would.
+    ResourceLimited,
+}
+
+/// A rule of which ocalls to fail, how, and how often.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FailureRule {
+    /// Name of the ocall, e.g. `tcp_connect`. A trailing `*` matches every ocall starting with the
+    /// prefix, e.g. `local_cache_*`.
+    pub ocall: String,
+    pub kind: FailureKind,
+    /// Probability to fail each call, from 0.0 to 1.0.
+    pub probability: f64,
+}
+
+impl FailureRule {
+    fn matches(&self, name: &str) -> bool {
+        match self.ocall.strip_suffix('*') {
+            Some(prefix) => name.starts_with(prefix),
+            None => name == self.ocall,
+        }
+    }
+}
+
+static RULES: Mutex<Vec<FailureRule>> = Mutex::new(Vec::new());
+
+/// Set the failure injection rules applied to the ocalls of all sidevm instances.
+///
+/// The first rule matching an ocall decides whether it fails.
+pub fn set_failure_rules(rules: Vec<FailureRule>) {
+    *RULES.lock().unwrap() = rules;
+}
+
+/// Returns the error to fail the ocall with, if any.
+pub(crate) fn injected_failure(func_id: i32) -> Option<OcallError> {
+    let rules = RULES.lock().unwrap();
+    if rules.is_empty() {
+        return None;
+    }
+    let name = ocall_id2name(func_id);
+    let rule = rules.iter().find(|rule| rule.matches(name))?;
+    if !rand::thread_rng().gen_bool(rule.probability.clamp(0.0, 1.0)) {
+        return None;
+    }
+    Some(match rule.kind {
+        FailureKind::Timeout if name.starts_with("poll") => OcallError::Pending,
+        FailureKind::Timeout | FailureKind::ConnectionReset | FailureKind::IoError => {
+            OcallError::IoError
+        }
+        FailureKind::ResourceLimited => OcallError::ResourceLimited,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ocall_id(name: &str) -> i32 {
+        (0..1000)
+            .find(|&id| ocall_id2name(id) == name)
+            .expect("unknown ocall")
+    }
+
+    fn rule(ocall: &str, kind: FailureKind, probability: f64) -> FailureRule {
+        FailureRule {
+            ocall: ocall.into(),
+            kind,
+            probability,
+        }
+    }
+
+    #[test]
+    fn rules_match_by_name_or_prefix() {
+        let exact = rule("tcp_connect", FailureKind::IoError, 1.0);
+        assert!(exact.matches("tcp_connect"));
+        assert!(!exact.matches("tcp_connect_tls"));
+        let prefix = rule("local_cache_*", FailureKind::IoError, 1.0);
+        assert!(prefix.matches("local_cache_get"));
+        assert!(!prefix.matches("tcp_connect"));
+    }
+
+    #[test]
+    fn first_matching_rule_decides_the_failure() {
+        set_failure_rules(vec![
+            rule("poll_read", FailureKind::Timeout, 1.0),
+            rule("tcp_connect", FailureKind::Timeout, 1.0),
+            rule("local_cache_set", FailureKind::ResourceLimited, 0.0),
+            rule("local_cache_*", FailureKind::ResourceLimited, 1.0),
+        ]);
+        let failure = |name| injected_failure(ocall_id(name));
+        assert!(matches!(failure("poll_read"), Some(OcallError::Pending)));
+        assert!(matches!(failure("tcp_connect"), Some(OcallError::IoError)));
+        assert!(failure("local_cache_set").is_none());
+        assert!(matches!(
+            failure("local_cache_get"),
+            Some(OcallError::ResourceLimited)
+        ));
+        assert!(failure("tcp_connect_tls").is_none());
+        set_failure_rules(vec![]);
+    }
+}
@@ -0,0 +1,91 @@
+//! The identity keys given by the host to the sidevm instances.
+//!
+//! The key of an instance is derived from a secret of the host, the vmid, and the hash of the
+//! code, so that a given program on a given host always gets the same key, while different
+//! instances get different ones. The secret key never leaves the host. The guest can only get
+//! the public key and ask the host to sign or verify messages.
+
+use blake2::{digest::consts::U32, Blake2b, Digest};
+use schnorrkel::{signing_context, ExpansionMode, Keypair, MiniSecretKey, Signature};
+
+use crate::VmId;
+
+/// Domain separator of the key derivation.
+const DERIVATION_TAG: &[u8] = b"sidevm/identity/v1";
+/// The signing context used by the sr25519 primitives of substrate.
+const SIGNING_CONTEXT: &[u8] = b"substrate";
+
+/// The identity keypair of a sidevm instance.
+#[derive(Clone)]
+pub struct VmIdentity {
+    keypair: Keypair,
+}
+
+impl VmIdentity {
+    /// Derive the identity of the instance `id` running `code` from the host secret.
+    pub fn derive(host_secret: &[u8; 32], id: &VmId, code: &[u8]) -> Self {
+        let code_hash = Blake2b::<U32>::digest(code);
+        let seed = Blake2b::<U32>::new()
+            .chain_update(DERIVATION_TAG)
+            .chain_update(host_secret)
+            .chain_update(id)
+            .chain_update(code_hash)
+            .finalize();
+        let keypair = MiniSecretKey::from_bytes(&seed)
+            .expect("the seed is always 32 bytes")
+            .expand_to_keypair(ExpansionMode::Ed25519);
+        Self { keypair }
+    }
+
+    /// The sr25519 public key of the identity.
+    pub fn public_key(&self) -> [u8; 32] {
+        self.keypair.public.to_bytes()
+    }
+
+    pub(crate) fn sign(&self, message: &[u8]) -> [u8; 64] {
+        let context = signing_context(SIGNING_CONTEXT);
+        self.keypair.sign(context.bytes(message)).to_bytes()
+    }
+
+    pub(crate) fn verify(&self, message: &[u8], signature: &[u8]) -> bool {
+        let Ok(signature) = Signature::from_bytes(signature) else {
+            return false;
+        };
+        let context = signing_context(SIGNING_CONTEXT);
+        self.keypair
+            .public
+            .verify(context.bytes(message), &signature)
+            .is_ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn identity_is_stable_per_host_instance_and_code() {
+        let identity = VmIdentity::derive(&[1; 32], &[2; 32], b"code");
+        let again = VmIdentity::derive(&[1; 32], &[2; 32], b"code");
+        assert_eq!(identity.public_key(), again.public_key());
+        for other in [
+            VmIdentity::derive(&[0; 32], &[2; 32], b"code"),
+            VmIdentity::derive(&[1; 32], &[0; 32], b"code"),
+            VmIdentity::derive(&[1; 32], &[2; 32], b"other code"),
+        ] {
+            assert_ne!(identity.public_key(), other.public_key());
+        }
+    }
+
+    #[test]
+    fn signatures_are_verified_against_the_identity() {
+        let identity = VmIdentity::derive(&[1; 32], &[2; 32], b"code");
+        let signature = identity.sign(b"message");
+        assert!(identity.verify(b"message", &signature));
+        assert!(!identity.verify(b"tampered", &signature));
+        assert!(!identity.verify(b"message", &signature[1..]));
+
+        let other = VmIdentity::derive(&[1; 32], &[3; 32], b"code");
+        assert!(!other.verify(b"message", &signature));
+    }
+}
@@ -1,20 +1,60 @@
 mod async_context;
+mod body_limit;
+mod cache;
+mod clock;
+mod dns;
 mod env;
+#[cfg(feature = "failure-injection")]
+mod failure;
+mod identity;
 pub mod instrument;
 mod metering;
+mod module_limits;
+#[cfg(feature = "ocall-trace")]
+mod ocall_trace;
+mod outbound;
+mod proxy;
+mod pubsub;
 mod resource;
 #[cfg(feature = "rocket-stream")]
 pub mod rocket_stream;
 mod run;
 pub mod service;
+mod session;
+mod snapshot;
 mod tls;
+mod websocket;
 
+pub use body_limit::BodyLimits;
+pub use cache::LruCache;
+pub use clock::ManualClock;
+pub use dns::{public_only, AddrPolicy, CachingResolver, Dns, Resolver, StaticHosts};
 pub use env::{
-    vm_count, CacheOps, DynCacheOps, OcallAborted, OutgoingRequest, OutgoingRequestChannel, ShortId,
+    set_chain_head, vm_count, CacheOps, DynCacheOps, LogLimit, LogRecord, LogSink, OcallAborted,
+    OutgoingRequest, OutgoingRequestChannel, ShortId, VmDump, VmStats,
 };
+#[cfg(feature = "failure-injection")]
+pub use failure::{set_failure_rules, FailureKind, FailureRule};
+pub use identity::VmIdentity;
+pub use metering::{
+    set_gas_cost_table, CpuBudget, FuelExhaustedHandler, FuelPolicy, GasCostTable, HelperCost,
+    HelperCosts, OpCategory,
+};
+pub use module_limits::{ModuleLimitExceeded, ModuleLimits};
+#[cfg(feature = "ocall-trace")]
+pub use ocall_trace::{clear_ocall_trace, ocall_trace, set_ocall_trace_capacity, OcallRecord};
+pub use outbound::OutboundLimits;
+pub use proxy::{set_outbound_proxy, OutboundProxy};
+pub use resource::{Evicted, MemoryCeiling, MemoryPool, ResourceInfo};
+pub use session::{SessionLimits, TooManySessions};
+pub use snapshot::{GlobalValue, MemorySnapshot, SNAPSHOT_VERSION};
+pub use tls::{TlsClientIdentity, TlsRoots};
 
 pub type VmId = [u8; 32];
-pub use run::{WasmRun, WasmEngine, WasmInstanceConfig, WasmModule};
+pub use run::{ShutdownTimedOut, WasmEngine, WasmInstanceConfig, WasmModule, WasmRun};
 
 pub use service::IncomingHttpRequest;
-pub use sidevm_env::OcallError;
+pub use sidevm_env::{
+    messages::{ChainHead, QueryError},
+    OcallError,
+};
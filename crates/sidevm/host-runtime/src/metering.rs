@@ -1,13 +1,605 @@
-use std::sync::Arc;
-use wasmer::{wasmparser::Operator, CompilerConfig};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::time::{sleep_until, Instant, Sleep};
+use wasmer::{
+    wasmparser::{BlockType, Operator},
+    AsStoreMut, CompilerConfig, ExportIndex, FunctionMiddleware, GlobalInit, GlobalType, Instance,
+    LocalFunctionIndex, MiddlewareError, MiddlewareReaderState, ModuleMiddleware, Mutability, Type,
+    Value,
+};
 use wasmer_middlewares::metering::Metering;
+use wasmer_types::{GlobalIndex, ModuleInfo};
 
-pub(crate) fn metering<C: CompilerConfig>(mut compiler: C) -> C {
+use crate::{OcallAborted, VmId};
+use sidevm_env::messages::FuelStatus;
+
+/// Add the gas metering, and the instruction counter if `count_instructions`, to the compiler.
+pub(crate) fn metering<C: CompilerConfig>(compiler: C, count_instructions: bool) -> C {
+    let costs = GAS_COST_TABLE.lock().unwrap().clone();
+    metering_with(compiler, costs, count_instructions)
+}
+
+fn metering_with<C: CompilerConfig>(
+    mut compiler: C,
+    costs: GasCostTable,
+    count_instructions: bool,
+) -> C {
+    // The counter goes first, so it only sees the instructions of the guest rather than the gas
+    // checks. Its own checks are charged gas as any other instruction.
+    if count_instructions {
+        compiler.push_middleware(Arc::new(InstructionCounter::default()));
+    }
+    let cost_function = move |operator: &Operator| costs.cost(operator);
     compiler.push_middleware(Arc::new(Metering::new(u64::MAX, cost_function)));
     compiler
 }
 
-fn cost_function(operator: &Operator) -> u64 {
+const INSTRUCTIONS_REMAINING: &str = "sidevm_instructions_remaining";
+const INSTRUCTIONS_EXHAUSTED: &str = "sidevm_instructions_exhausted";
+
+/// Counts the instructions retired by the guest, trapping once it has run a given number of them.
+///
+/// Unlike the gas, every instruction counts as one, so the point at which a program traps only
+/// depends on the program and the limit. As with the gas, the count is settled at the end of each
+/// basic block, and the block that would go over the limit traps before it runs.
+#[derive(Debug, Default)]
+struct InstructionCounter {
+    globals: Mutex<Option<CounterGlobals>>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct CounterGlobals {
+    remaining: GlobalIndex,
+    exhausted: GlobalIndex,
+}
+
+impl ModuleMiddleware for InstructionCounter {
+    fn generate_function_middleware(&self, _: LocalFunctionIndex) -> Box<dyn FunctionMiddleware> {
+        Box::new(FunctionCounter {
+            globals: self
+                .globals
+                .lock()
+                .unwrap()
+                .expect("BUG: the module info is transformed first"),
+            pending: 0,
+        })
+    }
+
+    fn transform_module_info(&self, module_info: &mut ModuleInfo) {
+        let mut globals = self.globals.lock().unwrap();
+        if globals.is_some() {
+            panic!("InstructionCounter: a counter can only be used by a single module");
+        }
+        let remaining = module_info
+            .globals
+            .push(GlobalType::new(Type::I64, Mutability::Var));
+        module_info
+            .global_initializers
+            .push(GlobalInit::I64Const(-1));
+        module_info.exports.insert(
+            INSTRUCTIONS_REMAINING.into(),
+            ExportIndex::Global(remaining),
+        );
+        let exhausted = module_info
+            .globals
+            .push(GlobalType::new(Type::I32, Mutability::Var));
+        module_info
+            .global_initializers
+            .push(GlobalInit::I32Const(0));
+        module_info.exports.insert(
+            INSTRUCTIONS_EXHAUSTED.into(),
+            ExportIndex::Global(exhausted),
+        );
+        *globals = Some(CounterGlobals {
+            remaining,
+            exhausted,
+        });
+    }
+}
+
+#[derive(Debug)]
+struct FunctionCounter {
+    globals: CounterGlobals,
+    /// Instructions of the current basic block not settled yet.
+    pending: u64,
+}
+
+impl FunctionMiddleware for FunctionCounter {
+    fn feed<'a>(
+        &mut self,
+        operator: Operator<'a>,
+        state: &mut MiddlewareReaderState<'a>,
+    ) -> Result<(), MiddlewareError> {
+        self.pending += 1;
+        let ends_block = matches!(
+            operator,
+            Operator::Loop { .. }
+                | Operator::End
+                | Operator::Else
+                | Operator::Br { .. }
+                | Operator::BrTable { .. }
+                | Operator::BrIf { .. }
+                | Operator::Call { .. }
+                | Operator::CallIndirect { .. }
+                | Operator::Return
+        );
+        if ends_block {
+            let remaining = self.globals.remaining.as_u32();
+            let exhausted = self.globals.exhausted.as_u32();
+            let pending = self.pending as i64;
+            state.extend(&[
+                Operator::GlobalGet {
+                    global_index: remaining,
+                },
+                Operator::I64Const { value: pending },
+                Operator::I64LtU,
+                Operator::If {
+                    blockty: BlockType::Empty,
+                },
+                Operator::I32Const { value: 1 },
+                Operator::GlobalSet {
+                    global_index: exhausted,
+                },
+                Operator::Unreachable,
+                Operator::End,
+                Operator::GlobalGet {
+                    global_index: remaining,
+                },
+                Operator::I64Const { value: pending },
+                Operator::I64Sub,
+                Operator::GlobalSet {
+                    global_index: remaining,
+                },
+            ]);
+            self.pending = 0;
+        }
+        state.push_operator(operator);
+        Ok(())
+    }
+}
+
+/// Set how many more instructions the instance can run.
+///
+/// Fails if the module wasn't compiled by an engine counting the instructions.
+pub(crate) fn set_remaining_instructions(
+    store: &mut impl AsStoreMut,
+    instance: &Instance,
+    count: u64,
+) -> anyhow::Result<()> {
+    instance
+        .exports
+        .get_global(INSTRUCTIONS_REMAINING)?
+        .set(store, Value::I64(count as i64))?;
+    Ok(())
+}
+
+/// How many more instructions the instance can run, or None if they aren't counted.
+pub(crate) fn remaining_instructions(
+    store: &mut impl AsStoreMut,
+    instance: &Instance,
+) -> Option<u64> {
+    let global = instance.exports.get_global(INSTRUCTIONS_REMAINING).ok()?;
+    Some(global.get(store).i64()? as u64)
+}
+
+/// Read what the instance has left to run.
+///
+/// Only reads the globals of the meters, so the guest can ask for it as often as it likes.
+pub(crate) fn read_fuel_status(
+    store: &mut impl AsStoreMut,
+    instance: &Instance,
+    gas_per_breath: u64,
+    fuel_left: Option<u64>,
+    count_instructions: bool,
+) -> FuelStatus {
+    use wasmer_middlewares::metering::{get_remaining_points, MeteringPoints};
+    let gas_left = match get_remaining_points(store, instance) {
+        MeteringPoints::Remaining(points) => points,
+        MeteringPoints::Exhausted => 0,
+    };
+    let instructions_left = if count_instructions {
+        remaining_instructions(store, instance)
+    } else {
+        None
+    };
+    FuelStatus {
+        gas_left,
+        gas_per_breath,
+        fuel_left,
+        instructions_left,
+    }
+}
+
+/// Whether the instance has trapped for reaching its instruction limit.
+pub(crate) fn instructions_exhausted(store: &mut impl AsStoreMut, instance: &Instance) -> bool {
+    let Ok(global) = instance.exports.get_global(INSTRUCTIONS_EXHAUSTED) else {
+        return false;
+    };
+    global.get(store).i32() == Some(1)
+}
+
+/// A category of wasm instructions sharing the same gas cost in a [`GasCostTable`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OpCategory {
+    /// Structured control markers, `nop`, `drop`, `unreachable`, `return` and exceptions.
+    Control,
+    /// Branches, `if` and `select`.
+    Branch,
+    Call,
+    /// Accesses to the locals and the globals.
+    Variable,
+    Const,
+    Load,
+    Store,
+    /// Memory size, grow and the bulk memory instructions.
+    Memory,
+    Table,
+    /// Integer arithmetic, bitwise and comparison instructions.
+    Integer,
+    /// Float arithmetic and comparison instructions.
+    Float,
+    /// Conversions between the value types.
+    Conversion,
+    /// Any instruction not in the categories above, e.g. atomics and references.
+    Other,
+}
+
+impl OpCategory {
+    pub fn of(operator: &Operator) -> Self {
+        use Operator::*;
+        match operator {
+            Unreachable
+            | Nop
+            | Block { .. }
+            | Loop { .. }
+            | Else
+            | End
+            | Return
+            | Drop
+            | Try { .. }
+            | Catch { .. }
+            | Throw { .. }
+            | Rethrow { .. }
+            | Delegate { .. }
+            | CatchAll => Self::Control,
+            If { .. }
+            | Br { .. }
+            | BrIf { .. }
+            | BrTable { .. }
+            | Select { .. }
+            | TypedSelect { .. } => Self::Branch,
+            Call { .. } | CallIndirect { .. } | ReturnCall { .. } | ReturnCallIndirect { .. } => {
+                Self::Call
+            }
+            LocalGet { .. }
+            | LocalSet { .. }
+            | LocalTee { .. }
+            | GlobalGet { .. }
+            | GlobalSet { .. } => Self::Variable,
+            I32Const { .. } | I64Const { .. } | F32Const { .. } | F64Const { .. } => Self::Const,
+            I32Load { .. }
+            | I64Load { .. }
+            | F32Load { .. }
+            | F64Load { .. }
+            | I32Load8S { .. }
+            | I32Load8U { .. }
+            | I32Load16S { .. }
+            | I32Load16U { .. }
+            | I64Load8S { .. }
+            | I64Load8U { .. }
+            | I64Load16S { .. }
+            | I64Load16U { .. }
+            | I64Load32S { .. }
+            | I64Load32U { .. } => Self::Load,
+            I32Store { .. }
+            | I64Store { .. }
+            | F32Store { .. }
+            | F64Store { .. }
+            | I32Store8 { .. }
+            | I32Store16 { .. }
+            | I64Store8 { .. }
+            | I64Store16 { .. }
+            | I64Store32 { .. } => Self::Store,
+            MemorySize { .. }
+            | MemoryGrow { .. }
+            | MemoryInit { .. }
+            | DataDrop { .. }
+            | MemoryCopy { .. }
+            | MemoryFill { .. } => Self::Memory,
+            TableInit { .. }
+            | ElemDrop { .. }
+            | TableCopy { .. }
+            | TableFill { .. }
+            | TableGet { .. }
+            | TableSet { .. }
+            | TableGrow { .. }
+            | TableSize { .. } => Self::Table,
+            I32Eqz | I32Eq | I32Ne | I32LtS | I32LtU | I32GtS | I32GtU | I32LeS | I32LeU
+            | I32GeS | I32GeU | I64Eqz | I64Eq | I64Ne | I64LtS | I64LtU | I64GtS | I64GtU
+            | I64LeS | I64LeU | I64GeS | I64GeU | I32Clz | I32Ctz | I32Popcnt | I32Add | I32Sub
+            | I32Mul | I32DivS | I32DivU | I32RemS | I32RemU | I32And | I32Or | I32Xor | I32Shl
+            | I32ShrS | I32ShrU | I32Rotl | I32Rotr | I64Clz | I64Ctz | I64Popcnt | I64Add
+            | I64Sub | I64Mul | I64DivS | I64DivU | I64RemS | I64RemU | I64And | I64Or | I64Xor
+            | I64Shl | I64ShrS | I64ShrU | I64Rotl | I64Rotr => Self::Integer,
+            F32Eq | F32Ne | F32Lt | F32Gt | F32Le | F32Ge | F64Eq | F64Ne | F64Lt | F64Gt
+            | F64Le | F64Ge | F32Abs | F32Neg | F32Ceil | F32Floor | F32Trunc | F32Nearest
+            | F32Sqrt | F32Add | F32Sub | F32Mul | F32Div | F32Min | F32Max | F32Copysign
+            | F64Abs | F64Neg | F64Ceil | F64Floor | F64Trunc | F64Nearest | F64Sqrt | F64Add
+            | F64Sub | F64Mul | F64Div | F64Min | F64Max | F64Copysign => Self::Float,
+            I32WrapI64 | I32TruncF32S | I32TruncF32U | I32TruncF64S | I32TruncF64U
+            | I64ExtendI32S | I64ExtendI32U | I64TruncF32S | I64TruncF32U | I64TruncF64S
+            | I64TruncF64U | F32ConvertI32S | F32ConvertI32U | F32ConvertI64S | F32ConvertI64U
+            | F32DemoteF64 | F64ConvertI32S | F64ConvertI32U | F64ConvertI64S | F64ConvertI64U
+            | F64PromoteF32 | I32ReinterpretF32 | I64ReinterpretF64 | F32ReinterpretI32
+            | F64ReinterpretI64 | I32Extend8S | I32Extend16S | I64Extend8S | I64Extend16S
+            | I64Extend32S | I32TruncSatF32S | I32TruncSatF32U | I32TruncSatF64S
+            | I32TruncSatF64U | I64TruncSatF32S | I64TruncSatF32U | I64TruncSatF64S
+            | I64TruncSatF64U => Self::Conversion,
+            _ => Self::Other,
+        }
+    }
+}
+
+/// Gas charged for each instruction of the listed categories, in place of the built-in costs.
+///
+/// Deserialized from a map like `{"integer": 30, "float": 50}`. The categories not listed keep
+/// the built-in per-instruction costs, so the default empty table charges exactly as before.
+/// Every instruction costs at least 1.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct GasCostTable(pub BTreeMap<OpCategory, u64>);
+
+impl GasCostTable {
+    pub fn cost(&self, operator: &Operator) -> u64 {
+        match self.0.get(&OpCategory::of(operator)) {
+            Some(&cost) => cost.max(1),
+            None => default_cost(operator),
+        }
+    }
+}
+
+static GAS_COST_TABLE: Mutex<GasCostTable> = Mutex::new(GasCostTable(BTreeMap::new()));
+
+/// Set the gas cost table used to meter the modules compiled by the engines created afterward.
+pub fn set_gas_cost_table(table: GasCostTable) {
+    *GAS_COST_TABLE.lock().unwrap() = table;
+}
+
+/// Called with the id of a VM and the gas it has consumed in total, when it gets stifled for
+/// running out of gas in a poll.
+pub type FuelExhaustedHandler = Arc<dyn Fn(VmId, u64) + Send + Sync>;
+
+/// Fuel replenishment policy for long running instances.
+///
+/// The instance starts with `initial` fuel, and gets `refill` more every `interval_ms`, up to
+/// `cap`. Each poll reserves a full breath of fuel before running, so the instance is paused
+/// rather than stifled when the fuel runs low.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FuelPolicy {
+    pub initial: u64,
+    pub cap: u64,
+    pub refill: u64,
+    pub interval_ms: u64,
+    /// Cost of the host helper ocalls.
+    #[serde(default)]
+    pub helper_costs: HelperCosts,
+}
+
+/// Fuel charged by a host helper ocall, `base + per_byte * input length`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HelperCost {
+    pub base: u64,
+    pub per_byte: u64,
+}
+
+impl HelperCost {
+    pub fn cost(&self, input_len: usize) -> u64 {
+        self.base
+            .saturating_add(self.per_byte.saturating_mul(input_len as u64))
+    }
+}
+
+/// Cost coefficients of the host helper ocalls.
+///
+/// The helpers do their work on the host side, so they are charged in proportion to their input
+/// to keep the guest from offloading heavy computation for free.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HelperCosts {
+    pub hash: HelperCost,
+    pub publish: HelperCost,
+    /// Signing or verifying with the identity key.
+    pub sign: HelperCost,
+}
+
+impl Default for HelperCosts {
+    fn default() -> Self {
+        Self {
+            hash: HelperCost {
+                base: 1_000_000,
+                per_byte: 10_000,
+            },
+            publish: HelperCost {
+                base: 10_000_000,
+                per_byte: 10_000,
+            },
+            sign: HelperCost {
+                base: 100_000_000,
+                per_byte: 10_000,
+            },
+        }
+    }
+}
+
+pub(crate) struct FuelTank {
+    cap: u64,
+    refill: u64,
+    interval: Duration,
+    breath: u64,
+    level: u64,
+    last_refill: Instant,
+    reserved: bool,
+    timer: Option<Pin<Box<Sleep>>>,
+}
+
+impl FuelTank {
+    pub(crate) fn new(policy: FuelPolicy, gas_per_breath: u64) -> Self {
+        // A breath must fit in the tank, or the instance would never be polled again.
+        let cap = policy.cap.max(gas_per_breath);
+        Self {
+            cap,
+            refill: policy.refill,
+            interval: Duration::from_millis(policy.interval_ms),
+            breath: gas_per_breath,
+            level: policy.initial.min(cap),
+            last_refill: Instant::now(),
+            reserved: false,
+            timer: None,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        if self.interval.is_zero() || self.refill == 0 {
+            return;
+        }
+        let elapsed = now.saturating_duration_since(self.last_refill);
+        let n = (elapsed.as_nanos() / self.interval.as_nanos()).min(u32::MAX as _) as u32;
+        if n == 0 {
+            return;
+        }
+        self.level = self
+            .level
+            .saturating_add(self.refill.saturating_mul(n as _))
+            .min(self.cap);
+        self.last_refill += self.interval * n;
+    }
+
+    /// Reserve the fuel for the next breath, waiting for a refill if the tank is low.
+    pub(crate) fn poll_reserve(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        if self.reserved {
+            return Poll::Ready(());
+        }
+        loop {
+            self.refill(Instant::now());
+            if self.level >= self.breath {
+                self.level -= self.breath;
+                self.reserved = true;
+                self.timer = None;
+                return Poll::Ready(());
+            }
+            if self.interval.is_zero() || self.refill == 0 {
+                // Never refilled, the instance stays paused.
+                return Poll::Pending;
+            }
+            let needed = self.breath - self.level;
+            let intervals = needed.div_ceil(self.refill).min(u32::MAX as _) as u32;
+            let at = self.last_refill + self.interval * intervals;
+            let timer = self.timer.insert(Box::pin(sleep_until(at)));
+            futures::ready!(timer.as_mut().poll(cx));
+        }
+    }
+
+    /// Fuel left in the tank, besides the breath reserved if any.
+    pub(crate) fn level(&self) -> u64 {
+        self.level
+    }
+
+    /// Return the unused part of the reserved breath to the tank.
+    pub(crate) fn settle(&mut self, used: u64) {
+        if self.reserved {
+            self.level = self
+                .level
+                .saturating_add(self.breath.saturating_sub(used))
+                .min(self.cap);
+            self.reserved = false;
+        }
+    }
+}
+
+/// A cap of the CPU time an instance can spend within each window.
+///
+/// Gas only counts the guest instructions, while the CPU time also covers the work done by the
+/// host in the ocalls. An instance that used up its budget isn't polled again until the next
+/// window, letting the other instances run, or is aborted if `abort` is set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CpuBudget {
+    pub budget_ms: u64,
+    pub window_ms: u64,
+    #[serde(default)]
+    pub abort: bool,
+}
+
+/// The CPU time accounting of an instance against its [`CpuBudget`].
+pub(crate) struct CpuMeter {
+    budget: Duration,
+    window: Duration,
+    abort: bool,
+    window_start: Instant,
+    used: Duration,
+    timer: Option<Pin<Box<Sleep>>>,
+}
+
+impl CpuMeter {
+    pub(crate) fn new(budget: CpuBudget) -> Self {
+        Self {
+            budget: Duration::from_millis(budget.budget_ms),
+            window: Duration::from_millis(budget.window_ms.max(1)),
+            abort: budget.abort,
+            window_start: Instant::now(),
+            used: Duration::ZERO,
+            timer: None,
+        }
+    }
+
+    fn roll(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.window_start);
+        let n = (elapsed.as_nanos() / self.window.as_nanos()).min(u32::MAX as _) as u32;
+        if n > 0 {
+            self.window_start += self.window * n;
+            self.used = Duration::ZERO;
+        }
+    }
+
+    /// Wait for the budget to allow another poll, or fail if the instance is to be aborted.
+    pub(crate) fn poll_admit(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), OcallAborted>> {
+        loop {
+            self.roll(Instant::now());
+            if self.used < self.budget {
+                self.timer = None;
+                return Poll::Ready(Ok(()));
+            }
+            if self.abort {
+                return Poll::Ready(Err(OcallAborted::CpuBudgetExceeded));
+            }
+            let next_window = self.window_start + self.window;
+            let timer = self.timer.insert(Box::pin(sleep_until(next_window)));
+            futures::ready!(timer.as_mut().poll(cx));
+        }
+    }
+
+    pub(crate) fn record(&mut self, used: Duration) {
+        self.used = self.used.saturating_add(used);
+    }
+}
+
+/// The CPU time consumed by the current thread.
+pub(crate) fn thread_cpu_time() -> Duration {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    // Can't fail with a valid clock id and pointer.
+    unsafe { libc::clock_gettime(libc::CLOCK_THREAD_CPUTIME_ID, &mut ts) };
+    Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32)
+}
+
+fn default_cost(operator: &Operator) -> u64 {
     use Operator::*;
 
     let cost = match operator {
@@ -228,3 +820,198 @@ fn cost_function(operator: &Operator) -> u64 {
     };
     1.max(cost / 100)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::future::poll_fn;
+    use wasmer::{imports, Engine, Module, Store};
+    use wasmer_compiler_singlepass::Singlepass;
+
+    const ENDLESS_LOOP: &str = r#"
+        (module
+          (global $progress (export "progress") (mut i32) (i32.const 0))
+          (func (export "run")
+            (loop $again
+              (global.set $progress (i32.add (global.get $progress) (i32.const 1)))
+              (br $again))))
+    "#;
+
+    /// Run the endless loop under the instruction limit, returning how far it went.
+    fn progress_at_limit(costs: GasCostTable, limit: u64) -> i32 {
+        let engine: Engine = metering_with(Singlepass::default(), costs, true).into();
+        let mut store = Store::new(engine);
+        let module = Module::new(&store, ENDLESS_LOOP).unwrap();
+        let instance = wasmer::Instance::new(&mut store, &module, &imports! {}).unwrap();
+        set_remaining_instructions(&mut store, &instance, limit).unwrap();
+        let run = instance.exports.get_function("run").unwrap();
+
+        assert!(run.call(&mut store, &[]).is_err());
+        assert!(instructions_exhausted(&mut store, &instance));
+        assert!(matches!(
+            wasmer_middlewares::metering::get_remaining_points(&mut store, &instance),
+            wasmer_middlewares::metering::MeteringPoints::Remaining(_)
+        ));
+        let progress = instance.exports.get_global("progress").unwrap();
+        progress.get(&mut store).i32().unwrap()
+    }
+
+    const BOUNDED_LOOP: &str = r#"
+        (module
+          (func (export "work") (param $n i32)
+            (loop $again
+              (local.set $n (i32.sub (local.get $n) (i32.const 1)))
+              (br_if $again (local.get $n)))))
+    "#;
+
+    #[test]
+    fn fuel_status_decreases_with_work() {
+        let engine: Engine = metering_with(Singlepass::default(), Default::default(), true).into();
+        let mut store = Store::new(engine);
+        let module = Module::new(&store, BOUNDED_LOOP).unwrap();
+        let instance = wasmer::Instance::new(&mut store, &module, &imports! {}).unwrap();
+        wasmer_middlewares::metering::set_remaining_points(&mut store, &instance, 1_000_000);
+        set_remaining_instructions(&mut store, &instance, 1_000_000).unwrap();
+        let work = instance.exports.get_function("work").unwrap();
+
+        let mut last = read_fuel_status(&mut store, &instance, 1_000_000, Some(42), true);
+        assert_eq!(last.gas_left, 1_000_000);
+        assert_eq!(last.instructions_left, Some(1_000_000));
+        for _ in 0..3 {
+            work.call(&mut store, &[Value::I32(100)]).unwrap();
+            let status = read_fuel_status(&mut store, &instance, 1_000_000, Some(42), true);
+            assert!(status.gas_left < last.gas_left);
+            assert!(status.instructions_left < last.instructions_left);
+            assert_eq!(status.fuel_left, Some(42));
+            last = status;
+        }
+
+        // Reading the status doesn't run any guest code, so it doesn't move the meters.
+        let again = read_fuel_status(&mut store, &instance, 1_000_000, Some(42), true);
+        assert_eq!(again, last);
+        let uncounted = read_fuel_status(&mut store, &instance, 1_000_000, None, false);
+        assert_eq!(uncounted.instructions_left, None);
+    }
+
+    #[test]
+    fn instruction_limit_ignores_gas_costs() {
+        let pricey = GasCostTable(
+            [(OpCategory::Integer, 1_000), (OpCategory::Variable, 500)]
+                .into_iter()
+                .collect(),
+        );
+        let progress = progress_at_limit(GasCostTable::default(), 1_000);
+        assert!(progress > 0);
+        assert_eq!(progress, progress_at_limit(pricey, 1_000));
+    }
+
+    fn admitted(meter: &mut CpuMeter) -> bool {
+        let waker = futures::task::noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        matches!(meter.poll_admit(&mut cx), Poll::Ready(Ok(())))
+    }
+
+    #[tokio::test]
+    async fn cpu_hungry_instance_is_deferred() {
+        let budget = CpuBudget {
+            budget_ms: 10,
+            window_ms: 50,
+            abort: false,
+        };
+        let mut hungry = CpuMeter::new(budget);
+        let mut polite = CpuMeter::new(budget);
+
+        hungry.record(Duration::from_millis(40));
+        polite.record(Duration::from_millis(1));
+        assert!(!admitted(&mut hungry));
+        assert!(admitted(&mut polite));
+
+        // Admitted again in the next window.
+        tokio::time::timeout(Duration::from_secs(1), poll_fn(|cx| hungry.poll_admit(cx)))
+            .await
+            .expect("deferred past the next window")
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn cpu_hungry_instance_can_be_aborted() {
+        let mut meter = CpuMeter::new(CpuBudget {
+            budget_ms: 10,
+            window_ms: 1000,
+            abort: true,
+        });
+        assert!(admitted(&mut meter));
+        meter.record(Duration::from_millis(10));
+        let waker = futures::task::noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        assert!(matches!(
+            meter.poll_admit(&mut cx),
+            Poll::Ready(Err(OcallAborted::CpuBudgetExceeded))
+        ));
+    }
+
+    fn policy(initial: u64, cap: u64, refill: u64, interval_ms: u64) -> FuelPolicy {
+        FuelPolicy {
+            initial,
+            cap,
+            refill,
+            interval_ms,
+            helper_costs: Default::default(),
+        }
+    }
+
+    fn reserved(tank: &mut FuelTank) -> bool {
+        let waker = futures::task::noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        tank.poll_reserve(&mut cx).is_ready()
+    }
+
+    #[tokio::test]
+    async fn fuel_tank_pauses_when_low_and_settles_the_unused_fuel() {
+        let mut tank = FuelTank::new(policy(250, 1000, 0, 0), 100);
+        assert!(reserved(&mut tank));
+        assert_eq!(tank.level(), 150);
+        tank.settle(40);
+        assert_eq!(tank.level(), 210);
+
+        assert!(reserved(&mut tank));
+        tank.settle(100);
+        assert!(reserved(&mut tank));
+        tank.settle(100);
+        assert_eq!(tank.level(), 10);
+        // Never refilled, so it stays paused.
+        assert!(!reserved(&mut tank));
+    }
+
+    #[tokio::test]
+    async fn fuel_tank_is_refilled_up_to_the_cap() {
+        // The cap is raised to fit a breath.
+        let tank = FuelTank::new(policy(u64::MAX, 10, 0, 0), 100);
+        assert_eq!(tank.level(), 100);
+
+        let mut tank = FuelTank::new(policy(0, 300, 50, 10), 100);
+        assert!(!reserved(&mut tank));
+        tokio::time::timeout(Duration::from_secs(1), poll_fn(|cx| tank.poll_reserve(cx)))
+            .await
+            .expect("refilled in time");
+        tank.settle(100);
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(reserved(&mut tank));
+        tank.settle(0);
+        assert_eq!(tank.level(), 300);
+    }
+
+    #[test]
+    fn helper_cost_grows_with_the_input_and_saturates() {
+        let hash = HelperCosts::default().hash;
+        assert_eq!(hash.cost(0), 1_000_000);
+        assert_eq!(hash.cost(100), 2_000_000);
+        let pricey = HelperCost {
+            base: 1,
+            per_byte: u64::MAX / 2,
+        };
+        assert_eq!(pricey.cost(1), u64::MAX / 2 + 1);
+        assert_eq!(pricey.cost(3), u64::MAX);
+    }
+}
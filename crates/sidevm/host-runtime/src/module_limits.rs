@@ -0,0 +1,150 @@
+//! Limits of the modules accepted at spawn time.
+//!
+//! The code is checked before it is compiled, so an oversized module, or one asking for a huge
+//! initial memory or table, is rejected before the host allocates anything for it.
+
+use parity_wasm::elements::{External, Module};
+use serde::{Deserialize, Serialize};
+
+/// Limits of the modules the instances can be spawned from. No limit if None.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ModuleLimits {
+    /// Max size of the code of the module.
+    pub max_module_bytes: Option<usize>,
+    /// Max number of pages of the initial linear memory.
+    pub max_initial_pages: Option<u32>,
+    /// Max number of initial elements of each table.
+    pub max_table_elements: Option<u32>,
+}
+
+/// Returned when a module is rejected by the [`ModuleLimits`].
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum ModuleLimitExceeded {
+    #[error("the module is {size} bytes, over the limit of {max}")]
+    ModuleBytes { size: usize, max: usize },
+    #[error("the module asks for {pages} initial memory pages, over the limit of {max}")]
+    InitialPages { pages: u32, max: u32 },
+    #[error("the module asks for {elements} initial table elements, over the limit of {max}")]
+    TableElements { elements: u32, max: u32 },
+    #[error("the module can't be parsed to check its limits")]
+    Malformed,
+}
+
+impl ModuleLimits {
+    fn is_unlimited(&self) -> bool {
+        self.max_initial_pages.is_none() && self.max_table_elements.is_none()
+    }
+
+    /// Check the code of a module against the limits.
+    pub fn check(&self, code: &[u8]) -> Result<(), ModuleLimitExceeded> {
+        if let Some(max) = self.max_module_bytes {
+            if code.len() > max {
+                return Err(ModuleLimitExceeded::ModuleBytes {
+                    size: code.len(),
+                    max,
+                });
+            }
+        }
+        if self.is_unlimited() {
+            return Ok(());
+        }
+        let module: Module =
+            parity_wasm::deserialize_buffer(code).or(Err(ModuleLimitExceeded::Malformed))?;
+        let imported = module
+            .import_section()
+            .map(|section| section.entries())
+            .unwrap_or_default();
+        let imported_memories = imported.iter().filter_map(|entry| match entry.external() {
+            External::Memory(memory) => Some(memory.limits().initial()),
+            _ => None,
+        });
+        let imported_tables = imported.iter().filter_map(|entry| match entry.external() {
+            External::Table(table) => Some(table.limits().initial()),
+            _ => None,
+        });
+        let memories = module
+            .memory_section()
+            .map(|section| section.entries())
+            .unwrap_or_default()
+            .iter()
+            .map(|memory| memory.limits().initial());
+        let tables = module
+            .table_section()
+            .map(|section| section.entries())
+            .unwrap_or_default()
+            .iter()
+            .map(|table| table.limits().initial());
+
+        if let Some(max) = self.max_initial_pages {
+            if let Some(pages) = imported_memories.chain(memories).find(|&pages| pages > max) {
+                return Err(ModuleLimitExceeded::InitialPages { pages, max });
+            }
+        }
+        if let Some(max) = self.max_table_elements {
+            if let Some(elements) = imported_tables.chain(tables).find(|&n| n > max) {
+                return Err(ModuleLimitExceeded::TableElements { elements, max });
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn module(pages: u32, elements: u32) -> Vec<u8> {
+        let module = parity_wasm::builder::module()
+            .memory()
+            .with_min(pages)
+            .build()
+            .table()
+            .with_min(elements)
+            .build()
+            .build();
+        parity_wasm::serialize(module).unwrap()
+    }
+
+    #[test]
+    fn oversized_modules_are_rejected() {
+        let code = module(17, 100);
+        assert_eq!(ModuleLimits::default().check(&code), Ok(()));
+        assert_eq!(ModuleLimits::default().check(b"not a module"), Ok(()));
+
+        let limits = ModuleLimits {
+            max_module_bytes: Some(code.len() - 1),
+            ..Default::default()
+        };
+        assert!(matches!(
+            limits.check(&code),
+            Err(ModuleLimitExceeded::ModuleBytes { .. })
+        ));
+
+        let limits = ModuleLimits {
+            max_initial_pages: Some(16),
+            ..Default::default()
+        };
+        assert_eq!(
+            limits.check(&code),
+            Err(ModuleLimitExceeded::InitialPages { pages: 17, max: 16 })
+        );
+        assert_eq!(
+            limits.check(b"not a module"),
+            Err(ModuleLimitExceeded::Malformed)
+        );
+
+        let limits = ModuleLimits {
+            max_initial_pages: Some(17),
+            max_table_elements: Some(99),
+            ..Default::default()
+        };
+        assert_eq!(
+            limits.check(&code),
+            Err(ModuleLimitExceeded::TableElements {
+                elements: 100,
+                max: 99
+            })
+        );
+    }
+}
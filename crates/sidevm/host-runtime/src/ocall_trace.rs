@@ -0,0 +1,109 @@
+//! Recording of the ocalls made by the guests, to see what a program is doing with the host.
+//!
+//! Only compiled with the `ocall-trace` feature, so the production builds don't pay for it. Each
+//! instance gets a ring buffer of its latest ocalls, kept after the instance exits until cleared.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use sidevm_env::{ocall_id2name, IntPtr, OcallError};
+
+use crate::VmId;
+
+/// An ocall made by a guest.
+#[derive(Debug, Clone, Serialize)]
+pub struct OcallRecord {
+    pub name: &'static str,
+    /// The raw arguments. A slice is passed as its address followed by its length.
+    pub args: [IntPtr; 4],
+    pub duration_us: u64,
+    /// The returned value, or the name of the error.
+    pub result: Result<i32, String>,
+}
+
+static CAPACITY: AtomicUsize = AtomicUsize::new(256);
+static TRACES: Lazy<Mutex<HashMap<VmId, VecDeque<OcallRecord>>>> = Lazy::new(Default::default);
+
+/// Set how many ocalls are kept for each instance. The oldest ones are dropped beyond it, and
+/// nothing is recorded if 0.
+pub fn set_ocall_trace_capacity(capacity: usize) {
+    CAPACITY.store(capacity, Ordering::Relaxed);
+    for trace in TRACES.lock().unwrap().values_mut() {
+        let excess = trace.len().saturating_sub(capacity);
+        trace.drain(..excess);
+    }
+}
+
+/// The latest ocalls made by the instance, oldest first.
+pub fn ocall_trace(id: &VmId) -> Vec<OcallRecord> {
+    TRACES
+        .lock()
+        .unwrap()
+        .get(id)
+        .map(|trace| trace.iter().cloned().collect())
+        .unwrap_or_default()
+}
+
+/// Forget the ocalls recorded for the instance.
+pub fn clear_ocall_trace(id: &VmId) {
+    TRACES.lock().unwrap().remove(id);
+}
+
+pub(crate) fn record(
+    id: VmId,
+    func_id: i32,
+    args: [IntPtr; 4],
+    duration: Duration,
+    result: &Result<i32, OcallError>,
+) {
+    let capacity = CAPACITY.load(Ordering::Relaxed);
+    if capacity == 0 {
+        return;
+    }
+    let record = OcallRecord {
+        name: ocall_id2name(func_id),
+        args,
+        duration_us: duration.as_micros() as u64,
+        result: result.as_ref().copied().map_err(|err| format!("{err:?}")),
+    };
+    let mut traces = TRACES.lock().unwrap();
+    let trace = traces.entry(id).or_default();
+    if trace.len() >= capacity {
+        trace.pop_front();
+    }
+    trace.push_back(record);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trace_wraps_around() {
+        let id = [0x36; 32];
+        set_ocall_trace_capacity(3);
+        for i in 0..5 {
+            let result = if i == 4 {
+                Err(OcallError::IoError)
+            } else {
+                Ok(i)
+            };
+            record(id, 0, [i, 0, 0, 0], Duration::from_micros(10), &result);
+        }
+        let trace = ocall_trace(&id);
+        let args: Vec<_> = trace.iter().map(|record| record.args[0]).collect();
+        assert_eq!(args, [2, 3, 4]);
+        assert_eq!(trace[2].result, Err("IoError".into()));
+
+        clear_ocall_trace(&id);
+        assert!(ocall_trace(&id).is_empty());
+    }
+}
@@ -0,0 +1,130 @@
+//! Limits of the outbound connections of the guests.
+//!
+//! Each connection holds a slot from the time it starts connecting until it is closed. A
+//! connection started while all the slots are taken waits in a queue for one to free up, and one
+//! started while the queue is full fails right away, so a program can't exhaust the sockets of
+//! the host.
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use serde::{Deserialize, Serialize};
+use sidevm_env::{OcallError, Result};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Limits of the outbound connections each instance can have.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OutboundLimits {
+    /// Max number of outbound connections, being established or open, at a time.
+    pub max_in_flight: usize,
+    /// Max number of connections waiting for a slot. New connections fail beyond it.
+    pub max_queued: usize,
+}
+
+impl Default for OutboundLimits {
+    fn default() -> Self {
+        Self {
+            max_in_flight: 256,
+            max_queued: 256,
+        }
+    }
+}
+
+/// The slot held by an outbound connection.
+pub(crate) type OutboundPermit = OwnedSemaphorePermit;
+
+struct Queued(Arc<AtomicUsize>);
+
+impl Drop for Queued {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+pub(crate) struct OutboundGate {
+    limits: OutboundLimits,
+    slots: Arc<Semaphore>,
+    queued: Arc<AtomicUsize>,
+    /// The slots of the established connections, by resource id.
+    held: HashMap<i32, OutboundPermit>,
+}
+
+impl Default for OutboundGate {
+    fn default() -> Self {
+        Self::new(Default::default())
+    }
+}
+
+impl OutboundGate {
+    fn new(limits: OutboundLimits) -> Self {
+        let max_in_flight = limits.max_in_flight.min(Semaphore::MAX_PERMITS);
+        Self {
+            limits: OutboundLimits {
+                max_in_flight,
+                ..limits
+            },
+            slots: Arc::new(Semaphore::new(max_in_flight)),
+            queued: Default::default(),
+            held: Default::default(),
+        }
+    }
+
+    /// Replace the limits. Only meant to be called before any connection is made.
+    pub(crate) fn set_limits(&mut self, limits: OutboundLimits) {
+        *self = Self::new(limits);
+    }
+
+    /// Take a slot for a new connection, resolving once one is free.
+    ///
+    /// Fails with `OcallError::ResourceLimited` if too many connections are already waiting.
+    pub(crate) fn acquire(&self) -> Result<impl Future<Output = OutboundPermit> + Send + 'static> {
+        let slots = self.slots.clone();
+        let permit = slots.clone().try_acquire_owned().ok();
+        let queued = match permit {
+            Some(_) => None,
+            None => {
+                if self.queued.fetch_add(1, Ordering::Relaxed) >= self.limits.max_queued {
+                    self.queued.fetch_sub(1, Ordering::Relaxed);
+                    return Err(OcallError::ResourceLimited);
+                }
+                Some(Queued(self.queued.clone()))
+            }
+        };
+        Ok(async move {
+            if let Some(permit) = permit {
+                return permit;
+            }
+            let _queued = queued;
+            slots
+                .acquire_owned()
+                .await
+                .expect("the semaphore is never closed")
+        })
+    }
+
+    /// Keep the slot of the connection until the resource is released.
+    pub(crate) fn hold(&mut self, resource_id: i32, permit: OutboundPermit) {
+        self.held.insert(resource_id, permit);
+    }
+
+    pub(crate) fn release(&mut self, resource_id: i32) {
+        self.held.remove(&resource_id);
+    }
+
+    /// Number of connections being established or open.
+    pub(crate) fn in_flight(&self) -> usize {
+        self.limits.max_in_flight - self.slots.available_permits()
+    }
+
+    /// Number of connections waiting for a slot.
+    pub(crate) fn queued(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+}
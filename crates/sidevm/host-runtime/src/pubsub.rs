@@ -0,0 +1,183 @@
+//! The broker of the pub/sub topics shared by the instances running in the same process.
+//!
+//! Topics are named `<namespace>/<name>`. An instance can only publish or subscribe to the topics
+//! under the namespaces granted by the host.
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use scale::Encode;
+use sidevm_env::{messages::TopicMessage, OcallError, Result};
+use tokio::sync::mpsc::{channel, error::TrySendError, Receiver, Sender};
+
+/// Number of messages buffered for each subscriber before the new ones get dropped.
+const SUBSCRIBER_BUFFER: usize = 32;
+/// Max number of subscribers of a single topic.
+const MAX_SUBSCRIBERS: usize = 64;
+/// Max number of topics having subscribers.
+const MAX_TOPICS: usize = 1024;
+/// Max length of a topic name.
+const MAX_TOPIC_LEN: usize = 256;
+/// Max size of a published message.
+pub(crate) const MAX_MESSAGE_SIZE: usize = 64 * 1024;
+
+struct Subscriber {
+    tx: Sender<Vec<u8>>,
+    /// Number of messages missed since the last delivered one.
+    dropped: u32,
+}
+
+impl Subscriber {
+    /// Deliver an encoded message without blocking. Returns false if the subscriber is gone.
+    fn deliver(&mut self, encoded: &[u8]) -> bool {
+        if self.dropped > 0 {
+            // Report the loss before anything newer, so the subscriber knows where the gap is.
+            let marker = TopicMessage::Dropped(self.dropped).encode();
+            match self.tx.try_send(marker) {
+                Ok(()) => self.dropped = 0,
+                Err(TrySendError::Full(_)) => {
+                    self.dropped = self.dropped.saturating_add(1);
+                    return true;
+                }
+                Err(TrySendError::Closed(_)) => return false,
+            }
+        }
+        match self.tx.try_send(encoded.to_vec()) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                self.dropped = 1;
+                true
+            }
+            Err(TrySendError::Closed(_)) => false,
+        }
+    }
+}
+
+static TOPICS: Mutex<BTreeMap<String, Vec<Subscriber>>> = Mutex::new(BTreeMap::new());
+
+/// Check that the topic is well-formed and under one of the granted namespaces.
+pub(crate) fn check_topic(topic: &str, namespaces: &[String]) -> Result<()> {
+    if topic.len() > MAX_TOPIC_LEN {
+        return Err(OcallError::InvalidParameter);
+    }
+    let Some((namespace, _name)) = topic.split_once('/') else {
+        return Err(OcallError::InvalidParameter);
+    };
+    if !namespaces.iter().any(|granted| granted == namespace) {
+        return Err(OcallError::PermissionDenied);
+    }
+    Ok(())
+}
+
+pub(crate) fn subscribe(topic: &str) -> Result<Receiver<Vec<u8>>> {
+    let mut topics = TOPICS.lock().unwrap();
+    if !topics.contains_key(topic) && topics.len() >= MAX_TOPICS {
+        // Make room by forgetting the topics whose subscribers are all gone.
+        topics.retain(|_, subscribers| subscribers.iter().any(|s| !s.tx.is_closed()));
+        if topics.len() >= MAX_TOPICS {
+            return Err(OcallError::ResourceLimited);
+        }
+    }
+    let subscribers = topics.entry(topic.to_owned()).or_default();
+    subscribers.retain(|s| !s.tx.is_closed());
+    if subscribers.len() >= MAX_SUBSCRIBERS {
+        return Err(OcallError::ResourceLimited);
+    }
+    let (tx, rx) = channel(SUBSCRIBER_BUFFER);
+    subscribers.push(Subscriber { tx, dropped: 0 });
+    Ok(rx)
+}
+
+pub(crate) fn publish(topic: &str, message: &[u8]) -> Result<()> {
+    if message.len() > MAX_MESSAGE_SIZE {
+        return Err(OcallError::InvalidParameter);
+    }
+    let mut topics = TOPICS.lock().unwrap();
+    let Some(subscribers) = topics.get_mut(topic) else {
+        return Ok(());
+    };
+    let encoded = TopicMessage::Message(message.to_vec()).encode();
+    subscribers.retain_mut(|subscriber| subscriber.deliver(&encoded));
+    if subscribers.is_empty() {
+        topics.remove(topic);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use scale::Decode;
+
+    fn recv(rx: &mut Receiver<Vec<u8>>) -> Option<TopicMessage> {
+        let encoded = rx.try_recv().ok()?;
+        Some(TopicMessage::decode(&mut &encoded[..]).unwrap())
+    }
+
+    fn message(data: &[u8]) -> Option<TopicMessage> {
+        Some(TopicMessage::Message(data.to_vec()))
+    }
+
+    #[test]
+    fn topics_are_checked_against_the_granted_namespaces() {
+        let granted = ["oracle".to_string()];
+        assert!(check_topic("oracle/prices", &granted).is_ok());
+        assert!(matches!(
+            check_topic("other/prices", &granted),
+            Err(OcallError::PermissionDenied)
+        ));
+        assert!(matches!(
+            check_topic("oracle", &granted),
+            Err(OcallError::InvalidParameter)
+        ));
+        let long = format!("oracle/{}", "x".repeat(MAX_TOPIC_LEN));
+        assert!(matches!(
+            check_topic(&long, &granted),
+            Err(OcallError::InvalidParameter)
+        ));
+    }
+
+    #[test]
+    fn messages_are_delivered_to_every_subscriber() {
+        let mut a = subscribe("test/fanout").unwrap();
+        let mut b = subscribe("test/fanout").unwrap();
+        let mut other = subscribe("test/fanout-other").unwrap();
+        publish("test/fanout", b"hello").unwrap();
+        assert_eq!(recv(&mut a), message(b"hello"));
+        assert_eq!(recv(&mut b), message(b"hello"));
+        assert_eq!(recv(&mut other), None);
+
+        // Nobody listens to it, which is fine.
+        publish("test/nobody", b"hello").unwrap();
+        let oversized = vec![0; MAX_MESSAGE_SIZE + 1];
+        assert!(matches!(
+            publish("test/fanout", &oversized),
+            Err(OcallError::InvalidParameter)
+        ));
+    }
+
+    #[test]
+    fn slow_subscribers_are_told_how_many_messages_they_missed() {
+        let mut rx = subscribe("test/slow").unwrap();
+        for i in 0..SUBSCRIBER_BUFFER + 3 {
+            publish("test/slow", &i.to_le_bytes()).unwrap();
+        }
+        for i in 0..SUBSCRIBER_BUFFER {
+            assert_eq!(recv(&mut rx), message(&i.to_le_bytes()));
+        }
+        assert_eq!(recv(&mut rx), None);
+
+        publish("test/slow", b"next").unwrap();
+        assert_eq!(recv(&mut rx), Some(TopicMessage::Dropped(3)));
+        assert_eq!(recv(&mut rx), message(b"next"));
+    }
+
+    #[test]
+    fn topics_without_subscribers_are_forgotten() {
+        let rx = subscribe("test/gone").unwrap();
+        assert!(TOPICS.lock().unwrap().contains_key("test/gone"));
+        drop(rx);
+        publish("test/gone", b"hello").unwrap();
+        assert!(!TOPICS.lock().unwrap().contains_key("test/gone"));
+    }
+}
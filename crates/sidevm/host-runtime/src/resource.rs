@@ -1,9 +1,15 @@
-use sidevm_env::{OcallError, Result};
+use phala_wasmer_tunables::MemoryGrowGuard;
+use serde::{Deserialize, Serialize};
+use sidevm_env::{messages::WsMessage, OcallError, Result};
+use std::collections::BTreeMap;
 use std::future::Future;
 use std::io::ErrorKind;
+use std::net::SocketAddr;
 use std::pin::Pin;
-use std::sync::Arc;
-use std::task::Poll::*;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Poll::*, Waker};
+use std::time::{Duration, Instant};
 use tokio::io::{AsyncRead, AsyncWrite, DuplexStream};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::mpsc::Receiver;
@@ -13,26 +19,94 @@ use tokio_rustls::rustls::ServerConfig;
 use Resource::*;
 
 use crate::async_context::{get_task_cx, GuestWaker};
+use crate::clock::Timer;
+use crate::dns::connect_error;
+use crate::outbound::OutboundPermit;
 use crate::tls::TlsStream;
+use crate::websocket::WebSocket;
+use crate::{ShortId, VmId};
 
 pub struct TcpListenerResource {
     pub listener: TcpListener,
     pub tls_config: Option<Arc<ServerConfig>>,
 }
 
+/// An outbound connection along with the slot it holds.
+pub(crate) type Outbound<S> = (S, OutboundPermit);
+
 pub enum Resource {
-    Sleep(Pin<Box<Sleep>>),
+    Sleep(Timer),
     ChannelRx(Receiver<Vec<u8>>),
     OneshotTx(Option<Sender<Vec<u8>>>),
     TcpListener(Box<TcpListenerResource>),
     TcpStream(Box<TcpStream>),
     TlsStream(Box<TlsStream>),
-    TcpConnect(Pin<Box<dyn Future<Output = std::io::Result<TcpStream>> + Send>>),
-    TlsConnect(Pin<Box<dyn Future<Output = std::io::Result<TlsStream>> + Send>>),
+    TcpConnect(Pin<Box<dyn Future<Output = std::io::Result<Outbound<TcpStream>>> + Send>>),
+    TlsConnect(Pin<Box<dyn Future<Output = std::io::Result<Outbound<TlsStream>>> + Send>>),
     DuplexStream(DuplexStream),
+    WebSocket(Box<WebSocket>),
+}
+
+/// A snapshot of an open resource in a VM, used for diagnostics.
+#[derive(Debug, Clone, Serialize)]
+pub struct ResourceInfo {
+    pub id: i32,
+    pub kind: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub local_addr: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub remote_addr: Option<String>,
+}
+
+fn format_addr(addr: std::io::Result<SocketAddr>, redact: bool) -> Option<String> {
+    let addr = addr.ok()?;
+    if redact {
+        Some(format!("*:{}", addr.port()))
+    } else {
+        Some(addr.to_string())
+    }
 }
 
 impl Resource {
+    fn kind(&self) -> &'static str {
+        match self {
+            Sleep(_) => "Sleep",
+            ChannelRx(_) => "ChannelRx",
+            OneshotTx(_) => "OneshotTx",
+            TcpListener(_) => "TcpListener",
+            TcpStream(_) => "TcpStream",
+            TlsStream(_) => "TlsStream",
+            TcpConnect(_) => "TcpConnect",
+            TlsConnect(_) => "TlsConnect",
+            DuplexStream(_) => "DuplexStream",
+            WebSocket(_) => "WebSocket",
+        }
+    }
+
+    pub(crate) fn info(&self, id: i32, redact: bool) -> ResourceInfo {
+        let (local_addr, remote_addr) = match self {
+            TcpListener(res) => (format_addr(res.listener.local_addr(), redact), None),
+            TcpStream(stream) => (
+                format_addr(stream.local_addr(), redact),
+                format_addr(stream.peer_addr(), redact),
+            ),
+            TlsStream(stream) => match stream.tcp_stream() {
+                Some(stream) => (
+                    format_addr(stream.local_addr(), redact),
+                    format_addr(stream.peer_addr(), redact),
+                ),
+                None => (None, None),
+            },
+            _ => (None, None),
+        };
+        ResourceInfo {
+            id,
+            kind: self.kind(),
+            local_addr,
+            remote_addr,
+        }
+    }
+
     pub(crate) fn poll(&mut self, waker_id: i32) -> Result<Vec<u8>> {
         use crate::async_context::poll_in_task_cx;
         let waker = GuestWaker::from_id(waker_id);
@@ -47,11 +121,22 @@ impl Resource {
                     Pending => Err(OcallError::Pending),
                 }
             }
+            // Ready when the receiver side gave up, e.g. the query was cancelled or timed out.
+            OneshotTx(Some(tx)) => {
+                let fut = tx.closed();
+                futures::pin_mut!(fut);
+                match poll_in_task_cx(waker, fut) {
+                    Ready(()) => Ok(vec![]),
+                    Pending => Err(OcallError::Pending),
+                }
+            }
+            OneshotTx(None) => Err(OcallError::EndOfFile),
             _ => Err(OcallError::UnsupportedOperation),
         }
     }
 
-    pub(crate) fn poll_res(&mut self, waker_id: i32) -> Result<Resource> {
+    /// Poll a pending resource, resolving to the resulting one and the outbound slot it holds.
+    pub(crate) fn poll_res(&mut self, waker_id: i32) -> Result<(Resource, OutboundPermit)> {
         use crate::async_context::poll_in_task_cx;
         let waker = GuestWaker::from_id(waker_id);
         match self {
@@ -59,10 +144,12 @@ impl Resource {
                 let rv = poll_in_task_cx(waker, fut.as_mut());
                 match rv {
                     Pending => Err(OcallError::Pending),
-                    Ready(Ok(stream)) => Ok(Resource::TcpStream(Box::new(stream))),
+                    Ready(Ok((stream, permit))) => {
+                        Ok((Resource::TcpStream(Box::new(stream)), permit))
+                    }
                     Ready(Err(err)) => {
                         log::error!("Tcp connect error: {}", err);
-                        Err(OcallError::IoError)
+                        Err(connect_error(&err))
                     }
                 }
             }
@@ -70,10 +157,12 @@ impl Resource {
                 let rv = poll_in_task_cx(waker, fut.as_mut());
                 match rv {
                     Pending => Err(OcallError::Pending),
-                    Ready(Ok(stream)) => Ok(Resource::TlsStream(Box::new(stream))),
+                    Ready(Ok((stream, permit))) => {
+                        Ok((Resource::TlsStream(Box::new(stream)), permit))
+                    }
                     Ready(Err(err)) => {
                         log::error!("Tls connect error: {}", err);
-                        Err(OcallError::IoError)
+                        Err(connect_error(&err))
                     }
                 }
             }
@@ -184,6 +273,38 @@ impl Resource {
             _ => Err(OcallError::UnsupportedOperation),
         }
     }
+
+    pub(crate) fn ws_poll_recv(&mut self, waker_id: i32) -> Result<WsMessage> {
+        let WebSocket(ws) = self else {
+            return Err(OcallError::UnsupportedOperation);
+        };
+        match get_task_cx(GuestWaker::from_id(waker_id), |cx| ws.poll_recv(cx)) {
+            Pending => Err(OcallError::Pending),
+            Ready(result) => result,
+        }
+    }
+
+    pub(crate) fn ws_poll_send(&mut self, waker_id: i32, message: WsMessage) -> Result<()> {
+        let WebSocket(ws) = self else {
+            return Err(OcallError::UnsupportedOperation);
+        };
+        match get_task_cx(GuestWaker::from_id(waker_id), |cx| {
+            ws.poll_send(cx, message)
+        }) {
+            Pending => Err(OcallError::Pending),
+            Ready(result) => result,
+        }
+    }
+
+    pub(crate) fn ws_poll_flush(&mut self, waker_id: i32) -> Result<()> {
+        let WebSocket(ws) = self else {
+            return Err(OcallError::UnsupportedOperation);
+        };
+        match get_task_cx(GuestWaker::from_id(waker_id), |cx| ws.poll_flush(cx)) {
+            Pending => Err(OcallError::Pending),
+            Ready(result) => result,
+        }
+    }
 }
 
 #[derive(Default)]
@@ -221,6 +342,36 @@ impl ResourceKeeper {
         Ok(id)
     }
 
+    /// Put a resource at the given id, e.g. to bring it back from a snapshot.
+    pub fn put(&mut self, id: i32, resource: Resource) -> Result<()> {
+        let index = usize::try_from(id).or(Err(OcallError::InvalidParameter))?;
+        if index >= RESOURCE_ID_MAX {
+            return Err(OcallError::ResourceLimited);
+        }
+        if self.resources.len() <= index {
+            self.resources.resize_with(index + 1, || None);
+        }
+        if self.resources[index].is_some() {
+            return Err(OcallError::AlreadyExists);
+        }
+        self.resources[index] = Some(resource);
+        Ok(())
+    }
+
+    /// Number of the open resources.
+    pub fn count(&self) -> usize {
+        self.resources.iter().filter(|res| res.is_some()).count()
+    }
+
+    /// Describe all the open resources.
+    pub fn dump(&self, redact: bool) -> Vec<ResourceInfo> {
+        self.resources
+            .iter()
+            .enumerate()
+            .filter_map(|(id, res)| Some(res.as_ref()?.info(id as i32, redact)))
+            .collect()
+    }
+
     pub fn take(&mut self, resource_id: i32) -> Option<Resource> {
         let resource_id = resource_id as u32 as usize;
         if resource_id >= self.resources.len() {
@@ -229,3 +380,348 @@ impl ResourceKeeper {
         self.resources[resource_id].take()
     }
 }
+
+/// Delay for each page the memory grows past the soft limit.
+const SOFT_LIMIT_DELAY_PER_PAGE: Duration = Duration::from_millis(1);
+/// Max delay between two polls, however much the memory grows.
+const SOFT_LIMIT_MAX_DELAY: Duration = Duration::from_millis(100);
+
+/// The soft tier of the memory limit of an instance.
+///
+/// The hard limit is enforced by the tunables, failing `memory.grow` past the max pages, which
+/// usually aborts the guest. Past the soft limit the growth is still granted, but the next poll of
+/// the instance is delayed in proportion to the growth, so that bursty programs slow down instead
+/// of being killed. The delay is a timer awaited by the runtime rather than a blocking sleep, so
+/// the executor keeps running the other tasks while the instance waits.
+pub(crate) struct MemoryThrottle {
+    soft_pages: u32,
+    last_pages: u32,
+    delay: Option<Pin<Box<Sleep>>>,
+}
+
+impl MemoryThrottle {
+    pub(crate) fn new(soft_pages: u32) -> Self {
+        Self {
+            soft_pages,
+            last_pages: 0,
+            delay: None,
+        }
+    }
+
+    /// Record the memory size after a poll, arming the delay if it grew past the soft limit.
+    pub(crate) fn record(&mut self, pages: u32) {
+        let grown = pages.saturating_sub(self.last_pages.max(self.soft_pages));
+        self.last_pages = pages;
+        if grown > 0 {
+            let delay = SOFT_LIMIT_DELAY_PER_PAGE
+                .saturating_mul(grown)
+                .min(SOFT_LIMIT_MAX_DELAY);
+            self.delay = Some(Box::pin(tokio::time::sleep(delay)));
+        }
+    }
+
+    /// Wait for the delay armed by the last growth if any.
+    pub(crate) fn poll_wait(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        if let Some(delay) = &mut self.delay {
+            futures::ready!(delay.as_mut().poll(cx));
+            self.delay = None;
+        }
+        Ready(())
+    }
+}
+
+/// Ceiling of the linear memories of all the instances sharing a [`MemoryPool`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MemoryCeiling {
+    /// Max number of pages of all the memories together. Unlimited if None.
+    pub max_total_pages: Option<u64>,
+    /// When a growth is refused, evict the instance of the lowest priority among the ones not
+    /// polled for this many seconds, to make room for the next ones. No eviction if None.
+    pub evict_idle_after_secs: Option<u64>,
+}
+
+/// Returned by [`WasmRun`](crate::WasmRun) when the instance has been evicted to make room in
+/// its [`MemoryPool`].
+#[derive(Debug, thiserror::Error)]
+#[error("the instance has been evicted to give its memory to the others")]
+pub struct Evicted;
+
+struct PooledVm {
+    id: VmId,
+    priority: u32,
+    pages: u64,
+    last_poll: Instant,
+    /// The waker of the last poll, to get the instance to notice its eviction.
+    waker: Option<Waker>,
+    evicted: bool,
+}
+
+#[derive(Default)]
+struct PoolState {
+    total_pages: u64,
+    next_serial: u64,
+    vms: BTreeMap<u64, PooledVm>,
+}
+
+/// The pages taken by the linear memories of a group of instances, e.g. all the ones started
+/// by a spawner.
+///
+/// The hard limit of each instance is enforced by the tunables, while the pool caps them all
+/// together, so that many small instances can't exhaust the memory of the host. Past the
+/// ceiling, `memory.grow` fails in the guest as it does at the hard limit.
+#[derive(Clone, Default)]
+pub struct MemoryPool {
+    ceiling: MemoryCeiling,
+    state: Arc<Mutex<PoolState>>,
+}
+
+impl MemoryPool {
+    pub fn new(ceiling: MemoryCeiling) -> Self {
+        Self {
+            ceiling,
+            state: Default::default(),
+        }
+    }
+
+    /// Number of pages taken by the memories of all the instances.
+    pub fn total_pages(&self) -> u64 {
+        self.state.lock().unwrap().total_pages
+    }
+
+    /// Add an instance to the pool. The less is its priority, the sooner it is evicted.
+    pub(crate) fn join(&self, id: VmId, priority: u32) -> Arc<PoolMember> {
+        let mut state = self.state.lock().unwrap();
+        let serial = state.next_serial;
+        state.next_serial += 1;
+        state.vms.insert(
+            serial,
+            PooledVm {
+                id,
+                priority,
+                pages: 0,
+                last_poll: Instant::now(),
+                waker: None,
+                evicted: false,
+            },
+        );
+        Arc::new(PoolMember {
+            pool: self.clone(),
+            serial,
+            refused: AtomicBool::new(false),
+        })
+    }
+
+    /// Evict the idle instance of the lowest priority, the biggest one first among equals.
+    fn evict_one(&self, state: &mut PoolState, requester: u64) {
+        let Some(idle_secs) = self.ceiling.evict_idle_after_secs else {
+            return;
+        };
+        let idle = Duration::from_secs(idle_secs);
+        let Some(vm) = state
+            .vms
+            .iter_mut()
+            .filter(|(serial, vm)| {
+                **serial != requester && !vm.evicted && vm.last_poll.elapsed() >= idle
+            })
+            .map(|(_, vm)| vm)
+            .min_by_key(|vm| (vm.priority, std::cmp::Reverse(vm.pages)))
+        else {
+            return;
+        };
+        tracing::warn!(
+            target: "sidevm",
+            id = %ShortId(vm.id),
+            pages = vm.pages,
+            "Evicting an idle instance to make room in the memory pool"
+        );
+        vm.evicted = true;
+        if let Some(waker) = vm.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// The share of an instance in a [`MemoryPool`], leaving the pool when dropped.
+pub(crate) struct PoolMember {
+    pool: MemoryPool,
+    serial: u64,
+    /// Whether a growth of the instance has been refused by the pool.
+    refused: AtomicBool,
+}
+
+impl PoolMember {
+    /// Record a poll of the instance, returning whether it has been evicted.
+    pub(crate) fn record_poll(&self, waker: &Waker) -> bool {
+        let mut state = self.pool.state.lock().unwrap();
+        let Some(vm) = state.vms.get_mut(&self.serial) else {
+            return false;
+        };
+        vm.last_poll = Instant::now();
+        vm.waker = Some(waker.clone());
+        vm.evicted
+    }
+
+    /// Whether the instance has been refused some memory because of the ceiling of the pool.
+    pub(crate) fn is_refused(&self) -> bool {
+        self.refused.load(Ordering::Relaxed)
+    }
+}
+
+impl MemoryGrowGuard for PoolMember {
+    fn reserve(&self, _current: wasmer::Pages, delta: wasmer::Pages) -> bool {
+        let delta = u64::from(delta.0);
+        let mut state = self.pool.state.lock().unwrap();
+        let total = state.total_pages.saturating_add(delta);
+        if matches!(self.pool.ceiling.max_total_pages, Some(max) if total > max) {
+            self.refused.store(true, Ordering::Relaxed);
+            self.pool.evict_one(&mut state, self.serial);
+            return false;
+        }
+        state.total_pages = total;
+        if let Some(vm) = state.vms.get_mut(&self.serial) {
+            vm.pages = vm.pages.saturating_add(delta);
+        }
+        true
+    }
+
+    fn release(&self, pages: wasmer::Pages) {
+        let pages = u64::from(pages.0);
+        let mut state = self.pool.state.lock().unwrap();
+        state.total_pages = state.total_pages.saturating_sub(pages);
+        if let Some(vm) = state.vms.get_mut(&self.serial) {
+            vm.pages = vm.pages.saturating_sub(pages);
+        }
+    }
+}
+
+impl Drop for PoolMember {
+    fn drop(&mut self) {
+        let mut state = self.pool.state.lock().unwrap();
+        if let Some(vm) = state.vms.remove(&self.serial) {
+            // Normally zero, as the memories give their pages back before.
+            state.total_pages = state.total_pages.saturating_sub(vm.pages);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wasmer::Pages;
+
+    fn idle_evicting_pool(max_total_pages: u64) -> MemoryPool {
+        MemoryPool::new(MemoryCeiling {
+            max_total_pages: Some(max_total_pages),
+            evict_idle_after_secs: Some(0),
+        })
+    }
+
+    #[test]
+    fn growth_past_the_ceiling_is_refused() {
+        let pool = MemoryPool::new(MemoryCeiling {
+            max_total_pages: Some(10),
+            evict_idle_after_secs: None,
+        });
+        let a = pool.join([1; 32], 0);
+        let b = pool.join([2; 32], 0);
+        assert!(a.reserve(Pages(0), Pages(6)));
+        assert!(b.reserve(Pages(0), Pages(4)));
+        assert_eq!(pool.total_pages(), 10);
+        assert!(!b.reserve(Pages(4), Pages(1)));
+        assert!(b.is_refused());
+        assert!(!a.is_refused());
+
+        a.release(Pages(6));
+        assert!(b.reserve(Pages(4), Pages(1)));
+        drop(b);
+        assert_eq!(pool.total_pages(), 0);
+    }
+
+    #[test]
+    fn lowest_priority_idle_instance_is_evicted() {
+        let pool = idle_evicting_pool(10);
+        let waker = futures::task::noop_waker();
+        let low = pool.join([1; 32], 1);
+        let high = pool.join([2; 32], 5);
+        let requester = pool.join([3; 32], 0);
+        assert!(low.reserve(Pages(0), Pages(3)));
+        assert!(high.reserve(Pages(0), Pages(3)));
+        assert!(requester.reserve(Pages(0), Pages(4)));
+
+        // The requester isn't evicted for itself, even though its priority is the lowest.
+        assert!(!requester.reserve(Pages(4), Pages(1)));
+        assert!(!requester.record_poll(&waker));
+        assert!(low.record_poll(&waker));
+        assert!(!high.record_poll(&waker));
+
+        // The evicted instance gives its pages back once dropped.
+        drop(low);
+        assert!(requester.reserve(Pages(4), Pages(1)));
+    }
+
+    #[test]
+    fn busy_instances_are_not_evicted() {
+        let pool = MemoryPool::new(MemoryCeiling {
+            max_total_pages: Some(1),
+            evict_idle_after_secs: Some(3600),
+        });
+        let waker = futures::task::noop_waker();
+        let busy = pool.join([1; 32], 0);
+        let requester = pool.join([2; 32], 1);
+        assert!(busy.reserve(Pages(0), Pages(1)));
+        assert!(!requester.reserve(Pages(0), Pages(1)));
+        assert!(!busy.record_poll(&waker));
+    }
+
+    fn poll_in_guest(res: &mut Resource) -> Result<Vec<u8>> {
+        use crate::async_context::{set_task_cx, set_task_env};
+        use crate::env::TaskSet;
+
+        let waker = futures::task::noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        set_task_env(Arc::new(TaskSet::with_task0()), 0, || {
+            set_task_cx(&mut cx, || res.poll(0))
+        })
+    }
+
+    #[test]
+    fn oneshot_tx_is_ready_once_the_receiver_is_dropped() {
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        let mut res = OneshotTx(Some(tx));
+        assert!(matches!(poll_in_guest(&mut res), Err(OcallError::Pending)));
+        drop(rx);
+        assert!(matches!(poll_in_guest(&mut res), Ok(data) if data.is_empty()));
+        assert!(matches!(
+            poll_in_guest(&mut OneshotTx(None)),
+            Err(OcallError::EndOfFile)
+        ));
+    }
+
+    #[tokio::test]
+    async fn dump_lists_the_open_resources() {
+        let mut keeper = ResourceKeeper::default();
+        let (_tx, rx) = tokio::sync::mpsc::channel(1);
+        let rx_id = keeper.push(ChannelRx(rx)).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let listener = TcpListenerResource {
+            listener,
+            tls_config: None,
+        };
+        let listener_id = keeper.push(TcpListener(Box::new(listener))).unwrap();
+
+        let dump = keeper.dump(false);
+        assert_eq!(dump.len(), 2);
+        assert_eq!((dump[0].id, dump[0].kind), (rx_id, "ChannelRx"));
+        assert_eq!(dump[0].local_addr, None);
+        assert_eq!((dump[1].id, dump[1].kind), (listener_id, "TcpListener"));
+        assert_eq!(dump[1].local_addr, Some(format!("127.0.0.1:{port}")));
+
+        let _ = keeper.take(rx_id);
+        let dump = keeper.dump(true);
+        assert_eq!(dump.len(), 1);
+        assert_eq!(dump[0].local_addr, Some(format!("*:{port}")));
+        assert_eq!(dump[0].remote_addr, None);
+    }
+}
@@ -1,16 +1,21 @@
-use std::pin::Pin;
+use std::{
+    future::{poll_fn, Future},
+    io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
 
 use anyhow::{anyhow, Result};
 use rocket::{
-    data::{ByteUnit, IoHandler, IoStream},
-    http::Status,
+    data::{ByteUnit, DataStream, IoHandler, IoStream},
+    http::{uncased::Uncased, Status},
     request::{FromRequest, Outcome},
     response::Responder,
     Data, Request,
 };
 use sidevm_env::messages::{HttpHead, HttpResponseHead};
 use tokio::{
-    io::{split, AsyncWriteExt, DuplexStream},
+    io::{split, AsyncRead, AsyncWrite, DuplexStream, ReadBuf, ReadHalf, WriteHalf},
     sync::mpsc::Sender as ChannelSender,
     sync::oneshot::channel as oneshot_channel,
 };
@@ -25,21 +30,127 @@ pub struct RequestInfo {
     headers: Vec<(String, String)>,
 }
 
-pub struct StreamResponse {
+/// Capacity of the pipe between the HTTP connection and the program, in each direction.
+///
+/// The pipe is all that is buffered by the host, so a program reading the request body slowly
+/// throttles the reads from the network, and the response body is read from the program only as
+/// fast as the client takes it.
+const PIPE_CAPACITY: usize = 1024 * 16;
+
+/// The response of a program to an HTTP request, with the body streamed from the program.
+pub struct StreamResponse<'r> {
     head: HttpResponseHead,
-    io_stream: DuplexStream,
+    body: ResponseBody<'r>,
 }
 
-impl StreamResponse {
+impl StreamResponse<'static> {
     pub fn new(head: HttpResponseHead, io_stream: DuplexStream) -> Self {
-        Self { head, io_stream }
+        let (reader, writer) = split(io_stream);
+        Self {
+            head,
+            body: ResponseBody {
+                reader,
+                writer,
+                upload: None,
+            },
+        }
+    }
+}
+
+/// Forwards the request body to the program, through a buffer of a fixed size.
+struct Upload<'r> {
+    body: DataStream<'r>,
+    buf: Box<[u8]>,
+    start: usize,
+    end: usize,
+    eof: bool,
+}
+
+impl<'r> Upload<'r> {
+    fn new(body: DataStream<'r>) -> Self {
+        Self {
+            body,
+            buf: vec![0; PIPE_CAPACITY].into_boxed_slice(),
+            start: 0,
+            end: 0,
+            eof: false,
+        }
+    }
+
+    /// Forward the body until it's finished, shutting down the writer then.
+    fn poll_forward(
+        &mut self,
+        cx: &mut Context<'_>,
+        writer: &mut WriteHalf<DuplexStream>,
+    ) -> Poll<io::Result<()>> {
+        loop {
+            if self.start < self.end {
+                let buf = &self.buf[self.start..self.end];
+                let written = ready!(Pin::new(&mut *writer).poll_write(cx, buf))?;
+                if written == 0 {
+                    return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+                }
+                self.start += written;
+                continue;
+            }
+            if self.eof {
+                return Pin::new(writer).poll_shutdown(cx);
+            }
+            let mut buf = ReadBuf::new(&mut self.buf);
+            ready!(Pin::new(&mut self.body).poll_read(cx, &mut buf))?;
+            self.start = 0;
+            self.end = buf.filled().len();
+            self.eof = self.end == 0;
+        }
+    }
+
+    /// Forward the body as far as possible without blocking, dropping the upload once finished.
+    fn progress(
+        upload: &mut Option<Self>,
+        cx: &mut Context<'_>,
+        writer: &mut WriteHalf<DuplexStream>,
+    ) {
+        let Some(this) = upload else {
+            return;
+        };
+        if let Poll::Ready(result) = this.poll_forward(cx, writer) {
+            if let Err(err) = result {
+                error!(target: "sidevm", "Failed to pipe the body: {err:?}");
+            }
+            *upload = None;
+        }
     }
 }
 
+/// The response body read from the program, which also keeps forwarding the request body to it,
+/// so that the program can stream the response while still reading the request.
+struct ResponseBody<'r> {
+    reader: ReadHalf<DuplexStream>,
+    writer: WriteHalf<DuplexStream>,
+    upload: Option<Upload<'r>>,
+}
+
+impl AsyncRead for ResponseBody<'_> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        Upload::progress(&mut this.upload, cx, &mut this.writer);
+        Pin::new(&mut this.reader).poll_read(cx, buf)
+    }
+}
+
+/// The raw stream of a connection upgraded to another protocol.
+struct Upgraded {
+    io_stream: DuplexStream,
+}
+
 #[rocket::async_trait]
-impl IoHandler for StreamResponse {
+impl IoHandler for Upgraded {
     async fn io(self: Pin<Box<Self>>, io: IoStream) -> std::io::Result<()> {
-        let Self { io_stream, .. } = *Pin::into_inner(self);
+        let Self { io_stream } = *Pin::into_inner(self);
         let (mut server_reader, mut server_writer) = split(io_stream);
         let (mut client_reader, mut client_writer) = split(io);
         let (res_c2s, res_s2c) = tokio::join! {
@@ -58,8 +169,8 @@ impl IoHandler for StreamResponse {
     }
 }
 
-impl<'r> Responder<'r, 'r> for StreamResponse {
-    fn respond_to(mut self, _req: &'r Request<'_>) -> rocket::response::Result<'r> {
+impl<'r, 'o: 'r> Responder<'r, 'o> for StreamResponse<'o> {
+    fn respond_to(mut self, _req: &'r Request<'_>) -> rocket::response::Result<'o> {
         let mut builder = rocket::response::Response::build();
         self.head
             .headers
@@ -78,14 +189,16 @@ impl<'r> Responder<'r, 'r> for StreamResponse {
                     builder.raw_header_adjoin(name, value);
                 }
             }
-            builder.upgrade(protocol, self);
+            let ResponseBody { reader, writer, .. } = self.body;
+            let io_stream = reader.unsplit(writer);
+            builder.upgrade(Uncased::from(protocol), Upgraded { io_stream });
             builder.streamed_body(&[] as &[u8]);
         } else {
             builder.status(Status::new(self.head.status));
             for (name, value) in self.head.headers.into_iter() {
                 builder.raw_header_adjoin(name, value);
             }
-            builder.streamed_body(self.io_stream);
+            builder.streamed_body(self.body);
         }
         Ok(builder.finalize())
     }
@@ -146,7 +259,12 @@ fn is_upgrade_request(req: &RequestInfo) -> bool {
         .iter()
         .find_map(|(name, value)| {
             if name.to_lowercase() == "connection" {
-                Some(value.to_lowercase() == "upgrade")
+                // Browsers send `Connection: keep-alive, Upgrade` for WebSocket handshakes.
+                Some(
+                    value
+                        .split(',')
+                        .any(|token| token.trim().eq_ignore_ascii_case("upgrade")),
+                )
             } else {
                 None
             }
@@ -154,15 +272,19 @@ fn is_upgrade_request(req: &RequestInfo) -> bool {
         .unwrap_or(false)
 }
 
-pub async fn connect(
+/// Pass an HTTP request to the program.
+///
+/// The request body is forwarded to the program while waiting for the response head and then
+/// while streaming the response body, so the program can answer before reading the whole request.
+pub async fn connect<'r>(
     head: RequestInfo,
     path: &str,
-    body: Option<Data<'_>>,
+    body: Option<Data<'r>>,
     command_tx: ChannelSender<Command>,
-) -> Result<StreamResponse> {
+) -> Result<StreamResponse<'r>> {
     let is_upgrade = is_upgrade_request(&head);
-    let (response_tx, response_rx) = oneshot_channel();
-    let (mut stream0, stream1) = tokio::io::duplex(1024);
+    let (response_tx, mut response_rx) = oneshot_channel();
+    let (stream0, stream1) = tokio::io::duplex(PIPE_CAPACITY);
     let command = Command::HttpRequest(IncomingHttpRequest {
         head: head.into_head(path),
         body_stream: stream1,
@@ -172,27 +294,94 @@ pub async fn connect(
         .send(command)
         .await
         .or(Err(anyhow!("Command channel closed")))?;
-    if !is_upgrade {
-        // If it is a vanilla HTTP request, we need to send the body.
-        if let Some(body) = body {
-            let data_stream = body.open(ByteUnit::max_value());
-            let stream0 = &mut stream0;
-            let result: Result<()> = async move {
-                data_stream.stream_to(&mut *stream0).await?;
-                stream0
-                    .shutdown()
-                    .await
-                    .or(Err(anyhow!("Stream shutdown error")))?;
-                Ok(())
-            }
+    let (reader, mut writer) = split(stream0);
+    // If it is a vanilla HTTP request, we need to send the body.
+    let mut upload = match body {
+        Some(body) if !is_upgrade => Some(Upload::new(body.open(ByteUnit::max_value()))),
+        _ => None,
+    };
+    let resposne = poll_fn(|cx| {
+        Upload::progress(&mut upload, cx, &mut writer);
+        Pin::new(&mut response_rx).poll(cx)
+    })
+    .await
+    .map_err(|_| anyhow!("Response channel closed"))??;
+    Ok(StreamResponse {
+        head: resposne,
+        body: ResponseBody {
+            reader,
+            writer,
+            upload,
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::{http::ContentType, local::asynchronous::Client, State};
+    use std::path::PathBuf;
+    use tokio::sync::mpsc::channel;
+
+    #[rocket::post("/<path..>", data = "<body>")]
+    async fn forward<'r>(
+        command_tx: &State<ChannelSender<Command>>,
+        head: RequestInfo,
+        path: PathBuf,
+        body: Data<'r>,
+    ) -> Result<StreamResponse<'r>, String> {
+        let path = path.to_str().unwrap_or_default();
+        let command_tx = command_tx.inner().clone();
+        connect(head, path, Some(body), command_tx)
+            .await
+            .map_err(|err| err.to_string())
+    }
+
+    /// A program answering before reading the request, then echoing the request body.
+    async fn echo(mut command_rx: tokio::sync::mpsc::Receiver<Command>) {
+        let Some(Command::HttpRequest(request)) = command_rx.recv().await else {
+            panic!("expected an http request");
+        };
+        // The local client sends no host.
+        assert_eq!(request.head.url, "http:///echo?x=1");
+        let head = HttpResponseHead {
+            status: 200,
+            headers: vec![],
+        };
+        request.response_tx.send(Ok(head)).unwrap();
+        let (mut reader, mut writer) = split(request.body_stream);
+        tokio::io::copy(&mut reader, &mut writer).await.unwrap();
+        tokio::io::AsyncWriteExt::shutdown(&mut writer)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn request_body_is_streamed_along_with_the_response() {
+        let (command_tx, command_rx) = channel(1);
+        let program = tokio::spawn(echo(command_rx));
+        let rocket = rocket::build()
+            .manage(command_tx)
+            .mount("/", rocket::routes![forward]);
+        let client = Client::tracked(rocket).await.unwrap();
+
+        // Much more than the pipes can buffer, so the program can only echo it if the body is
+        // forwarded while the response is read.
+        let body: Vec<u8> = (0..PIPE_CAPACITY * 4).map(|i| i as u8).collect();
+        let response = client
+            .post("/echo?x=1")
+            .header(ContentType::Binary)
+            .body(&body)
+            .dispatch()
             .await;
-            if let Err(err) = result {
-                error!(target: "sidevm", "Failed to pipe the body: {err:?}");
-            }
-        }
+        assert_eq!(response.status(), Status::Ok);
+        let echoed = response.into_bytes().await.unwrap();
+        assert!(
+            echoed == body,
+            "echoed {} of {} bytes",
+            echoed.len(),
+            body.len()
+        );
+        program.await.unwrap();
     }
-    let resposne = response_rx
-        .await
-        .map_err(|_| anyhow!("Response channel closed"))??;
-    Ok(StreamResponse::new(resposne, stream0))
 }
@@ -1,28 +1,38 @@
 use anyhow::{Context as _, Result};
 use phala_scheduler::TaskScheduler;
+use phala_wasmer_tunables::LimitingTunables;
 use std::future::Future;
 use std::pin::Pin;
-use std::task::{Context, Poll};
+use std::sync::Arc;
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+use tokio::time::Sleep;
 use wasmer::{BaseTunables, Engine, Instance, Module, Pages, RuntimeError, Store, TypedFunction};
 #[cfg(feature = "wasmer-compiler-cranelift")]
 use wasmer_compiler_cranelift::Cranelift;
 #[cfg(feature = "wasmer-compiler-llvm")]
 use wasmer_compiler_llvm::LLVM;
 use wasmer_compiler_singlepass::Singlepass;
-use phala_wasmer_tunables::LimitingTunables;
 
-use crate::env::{DynCacheOps, LogHandler};
-use crate::{async_context, env, metering::metering, VmId};
+use crate::env::{DynCacheOps, LogHandler, LogLimit, LogSink};
+use crate::metering::{
+    metering, thread_cpu_time, CpuBudget, CpuMeter, FuelExhaustedHandler, FuelPolicy, FuelTank,
+};
+use crate::resource::{Evicted, MemoryPool, MemoryThrottle, PoolMember};
+use crate::snapshot::{self, MemorySnapshot};
+use crate::{async_context, env, VmId};
 
 #[derive(Clone)]
 pub struct WasmModule {
-    engine: WasmEngine,
+    engine: Engine,
     module: Module,
+    /// The hash of the code, if the module has been prepared to be snapshotted.
+    snapshot_hash: Option<[u8; 32]>,
 }
 
 #[derive(Clone)]
 pub struct WasmEngine {
-    inner: Engine,
+    count_instructions: bool,
 }
 
 impl Default for WasmEngine {
@@ -33,26 +43,50 @@ impl Default for WasmEngine {
 
 impl WasmEngine {
     pub fn new() -> Self {
+        Self {
+            count_instructions: false,
+        }
+    }
+
+    /// Create an engine whose modules also count the instructions they retire, as needed by the
+    /// instances given a [`WasmInstanceConfig::max_instructions`].
+    pub fn with_instruction_counter() -> Self {
+        Self {
+            count_instructions: true,
+        }
+    }
+
+    /// The metering middlewares keep the indices of the globals they add to the module they
+    /// transform, so each module is compiled by a wasmer engine of its own.
+    fn build(&self) -> Engine {
         let compiler_env = std::env::var("WASMER_COMPILER");
         let compiler_env = compiler_env
             .as_ref()
             .map(AsRef::as_ref)
             .unwrap_or("singlepass");
-        let engine = match compiler_env {
-            "singlepass" => metering(Singlepass::default()).into(),
+        match compiler_env {
+            "singlepass" => metering(Singlepass::default(), self.count_instructions).into(),
             #[cfg(feature = "wasmer-compiler-cranelift")]
-            "cranelift" => metering(Cranelift::default()).into(),
+            "cranelift" => metering(Cranelift::default(), self.count_instructions).into(),
             #[cfg(feature = "wasmer-compiler-llvm")]
             "llvm" => LLVM::default().into(),
             _ => panic!("Unsupported compiler engine: {compiler_env}"),
-        };
-        Self { inner: engine }
+        }
     }
 
     pub fn compile(&self, wasm_code: &[u8]) -> Result<WasmModule> {
+        let engine = self.build();
+        let (module, snapshot_hash) = match snapshot::export_globals(wasm_code) {
+            Some(code) => (
+                Module::new(&engine, code)?,
+                Some(snapshot::module_hash(wasm_code)),
+            ),
+            None => (Module::new(&engine, wasm_code)?, None),
+        };
         Ok(WasmModule {
-            engine: self.clone(),
-            module: Module::new(&self.inner, wasm_code)?,
+            engine,
+            module,
+            snapshot_hash,
         })
     }
 }
@@ -72,6 +106,27 @@ impl WasmModule {
             weight,
             event_tx,
             log_handler,
+            log_sink,
+            pinned_chain_head,
+            fuel_policy,
+            log_limit,
+            pubsub_namespaces,
+            shared_cache,
+            on_fuel_exhausted,
+            identity,
+            soft_memory_pages,
+            tls_client_identity,
+            session_limits,
+            outbound_limits,
+            body_limits,
+            dns,
+            tls_roots,
+            clock,
+            cpu_budget,
+            snapshot,
+            max_instructions,
+            memory_pool,
+            memory_priority,
         } = config;
         let base = BaseTunables {
             // Always use dynamic heap memory to save memory
@@ -79,8 +134,12 @@ impl WasmModule {
             static_memory_offset_guard_size: 0,
             dynamic_memory_offset_guard_size: page_size::get() as _,
         };
-        let tunables = LimitingTunables::new(base, Pages(max_memory_pages));
-        let mut engine = self.engine.inner.clone();
+        let mut tunables = LimitingTunables::new(base, Pages(max_memory_pages));
+        let pool_member = memory_pool.map(|pool| pool.join(id, memory_priority));
+        if let Some(member) = &pool_member {
+            tunables = tunables.with_grow_guard(member.clone());
+        }
+        let mut engine = self.engine.clone();
         engine.set_tunables(tunables);
         let mut store = Store::new(engine);
         let (env, import_object) =
@@ -91,10 +150,35 @@ impl WasmModule {
             .get_memory("memory")
             .context("No memory exported")?;
         let wasm_poll_entry = instance.exports.get_typed_function(&store, "sidevm_poll")?;
+        if let Some(limit) = max_instructions {
+            crate::metering::set_remaining_instructions(&mut store, &instance, limit)
+                .context("the module doesn't count its instructions")?;
+        }
         env.set_memory(memory.clone());
         env.set_instance(instance);
+        env.set_instruction_limit(max_instructions);
         env.set_gas_per_breath(gas_per_breath);
         env.set_weight(weight);
+        env.set_pinned_chain_head(pinned_chain_head);
+        env.set_log_limit(log_limit);
+        env.set_log_sink(log_sink);
+        env.set_helper_costs(fuel_policy.map(|p| p.helper_costs).unwrap_or_default());
+        env.set_pubsub_namespaces(pubsub_namespaces);
+        env.set_shared_cache(shared_cache.as_deref());
+        env.set_identity(identity);
+        env.set_tls_client(tls_client_identity.as_ref(), tls_roots.as_ref());
+        env.set_session_limits(session_limits);
+        env.set_outbound_limits(outbound_limits);
+        env.set_body_limits(body_limits);
+        env.set_dns(dns);
+        env.set_manual_clock(clock);
+        if let Some(snapshot) = &snapshot {
+            let module_hash = self
+                .snapshot_hash
+                .context("the module can't be restored from snapshots")?;
+            snapshot.check(&module_hash)?;
+            env.restore(&mut store, snapshot)?;
+        }
         if let Some(scheduler) = &scheduler {
             scheduler.reset(&id);
         }
@@ -105,6 +189,15 @@ impl WasmModule {
                 store,
                 scheduler,
                 id,
+                fuel: fuel_policy.map(|policy| FuelTank::new(policy, gas_per_breath)),
+                on_fuel_exhausted,
+                memory_throttle: soft_memory_pages.map(MemoryThrottle::new),
+                pool_member,
+                cpu: cpu_budget.map(CpuMeter::new),
+                paused: false,
+                parked: None,
+                shutdown_deadline: None,
+                module_hash: self.snapshot_hash,
             },
             env,
         ))
@@ -120,6 +213,54 @@ pub struct WasmInstanceConfig {
     pub weight: u32,
     pub event_tx: crate::OutgoingRequestChannel,
     pub log_handler: Option<LogHandler>,
+    /// Receives the logs of the instance, including the lines it writes to stdout and stderr,
+    /// rather than the global logger. They go to the global logger if None.
+    pub log_sink: Option<LogSink>,
+    /// The chain head reported to the guest. Falls back to the global one if not pinned.
+    pub pinned_chain_head: Option<crate::ChainHead>,
+    /// Limit the total fuel consumed over time. The fuel is unlimited if None.
+    pub fuel_policy: Option<FuelPolicy>,
+    /// Cap of the logs emitted while serving a single query. Unlimited if None.
+    pub log_limit: Option<LogLimit>,
+    /// Namespaces of the pub/sub topics the instance can publish or subscribe to.
+    pub pubsub_namespaces: Vec<String>,
+    /// Name of the cache namespace shared with the other instances given the same name. The
+    /// cache entries are private to the instance if None.
+    pub shared_cache: Option<String>,
+    /// Notified when the instance runs out of gas.
+    pub on_fuel_exhausted: Option<FuelExhaustedHandler>,
+    /// The identity key the instance can sign with. The identity ocalls are unsupported if None.
+    pub identity: Option<crate::VmIdentity>,
+    /// Past this many memory pages, the instance is slowed down when growing its memory rather
+    /// than aborted as at `max_memory_pages`. No soft limit if None.
+    pub soft_memory_pages: Option<u32>,
+    /// The certificate presented to the TLS servers requiring client authentication.
+    pub tls_client_identity: Option<crate::TlsClientIdentity>,
+    /// Limits of the host-side sessions, such as the incoming HTTP requests, kept open.
+    pub session_limits: crate::SessionLimits,
+    /// Limits of the outbound connections the instance can have.
+    pub outbound_limits: crate::OutboundLimits,
+    /// Caps of the responses and the incoming request bodies the instance can receive.
+    pub body_limits: crate::BodyLimits,
+    /// How the hosts the instance connects to are resolved and vetted.
+    pub dns: crate::Dns,
+    /// The roots trusted by the TLS connections of the instance. The public webpki roots if None.
+    pub tls_roots: Option<crate::TlsRoots>,
+    /// The clock driving the timers and the clocks of the instance. The system clock if None.
+    pub clock: Option<crate::ManualClock>,
+    /// Cap of the CPU time the instance can spend over time. Unlimited if None.
+    pub cpu_budget: Option<CpuBudget>,
+    /// Restore the instance from the snapshot rather than starting the program afresh.
+    pub snapshot: Option<Arc<MemorySnapshot>>,
+    /// Trap once the guest has retired this many instructions in total, however the gas is
+    /// priced. The module must be compiled by [`WasmEngine::with_instruction_counter`]. Unlimited
+    /// if None.
+    pub max_instructions: Option<u64>,
+    /// The pool the memory of the instance is taken from, capping it along with the memories of
+    /// the other instances in the pool. Only capped by `max_memory_pages` if None.
+    pub memory_pool: Option<MemoryPool>,
+    /// The less it is, the sooner the instance is evicted when the memory pool runs short.
+    pub memory_priority: u32,
 }
 
 pub struct WasmRun {
@@ -128,6 +269,73 @@ pub struct WasmRun {
     store: Store,
     wasm_poll_entry: TypedFunction<(), i32>,
     scheduler: Option<TaskScheduler<VmId>>,
+    fuel: Option<FuelTank>,
+    on_fuel_exhausted: Option<FuelExhaustedHandler>,
+    memory_throttle: Option<MemoryThrottle>,
+    pool_member: Option<Arc<PoolMember>>,
+    cpu: Option<CpuMeter>,
+    paused: bool,
+    /// The waker of the last poll while paused, woken up on resume.
+    parked: Option<Waker>,
+    /// When to stop the program forcibly after it has been asked to exit.
+    shutdown_deadline: Option<Pin<Box<Sleep>>>,
+    /// The hash of the code, if the module has been prepared to be snapshotted.
+    module_hash: Option<[u8; 32]>,
+}
+
+/// Returned by [`WasmRun`] when the program is still running past the timeout given to
+/// [`WasmRun::shut_down`].
+#[derive(Debug, thiserror::Error)]
+#[error("the program didn't exit in time after being asked to shut down")]
+pub struct ShutdownTimedOut;
+
+impl WasmRun {
+    /// Stop polling the guest until [`Self::resume`] is called.
+    ///
+    /// The instance keeps its memory, resources and pending ocalls. The events arriving in the
+    /// meantime are kept for the guest to handle once resumed.
+    pub fn pause(&mut self) {
+        self.paused = true;
+    }
+
+    pub fn resume(&mut self) {
+        self.paused = false;
+        if let Some(waker) = self.parked.take() {
+            waker.wake();
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Give the program `timeout` to exit by itself, after which the run resolves to
+    /// [`ShutdownTimedOut`] rather than to the exit code of the program.
+    ///
+    /// A paused instance is resumed, so it can wrap up. Telling the program to exit is up to the
+    /// caller.
+    pub fn shut_down(&mut self, timeout: Duration) {
+        if self.shutdown_deadline.is_none() {
+            self.shutdown_deadline = Some(Box::pin(tokio::time::sleep(timeout)));
+        }
+        self.resume();
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutdown_deadline.is_some()
+    }
+
+    /// Take a snapshot of the guest, to restore it later through
+    /// [`WasmInstanceConfig::snapshot`].
+    ///
+    /// Fails if the module couldn't be prepared for snapshots, or if the guest holds resources
+    /// other than its input channels, such as a connection or a timer.
+    pub fn snapshot(&mut self) -> Result<MemorySnapshot> {
+        let module_hash = self
+            .module_hash
+            .context("the module can't be snapshotted")?;
+        self.env.snapshot(&mut self.store, module_hash)
+    }
 }
 
 impl Drop for WasmRun {
@@ -143,17 +351,55 @@ impl Future for WasmRun {
     type Output = Result<i32, RuntimeError>;
 
     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        let _guard = match &self.scheduler {
+        let run = self.get_mut();
+        if let Some(deadline) = &mut run.shutdown_deadline {
+            if deadline.as_mut().poll(cx).is_ready() {
+                return Poll::Ready(Err(RuntimeError::user(Box::new(ShutdownTimedOut))));
+            }
+        }
+        if let Some(member) = &run.pool_member {
+            if member.record_poll(cx.waker()) {
+                return Poll::Ready(Err(RuntimeError::user(Box::new(Evicted))));
+            }
+        }
+        if run.paused {
+            run.parked = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        if let Some(throttle) = &mut run.memory_throttle {
+            futures::ready!(throttle.poll_wait(cx));
+        }
+        if let Some(cpu) = &mut run.cpu {
+            if let Err(aborted) = futures::ready!(cpu.poll_admit(cx)) {
+                return Poll::Ready(Err(RuntimeError::user(aborted.into())));
+            }
+        }
+        if let Some(fuel) = &mut run.fuel {
+            futures::ready!(fuel.poll_reserve(cx));
+            run.env.set_fuel_left(Some(fuel.level()));
+        }
+        let _guard = match &run.scheduler {
             Some(scheduler) => Some(futures::ready!(scheduler.poll_resume(
                 cx,
-                &self.id,
-                self.env.weight()
+                &run.id,
+                run.env.weight()
             ))),
             None => None,
         };
-        let run = self.get_mut();
         run.env.reset_gas_to_breath(&mut run.store);
-        match async_context::set_task_cx(cx, || run.wasm_poll_entry.call(&mut run.store)) {
+        let cpu_start = run.cpu.is_some().then(thread_cpu_time);
+        let result = async_context::set_task_cx(cx, || run.wasm_poll_entry.call(&mut run.store));
+        if let (Some(cpu), Some(start)) = (&mut run.cpu, cpu_start) {
+            cpu.record(thread_cpu_time().saturating_sub(start));
+        }
+        let used = run.env.record_poll(&mut run.store);
+        if let Some(fuel) = &mut run.fuel {
+            fuel.settle(used);
+        }
+        if let Some(throttle) = &mut run.memory_throttle {
+            throttle.record(run.env.memory_pages());
+        }
+        match result {
             Ok(rv) => {
                 if rv == 0 {
                     if run.env.has_more_ready() {
@@ -165,7 +411,20 @@ impl Future for WasmRun {
                 }
             }
             Err(err) => {
-                if run.env.is_stifled(&mut run.store) {
+                if run.env.instructions_exhausted(&mut run.store) {
+                    Poll::Ready(Err(RuntimeError::user(
+                        crate::env::OcallAborted::InstructionLimitExceeded.into(),
+                    )))
+                } else if run.pool_member.as_ref().is_some_and(|m| m.is_refused()) {
+                    // Most likely aborted for the memory refused, as the allocators do.
+                    Poll::Ready(Err(RuntimeError::user(
+                        crate::env::OcallAborted::MemoryCeilingReached.into(),
+                    )))
+                } else if run.env.is_stifled(&mut run.store) {
+                    // Called here, once the guest has trapped, so the handler can't reenter it.
+                    if let Some(handler) = &run.on_fuel_exhausted {
+                        handler(run.id, run.env.gas_used());
+                    }
                     Poll::Ready(Err(RuntimeError::user(
                         crate::env::OcallAborted::Stifled.into(),
                     )))
@@ -176,3 +435,94 @@ impl Future for WasmRun {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    const ENDLESS_POLL: &str = r#"
+        (module
+          (memory (export "memory") 1)
+          (func (export "sidevm_poll") (result i32)
+            (loop $again (br $again))
+            (i32.const 0)))
+    "#;
+
+    fn config(id: VmId, gas_per_breath: u64) -> WasmInstanceConfig {
+        WasmInstanceConfig {
+            max_memory_pages: 16,
+            id,
+            gas_per_breath,
+            cache_ops: Box::leak(Box::new(crate::LruCache::new(1024))),
+            scheduler: None,
+            weight: 0,
+            event_tx: tokio::sync::mpsc::channel(1).0,
+            log_handler: None,
+            log_sink: None,
+            pinned_chain_head: None,
+            fuel_policy: None,
+            log_limit: None,
+            pubsub_namespaces: vec![],
+            shared_cache: None,
+            on_fuel_exhausted: None,
+            identity: None,
+            soft_memory_pages: None,
+            tls_client_identity: None,
+            session_limits: Default::default(),
+            outbound_limits: Default::default(),
+            body_limits: Default::default(),
+            dns: Default::default(),
+            tls_roots: None,
+            clock: None,
+            cpu_budget: None,
+            snapshot: None,
+            max_instructions: None,
+            memory_pool: None,
+            memory_priority: 0,
+        }
+    }
+
+    #[tokio::test]
+    async fn running_out_of_gas_notifies_the_handler() {
+        let exhausted = Arc::new(Mutex::new(vec![]));
+        let handler: FuelExhaustedHandler = {
+            let exhausted = exhausted.clone();
+            Arc::new(move |id, gas_used| exhausted.lock().unwrap().push((id, gas_used)))
+        };
+        let module = WasmEngine::new().compile(ENDLESS_POLL.as_bytes()).unwrap();
+        let config = WasmInstanceConfig {
+            on_fuel_exhausted: Some(handler),
+            ..config([7; 32], 10_000)
+        };
+        let (run, _env) = module.run(vec![], config).unwrap();
+
+        let err = run.await.unwrap_err();
+        assert!(matches!(
+            err.downcast::<crate::env::OcallAborted>(),
+            Ok(crate::env::OcallAborted::Stifled)
+        ));
+        assert_eq!(*exhausted.lock().unwrap(), [([7; 32], 10_000)]);
+    }
+
+    #[tokio::test]
+    async fn one_engine_compiles_many_counted_modules() {
+        let engine = WasmEngine::with_instruction_counter();
+        let endless = engine.compile(ENDLESS_POLL.as_bytes()).unwrap();
+        let other = engine
+            .compile(ENDLESS_POLL.replace("1)", "2)").as_bytes())
+            .unwrap();
+        for (id, module) in [endless, other].into_iter().enumerate() {
+            let config = WasmInstanceConfig {
+                max_instructions: Some(10_000),
+                ..config([id as u8; 32], 1 << 40)
+            };
+            let (run, _env) = module.run(vec![], config).unwrap();
+            let err = run.await.unwrap_err();
+            assert!(matches!(
+                err.downcast::<crate::env::OcallAborted>(),
+                Ok(crate::env::OcallAborted::InstructionLimitExceeded)
+            ));
+        }
+    }
+}
@@ -1,23 +1,72 @@
-use crate::env::{DynCacheOps, OcallAborted};
+use crate::env::{DynCacheOps, Env, LogLimit, LogSink, OcallAborted, VmDump, VmStats};
+use crate::metering::{CpuBudget, FuelExhaustedHandler, FuelPolicy};
 use crate::run::{WasmEngine, WasmInstanceConfig};
-use crate::{ShortId, VmId};
+use crate::snapshot::MemorySnapshot;
+use crate::{
+    BodyLimits, Dns, ManualClock, MemoryCeiling, MemoryPool, ModuleLimits, OutboundLimits,
+    SessionLimits, ShortId, TlsClientIdentity, TlsRoots, VmId, VmIdentity,
+};
 use anyhow::Result;
 use phala_scheduler::TaskScheduler;
 use serde::{Deserialize, Serialize};
 use sidevm_env::messages::{AccountId, HttpHead, HttpResponseHead};
+use std::collections::{BTreeMap, VecDeque};
 use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use tokio::io::DuplexStream;
 use tokio::{
     sync::mpsc::{channel, Receiver, Sender},
     sync::oneshot::Sender as OneshotSender,
     sync::watch::Receiver as WatchReceiver,
     task::JoinHandle,
+    time::{sleep, Sleep},
 };
 use tracing::{debug, error, info, trace, warn, Instrument};
 
 pub use sidevm_env::messages::{Metric, SystemMessage};
 pub type CommandSender = Sender<Command>;
 
+/// The running instances, along with a serial telling a restarted instance from its predecessor.
+static LIVE_VMS: Mutex<BTreeMap<VmId, (u64, Env)>> = Mutex::new(BTreeMap::new());
+
+/// Keeps the instance listed in `LIVE_VMS` until dropped.
+struct LiveVm {
+    id: VmId,
+    serial: u64,
+}
+
+impl LiveVm {
+    fn register(id: VmId, env: Env) -> Self {
+        static NEXT_SERIAL: AtomicU64 = AtomicU64::new(0);
+        let serial = NEXT_SERIAL.fetch_add(1, Ordering::Relaxed);
+        LIVE_VMS.lock().unwrap().insert(id, (serial, env));
+        Self { id, serial }
+    }
+}
+
+impl Drop for LiveVm {
+    fn drop(&mut self) {
+        let mut vms = LIVE_VMS.lock().unwrap();
+        if matches!(vms.get(&self.id), Some((serial, _)) if *serial == self.serial) {
+            vms.remove(&self.id);
+        }
+    }
+}
+
+/// The ids of all the running instances.
+pub fn list_vms() -> Vec<VmId> {
+    LIVE_VMS.lock().unwrap().keys().copied().collect()
+}
+
+/// The runtime statistics of a running instance, or None if there isn't one with the id.
+pub fn vm_stats(id: &VmId) -> Option<VmStats> {
+    let env = LIVE_VMS.lock().unwrap().get(id)?.1.clone();
+    Some(env.stats())
+}
+
 #[derive(Debug)]
 pub enum Report {
     VmTerminated { id: VmId, reason: ExitReason },
@@ -45,6 +94,12 @@ pub enum ExitReason {
     CodeTooLarge,
     /// Failed to create the sidevm instance.
     FailedToStart,
+    /// The program didn't signal ready before the warmup timeout.
+    WarmupTimeout,
+    /// Stopped forcibly as the program didn't exit in time after a Shutdown command.
+    ShutdownTimedOut,
+    /// Stopped while idle to give its memory to the other instances.
+    Evicted,
 }
 
 pub enum Command {
@@ -64,6 +119,120 @@ pub enum Command {
     UpdateWeight(u32),
     // An incoming HTTP request
     HttpRequest(IncomingHttpRequest),
+    // Dump the open resources and runtime stats of the instance.
+    Dump {
+        redact: bool,
+        reply_tx: OneshotSender<VmDump>,
+    },
+    // Suspend the instance, keeping its state.
+    Pause,
+    // Resume a paused instance.
+    Resume,
+    // Ask the program to exit, and stop it forcibly if it is still running after the timeout.
+    Shutdown {
+        timeout: Duration,
+    },
+    // Take a snapshot of the instance, to restore it later with `Spawner::with_snapshot`.
+    Snapshot {
+        reply_tx: OneshotSender<Result<MemorySnapshot>>,
+    },
+}
+
+/// Returned to the incoming HTTP requests of a paused instance.
+#[derive(Debug, thiserror::Error)]
+#[error("the instance is paused")]
+pub struct VmPaused;
+
+impl Command {
+    /// Whether the command is a request to be served by the guest program.
+    fn is_request(&self) -> bool {
+        matches!(
+            self,
+            Command::PushMessage(_)
+                | Command::PushSystemMessage(_)
+                | Command::PushQuery { .. }
+                | Command::HttpRequest(_)
+        )
+    }
+
+    /// Whether the command carries a message from the chain, which must never be dropped.
+    fn is_chain_message(&self) -> bool {
+        matches!(
+            self,
+            Command::PushMessage(_) | Command::PushSystemMessage(_)
+        )
+    }
+}
+
+/// Configuration of the warmup phase of an instance.
+///
+/// During warmup, incoming requests are queued by the host until the guest calls
+/// `signal_ready`. If the guest doesn't become ready within `timeout`, the instance is
+/// terminated with `ExitReason::WarmupTimeout`. Messages from the chain are always held, as
+/// dropping one would leave the guest out of sync with the chain.
+#[derive(Debug, Clone, Copy)]
+pub struct WarmupConfig {
+    pub timeout: Duration,
+    /// Max number of queries and HTTP requests held during warmup. Those beyond it are
+    /// rejected.
+    pub max_pending: usize,
+}
+
+struct Warmup {
+    deadline: Pin<Box<Sleep>>,
+    ready_rx: WatchReceiver<bool>,
+    pending: VecDeque<Command>,
+    /// Number of the pending commands subject to `max_pending`.
+    pending_requests: usize,
+    max_pending: usize,
+}
+
+impl Warmup {
+    fn new(config: WarmupConfig, ready_rx: WatchReceiver<bool>) -> Self {
+        Self {
+            deadline: Box::pin(sleep(config.timeout)),
+            ready_rx,
+            pending: Default::default(),
+            pending_requests: 0,
+            max_pending: config.max_pending,
+        }
+    }
+
+    /// Queue a request until the guest is ready. Returns false if the request was rejected.
+    fn hold(&mut self, cmd: Command) -> bool {
+        if !cmd.is_chain_message() {
+            if self.pending_requests >= self.max_pending {
+                return false;
+            }
+            self.pending_requests += 1;
+        }
+        self.pending.push_back(cmd);
+        true
+    }
+
+    /// Returns true if the guest became ready, or false if the warmup timed out.
+    async fn wait(&mut self) -> bool {
+        loop {
+            if *self.ready_rx.borrow() {
+                return true;
+            }
+            tokio::select! {
+                changed = self.ready_rx.changed() => {
+                    if changed.is_err() {
+                        return false;
+                    }
+                }
+                _ = &mut self.deadline => return false,
+            }
+        }
+    }
+}
+
+async fn wait_warmup(warmup: &mut Option<Warmup>) -> bool {
+    match warmup {
+        Some(warmup) => warmup.wait().await,
+        None => std::future::pending().await,
+    }
 }
 
 pub struct IncomingHttpRequest {
@@ -83,6 +252,28 @@ pub struct Spawner {
     report_tx: Sender<Report>,
     out_tx: crate::OutgoingRequestChannel,
     scheduler: TaskScheduler<VmId>,
+    on_fuel_exhausted: Option<FuelExhaustedHandler>,
+    identity_secret: Option<[u8; 32]>,
+    session_limits: SessionLimits,
+    outbound_limits: OutboundLimits,
+    body_limits: BodyLimits,
+    dns: Dns,
+    tls_roots: Option<TlsRoots>,
+    clock: Option<ManualClock>,
+    cpu_budget: Option<CpuBudget>,
+    snapshot: Option<Arc<MemorySnapshot>>,
+    module_limits: ModuleLimits,
+    max_instructions: Option<u64>,
+    log_sink: Option<LogSink>,
+    memory_pool: Option<MemoryPool>,
+    memory_priority: u32,
+    warmup: Option<WarmupConfig>,
+    fuel_policy: Option<FuelPolicy>,
+    log_limit: Option<LogLimit>,
+    pubsub_namespaces: Vec<String>,
+    soft_memory_pages: Option<u32>,
+    tls_client_identity: Option<TlsClientIdentity>,
+    shared_cache: Option<String>,
 }
 
 pub fn service(
@@ -107,6 +298,28 @@ pub fn service(
         report_tx,
         out_tx,
         scheduler: TaskScheduler::new(worker_threads as _),
+        on_fuel_exhausted: None,
+        identity_secret: None,
+        session_limits: Default::default(),
+        outbound_limits: Default::default(),
+        body_limits: Default::default(),
+        dns: Default::default(),
+        tls_roots: None,
+        clock: None,
+        cpu_budget: None,
+        snapshot: None,
+        module_limits: Default::default(),
+        max_instructions: None,
+        log_sink: None,
+        memory_pool: None,
+        memory_priority: 0,
+        warmup: None,
+        fuel_policy: None,
+        log_limit: None,
+        pubsub_namespaces: vec![],
+        soft_memory_pages: None,
+        tls_client_identity: None,
+        shared_cache: None,
     };
     (run, spawner)
 }
@@ -137,8 +350,164 @@ impl ServiceRun {
 }
 
 impl Spawner {
+    /// Set the handler notified when any of the spawned instances runs out of gas.
+    pub fn with_fuel_exhausted_handler(mut self, handler: FuelExhaustedHandler) -> Self {
+        self.on_fuel_exhausted = Some(handler);
+        self
+    }
+
+    /// Give each spawned instance an identity key derived from the secret, its vmid and its code.
+    pub fn with_identity_secret(mut self, secret: [u8; 32]) -> Self {
+        self.identity_secret = Some(secret);
+        self
+    }
+
+    /// Set the limits of the host-side sessions each spawned instance can keep open.
+    pub fn with_session_limits(mut self, limits: SessionLimits) -> Self {
+        self.session_limits = limits;
+        self
+    }
+
+    /// Set the limits of the outbound connections each spawned instance can have.
+    pub fn with_outbound_limits(mut self, limits: OutboundLimits) -> Self {
+        self.outbound_limits = limits;
+        self
+    }
+
+    /// Cap the responses and the incoming request bodies each spawned instance can receive.
+    pub fn with_body_limits(mut self, limits: BodyLimits) -> Self {
+        self.body_limits = limits;
+        self
+    }
+
+    /// Set the resolver and the address policy of the outbound connections of the instances.
+    pub fn with_dns(mut self, dns: Dns) -> Self {
+        self.dns = dns;
+        self
+    }
+
+    /// Set the roots trusted by the TLS connections of the spawned instances, e.g. to reach the
+    /// endpoints behind a private CA.
+    pub fn with_tls_roots(mut self, roots: TlsRoots) -> Self {
+        self.tls_roots = Some(roots);
+        self
+    }
+
+    /// Drive the timers and the clocks of the spawned instances by the given clock, which only
+    /// moves when advanced by the embedder. Meant for tests and deterministic replays.
+    pub fn with_manual_clock(mut self, clock: ManualClock) -> Self {
+        self.clock = Some(clock);
+        self
+    }
+
+    /// Cap the CPU time each spawned instance can spend within a window, including the time
+    /// spent by the host in its ocalls.
+    pub fn with_cpu_budget(mut self, budget: CpuBudget) -> Self {
+        self.cpu_budget = Some(budget);
+        self
+    }
+
+    /// Restore the spawned instances from the snapshot rather than starting their programs afresh.
+    ///
+    /// The snapshot only fits the module it was taken from, so this is meant to be called on a
+    /// clone of the spawner used to start a single instance.
+    pub fn with_snapshot(mut self, snapshot: MemorySnapshot) -> Self {
+        self.snapshot = Some(Arc::new(snapshot));
+        self
+    }
+
+    /// Reject the modules over the limits when starting them, before they are compiled.
+    pub fn with_module_limits(mut self, limits: ModuleLimits) -> Self {
+        self.module_limits = limits;
+        self
+    }
+
+    /// Stop the spawned instances once they have retired this many instructions, a ceiling of
+    /// their work independent of how the gas is priced.
+    pub fn with_max_instructions(mut self, max_instructions: u64) -> Self {
+        self.max_instructions = Some(max_instructions);
+        self
+    }
+
+    /// Send the logs of the spawned instances to the sink rather than the global logger. The
+    /// records carry the id of the instance, to tell the tenants apart.
+    pub fn with_log_sink(mut self, sink: LogSink) -> Self {
+        self.log_sink = Some(sink);
+        self
+    }
+
+    /// Cap the memory of all the instances spawned from now on together, on top of the max
+    /// memory pages of each.
+    pub fn with_memory_ceiling(mut self, ceiling: MemoryCeiling) -> Self {
+        self.memory_pool = Some(MemoryPool::new(ceiling));
+        self
+    }
+
+    /// Set the priority of the spawned instances in the memory pool. The less it is, the sooner
+    /// an instance is evicted when the pool runs short. Defaults to 0.
+    ///
+    /// This is meant to be called on a clone of the spawner used to start a single instance.
+    pub fn with_memory_priority(mut self, priority: u32) -> Self {
+        self.memory_priority = priority;
+        self
+    }
+
+    /// Hold the requests to the spawned instances until they signal ready, see [`WarmupConfig`].
+    pub fn with_warmup(mut self, warmup: WarmupConfig) -> Self {
+        self.warmup = Some(warmup);
+        self
+    }
+
+    /// Limit the total fuel each spawned instance consumes over time. Unlimited by default.
+    pub fn with_fuel_policy(mut self, policy: FuelPolicy) -> Self {
+        self.fuel_policy = Some(policy);
+        self
+    }
+
+    /// Cap the logs a spawned instance emits while serving a single query. Unlimited by default.
+    pub fn with_log_limit(mut self, limit: LogLimit) -> Self {
+        self.log_limit = Some(limit);
+        self
+    }
+
+    /// Set the namespaces of the pub/sub topics the spawned instances can publish or subscribe
+    /// to. None by default.
+    pub fn with_pubsub_namespaces(mut self, namespaces: Vec<String>) -> Self {
+        self.pubsub_namespaces = namespaces;
+        self
+    }
+
+    /// Slow down the spawned instances growing their memory past this many pages, rather than
+    /// aborting them as at their max memory pages.
+    pub fn with_soft_memory_pages(mut self, pages: u32) -> Self {
+        self.soft_memory_pages = Some(pages);
+        self
+    }
+
+    /// Present the certificate to the TLS servers requiring client authentication.
+    pub fn with_tls_client_identity(mut self, identity: TlsClientIdentity) -> Self {
+        self.tls_client_identity = Some(identity);
+        self
+    }
+
+    /// Share the cache namespace of the given name with the other instances started with it.
+    /// The cache entries of an instance are private to it by default.
+    pub fn with_shared_cache(mut self, name: String) -> Self {
+        self.shared_cache = Some(name);
+        self
+    }
+
+    /// Number of memory pages taken by the running instances, if their memory is capped by
+    /// [`Spawner::with_memory_ceiling`].
+    pub fn memory_pages_in_use(&self) -> Option<u64> {
+        self.memory_pool.as_ref().map(MemoryPool::total_pages)
+    }
+
+    /// Start an instance of the module.
+    ///
+    /// Fails with a [`ModuleLimitExceeded`](crate::ModuleLimitExceeded) if the module is over the
+    /// limits given by [`Spawner::with_module_limits`].
     #[tracing::instrument(parent=None, name="sidevm", fields(id = %ShortId(id)), skip_all)]
-    #[allow(clippy::too_many_arguments)]
     pub fn start(
         &self,
         wasm_bytes: &[u8],
@@ -149,10 +518,34 @@ impl Spawner {
         weight: u32,
         prev_stopped: Option<WatchReceiver<bool>>,
     ) -> Result<(CommandSender, JoinHandle<ExitReason>)> {
+        self.module_limits.check(wasm_bytes)?;
         let event_tx = self.out_tx.clone();
         let (cmd_tx, mut cmd_rx) = channel(128);
         let spawner = self.runtime_handle.clone();
         let scheduler = self.scheduler.clone();
+        let on_fuel_exhausted = self.on_fuel_exhausted.clone();
+        let session_limits = self.session_limits;
+        let outbound_limits = self.outbound_limits;
+        let body_limits = self.body_limits;
+        let dns = self.dns.clone();
+        let tls_roots = self.tls_roots.clone();
+        let clock = self.clock.clone();
+        let cpu_budget = self.cpu_budget;
+        let snapshot = self.snapshot.clone();
+        let max_instructions = self.max_instructions;
+        let log_sink = self.log_sink.clone();
+        let memory_pool = self.memory_pool.clone();
+        let memory_priority = self.memory_priority;
+        let warmup = self.warmup;
+        let fuel_policy = self.fuel_policy;
+        let log_limit = self.log_limit;
+        let pubsub_namespaces = self.pubsub_namespaces.clone();
+        let soft_memory_pages = self.soft_memory_pages;
+        let tls_client_identity = self.tls_client_identity.clone();
+        let shared_cache = self.shared_cache.clone();
+        let identity = self
+            .identity_secret
+            .map(|secret| VmIdentity::derive(&secret, &id, wasm_bytes));
         let wasm_bytes = wasm_bytes.to_vec();
         let handle = self.spawn(async move {
             macro_rules! push_msg {
@@ -197,6 +590,10 @@ impl Spawner {
                                     info!(target: "sidevm", "Received stop command. Exiting...");
                                     return ExitReason::Stopped;
                                 }
+                                Some(Command::Shutdown { .. }) => {
+                                    info!(target: "sidevm", "Received shutdown command. Exiting...");
+                                    return ExitReason::Stopped;
+                                }
                                 Some(Command::UpdateWeight(w)) => {
                                     weight = w;
                                 }
@@ -204,7 +601,11 @@ impl Spawner {
                                     Command::PushMessage(_) |
                                     Command::PushSystemMessage(_) |
                                     Command::PushQuery { .. } |
-                                    Command::HttpRequest(_)
+                                    Command::HttpRequest(_) |
+                                    Command::Dump { .. } |
+                                    Command::Pause |
+                                    Command::Resume |
+                                    Command::Snapshot { .. }
                                 ) => {
                                     info!(
                                         target: "sidevm",
@@ -217,7 +618,10 @@ impl Spawner {
                 }
             }
             info!(target: "sidevm", "Starting sidevm instance...");
-            let engine = WasmEngine::new();
+            let engine = match max_instructions {
+                Some(_) => WasmEngine::with_instruction_counter(),
+                None => WasmEngine::new(),
+            };
             let module = match engine.compile(&wasm_bytes) {
                 Ok(m) => m,
                 Err(err) => {
@@ -235,6 +639,27 @@ impl Spawner {
                 weight,
                 event_tx,
                 log_handler: None,
+                log_sink,
+                pinned_chain_head: None,
+                fuel_policy,
+                log_limit,
+                pubsub_namespaces,
+                shared_cache,
+                on_fuel_exhausted,
+                identity,
+                soft_memory_pages,
+                tls_client_identity,
+                session_limits,
+                outbound_limits,
+                body_limits,
+                dns,
+                tls_roots,
+                clock,
+                cpu_budget,
+                snapshot,
+                max_instructions,
+                memory_pool,
+                memory_priority,
             };
             let (mut wasm_run, env) = match module.run(vec![], config) {
                 Ok(i) => i,
@@ -243,8 +668,67 @@ impl Spawner {
                     return ExitReason::FailedToStart;
                 }
             };
+            let _live = LiveVm::register(id, env.clone());
+            macro_rules! dispatch {
+                ($cmd: expr) => {
+                    match $cmd {
+                        Command::Stop => unreachable!("Stop is handled by the caller"),
+                        Command::PushMessage(msg) => {
+                            push_msg!(@sync: env.push_message(msg), debug, "message");
+                        }
+                        Command::PushSystemMessage(msg) => {
+                            push_msg!(@sync: env.push_system_message(msg), trace, "system message");
+                        }
+                        Command::PushQuery{ origin, payload, reply_tx } => {
+                            push_msg!(@async: env.push_query(origin, payload, reply_tx), debug, "query");
+                        }
+                        Command::HttpRequest(request) if wasm_run.is_paused() => {
+                            debug!(target: "sidevm", "Rejected http request while paused");
+                            let _ = request.response_tx.send(Err(VmPaused.into()));
+                        }
+                        Command::HttpRequest(request) => {
+                            push_msg!(@async: env.push_http_request(request), debug, "http request");
+                        }
+                        Command::UpdateWeight(weight) => {
+                            env.set_weight(weight);
+                        }
+                        Command::Dump { redact, reply_tx } => {
+                            _ = reply_tx.send(env.dump(redact));
+                        }
+                        Command::Pause => {
+                            info!(target: "sidevm", "Pausing the instance");
+                            wasm_run.pause();
+                        }
+                        Command::Resume => {
+                            info!(target: "sidevm", "Resuming the instance");
+                            wasm_run.resume();
+                        }
+                        Command::Shutdown { timeout } => {
+                            info!(target: "sidevm", ?timeout, "Shutting down the instance");
+                            let timeout_ms = timeout.as_millis().try_into().unwrap_or(u64::MAX);
+                            let msg = SystemMessage::Shutdown { timeout_ms };
+                            if let Some(Err(err)) = env.push_system_message(msg) {
+                                warn!(target: "sidevm", %err, "Failed to tell the program to exit");
+                            }
+                            wasm_run.shut_down(timeout);
+                        }
+                        Command::Snapshot { reply_tx } => {
+                            _ = reply_tx.send(wasm_run.snapshot());
+                        }
+                    }
+                };
+            }
+            let mut warmup = warmup.map(|config| {
+                info!(target: "sidevm", ?config, "Holding requests until the program is ready");
+                Warmup::new(config, env.subscribe_ready())
+            });
+            let reap_interval = Duration::from_secs(session_limits.idle_ttl_secs.clamp(1, 60));
+            let mut session_reaper = tokio::time::interval(reap_interval);
             loop {
                 tokio::select! {
+                    _ = session_reaper.tick() => {
+                        env.reap_sessions();
+                    }
                     cmd = cmd_rx.recv() => {
                         match cmd {
                             None => {
@@ -255,23 +739,33 @@ impl Spawner {
                                 info!(target: "sidevm", "Received stop command. Exiting...");
                                 break ExitReason::Stopped;
                             }
-                            Some(Command::PushMessage(msg)) => {
-                                push_msg!(@sync: env.push_message(msg), debug, "message");
+                            Some(cmd) if cmd.is_request() && wasm_run.is_shutting_down() => {
+                                debug!(target: "sidevm", "Rejected request while shutting down");
                             }
-                            Some(Command::PushSystemMessage(msg)) => {
-                                push_msg!(@sync: env.push_system_message(msg), trace, "system message");
-                            }
-                            Some(Command::PushQuery{ origin, payload, reply_tx }) => {
-                                push_msg!(@async: env.push_query(origin, payload, reply_tx), debug, "query");
-                            }
-                            Some(Command::HttpRequest(request)) => {
-                                push_msg!(@async: env.push_http_request(request), debug, "http request");
-                            }
-                            Some(Command::UpdateWeight(weight)) => {
-                                env.set_weight(weight);
+                            Some(cmd) => {
+                                if let Some(warmup) = warmup.as_mut().filter(|_| cmd.is_request()) {
+                                    if !warmup.hold(cmd) {
+                                        warn!(target: "sidevm", "Too many requests during warmup, rejected");
+                                    }
+                                    continue;
+                                }
+                                dispatch!(cmd);
                             }
                         }
                     }
+                    ready = wait_warmup(&mut warmup) => {
+                        let Some(warmup) = warmup.take() else {
+                            continue;
+                        };
+                        if !ready {
+                            warn!(target: "sidevm", "The program didn't get ready in time. Exiting...");
+                            break ExitReason::WarmupTimeout;
+                        }
+                        info!(target: "sidevm", pending = warmup.pending.len(), "The program is ready");
+                        for cmd in warmup.pending {
+                            dispatch!(cmd);
+                        }
+                    }
                     rv = &mut wasm_run => {
                         match rv {
                             Ok(ret) => {
@@ -280,6 +774,12 @@ impl Spawner {
                             }
                             Err(err) => {
                                 info!(target: "sidevm", ?err, "The sidevm instance exited.");
+                                if err.is::<crate::ShutdownTimedOut>() {
+                                    break ExitReason::ShutdownTimedOut;
+                                }
+                                if err.is::<crate::Evicted>() {
+                                    break ExitReason::Evicted;
+                                }
                                 match err.downcast::<crate::env::OcallAborted>() {
                                     Ok(err) => {
                                         break ExitReason::OcallAborted(err);
@@ -326,3 +826,57 @@ impl Spawner {
         self.out_tx.clone()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query() -> (Command, tokio::sync::oneshot::Receiver<Vec<u8>>) {
+        let (reply_tx, reply_rx) = tokio::sync::oneshot::channel();
+        let cmd = Command::PushQuery {
+            origin: None,
+            payload: vec![],
+            reply_tx,
+        };
+        (cmd, reply_rx)
+    }
+
+    #[tokio::test]
+    async fn warmup_cap_never_drops_chain_messages() {
+        let (_ready_tx, ready_rx) = tokio::sync::watch::channel(false);
+        let config = WarmupConfig {
+            timeout: Duration::from_secs(60),
+            max_pending: 1,
+        };
+        let mut warmup = Warmup::new(config, ready_rx);
+
+        let (first, _first_rx) = query();
+        assert!(warmup.hold(first));
+        for i in 0..3 {
+            assert!(warmup.hold(Command::PushMessage(vec![i])));
+        }
+        let (second, second_rx) = query();
+        assert!(!warmup.hold(second));
+        assert!(
+            second_rx.await.is_err(),
+            "the requester of a rejected query should be told"
+        );
+        assert!(
+            warmup.hold(Command::PushSystemMessage(SystemMessage::Shutdown {
+                timeout_ms: 0
+            }))
+        );
+
+        let held: Vec<_> = warmup
+            .pending
+            .iter()
+            .map(|cmd| match cmd {
+                Command::PushQuery { .. } => "query",
+                Command::PushMessage(_) => "message",
+                Command::PushSystemMessage(_) => "system",
+                _ => "other",
+            })
+            .collect();
+        assert_eq!(held, ["query", "message", "message", "message", "system"]);
+    }
+}
@@ -0,0 +1,156 @@
+//! Bookkeeping of the host-side sessions opened on behalf of the guests.
+//!
+//! A session is a group of resources serving a single exchange with the outside, such as the
+//! body stream and the response channel of an incoming HTTP request. A guest that never finishes
+//! the exchange would keep them forever, so the sessions idle for too long are reaped, and new
+//! ones are rejected once an instance has too many of them.
+
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+/// Limits of the sessions each instance can keep open.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SessionLimits {
+    /// Max number of open sessions. New sessions are rejected beyond it.
+    pub max_sessions: usize,
+    /// A session without any activity for this long is reaped.
+    pub idle_ttl_secs: u64,
+}
+
+impl Default for SessionLimits {
+    fn default() -> Self {
+        Self {
+            max_sessions: 64,
+            idle_ttl_secs: 300,
+        }
+    }
+}
+
+/// Returned when a session is rejected because the instance has too many open ones.
+#[derive(Debug, thiserror::Error)]
+#[error("too many open sessions")]
+pub struct TooManySessions;
+
+struct Session {
+    resources: Vec<i32>,
+    last_active: Instant,
+}
+
+#[derive(Default)]
+pub(crate) struct Sessions {
+    limits: SessionLimits,
+    sessions: Vec<Session>,
+}
+
+impl Sessions {
+    pub(crate) fn set_limits(&mut self, limits: SessionLimits) {
+        self.limits = limits;
+    }
+
+    pub(crate) fn is_full(&self) -> bool {
+        self.sessions.len() >= self.limits.max_sessions
+    }
+
+    pub(crate) fn open(&mut self, resources: Vec<i32>) -> Result<(), TooManySessions> {
+        if self.is_full() {
+            return Err(TooManySessions);
+        }
+        self.sessions.push(Session {
+            resources,
+            last_active: Instant::now(),
+        });
+        Ok(())
+    }
+
+    /// Record an activity on the resource, keeping its session alive.
+    pub(crate) fn touch(&mut self, resource_id: i32) {
+        if self.sessions.is_empty() {
+            return;
+        }
+        if let Some(session) = self
+            .sessions
+            .iter_mut()
+            .find(|s| s.resources.contains(&resource_id))
+        {
+            session.last_active = Instant::now();
+        }
+    }
+
+    /// Forget a resource closed by the guest. The session ends with its last resource.
+    pub(crate) fn forget(&mut self, resource_id: i32) {
+        self.sessions.retain_mut(|session| {
+            session.resources.retain(|&id| id != resource_id);
+            !session.resources.is_empty()
+        });
+    }
+
+    /// Remove the idle sessions, returning their resources to be freed.
+    pub(crate) fn reap(&mut self) -> Vec<i32> {
+        let ttl = Duration::from_secs(self.limits.idle_ttl_secs);
+        let now = Instant::now();
+        let mut expired = vec![];
+        self.sessions.retain_mut(|session| {
+            if now.saturating_duration_since(session.last_active) < ttl {
+                return true;
+            }
+            expired.append(&mut session.resources);
+            false
+        });
+        expired
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sessions(max_sessions: usize) -> Sessions {
+        let mut sessions = Sessions::default();
+        sessions.set_limits(SessionLimits {
+            max_sessions,
+            idle_ttl_secs: 60,
+        });
+        sessions
+    }
+
+    /// Pretend the sessions have been idle for the given time.
+    fn age(sessions: &mut Sessions, idle: Duration) {
+        for session in &mut sessions.sessions {
+            session.last_active -= idle;
+        }
+    }
+
+    #[test]
+    fn sessions_are_rejected_past_the_cap() {
+        let mut sessions = sessions(2);
+        sessions.open(vec![1, 2]).unwrap();
+        sessions.open(vec![3]).unwrap();
+        assert!(sessions.is_full());
+        assert!(sessions.open(vec![4]).is_err());
+
+        // The session of 1 and 2 only ends with both of them.
+        sessions.forget(1);
+        assert!(sessions.is_full());
+        sessions.forget(2);
+        assert!(!sessions.is_full());
+        sessions.open(vec![4]).unwrap();
+    }
+
+    #[test]
+    fn idle_sessions_are_reaped_unless_touched() {
+        let mut sessions = sessions(8);
+        sessions.open(vec![1, 2]).unwrap();
+        sessions.open(vec![3]).unwrap();
+        age(&mut sessions, Duration::from_secs(30));
+        assert!(sessions.reap().is_empty());
+
+        sessions.touch(2);
+        age(&mut sessions, Duration::from_secs(40));
+        assert_eq!(sessions.reap(), [3]);
+        age(&mut sessions, Duration::from_secs(30));
+        assert_eq!(sessions.reap(), [1, 2]);
+        assert!(sessions.sessions.is_empty());
+    }
+}
@@ -0,0 +1,193 @@
+//! Snapshots of the guests, to bring them back without running their initialization again.
+//!
+//! A snapshot holds the linear memory and the mutable globals of an instance, along with the
+//! host-side state the guest can't do without, i.e. its input channels. It can only be taken at a
+//! safe point, between two polls and while the guest holds no other resource, such as a
+//! connection or a timer, which couldn't be brought back.
+//!
+//! The globals of a wasm module are usually private to it, so the modules are compiled with an
+//! export added for each of their mutable globals.
+
+use anyhow::{bail, Result};
+use blake2::{digest::consts::U32, Blake2b, Digest};
+use parity_wasm::elements::{ExportEntry, ExportSection, External, Internal, Module, Section};
+use scale::{Decode, Encode};
+use sidevm_env::InputChannel;
+use wasmer::Value;
+
+/// Version of the snapshot format.
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+/// Prefix of the names the mutable globals are exported as, followed by their index.
+pub(crate) const GLOBAL_EXPORT_PREFIX: &str = "__sidevm_global_";
+
+/// The value of a global. Floats are kept as their bits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Encode, Decode)]
+pub enum GlobalValue {
+    I32(i32),
+    I64(i64),
+    F32(u32),
+    F64(u64),
+}
+
+impl GlobalValue {
+    pub(crate) fn from_value(value: Value) -> Option<Self> {
+        Some(match value {
+            Value::I32(v) => Self::I32(v),
+            Value::I64(v) => Self::I64(v),
+            Value::F32(v) => Self::F32(v.to_bits()),
+            Value::F64(v) => Self::F64(v.to_bits()),
+            _ => return None,
+        })
+    }
+}
+
+impl From<GlobalValue> for Value {
+    fn from(value: GlobalValue) -> Self {
+        match value {
+            GlobalValue::I32(v) => Value::I32(v),
+            GlobalValue::I64(v) => Value::I64(v),
+            GlobalValue::F32(v) => Value::F32(f32::from_bits(v)),
+            GlobalValue::F64(v) => Value::F64(f64::from_bits(v)),
+        }
+    }
+}
+
+/// The state of a guest, taken by [`WasmRun::snapshot`](crate::WasmRun::snapshot).
+#[derive(Clone, Encode, Decode)]
+pub struct MemorySnapshot {
+    /// Version of the snapshot format.
+    pub version: u32,
+    /// Hash of the code of the module the snapshot was taken from.
+    pub module_hash: [u8; 32],
+    /// Content of the linear memory.
+    pub memory: Vec<u8>,
+    /// Values of the mutable globals, by index in the module.
+    pub globals: Vec<(u32, GlobalValue)>,
+    /// The input channels opened by the guest, by resource id.
+    pub input_channels: Vec<(i32, u8)>,
+    /// Highest id of the tasks of the guest, all of which are woken up once restored.
+    pub max_task_id: i32,
+    /// Whether the guest had signaled ready.
+    pub ready: bool,
+}
+
+impl MemorySnapshot {
+    /// Check the snapshot can be restored into the module with the given hash.
+    pub(crate) fn check(&self, module_hash: &[u8; 32]) -> Result<()> {
+        if self.version != SNAPSHOT_VERSION {
+            bail!("unsupported snapshot version {}", self.version);
+        }
+        if &self.module_hash != module_hash {
+            bail!("the snapshot was taken from another module");
+        }
+        Ok(())
+    }
+}
+
+pub(crate) fn module_hash(code: &[u8]) -> [u8; 32] {
+    Blake2b::<U32>::digest(code).into()
+}
+
+pub(crate) fn input_channel_from_u8(ch: u8) -> Result<InputChannel> {
+    Ok(match ch {
+        1 => InputChannel::SystemMessage,
+        2 => InputChannel::GeneralMessage,
+        3 => InputChannel::Query,
+        4 => InputChannel::HttpRequest,
+        _ => bail!("unknown input channel {ch}"),
+    })
+}
+
+/// Add an export for each mutable global defined by the module.
+///
+/// Returns None if the module can't be handled, in which case it can't be snapshotted.
+pub(crate) fn export_globals(code: &[u8]) -> Option<Vec<u8>> {
+    let mut module: Module = parity_wasm::deserialize_buffer(code).ok()?;
+    let n_imported = module
+        .import_section()
+        .map(|section| {
+            section
+                .entries()
+                .iter()
+                .filter(|entry| matches!(entry.external(), External::Global(_)))
+                .count()
+        })
+        .unwrap_or(0);
+    let mutable: Vec<u32> = module
+        .global_section()
+        .map(|section| {
+            section
+                .entries()
+                .iter()
+                .enumerate()
+                .filter(|(_, global)| global.global_type().is_mutable())
+                .map(|(i, _)| (n_imported + i) as u32)
+                .collect()
+        })
+        .unwrap_or_default();
+    if module.export_section().is_none() {
+        module
+            .insert_section(Section::Export(ExportSection::default()))
+            .ok()?;
+    }
+    let exports = module.export_section_mut()?.entries_mut();
+    for index in mutable {
+        let name = format!("{GLOBAL_EXPORT_PREFIX}{index}");
+        exports.push(ExportEntry::new(name, Internal::Global(index)));
+    }
+    parity_wasm::serialize(module).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parity_wasm::elements::{Instruction, ValueType};
+
+    #[test]
+    fn only_mutable_globals_are_exported() {
+        let module = parity_wasm::builder::module()
+            .global()
+            .with_type(ValueType::I32)
+            .mutable()
+            .init_expr(Instruction::I32Const(1024))
+            .build()
+            .global()
+            .with_type(ValueType::I64)
+            .init_expr(Instruction::I64Const(0))
+            .build()
+            .build();
+        let code = parity_wasm::serialize(module).unwrap();
+
+        let exported = export_globals(&code).unwrap();
+        let module: Module = parity_wasm::deserialize_buffer(&exported).unwrap();
+        let exports: Vec<_> = module
+            .export_section()
+            .unwrap()
+            .entries()
+            .iter()
+            .map(|entry| (entry.field().to_owned(), *entry.internal()))
+            .collect();
+        assert_eq!(exports, [("__sidevm_global_0".into(), Internal::Global(0))]);
+    }
+
+    #[test]
+    fn mismatched_snapshot_is_rejected() {
+        let snapshot = MemorySnapshot {
+            version: SNAPSHOT_VERSION,
+            module_hash: module_hash(b"module a"),
+            memory: vec![],
+            globals: vec![],
+            input_channels: vec![],
+            max_task_id: 0,
+            ready: false,
+        };
+        assert!(snapshot.check(&module_hash(b"module a")).is_ok());
+        assert!(snapshot.check(&module_hash(b"module b")).is_err());
+        let snapshot = MemorySnapshot {
+            version: SNAPSHOT_VERSION + 1,
+            ..snapshot
+        };
+        assert!(snapshot.check(&module_hash(b"module a")).is_err());
+    }
+}
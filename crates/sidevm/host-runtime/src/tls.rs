@@ -38,37 +38,145 @@ impl From<ServerTlsStream<TcpStream>> for TlsStream {
     }
 }
 
+fn webpki_root_store() -> rustls::RootCertStore {
+    let mut root_store = rustls::RootCertStore::empty();
+    root_store.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
+        rustls::OwnedTrustAnchor::from_subject_spki_name_constraints(
+            ta.subject,
+            ta.spki,
+            ta.name_constraints,
+        )
+    }));
+    root_store
+}
+
 fn default_client_config() -> Arc<ClientConfig> {
     static CLIENT_CONFIG: Lazy<Arc<ClientConfig>> = Lazy::new(|| {
-        let mut root_store = rustls::RootCertStore::empty();
-        root_store.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
-            rustls::OwnedTrustAnchor::from_subject_spki_name_constraints(
-                ta.subject,
-                ta.spki,
-                ta.name_constraints,
-            )
-        }));
-
         let config = ClientConfig::builder()
             .with_safe_defaults()
-            .with_root_certificates(root_store)
+            .with_root_certificates(webpki_root_store())
             .with_no_client_auth();
         Arc::new(config)
     });
     CLIENT_CONFIG.clone()
 }
 
+/// The root certificates trusted by the TLS clients of the instances.
+#[derive(Clone)]
+pub struct TlsRoots(Arc<rustls::RootCertStore>);
+
+impl TlsRoots {
+    /// Trust the CAs of the PEM bundle in addition to the public webpki roots, or only them if
+    /// `replace_public` is true.
+    pub fn from_pem(pem: &str, replace_public: bool) -> anyhow::Result<Self> {
+        let mut root_store = if replace_public {
+            rustls::RootCertStore::empty()
+        } else {
+            webpki_root_store()
+        };
+        let certs = rustls_pemfile::certs(&mut pem.as_bytes())?;
+        if certs.is_empty() {
+            anyhow::bail!("no certificate found in the root bundle");
+        }
+        for cert in certs {
+            root_store
+                .add(&rustls::Certificate(cert))
+                .map_err(|err| anyhow::anyhow!("invalid root certificate: {err:?}"))?;
+        }
+        Ok(Self(Arc::new(root_store)))
+    }
+}
+
+/// The certificate an instance presents to the servers requiring client authentication.
+#[derive(Clone)]
+pub struct TlsClientIdentity {
+    /// The certificate chain in PEM format, leaf first.
+    pub cert: String,
+    /// The private key of the certificate, in PEM format.
+    pub key: String,
+}
+
+impl std::fmt::Debug for TlsClientIdentity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TlsClientIdentity")
+            .field("cert", &self.cert)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Build the client config of an instance, presenting the given identity if any.
+///
+/// The identity is checked here rather than during the handshakes, so that a malformed one fails
+/// with `OcallError::InvalidCertificate`.
+pub(crate) fn client_config(
+    identity: Option<&TlsClientIdentity>,
+    roots: Option<&TlsRoots>,
+) -> Result<Arc<ClientConfig>, OcallError> {
+    let root_store = match roots {
+        Some(roots) => (*roots.0).clone(),
+        None if identity.is_none() => return Ok(default_client_config()),
+        None => webpki_root_store(),
+    };
+    let builder = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(root_store);
+    let Some(identity) = identity else {
+        return Ok(Arc::new(builder.with_no_client_auth()));
+    };
+    let invalid = |_| OcallError::InvalidCertificate;
+    let certs = load_certs(&identity.cert).map_err(invalid)?;
+    if certs.is_empty() {
+        return Err(OcallError::InvalidCertificate);
+    }
+    let key = load_private_key(&identity.key).map_err(invalid)?;
+    let config = builder
+        .with_single_cert(certs, key)
+        .or(Err(OcallError::InvalidCertificate))?;
+    Ok(Arc::new(config))
+}
+
 impl TlsStream {
     pub(crate) fn accept(stream: TcpStream, config: Arc<ServerConfig>) -> TlsStream {
         let accept = TlsAcceptor::from(config).accept(stream);
         TlsStream::ServerHandshaking(accept)
     }
 
-    pub(crate) fn connect(domain: ServerName, stream: TcpStream) -> TlsStream {
-        let client_config = default_client_config();
+    pub(crate) fn connect(
+        domain: ServerName,
+        stream: TcpStream,
+        client_config: Arc<ClientConfig>,
+    ) -> TlsStream {
         let connector = TlsConnector::from(client_config);
         TlsStream::ClientHandshaking(connector.connect(domain, stream))
     }
+
+    /// Connect and wait for the handshake to be done.
+    pub(crate) async fn connect_handshaked(
+        domain: ServerName,
+        stream: TcpStream,
+        client_config: Arc<ClientConfig>,
+    ) -> io::Result<TlsStream> {
+        let connector = TlsConnector::from(client_config);
+        Ok(connector.connect(domain, stream).await?.into())
+    }
+
+    /// The protocol negotiated via ALPN, once the handshake is done.
+    pub(crate) fn alpn_protocol(&self) -> Option<&[u8]> {
+        match self {
+            Self::ClientStreaming(stream) => stream.get_ref().1.alpn_protocol(),
+            Self::ServerStreaming(stream) => stream.get_ref().1.alpn_protocol(),
+            _ => None,
+        }
+    }
+
+    /// The underlying TCP stream once the handshake is done.
+    pub(crate) fn tcp_stream(&self) -> Option<&TcpStream> {
+        match self {
+            Self::ClientStreaming(stream) => Some(stream.get_ref().0),
+            Self::ServerStreaming(stream) => Some(stream.get_ref().0),
+            _ => None,
+        }
+    }
 }
 
 impl AsyncRead for TlsStream {
@@ -241,3 +349,41 @@ fn load_private_key(pem_str: &str) -> Result<rustls::PrivateKey, OcallError> {
     };
     Ok(rustls::PrivateKey(key.clone()))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A self-signed P-256 CA.
+    const TEST_ROOT: &str = "
+-----BEGIN CERTIFICATE-----
+MIIBjTCCATOgAwIBAgIUcVNJTjuMgBPJdCePDi8QFkMz8oUwCgYIKoZIzj0EAwIw
+GzEZMBcGA1UEAwwQc2lkZXZtIHRlc3Qgcm9vdDAgFw0yNjEwMTQxMjU4MjZaGA8y
+MTI2MDkyMDEyNTgyNlowGzEZMBcGA1UEAwwQc2lkZXZtIHRlc3Qgcm9vdDBZMBMG
+ByqGSM49AgEGCCqGSM49AwEHA0IABKaAJPHedfqqKUVZuH3X/0FfiutyhD3fQdi7
+5UwzmSFkJHMVfLqrkmpRk5QZ0KQhdreSiddPNF7CHMMCigVtxAyjUzBRMB0GA1Ud
+DgQWBBRnGgcs1W/SZuM4fD5+wM5ZAaRlFjAfBgNVHSMEGDAWgBRnGgcs1W/SZuM4
+fD5+wM5ZAaRlFjAPBgNVHRMBAf8EBTADAQH/MAoGCCqGSM49BAMCA0gAMEUCIB9M
+wJ5QPY8YQaw8kvc1WO4b3SHYT3vBMSp6yGqxzG9LAiEAgbFLD35jhojBKH2jrayX
+M9CHfAcEOWn1wrANiJNyDZQ=
+-----END CERTIFICATE-----
+";
+
+    #[test]
+    fn extra_roots_are_trusted_along_with_the_public_ones() {
+        let public = webpki_root_store().len();
+        let extended = TlsRoots::from_pem(TEST_ROOT, false).unwrap();
+        assert_eq!(extended.0.len(), public + 1);
+        let replaced = TlsRoots::from_pem(TEST_ROOT, true).unwrap();
+        assert_eq!(replaced.0.len(), 1);
+
+        assert!(client_config(None, Some(&replaced)).is_ok());
+    }
+
+    #[test]
+    fn root_bundles_without_certificates_are_rejected() {
+        assert!(TlsRoots::from_pem("", false).is_err());
+        let truncated = TEST_ROOT.replace("-----END CERTIFICATE-----", "");
+        assert!(TlsRoots::from_pem(&truncated, true).is_err());
+    }
+}
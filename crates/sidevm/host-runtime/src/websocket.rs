@@ -0,0 +1,113 @@
+//! WebSocket connections accepted by the host on behalf of the guests.
+//!
+//! The host completes the handshake and does the framing, answering the pings and the closing
+//! handshake by itself, so that the guests only deal with the payloads of the messages.
+
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures::{FutureExt, Sink, Stream};
+use sidevm_env::{
+    messages::{HttpHead, HttpResponseHead, WsMessage},
+    OcallError, Result,
+};
+use tokio::io::DuplexStream;
+use tokio_tungstenite::{
+    tungstenite::{handshake::derive_accept_key, protocol::Role, Message},
+    WebSocketStream,
+};
+
+/// How long to wait for the peer to acknowledge the close of a connection dropped by the guest.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A WebSocket connection accepted for a guest.
+pub struct WebSocket {
+    stream: WebSocketStream<DuplexStream>,
+}
+
+impl WebSocket {
+    /// Wrap the stream of an upgraded connection.
+    pub(crate) fn new(stream: DuplexStream) -> Self {
+        // Wrapping a raw socket doesn't do any IO, so it's always ready.
+        let stream = WebSocketStream::from_raw_socket(stream, Role::Server, None)
+            .now_or_never()
+            .expect("BUG: wrapping a websocket should never block");
+        Self { stream }
+    }
+
+    pub(crate) fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Result<WsMessage>> {
+        loop {
+            let message = match Pin::new(&mut self.stream).poll_next(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(None) => return Poll::Ready(Err(OcallError::EndOfFile)),
+                Poll::Ready(Some(Err(_err))) => return Poll::Ready(Err(OcallError::IoError)),
+                Poll::Ready(Some(Ok(message))) => message,
+            };
+            let message = match message {
+                Message::Text(text) => WsMessage::Text(text),
+                Message::Binary(data) => WsMessage::Binary(data),
+                // The pongs and the close replies are queued by tungstenite and sent with the
+                // next read or write.
+                Message::Ping(_) | Message::Pong(_) | Message::Frame(_) => continue,
+                Message::Close(_) => return Poll::Ready(Err(OcallError::EndOfFile)),
+            };
+            return Poll::Ready(Ok(message));
+        }
+    }
+
+    pub(crate) fn poll_send(
+        &mut self,
+        cx: &mut Context<'_>,
+        message: WsMessage,
+    ) -> Poll<Result<()>> {
+        let mut stream = Pin::new(&mut self.stream);
+        if futures::ready!(stream.as_mut().poll_ready(cx)).is_err() {
+            return Poll::Ready(Err(OcallError::IoError));
+        }
+        let message = match message {
+            WsMessage::Text(text) => Message::Text(text),
+            WsMessage::Binary(data) => Message::Binary(data),
+        };
+        Poll::Ready(stream.start_send(message).or(Err(OcallError::IoError)))
+    }
+
+    pub(crate) fn poll_flush(&mut self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.stream)
+            .poll_flush(cx)
+            .map(|result| result.or(Err(OcallError::IoError)))
+    }
+
+    /// Close the connection in background, sending a close frame to the peer.
+    pub(crate) fn close(mut self) {
+        tokio::spawn(async move {
+            let _ = tokio::time::timeout(CLOSE_TIMEOUT, self.stream.close(None)).await;
+        });
+    }
+}
+
+/// Returns the key of the handshake if the request is an upgrade to WebSocket.
+pub(crate) fn handshake_key(head: &HttpHead) -> Option<String> {
+    if !head.is_websocket_upgrade() {
+        return None;
+    }
+    head.get_header("sec-websocket-key").map(Into::into)
+}
+
+/// Complete the response of the guest accepting the handshake.
+pub(crate) fn accept(response: &mut HttpResponseHead, key: &str) {
+    response.headers.retain(|(name, _)| {
+        let name = name.to_ascii_lowercase();
+        name != "upgrade" && name != "connection" && name != "sec-websocket-accept"
+    });
+    response.headers.extend([
+        ("Upgrade".into(), "websocket".into()),
+        ("Connection".into(), "Upgrade".into()),
+        (
+            "Sec-WebSocket-Accept".into(),
+            derive_accept_key(key.as_bytes()),
+        ),
+    ]);
+}
@@ -0,0 +1,183 @@
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use sidevm_host_runtime::FuelPolicy;
+use std::collections::HashMap;
+
+/// Resource limits applied to a deployed VM.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    pub gas_per_breath: u64,
+    pub max_memory_pages: u32,
+    pub soft_memory_pages: Option<u32>,
+    pub weight: u32,
+    pub fuel: Option<FuelPolicy>,
+    /// Max number of outbound connections the VM can have at a time.
+    pub max_outbound_connections: usize,
+    /// Max number of bytes the VM can read from an outbound connection.
+    pub max_response_bytes: Option<u64>,
+}
+
+/// A named set of limits. Fields left empty fall back to the host defaults.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    pub gas_per_breath: Option<u64>,
+    pub max_memory_pages: Option<u32>,
+    pub soft_memory_pages: Option<u32>,
+    pub weight: Option<u32>,
+    pub fuel: Option<FuelPolicy>,
+    pub max_outbound_connections: Option<usize>,
+    pub max_response_bytes: Option<u64>,
+}
+
+impl Profile {
+    /// Apply the fields set in this profile on top of `base`.
+    pub fn apply(&self, base: Limits) -> Limits {
+        Limits {
+            gas_per_breath: self.gas_per_breath.unwrap_or(base.gas_per_breath),
+            max_memory_pages: self.max_memory_pages.unwrap_or(base.max_memory_pages),
+            soft_memory_pages: self.soft_memory_pages.or(base.soft_memory_pages),
+            weight: self.weight.unwrap_or(base.weight),
+            fuel: self.fuel.or(base.fuel),
+            max_outbound_connections: self
+                .max_outbound_connections
+                .unwrap_or(base.max_outbound_connections),
+            max_response_bytes: self.max_response_bytes.or(base.max_response_bytes),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Profiles {
+    profiles: HashMap<String, Profile>,
+}
+
+impl Profiles {
+    /// Load profiles from a JSON file in the form of `{"<name>": {"gas_per_breath": ..}, ..}`.
+    pub fn load(path: &str) -> Result<Self> {
+        let content =
+            std::fs::read(path).with_context(|| format!("Failed to read profiles from {path}"))?;
+        Self::from_json(&content)
+    }
+
+    pub fn from_json(content: &[u8]) -> Result<Self> {
+        let profiles = serde_json::from_slice(content).context("Invalid profiles")?;
+        Ok(Self { profiles })
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Profile> {
+        self.profiles.get(name)
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &String> {
+        self.profiles.keys()
+    }
+
+    /// Resolve the effective limits for a deploy.
+    ///
+    /// The limits are layered as: host defaults < named profile < per deploy overrides.
+    pub fn resolve(
+        &self,
+        defaults: Limits,
+        profile: Option<&str>,
+        overrides: &Profile,
+    ) -> Result<Limits, &'static str> {
+        let base = match profile {
+            Some(name) => self.get(name).ok_or("Profile not found")?.apply(defaults),
+            None => defaults,
+        };
+        Ok(overrides.apply(base))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEFAULTS: Limits = Limits {
+        gas_per_breath: 1_000,
+        max_memory_pages: 256,
+        soft_memory_pages: None,
+        weight: 1,
+        fuel: None,
+        max_outbound_connections: 256,
+        max_response_bytes: None,
+    };
+
+    fn profiles() -> Profiles {
+        Profiles::from_json(
+            br#"{
+                "oracle": {
+                    "gas_per_breath": 5000,
+                    "max_memory_pages": 64,
+                    "max_outbound_connections": 4,
+                    "max_response_bytes": 1048576
+                },
+                "indexer": { "weight": 4 }
+            }"#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn named_profile_sets_the_effective_limits() {
+        let limits = profiles()
+            .resolve(DEFAULTS, Some("oracle"), &Profile::default())
+            .unwrap();
+        assert_eq!(
+            limits,
+            Limits {
+                gas_per_breath: 5000,
+                max_memory_pages: 64,
+                max_outbound_connections: 4,
+                max_response_bytes: Some(1 << 20),
+                ..DEFAULTS
+            }
+        );
+
+        let limits = profiles()
+            .resolve(DEFAULTS, Some("indexer"), &Profile::default())
+            .unwrap();
+        assert_eq!(
+            limits,
+            Limits {
+                weight: 4,
+                ..DEFAULTS
+            }
+        );
+    }
+
+    #[test]
+    fn override_takes_precedence_over_the_profile() {
+        let overrides = Profile {
+            max_memory_pages: Some(128),
+            max_outbound_connections: Some(1),
+            ..Default::default()
+        };
+        let limits = profiles()
+            .resolve(DEFAULTS, Some("oracle"), &overrides)
+            .unwrap();
+        assert_eq!(limits.max_memory_pages, 128);
+        assert_eq!(limits.max_outbound_connections, 1);
+        // The fields not overridden still come from the profile.
+        assert_eq!(limits.gas_per_breath, 5000);
+        assert_eq!(limits.max_response_bytes, Some(1 << 20));
+
+        assert_eq!(
+            profiles().resolve(DEFAULTS, None, &overrides).unwrap(),
+            Limits {
+                max_memory_pages: 128,
+                max_outbound_connections: 1,
+                ..DEFAULTS
+            }
+        );
+    }
+
+    #[test]
+    fn unknown_profile_is_rejected() {
+        assert!(profiles()
+            .resolve(DEFAULTS, Some("daemon"), &Profile::default())
+            .is_err());
+        assert!(Profiles::from_json(br#"{"oracle": {"max_memory": 1}}"#).is_err());
+    }
+}
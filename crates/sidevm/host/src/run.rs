@@ -16,6 +16,9 @@ pub struct Args {
     /// Max memory pages
     #[arg(long, default_value_t = 256)]
     max_memory_pages: u32,
+    /// Trap once the program has retired this many instructions. Unlimited if not set
+    #[arg(long)]
+    max_instructions: Option<u64>,
     /// The WASM program to run
     program: String,
     /// The rest of the arguments are passed to the WASM program
@@ -35,16 +38,39 @@ pub async fn run(mut args: Args) -> Result<JsValue> {
         id: Default::default(),
         event_tx,
         log_handler: None,
+        log_sink: None,
+        pinned_chain_head: None,
+        fuel_policy: None,
+        log_limit: None,
+        pubsub_namespaces: vec![],
+        shared_cache: None,
+        on_fuel_exhausted: None,
+        identity: None,
+        soft_memory_pages: None,
+        tls_client_identity: None,
+        session_limits: Default::default(),
+        outbound_limits: Default::default(),
+        body_limits: Default::default(),
+        dns: Default::default(),
+        tls_roots: None,
+        clock: None,
+        cpu_budget: None,
+        snapshot: None,
+        max_instructions: args.max_instructions,
+        memory_pool: None,
+        memory_priority: 0,
+    };
+    let engine = match args.max_instructions {
+        Some(_) => WasmEngine::with_instruction_counter(),
+        None => WasmEngine::new(),
     };
-    let engine = WasmEngine::new();
     let module = engine.compile(&code)?;
     args.args.insert(0, args.program);
     let vm_args = args
         .args
         .into_iter()
         .map(|s| -> Result<String> {
-            if s.starts_with('@') {
-                let path = &s[1..];
+            if let Some(path) = s.strip_prefix('@') {
                 let content = std::fs::read_to_string(path).context("Failed to read file")?;
                 Ok(content)
             } else {
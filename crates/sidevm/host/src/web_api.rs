@@ -5,7 +5,7 @@ use rocket::{get, post, routes};
 use rocket::{Data, State};
 use tracing::{info, warn};
 
-use scale::Decode;
+use scale::{Decode, Encode};
 use sidevm_host_runtime::ShortId;
 use sp_core::crypto::AccountId32;
 use tokio::task::JoinHandle;
@@ -13,27 +13,51 @@ use tokio::task::JoinHandle;
 use std::collections::HashMap;
 use std::path::PathBuf;
 use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
 
 use tokio::sync::mpsc::Sender;
 use tokio::sync::Mutex;
 
-use sidevm::{Command, CommandSender, Spawner, SystemMessage};
+use sidevm::{Command, CommandSender, Spawner, SystemMessage, WarmupConfig};
 use sidevm_host_runtime::rocket_stream::{connect, RequestInfo, StreamResponse};
 use sidevm_host_runtime::{
-    service::{self as sidevm, ExitReason},
-    OutgoingRequest,
+    public_only,
+    service::{self as sidevm, ExitReason, VmPaused},
+    BodyLimits, CpuBudget, Dns, LogLimit, LruCache, MemoryCeiling, ModuleLimitExceeded,
+    ModuleLimits, OutboundLimits, OutgoingRequest, QueryError, SessionLimits, TlsClientIdentity,
+    TlsRoots, TooManySessions,
 };
 
+use crate::profile::{Limits, Profile, Profiles};
 use crate::Args;
+
+/// Which VMs can be stopped to make room for a new deploy when the memory budget is reached.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Eviction {
+    /// Reject the deploy.
+    None,
+    /// Stop the VMs that have already exited but are still deployed.
+    Exited,
+    /// Stop the exited VMs first, then the running ones in the order they were deployed.
+    Oldest,
+}
+
+const MEMORY_BUDGET_EXCEEDED: (u16, &str) = (503, "Memory budget exceeded");
+
 struct VmHandle {
     sender: CommandSender,
     handle: JoinHandle<ExitReason>,
+    limits: Limits,
 }
 struct AppInner {
     next_id: u32,
     instances: HashMap<u32, VmHandle>,
     args: Args,
+    profiles: Profiles,
     spawner: Spawner,
+    tls_client_identity: Option<TlsClientIdentity>,
+    cache: &'static LruCache,
 }
 
 struct App {
@@ -41,13 +65,22 @@ struct App {
 }
 
 impl App {
-    fn new(spawner: Spawner, args: Args) -> Self {
+    fn new(
+        spawner: Spawner,
+        args: Args,
+        profiles: Profiles,
+        tls_client_identity: Option<TlsClientIdentity>,
+    ) -> Self {
+        let cache = Box::leak(Box::new(LruCache::new(args.cache_budget_bytes)));
         Self {
             inner: Mutex::new(AppInner {
                 instances: HashMap::new(),
                 next_id: 0,
                 spawner,
                 args,
+                profiles,
+                tls_client_identity,
+                cache,
             }),
         }
     }
@@ -73,41 +106,174 @@ impl App {
     async fn run_wasm(
         &self,
         wasm_bytes: Vec<u8>,
-        weight: u32,
+        profile: Option<&str>,
+        overrides: Profile,
+        warmup_secs: Option<u64>,
+        shared_cache: Option<&str>,
         id: Option<u32>,
-    ) -> Result<u32, &'static str> {
+    ) -> Result<u32, (u16, &'static str)> {
         let mut inner = self.inner.lock().await;
+        let defaults = Limits {
+            gas_per_breath: inner.args.gas_per_breath,
+            max_memory_pages: inner.args.max_memory_pages,
+            soft_memory_pages: inner.args.soft_memory_pages,
+            weight: 1,
+            fuel: None,
+            max_outbound_connections: inner.args.max_outbound_connections,
+            max_response_bytes: inner.args.max_response_bytes,
+        };
+        let limits = inner
+            .profiles
+            .resolve(defaults, profile, &overrides)
+            .map_err(|reason| (400, reason))?;
+        inner.admit(limits.max_memory_pages).await?;
+        let args = &inner.args;
+        let log_limit = (args.query_log_max_bytes.is_some() || args.query_log_max_lines.is_some())
+            .then(|| LogLimit {
+                max_bytes: args.query_log_max_bytes.unwrap_or(usize::MAX),
+                max_lines: args.query_log_max_lines.unwrap_or(usize::MAX),
+            });
+        let warmup = warmup_secs.map(|secs| WarmupConfig {
+            timeout: Duration::from_secs(secs),
+            max_pending: inner.args.warmup_max_pending,
+        });
         let id = match id {
             Some(id) => id,
             None => inner.next_id,
         };
         inner.next_id = id
             .checked_add(1)
-            .ok_or("Too many instances")?
+            .ok_or((500, "Too many instances"))?
             .max(inner.next_id);
 
         let mut vmid = [0u8; 32];
 
         vmid[0..4].copy_from_slice(&id.to_be_bytes());
 
-        println!("VM {id} running...");
-        let (sender, handle) = inner
+        println!("VM {id} running with {limits:?}...");
+        let mut spawner = inner
             .spawner
+            .clone()
+            .with_outbound_limits(OutboundLimits {
+                max_in_flight: limits.max_outbound_connections,
+                max_queued: inner.args.max_queued_outbound_connections,
+            })
+            .with_body_limits(BodyLimits {
+                max_response_bytes: limits.max_response_bytes,
+                max_request_body_bytes: inner.args.max_request_body_bytes,
+            })
+            .with_pubsub_namespaces(inner.args.pubsub_namespaces.clone());
+        if let Some(warmup) = warmup {
+            spawner = spawner.with_warmup(warmup);
+        }
+        if let Some(fuel) = limits.fuel {
+            spawner = spawner.with_fuel_policy(fuel);
+        }
+        if let Some(log_limit) = log_limit {
+            spawner = spawner.with_log_limit(log_limit);
+        }
+        if let Some(pages) = limits.soft_memory_pages {
+            spawner = spawner.with_soft_memory_pages(pages);
+        }
+        if let Some(identity) = inner.tls_client_identity.clone() {
+            spawner = spawner.with_tls_client_identity(identity);
+        }
+        if let Some(name) = shared_cache {
+            spawner = spawner.with_shared_cache(name.into());
+        }
+        let (sender, handle) = spawner
             .start(
                 &wasm_bytes,
-                inner.args.max_memory_pages,
+                limits.max_memory_pages,
                 vmid,
-                inner.args.gas_per_breath,
-                crate::simple_cache(),
-                weight,
+                limits.gas_per_breath,
+                inner.cache,
+                limits.weight,
                 None,
             )
-            .unwrap();
-        inner.instances.insert(id, VmHandle { sender, handle });
+            .map_err(|err| {
+                warn!("Rejected deploy of VM {id}: {err}");
+                if err.is::<ModuleLimitExceeded>() {
+                    (413, "Module over the limits")
+                } else {
+                    (500, "Failed to start VM")
+                }
+            })?;
+        inner.instances.insert(
+            id,
+            VmHandle {
+                sender,
+                handle,
+                limits,
+            },
+        );
         Ok(id)
     }
 }
 
+impl AppInner {
+    fn reserved_memory_pages(&self) -> u64 {
+        self.instances
+            .values()
+            .map(|vm| vm.limits.max_memory_pages as u64)
+            .sum()
+    }
+
+    fn eviction_candidate(&self) -> Option<u32> {
+        let exited = self
+            .instances
+            .iter()
+            .filter(|(_, vm)| vm.handle.is_finished())
+            .map(|(id, _)| *id)
+            .min();
+        match self.args.memory_eviction {
+            Eviction::None => None,
+            Eviction::Exited => exited,
+            Eviction::Oldest => exited.or_else(|| self.instances.keys().min().copied()),
+        }
+    }
+
+    /// Make sure a VM reserving `pages` fits in the memory budget, evicting others if allowed.
+    async fn admit(&mut self, pages: u32) -> Result<(), (u16, &'static str)> {
+        let Some(budget) = self.args.memory_budget_pages else {
+            return Ok(());
+        };
+        let pages = pages as u64;
+        if pages > budget {
+            return Err(MEMORY_BUDGET_EXCEEDED);
+        }
+        while self.reserved_memory_pages() + pages > budget {
+            let Some(id) = self.eviction_candidate() else {
+                warn!("Rejected deploy of {pages} pages over the memory budget");
+                return Err(MEMORY_BUDGET_EXCEEDED);
+            };
+            let vm = self.instances.remove(&id).expect("Candidate must exist");
+            info!(
+                "Evicting VM {id} to free {} pages",
+                vm.limits.max_memory_pages
+            );
+            stop_vm(id, vm, None).await;
+        }
+        Ok(())
+    }
+}
+
+/// Stop the VM, giving its program `timeout` to exit by itself if given.
+async fn stop_vm(id: u32, vm: VmHandle, timeout: Option<Duration>) {
+    info!("Stopping VM {id}...");
+    let cmd = match timeout {
+        Some(timeout) => Command::Shutdown { timeout },
+        None => Command::Stop,
+    };
+    if let Err(err) = vm.sender.send(cmd).await {
+        warn!("Failed to send stop command to the VM: {err:?}");
+    }
+    match vm.handle.await {
+        Ok(reason) => info!("VM exited: {reason:?}"),
+        Err(err) => warn!("Failed to wait VM exit: {err:?}"),
+    }
+}
+
 async fn read_data(data: Data<'_>) -> Option<Vec<u8>> {
     let stream = data.open(10000.mebibytes());
     let data = stream.into_bytes().await.ok()?;
@@ -205,17 +371,17 @@ async fn connect_vm_post<'r>(
     id: u32,
     path: PathBuf,
     body: Data<'r>,
-) -> Result<StreamResponse, (Status, String)> {
+) -> Result<StreamResponse<'r>, (Status, String)> {
     connect_vm(app, head, id, path, Some(body)).await
 }
 
 #[get("/sidevm/<id>/<path..>")]
-async fn connect_vm_get<'r>(
+async fn connect_vm_get(
     app: &State<App>,
     head: RequestInfo,
     id: u32,
     path: PathBuf,
-) -> Result<StreamResponse, (Status, String)> {
+) -> Result<StreamResponse<'static>, (Status, String)> {
     connect_vm(app, head, id, path, None).await
 }
 
@@ -225,7 +391,7 @@ async fn connect_vm<'r>(
     id: u32,
     path: PathBuf,
     body: Option<Data<'r>>,
-) -> Result<StreamResponse, (Status, String)> {
+) -> Result<StreamResponse<'r>, (Status, String)> {
     let Some(command_tx) = app.sender_for(id).await else {
         return Err((Status::NotFound, Default::default()));
     };
@@ -235,69 +401,214 @@ async fn connect_vm<'r>(
     let result = connect(head, path, body, command_tx).await;
     match result {
         Ok(response) => Ok(response),
+        Err(err) if err.is::<TooManySessions>() || err.is::<VmPaused>() => {
+            Err((Status::ServiceUnavailable, err.to_string()))
+        }
         Err(err) => Err((Status::InternalServerError, err.to_string())),
     }
 }
 
-#[post("/run?<weight>&<id>", data = "<data>")]
+#[allow(clippy::too_many_arguments)]
+#[post(
+    "/run?<weight>&<id>&<profile>&<gas_per_breath>&<max_memory_pages>&<soft_memory_pages>&<warmup_secs>&<shared_cache>",
+    data = "<data>"
+)]
 async fn run(
     app: &State<App>,
     weight: Option<u32>,
     id: Option<u32>,
+    profile: Option<&str>,
+    gas_per_breath: Option<u64>,
+    max_memory_pages: Option<u32>,
+    soft_memory_pages: Option<u32>,
+    warmup_secs: Option<u64>,
+    shared_cache: Option<&str>,
     data: Data<'_>,
 ) -> Result<String, Custom<&'static str>> {
     if let Some(id) = id {
         if let Some(handle) = app.take_handle(id).await {
-            info!("Stopping VM {id}...");
-            if let Err(err) = handle.sender.send(Command::Stop).await {
-                warn!("Failed to send stop command to the VM: {err:?}");
-            }
-            match handle.handle.await {
-                Ok(reason) => info!("VM exited: {reason:?}"),
-                Err(err) => warn!("Failed to wait VM exit: {err:?}"),
-            }
+            stop_vm(id, handle, None).await;
         };
     }
     let code = read_data(data)
         .await
         .ok_or(Custom(Status::BadRequest, "No message payload"))?;
+    let overrides = Profile {
+        gas_per_breath,
+        max_memory_pages,
+        soft_memory_pages,
+        weight,
+        fuel: None,
+        max_outbound_connections: None,
+        max_response_bytes: None,
+    };
     let id = app
-        .run_wasm(code, weight.unwrap_or(1), id)
+        .run_wasm(code, profile, overrides, warmup_secs, shared_cache, id)
         .await
-        .map_err(|reason| Custom(Status::InternalServerError, reason))?;
+        .map_err(|(code, reason)| Custom(Status { code }, reason))?;
     Ok(id.to_string())
 }
 
-#[post("/stop?<id>")]
-async fn stop(app: &State<App>, id: u32) -> Result<(), Custom<&'static str>> {
+#[post("/stop?<id>&<timeout_ms>")]
+async fn stop(
+    app: &State<App>,
+    id: u32,
+    timeout_ms: Option<u64>,
+) -> Result<(), Custom<&'static str>> {
     let Some(handle) = app.take_handle(id).await else {
         return Err(Custom(Status::NotFound, "Instance not found"));
     };
-    info!("Stopping VM {id}...");
-    if let Err(err) = handle.sender.send(Command::Stop).await {
-        warn!("Failed to send stop command to the VM: {err:?}");
-    }
-    match handle.handle.await {
-        Ok(reason) => info!("VM exited: {reason:?}"),
-        Err(err) => warn!("Failed to wait VM exit: {err:?}"),
-    }
+    stop_vm(id, handle, timeout_ms.map(Duration::from_millis)).await;
     Ok(())
 }
 
+#[post("/pause?<id>")]
+async fn pause(app: &State<App>, id: u32) -> Result<(), Custom<&'static str>> {
+    app.send(id, Command::Pause)
+        .await
+        .map_err(|(code, reason)| Custom(Status { code }, reason))
+}
+
+#[post("/resume?<id>")]
+async fn resume(app: &State<App>, id: u32) -> Result<(), Custom<&'static str>> {
+    app.send(id, Command::Resume)
+        .await
+        .map_err(|(code, reason)| Custom(Status { code }, reason))
+}
+
 #[get("/info")]
 async fn info(app: &State<App>) -> String {
     let inner = app.inner.lock().await;
+    let limits = inner
+        .instances
+        .iter()
+        .map(|(id, handle)| {
+            let limits = &handle.limits;
+            (
+                id.to_string(),
+                serde_json::json!({
+                    "gas_per_breath": limits.gas_per_breath,
+                    "max_memory_pages": limits.max_memory_pages,
+                    "soft_memory_pages": limits.soft_memory_pages,
+                    "weight": limits.weight,
+                    "fuel": limits.fuel,
+                    "max_outbound_connections": limits.max_outbound_connections,
+                    "max_response_bytes": limits.max_response_bytes,
+                }),
+            )
+        })
+        .collect::<serde_json::Map<_, _>>();
     serde_json::json!({
         "running": sidevm_host_runtime::vm_count(),
         "deployed": inner.instances.len(),
         "ids": inner.instances.keys().cloned().collect::<Vec<_>>(),
+        "profiles": inner.profiles.names().collect::<Vec<_>>(),
+        "limits": limits,
+        "memory": {
+            "budget_pages": inner.args.memory_budget_pages,
+            "reserved_pages": inner.reserved_memory_pages(),
+        },
+        "cache": {
+            "budget_bytes": inner.args.cache_budget_bytes,
+            "used_bytes": inner.cache.total_usage(),
+        },
     })
     .to_string()
 }
 
+#[get("/debug/resources?<redact>")]
+async fn dump_resources(app: &State<App>, redact: Option<bool>) -> String {
+    let redact = redact.unwrap_or(true);
+    let senders = {
+        let inner = app.inner.lock().await;
+        inner
+            .instances
+            .iter()
+            .map(|(id, handle)| (*id, handle.sender.clone()))
+            .collect::<Vec<_>>()
+    };
+    let mut vms = serde_json::Map::new();
+    for (id, sender) in senders {
+        let (reply_tx, rx) = tokio::sync::oneshot::channel();
+        if sender
+            .send(Command::Dump { redact, reply_tx })
+            .await
+            .is_err()
+        {
+            continue;
+        }
+        // The VM may not be started yet or may have exited.
+        let Ok(dump) = rx.await else {
+            continue;
+        };
+        vms.insert(
+            id.to_string(),
+            serde_json::to_value(dump).unwrap_or_default(),
+        );
+    }
+    serde_json::Value::Object(vms).to_string()
+}
+
+#[cfg(feature = "ocall-trace")]
+#[get("/debug/ocalls?<id>")]
+async fn dump_ocalls(id: u32) -> String {
+    let mut vmid = [0u8; 32];
+    vmid[0..4].copy_from_slice(&id.to_be_bytes());
+    let trace = sidevm_host_runtime::ocall_trace(&vmid);
+    serde_json::to_string(&trace).unwrap_or_default()
+}
+
 pub async fn serve(args: Args) -> anyhow::Result<()> {
     let (tx, mut rx) = tokio::sync::mpsc::channel(10);
     let (run, spawner) = sidevm::service(args.workers, tx);
+    let spawner = spawner.with_fuel_exhausted_handler(Arc::new(|id, gas_used| {
+        warn!(vmid = %ShortId(id), gas_used, "VM ran out of gas");
+    }));
+    let spawner = spawner.with_session_limits(SessionLimits {
+        max_sessions: args.max_sessions,
+        idle_ttl_secs: args.session_idle_ttl_secs,
+    });
+    let spawner = spawner.with_outbound_limits(OutboundLimits {
+        max_in_flight: args.max_outbound_connections,
+        max_queued: args.max_queued_outbound_connections,
+    });
+    let spawner = spawner.with_body_limits(BodyLimits {
+        max_response_bytes: args.max_response_bytes,
+        max_request_body_bytes: args.max_request_body_bytes,
+    });
+    let spawner = spawner.with_module_limits(ModuleLimits {
+        max_module_bytes: args.max_module_bytes,
+        max_initial_pages: args.max_initial_pages,
+        max_table_elements: args.max_table_elements,
+    });
+    let spawner = spawner.with_memory_ceiling(MemoryCeiling {
+        max_total_pages: args.max_total_memory_pages,
+        evict_idle_after_secs: args.evict_idle_after_secs,
+    });
+    let spawner = if args.deny_private_network {
+        spawner.with_dns(Dns::default().with_policy(public_only))
+    } else {
+        spawner
+    };
+    let spawner = match &args.tls_extra_roots {
+        Some(path) => {
+            let pem = std::fs::read_to_string(path)?;
+            spawner.with_tls_roots(TlsRoots::from_pem(&pem, args.tls_replace_roots)?)
+        }
+        None => spawner,
+    };
+    let spawner = match args.identity_secret {
+        Some(secret) => spawner.with_identity_secret(secret),
+        None => spawner,
+    };
+    let spawner = match args.cpu_budget_ms {
+        Some(budget_ms) => spawner.with_cpu_budget(CpuBudget {
+            budget_ms,
+            window_ms: args.cpu_window_ms,
+            abort: args.cpu_budget_abort,
+        }),
+        None => spawner,
+    };
     tokio::spawn(async move {
         while let Some((id, message)) = rx.recv().await {
             let vmid = ShortId(id);
@@ -315,6 +626,11 @@ pub async fn serve(args: Args) -> anyhow::Result<()> {
                 OutgoingRequest::Output(output) => {
                     info!(%vmid, "Outgoing message: {output:?}");
                 }
+                OutgoingRequest::ReadStorage { key, reply_tx } => {
+                    info!(%vmid, "Storage read request: {key:?}");
+                    let denied: Result<Option<Vec<u8>>, _> = Err(QueryError::PermissionDenied);
+                    _ = reply_tx.send(denied.encode());
+                }
             }
         }
     });
@@ -324,30 +640,125 @@ pub async fn serve(args: Args) -> anyhow::Result<()> {
         });
     });
     let program = args.program.clone();
-    let app = App::new(spawner, args);
+    let profiles = match &args.profiles {
+        Some(path) => Profiles::load(path)?,
+        None => Profiles::default(),
+    };
+    let tls_client_identity = match (&args.tls_client_cert, &args.tls_client_key) {
+        (Some(cert), Some(key)) => Some(TlsClientIdentity {
+            cert: std::fs::read_to_string(cert)?,
+            key: std::fs::read_to_string(key)?,
+        }),
+        (None, None) => None,
+        _ => anyhow::bail!("--tls-client-cert and --tls-client-key must be given together"),
+    };
+    let app = App::new(spawner, args, profiles, tls_client_identity);
     if let Some(program) = program {
         let wasm_codes = std::fs::read(&program)?;
-        app.run_wasm(wasm_codes, 1, None)
+        app.run_wasm(wasm_codes, None, Profile::default(), None, None, None)
             .await
-            .map_err(|reason| anyhow::anyhow!("Failed to run wasm: {}", reason))?;
+            .map_err(|(_, reason)| anyhow::anyhow!("Failed to run wasm: {}", reason))?;
     }
-    let _rocket = rocket::build()
-        .manage(app)
-        .mount(
-            "/",
-            routes![
-                push_message,
-                push_sys_message,
-                push_query,
-                push_query_no_origin,
-                run,
-                stop,
-                connect_vm_get,
-                connect_vm_post,
-                info,
-            ],
-        )
-        .launch()
-        .await?;
+    let rocket = rocket::build().manage(app).mount(
+        "/",
+        routes![
+            push_message,
+            push_sys_message,
+            push_query,
+            push_query_no_origin,
+            run,
+            stop,
+            pause,
+            resume,
+            connect_vm_get,
+            connect_vm_post,
+            info,
+            dump_resources,
+        ],
+    );
+    #[cfg(feature = "ocall-trace")]
+    let rocket = rocket.mount("/", routes![dump_ocalls]);
+    let _rocket = rocket.launch().await?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    fn app_inner(spawner: &Spawner, args: &[&str]) -> AppInner {
+        let args = Args::parse_from(["sidevm-host"].iter().chain(args));
+        App::new(spawner.clone(), args, Profiles::default(), None)
+            .inner
+            .into_inner()
+    }
+
+    async fn vm(max_memory_pages: u32, exited: bool) -> VmHandle {
+        let (sender, mut rx) = tokio::sync::mpsc::channel(1);
+        let handle = tokio::spawn(async move {
+            if !exited {
+                rx.recv().await;
+            }
+            ExitReason::Stopped
+        });
+        while exited && !handle.is_finished() {
+            tokio::task::yield_now().await;
+        }
+        VmHandle {
+            sender,
+            handle,
+            limits: Limits {
+                gas_per_breath: 1_000,
+                max_memory_pages,
+                soft_memory_pages: None,
+                weight: 1,
+                fuel: None,
+                max_outbound_connections: 256,
+                max_response_bytes: None,
+            },
+        }
+    }
+
+    fn ids(inner: &AppInner) -> Vec<u32> {
+        let mut ids: Vec<_> = inner.instances.keys().copied().collect();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn deploys_over_the_memory_budget_evict_by_policy() {
+        let (tx, _rx) = tokio::sync::mpsc::channel(1);
+        let (_run, spawner) = sidevm::service(1, tx);
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let budget = "--memory-budget-pages=100";
+            let mut inner = app_inner(&spawner, &[budget, "--memory-eviction=oldest"]);
+            inner.instances.insert(1, vm(40, false).await);
+            inner.instances.insert(2, vm(40, false).await);
+            inner.admit(30).await.unwrap();
+            assert_eq!(ids(&inner), [2]);
+            // Never fits, so nothing is evicted for it.
+            assert_eq!(inner.admit(101).await, Err(MEMORY_BUDGET_EXCEEDED));
+            assert_eq!(ids(&inner), [2]);
+
+            let mut inner = app_inner(&spawner, &[budget, "--memory-eviction=exited"]);
+            inner.instances.insert(1, vm(40, false).await);
+            inner.instances.insert(2, vm(40, true).await);
+            inner.admit(30).await.unwrap();
+            assert_eq!(ids(&inner), [1]);
+            assert_eq!(inner.admit(70).await, Err(MEMORY_BUDGET_EXCEEDED));
+            assert_eq!(ids(&inner), [1]);
+
+            let mut inner = app_inner(&spawner, &[budget]);
+            inner.instances.insert(1, vm(80, true).await);
+            assert_eq!(inner.admit(30).await, Err(MEMORY_BUDGET_EXCEEDED));
+            inner.admit(20).await.unwrap();
+            assert_eq!(ids(&inner), [1]);
+
+            let mut unlimited = app_inner(&spawner, &[]);
+            unlimited.instances.insert(1, vm(80, false).await);
+            unlimited.admit(u32::MAX).await.unwrap();
+        });
+    }
+}
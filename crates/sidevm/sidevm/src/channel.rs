@@ -2,12 +2,12 @@
 use sidevm_env::{
     messages::{
         AccountId, HttpHead, HttpRequest as MsgHttpReqeust, HttpResponseHead, QueryRequest,
-        SystemMessage,
+        SystemMessage, TopicMessage,
     },
     InputChannel, OcallError,
 };
 
-use crate::net::TcpStream;
+use crate::{net::TcpStream, websocket::WebSocket};
 
 use super::{ocall, ResourceId};
 use scale::{Decode, Encode, Error as CodecError};
@@ -26,6 +26,9 @@ pub struct Query {
     /// The query payload.
     pub payload: Vec<u8>,
     /// The reply channel. Invoke `send` on this channel to send the reply.
+    ///
+    /// Use `reply_tx.closed()` to get notified when the caller gave up waiting for the reply, so
+    /// that the handling can be aborted.
     pub reply_tx: OneshotSender,
 }
 
@@ -42,6 +45,30 @@ pub struct HttpRequest {
     pub response_tx: ScaleOneshotSender<HttpResponseHead>,
 }
 
+impl HttpRequest {
+    /// Whether the request is a WebSocket handshake.
+    ///
+    /// The `io_stream` of such a request can't be read or written. Accept it with
+    /// [`HttpRequest::accept_websocket`], or reject it by sending a response other than 101.
+    pub fn is_websocket(&self) -> bool {
+        self.head.is_websocket_upgrade()
+    }
+
+    /// Accept a WebSocket handshake. The host completes the handshake and the framing.
+    ///
+    /// Returns `OcallError::UnsupportedOperation` if the request is not a WebSocket handshake.
+    pub fn accept_websocket(self) -> Result<WebSocket, OcallError> {
+        if !self.is_websocket() {
+            return Err(OcallError::UnsupportedOperation);
+        }
+        self.response_tx.send(HttpResponseHead {
+            status: 101,
+            headers: vec![],
+        })?;
+        Ok(WebSocket::new(self.io_stream.into_res_id()))
+    }
+}
+
 /// Sender end of a oneshot channel connected to host-side.
 pub struct ScaleOneshotSender<M> {
     sender: OneshotSender,
@@ -53,6 +80,11 @@ impl<M: Encode> ScaleOneshotSender<M> {
     pub fn send(self, msg: M) -> Result<(), OcallError> {
         self.sender.send(&msg.encode())
     }
+
+    /// Wait until the receiver end is dropped.
+    pub fn closed(&self) -> Closed<'_> {
+        self.sender.closed()
+    }
 }
 
 impl<M: Encode> From<OneshotSender> for ScaleOneshotSender<M> {
@@ -78,6 +110,30 @@ impl OneshotSender {
     pub fn send(self, data: &[u8]) -> Result<(), OcallError> {
         ocall::oneshot_send(self.res_id.0, data)
     }
+
+    /// Wait until the receiver end is dropped, e.g. the query was cancelled or timed out.
+    pub fn closed(&self) -> Closed<'_> {
+        Closed { tx: self }
+    }
+}
+
+/// The future returned by `OneshotSender::closed`.
+pub struct Closed<'a> {
+    tx: &'a OneshotSender,
+}
+
+impl Future for Closed<'_> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let waker_id = crate::env::tasks::intern_waker(cx.waker().clone());
+        match ocall::poll(waker_id, self.tx.res_id.0) {
+            Ok(_) | Err(OcallError::EndOfFile) => Poll::Ready(()),
+            // Hosts not supporting it never report the receiver closed.
+            Err(OcallError::Pending | OcallError::UnsupportedOperation) => Poll::Pending,
+            Err(err) => panic!("unexpected error: {err:?}"),
+        }
+    }
 }
 
 /// Receiver end of a channel connected to host-side.
@@ -134,6 +190,20 @@ impl Future for Next<'_, SystemMessage> {
     }
 }
 
+impl Future for Next<'_, TopicMessage> {
+    type Output = Option<Result<TopicMessage, CodecError>>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let waker_id = crate::env::tasks::intern_waker(cx.waker().clone());
+        match ocall::poll(waker_id, self.ch.res_id.0) {
+            Ok(msg) => Poll::Ready(Some(TopicMessage::decode(&mut &msg[..]))),
+            Err(OcallError::EndOfFile) => Poll::Ready(None), // The tx dropped
+            Err(OcallError::Pending) => Poll::Pending,
+            Err(err) => panic!("unexpected error: {err:?}"),
+        }
+    }
+}
+
 impl Future for Next<'_, Query> {
     type Output = Option<Query>;
 
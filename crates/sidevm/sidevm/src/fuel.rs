@@ -0,0 +1,15 @@
+//! The limits of the metering this instance runs under.
+//!
+//! A program can check what it has left to adapt its workload, e.g. to stop batching or to save
+//! its progress before it runs out of gas.
+
+use sidevm_env::OcallError;
+
+use crate::ocall;
+
+pub use sidevm_env::messages::FuelStatus;
+
+/// The gas, fuel and instructions this instance has left.
+pub fn status() -> Result<FuelStatus, OcallError> {
+    ocall::fuel_status()
+}
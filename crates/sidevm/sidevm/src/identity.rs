@@ -0,0 +1,24 @@
+//! The identity key given to this instance by the host.
+//!
+//! The key is stable for the same program deployed with the same vmid on the same host, and
+//! distinct across instances, so it can be used to sign the outputs of the program. The secret
+//! key stays in the host, only the public key can be retrieved.
+
+use sidevm_env::OcallError;
+
+use crate::ocall;
+
+/// The sr25519 public key of this instance.
+pub fn public_key() -> Result<[u8; 32], OcallError> {
+    ocall::identity_public_key()
+}
+
+/// Sign the message with the identity key, in the `substrate` signing context.
+pub fn sign(message: &[u8]) -> Result<[u8; 64], OcallError> {
+    ocall::identity_sign(message)
+}
+
+/// Verify a signature made with the identity key of this instance.
+pub fn verify(message: &[u8], signature: &[u8; 64]) -> Result<bool, OcallError> {
+    ocall::identity_verify(message, signature)
+}
@@ -13,9 +13,13 @@ pub use env::tasks as task;
 
 pub mod channel;
 pub mod exec;
-pub mod net;
-pub mod time;
+pub mod fuel;
+pub mod identity;
 pub mod local_contract;
 pub mod logger;
+pub mod net;
+pub mod pubsub;
+pub mod time;
+pub mod websocket;
 
 mod res_id;
@@ -43,8 +43,23 @@ pub async fn query_pink(contract_id: [u8; 32], payload: Vec<u8>) -> Result<Vec<u
     let result = Result::<QueryResponse, QueryError>::decode(&mut &reply[..])
         .map_err(|_| QueryError::DecodeError)??;
     match result {
-        QueryResponse::OutputWithGasEstimation { output, .. } | QueryResponse::SimpleOutput(output) => {
-            Ok(output)
-        }
+        QueryResponse::OutputWithGasEstimation { output, .. }
+        | QueryResponse::SimpleOutput(output) => Ok(output),
     }
 }
+
+/// Read a storage key of the contract this sidevm instance belongs to.
+///
+/// The contract must grant the read access to the key with `pink::set_sidevm_readable_storage`
+/// before deploying the sidevm instance, otherwise `QueryError::PermissionDenied` is returned.
+/// Shares the concurrency limitation of [`query_pink`].
+pub async fn read_contract_storage(key: &[u8]) -> Result<Option<Vec<u8>>, QueryError> {
+    let res = ocall::read_contract_storage(key)?;
+    let rx = channel::Receiver::<Vec<u8>>::new(res.into());
+
+    let Some(reply) = rx.next().await else {
+        return Err(sidevm_env::OcallError::EndOfFile.into());
+    };
+    Result::<Option<Vec<u8>>, QueryError>::decode(&mut &reply[..])
+        .map_err(|_| QueryError::DecodeError)?
+}
@@ -20,12 +20,14 @@ pub struct TcpListener {
 #[derive(Debug)]
 pub struct TcpConnector {
     res: Result<ResourceId, env::OcallError>,
+    alpn: bool,
 }
 
 /// A connected TCP socket.
 #[derive(Debug)]
 pub struct TcpStream {
     res_id: ResourceId,
+    alpn_protocol: Option<Vec<u8>>,
 }
 
 /// Future returned by `TcpListener::accept`.
@@ -80,13 +82,20 @@ impl Future for TcpConnector {
     fn poll(self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Self::Output> {
         use env::OcallError;
 
-        let res_id = match &self.get_mut().res {
+        let this = self.get_mut();
+        let res_id = match &this.res {
             Ok(res_id) => res_id,
             Err(err) => return Poll::Ready(Err(*err)),
         };
 
         match ocall::poll_res(env::tasks::intern_waker(ctx.waker().clone()), res_id.0) {
-            Ok(res_id) => Poll::Ready(Ok(TcpStream::new(ResourceId(res_id)))),
+            Ok(res_id) => {
+                let mut stream = TcpStream::new(ResourceId(res_id));
+                if this.alpn {
+                    stream.alpn_protocol = ocall::tls_alpn_protocol(res_id)?;
+                }
+                Poll::Ready(Ok(stream))
+            }
             Err(OcallError::Pending) => Poll::Pending,
             Err(err) => Poll::Ready(Err(err)),
         }
@@ -96,7 +105,10 @@ impl Future for TcpConnector {
 impl TcpStream {
     /// Create a new TcpStream from a resource ID.
     pub fn new(res_id: ResourceId) -> Self {
-        Self { res_id }
+        Self {
+            res_id,
+            alpn_protocol: None,
+        }
     }
 
     /// Initiate a TCP connection to a remote host.
@@ -107,7 +119,27 @@ impl TcpStream {
             ocall::tcp_connect(host, port)
         };
         let res = res.map(ResourceId);
-        TcpConnector { res }
+        TcpConnector { res, alpn: false }
+    }
+
+    /// Initiate a TLS connection to a remote host, offering the given protocols via ALPN in
+    /// order of preference.
+    pub fn connect_tls_with_alpn(host: &str, port: u16, protocols: Vec<Vec<u8>>) -> TcpConnector {
+        let config = env::tls::TlsClientConfig::V1 {
+            alpn_protocols: protocols,
+        };
+        let res = ocall::tcp_connect_tls(host.into(), port, config).map(ResourceId);
+        TcpConnector { res, alpn: true }
+    }
+
+    pub(crate) fn into_res_id(self) -> ResourceId {
+        self.res_id
+    }
+
+    /// The protocol negotiated via ALPN, if the stream was connected with
+    /// [`TcpStream::connect_tls_with_alpn`] and the server picked one.
+    pub fn alpn_protocol(&self) -> Option<&[u8]> {
+        self.alpn_protocol.as_deref()
     }
 }
 
@@ -149,7 +181,11 @@ mod impl_hyper {
 
     impl Connection for TcpStream {
         fn connected(&self) -> Connected {
-            Connected::new()
+            if self.alpn_protocol() == Some(b"h2") {
+                Connected::new().negotiated_h2()
+            } else {
+                Connected::new()
+            }
         }
     }
 
@@ -161,13 +197,25 @@ mod impl_hyper {
     }
 
     /// An HTTP/HTTPS Connector for hyper working under sidevm.
+    ///
+    /// HTTPS connections negotiate HTTP/2 via ALPN, falling back to HTTP/1.1 if the server
+    /// doesn't support it. A hyper `Client` pools the connections by authority, so the requests
+    /// to the same HTTP/2 server are multiplexed over a single connection.
     #[derive(Clone, Default, Debug)]
-    pub struct HttpConnector;
+    pub struct HttpConnector {
+        http1_only: bool,
+    }
 
     impl HttpConnector {
         /// Create a new HttpConnector.
         pub fn new() -> Self {
-            Self
+            Self::default()
+        }
+
+        /// Only speak HTTP/1.1, not offering HTTP/2 to the servers. Useful for debugging.
+        pub fn http1_only(mut self, enabled: bool) -> Self {
+            self.http1_only = enabled;
+            self
         }
     }
 
@@ -187,7 +235,12 @@ mod impl_hyper {
                 .unwrap_or("")
                 .trim_matches(|c| c == '[' || c == ']');
             let port = dst.port_u16().unwrap_or(if is_https { 443 } else { 80 });
-            TcpStream::connect(host, port, is_https)
+            if is_https && !self.http1_only {
+                let protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+                TcpStream::connect_tls_with_alpn(host, port, protocols)
+            } else {
+                TcpStream::connect(host, port, is_https)
+            }
         }
     }
 
@@ -0,0 +1,25 @@
+//! Publish/subscribe messaging between the sidevm instances running on the same host.
+//!
+//! Topics are named `<namespace>/<name>`, and only the namespaces granted by the host are
+//! accessible. Otherwise `OcallError::PermissionDenied` is returned.
+
+use sidevm_env::{messages::TopicMessage, OcallError};
+
+use crate::{channel::Receiver, ocall};
+
+/// A subscription to a topic. Each item is either a message or a marker of the messages dropped
+/// because the subscriber didn't keep up.
+pub type Subscriber = Receiver<TopicMessage>;
+
+/// Publish a message to a topic.
+///
+/// Never waits for the subscribers. Slow ones miss the message instead.
+pub fn publish(topic: &str, message: &[u8]) -> Result<(), OcallError> {
+    ocall::publish(topic, message)
+}
+
+/// Subscribe to a topic. The subscription ends when the returned receiver is dropped.
+pub fn subscribe(topic: &str) -> Result<Subscriber, OcallError> {
+    let res_id = ocall::subscribe(topic)?;
+    Ok(Receiver::new(res_id.into()))
+}
@@ -0,0 +1,59 @@
+//! WebSocket connections accepted by the host.
+//!
+//! The host does the handshake and the framing, answering the pings and the closing handshake by
+//! itself, so only the payloads of the messages reach the program. See
+//! [`HttpRequest::accept_websocket`](crate::channel::HttpRequest::accept_websocket).
+
+use std::future::poll_fn;
+use std::task::Poll;
+
+pub use sidevm_env::messages::WsMessage as Message;
+use sidevm_env::OcallError;
+
+use crate::{env::tasks::intern_waker, ocall, ResourceId};
+
+/// A WebSocket connection. Dropping it closes the connection.
+#[derive(Debug)]
+pub struct WebSocket {
+    res_id: ResourceId,
+}
+
+impl WebSocket {
+    pub(crate) fn new(res_id: ResourceId) -> Self {
+        Self { res_id }
+    }
+
+    /// Receive the next message, or `None` once the connection is closed.
+    pub async fn recv(&self) -> Result<Option<Message>, OcallError> {
+        poll_fn(|cx| {
+            let waker_id = intern_waker(cx.waker().clone());
+            match ocall::ws_poll_recv(waker_id, self.res_id.0) {
+                Ok(message) => Poll::Ready(Ok(Some(message))),
+                Err(OcallError::EndOfFile) => Poll::Ready(Ok(None)),
+                Err(OcallError::Pending) => Poll::Pending,
+                Err(err) => Poll::Ready(Err(err)),
+            }
+        })
+        .await
+    }
+
+    /// Send a message, waiting until it is written to the connection.
+    pub async fn send(&self, message: Message) -> Result<(), OcallError> {
+        poll_fn(|cx| {
+            let waker_id = intern_waker(cx.waker().clone());
+            match ocall::ws_poll_send(waker_id, self.res_id.0, message.clone()) {
+                Err(OcallError::Pending) => Poll::Pending,
+                result => Poll::Ready(result),
+            }
+        })
+        .await?;
+        poll_fn(|cx| {
+            let waker_id = intern_waker(cx.waker().clone());
+            match ocall::ws_poll_flush(waker_id, self.res_id.0) {
+                Err(OcallError::Pending) => Poll::Pending,
+                result => Poll::Ready(result),
+            }
+        })
+        .await
+    }
+}
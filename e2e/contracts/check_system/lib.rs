@@ -8,7 +8,7 @@ use pink_extension as pink;
 mod check_system {
     use super::pink;
     use alloc::vec::Vec;
-    use pink::chain_extension::{JsCode, JsValue};
+    use pink::chain_extension::{HttpRequest, HttpRetryPolicy, JsBudget, JsCode, JsValue};
     use pink::system::{ContractDeposit, DriverError, Result, SystemRef};
     use pink::{PinkEnvironment, WorkerId};
 
@@ -81,6 +81,16 @@ mod check_system {
             pink::ext().cache_set(&key, &value).is_ok()
         }
 
+        /// Set the value expiring after `ttl` seconds. A TTL of 0 removes the key.
+        #[ink(message)]
+        pub fn cache_set_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: u64) -> bool {
+            if pink::ext().cache_set(&key, &value).is_err() {
+                return false;
+            }
+            pink::ext().cache_set_expiration(&key, ttl);
+            true
+        }
+
         #[ink(message)]
         pub fn cache_get(&self, key: Vec<u8>) -> Option<Vec<u8>> {
             pink::ext().cache_get(&key)
@@ -130,6 +140,19 @@ mod check_system {
 
         #[ink(message)]
         pub fn batch_http_get(&self, urls: Vec<String>, timeout_ms: u64) -> Vec<(u16, String)> {
+            self.batch_http_get_with_headers(urls, timeout_ms)
+                .into_iter()
+                .map(|(code, _headers, body)| (code, body))
+                .collect()
+        }
+
+        /// Like `batch_http_get`, with the headers of each response.
+        #[ink(message)]
+        pub fn batch_http_get_with_headers(
+            &self,
+            urls: Vec<String>,
+            timeout_ms: u64,
+        ) -> Vec<(u16, Vec<(String, String)>, String)> {
             pink::ext()
                 .batch_http_request(
                     urls.into_iter()
@@ -144,29 +167,115 @@ mod check_system {
                 )
                 .unwrap()
                 .into_iter()
+                .map(|result| match result {
+                    Ok(response) => (
+                        response.status_code,
+                        response.headers,
+                        String::from_utf8(response.body).unwrap_or_default(),
+                    ),
+                    Err(err) => {
+                        let (code, body) = http_error(err);
+                        (code, Vec::new(), body)
+                    }
+                })
+                .collect()
+        }
+
+        /// Like `batch_http_get`, giving up each request after `request_timeout_ms`.
+        #[ink(message)]
+        pub fn batch_http_get_with_timeouts(
+            &self,
+            urls: Vec<String>,
+            timeout_ms: u64,
+            request_timeout_ms: Option<u64>,
+        ) -> Vec<(u16, String)> {
+            pink::ext()
+                .batch_http_request_with_timeouts(
+                    urls.into_iter()
+                        .map(|url| pink::chain_extension::HttpRequest {
+                            url,
+                            method: "GET".into(),
+                            headers: Default::default(),
+                            body: Default::default(),
+                        })
+                        .collect(),
+                    timeout_ms,
+                    request_timeout_ms,
+                )
+                .unwrap()
+                .into_iter()
                 .map(|result| match result {
                     Ok(response) => (
                         response.status_code,
                         String::from_utf8(response.body).unwrap_or_default(),
                     ),
-                    Err(err) => (524, alloc::format!("Error: {err:?}")),
+                    Err(err) => http_error(err),
                 })
                 .collect()
         }
+
         #[ink(message)]
         pub fn http_get(&self, url: String) -> (u16, String) {
+            let (code, _headers, body) = self.http_get_with_headers(url);
+            (code, body)
+        }
+
+        /// Like `http_get`, with the headers of the response. The headers over the size limit of
+        /// the worker are dropped, and `x-pink-headers-truncated` is added in their place.
+        #[ink(message)]
+        pub fn http_get_with_headers(&self, url: String) -> (u16, Vec<(String, String)>, String) {
+            self.http_request("GET".into(), url, Vec::new(), Vec::new())
+        }
+
+        /// Send an HTTP request with any method, headers and body, returning the status code, the
+        /// headers and the body of the response.
+        #[ink(message)]
+        pub fn http_request(
+            &self,
+            method: String,
+            url: String,
+            headers: Vec<(String, String)>,
+            body: Vec<u8>,
+        ) -> (u16, Vec<(String, String)>, String) {
             let response = pink::ext().http_request(pink::chain_extension::HttpRequest {
                 url,
-                method: "GET".into(),
-                headers: Default::default(),
-                body: Default::default(),
+                method,
+                headers,
+                body,
             });
             (
                 response.status_code,
+                response.headers,
                 String::from_utf8(response.body).unwrap_or_default(),
             )
         }
 
+        /// Like `http_get`, retrying the request on the network errors, the timeouts and the
+        /// status codes in `retry_on_status`, up to `max_attempts` in total.
+        #[ink(message)]
+        pub fn http_get_with_retry(
+            &self,
+            url: String,
+            max_attempts: u32,
+            backoff_ms: u64,
+            retry_on_status: Vec<u16>,
+        ) -> (u16, String) {
+            let request = HttpRequest::new(url, "GET", Vec::new(), Vec::new());
+            let policy = HttpRetryPolicy {
+                max_attempts,
+                backoff_ms,
+                retry_on_status,
+                ..Default::default()
+            };
+            match pink::ext().http_request_with_retry(request, policy) {
+                Ok(response) => (
+                    response.status_code,
+                    String::from_utf8(response.body).unwrap_or_default(),
+                ),
+                Err(err) => http_error(err),
+            }
+        }
+
         #[ink(message)]
         pub fn stop_sidevm(&mut self) {
             pink::force_stop_sidevm()
@@ -262,6 +371,32 @@ mod check_system {
         pub fn pink_eval_js(&self, script: String, args: Vec<String>) -> JsValue {
             pink::ext().js_eval(alloc::vec![JsCode::Source(script)], args)
         }
+
+        #[ink(message)]
+        pub fn pink_eval_js_with_budget(
+            &self,
+            script: String,
+            args: Vec<String>,
+            timeout_ms: Option<u64>,
+            max_instructions: Option<u64>,
+        ) -> JsValue {
+            let budget = JsBudget {
+                timeout_ms,
+                max_instructions,
+            };
+            pink::ext().js_eval_with_budget(alloc::vec![JsCode::Source(script)], args, budget)
+        }
+
+        #[ink(message)]
+        pub fn pink_eval_js_with_modules(
+            &self,
+            script: String,
+            modules: Vec<(String, String)>,
+            args: Vec<String>,
+        ) -> JsValue {
+            let codes = alloc::vec![JsCode::Modules(modules), JsCode::Source(script)];
+            pink::ext().js_eval(codes, args)
+        }
     }
 
     impl ContractDeposit for CheckSystem {
@@ -279,6 +414,19 @@ mod check_system {
         }
     }
 
+    /// The status code and body reported for a failed request of a batch, or a retried one.
+    ///
+    /// A request over its own timeout is reported as `524`, like the other failures, while one
+    /// still pending at the deadline of the batch is reported as `504`.
+    fn http_error(err: pink::chain_extension::HttpRequestError) -> (u16, String) {
+        use pink::chain_extension::HttpRequestError::BatchDeadlineExceeded;
+        let code = match err {
+            BatchDeadlineExceeded => 504,
+            _ => 524,
+        };
+        (code, alloc::format!("Error: {err:?}"))
+    }
+
     #[cfg(test)]
     mod tests {
         use drink::session::Session;
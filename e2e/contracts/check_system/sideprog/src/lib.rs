@@ -38,9 +38,13 @@ async fn main() {
         match request {
             Request::Ping => query.reply_tx.send(b"pong").expect("failed to send reply"),
             Request::Callback { call_data } => {
-                let reply = local_contract::query_pink(vmid, call_data)
-                    .await
-                    .expect("query failed");
+                let reply = tokio::select! {
+                    reply = local_contract::query_pink(vmid, call_data) => reply.expect("query failed"),
+                    _ = query.reply_tx.closed() => {
+                        info!("The query was cancelled by the caller");
+                        continue;
+                    }
+                };
                 info!("Sending query result: {:?}", reply);
                 query.reply_tx.send(&reply).expect("failed to send reply");
             }
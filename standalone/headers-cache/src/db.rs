@@ -1,8 +1,12 @@
 use crate::BlockNumber;
 
-use anyhow::Result;
-use rocksdb::DB;
-use std::{mem::size_of, sync::Arc};
+use anyhow::{bail, Context as _, Result};
+use futures::stream::{self, BoxStream, StreamExt};
+use pherry::headers_cache::GenesisBlockInfo;
+use rocksdb::{Options, WriteBatch, DB};
+use scale::Decode;
+use std::{fmt, mem::size_of, sync::Arc};
+use tokio::sync::mpsc;
 
 use serde::{Deserialize, Serialize};
 
@@ -20,6 +24,10 @@ pub struct Metadata {
     pub higest: Counters,
     #[serde(default)]
     pub checked: Counters,
+    /// The lowest block of each kind that is available, raised by pruning and lowered by grabbing
+    /// backward.
+    #[serde(default)]
+    pub lowest: Counters,
 }
 
 macro_rules! update_field {
@@ -50,8 +58,101 @@ impl Metadata {
     }
 }
 
+const METADATA_KEY: &[u8] = b"m-metadata";
+
+/// Upper bound of the records buffered ahead by a range reader.
+pub const MAX_READ_AHEAD: usize = 4096;
+
 #[derive(Clone)]
-pub struct CacheDB(Arc<DB>);
+pub struct CacheDB {
+    db: Arc<DB>,
+    verify_checksums: bool,
+    compression: Option<i32>,
+}
+
+/// A stored record whose checksum doesn't match its payload.
+#[derive(Debug)]
+pub struct CorruptRecord {
+    pub block: BlockNumber,
+}
+
+impl fmt::Display for CorruptRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Checksum mismatch of the record at {}", self.block)
+    }
+}
+
+impl std::error::Error for CorruptRecord {}
+
+/// Prefix of the relaychain headers stored with a checksum.
+///
+/// The headers written by older versions are stored under `b'h'` without any checksum. They are
+/// still served, and get replaced once rewritten.
+const CHECKSUMMED_HEADER: u8 = b'H';
+const CHECKSUM_SIZE: usize = size_of::<u32>();
+
+fn with_checksum(value: &[u8]) -> Vec<u8> {
+    let mut record = Vec::with_capacity(CHECKSUM_SIZE + value.len());
+    record.extend_from_slice(&crc32fast::hash(value).to_be_bytes());
+    record.extend_from_slice(value);
+    record
+}
+
+fn strip_checksum(
+    block: BlockNumber,
+    mut record: Vec<u8>,
+    verify: bool,
+) -> Result<Vec<u8>, CorruptRecord> {
+    if record.len() < CHECKSUM_SIZE {
+        return Err(CorruptRecord { block });
+    }
+    let payload = record.split_off(CHECKSUM_SIZE);
+    if verify && record[..] != crc32fast::hash(&payload).to_be_bytes() {
+        return Err(CorruptRecord { block });
+    }
+    Ok(payload)
+}
+
+/// Prefix of the storage changes stored with a format byte ahead of the payload.
+///
+/// The storage changes written by older versions are stored raw under `b'c'`. They are still
+/// served, and get replaced once rewritten.
+const FORMATTED_STORAGE_CHANGES: u8 = b'C';
+const FORMAT_RAW: u8 = 0;
+const FORMAT_ZSTD: u8 = 1;
+
+/// Encode the storage changes as stored, zstd compressed at the given level if any.
+///
+/// The payload is kept raw if compressing it doesn't make it smaller.
+pub fn encode_storage_changes(value: &[u8], compression: Option<i32>) -> Result<Vec<u8>> {
+    if let Some(level) = compression {
+        let mut record = vec![FORMAT_ZSTD];
+        zstd::stream::copy_encode(value, &mut record, level)?;
+        if record.len() <= value.len() {
+            return Ok(record);
+        }
+    }
+    let mut record = Vec::with_capacity(1 + value.len());
+    record.push(FORMAT_RAW);
+    record.extend_from_slice(value);
+    Ok(record)
+}
+
+fn decode_storage_changes(block: BlockNumber, mut record: Vec<u8>) -> Result<Vec<u8>> {
+    match record.first() {
+        Some(&FORMAT_RAW) => {
+            record.remove(0);
+            Ok(record)
+        }
+        Some(&FORMAT_ZSTD) => zstd::stream::decode_all(&record[1..])
+            .with_context(|| format!("Failed to decompress the storage changes at {block}")),
+        Some(format) => bail!("Unknown format {format} of the storage changes at {block}"),
+        None => bail!("Empty storage changes record at {block}"),
+    }
+}
+
+/// Reads a record of the given kind at the given block number.
+pub type RecordGetter = fn(&CacheDB, BlockNumber) -> Option<Vec<u8>>;
 
 fn mk_key(prefix: u8, block_number: BlockNumber) -> [u8; size_of::<BlockNumber>() + 1] {
     let mut key = [prefix; size_of::<BlockNumber>() + 1];
@@ -59,31 +160,103 @@ fn mk_key(prefix: u8, block_number: BlockNumber) -> [u8; size_of::<BlockNumber>(
     key
 }
 
+/// Add the deletion of the records below `below` to the batch, except the ones at `keep`.
+fn prune_below(
+    batch: &mut WriteBatch,
+    prefixes: &[u8],
+    lowest: &mut Option<BlockNumber>,
+    below: Option<BlockNumber>,
+    keep: &[BlockNumber],
+) {
+    let Some(below) = below else {
+        return;
+    };
+    let from = lowest.unwrap_or_default();
+    if below <= from {
+        return;
+    }
+    for &prefix in prefixes {
+        let mut start = from;
+        for &kept in keep.iter().filter(|&&b| b >= from && b < below) {
+            if start < kept {
+                batch.delete_range(mk_key(prefix, start), mk_key(prefix, kept));
+            }
+            start = kept + 1;
+        }
+        if start < below {
+            batch.delete_range(mk_key(prefix, start), mk_key(prefix, below));
+        }
+    }
+    *lowest = Some(below);
+}
+
 impl CacheDB {
     pub fn open(path: &str) -> Result<Self> {
-        Ok(CacheDB(Arc::new(DB::open_default(path)?)))
+        Ok(CacheDB {
+            db: Arc::new(DB::open_default(path)?),
+            verify_checksums: true,
+            compression: None,
+        })
+    }
+
+    /// Open the DB without taking its lock, e.g. to inspect it while the server is running.
+    pub fn open_read_only(path: &str) -> Result<Self> {
+        Ok(CacheDB {
+            db: Arc::new(DB::open_for_read_only(&Options::default(), path, false)?),
+            verify_checksums: true,
+            compression: None,
+        })
+    }
+
+    /// Whether to verify the checksums of the records read. Defaults to true.
+    pub fn verify_checksums(mut self, verify: bool) -> Self {
+        self.verify_checksums = verify;
+        self
+    }
+
+    /// The zstd level to compress the storage changes written at, or None to store them raw.
+    /// Defaults to None. The records are read back whatever they were written with.
+    pub fn compress_storage_changes(mut self, level: Option<i32>) -> Self {
+        self.compression = level;
+        self
     }
 
     pub fn flush(&self) -> Result<()> {
-        self.0.flush()?;
+        self.db.flush()?;
         Ok(())
     }
 
     fn get(&self, prefix: u8, block: BlockNumber) -> Option<Vec<u8>> {
-        self.0.get(mk_key(prefix, block)).ok().flatten()
+        self.db.get(mk_key(prefix, block)).ok().flatten()
     }
 
     fn put(&self, prefix: u8, block: BlockNumber, value: &[u8]) -> Result<()> {
-        self.0.put(mk_key(prefix, block), value)?;
+        self.db.put(mk_key(prefix, block), value)?;
         Ok(())
     }
 
+    /// Get the header at `block`, treating a corrupt one as missing.
     pub fn get_header(&self, block: BlockNumber) -> Option<Vec<u8>> {
-        self.get(b'h', block)
+        match self.get_header_checked(block) {
+            Ok(header) => header,
+            Err(err) => {
+                log::warn!("{err}");
+                None
+            }
+        }
+    }
+
+    pub fn get_header_checked(&self, block: BlockNumber) -> Result<Option<Vec<u8>>, CorruptRecord> {
+        match self.get(CHECKSUMMED_HEADER, block) {
+            Some(record) => strip_checksum(block, record, self.verify_checksums).map(Some),
+            None => Ok(self.get(b'h', block)),
+        }
     }
 
     pub fn put_header(&self, block: BlockNumber, value: &[u8]) -> Result<()> {
-        self.put(b'h', block, value)
+        let mut batch = self.batch(true);
+        batch.put_header(block, value)?;
+        batch.commit()
     }
 
     pub fn get_para_header(&self, block: BlockNumber) -> Option<Vec<u8>> {
@@ -94,12 +267,32 @@ impl CacheDB {
         self.put(b'p', block, value)
     }
 
+    /// Get the storage changes at `block`, treating an undecodable record as missing.
     pub fn get_storage_changes(&self, block: BlockNumber) -> Option<Vec<u8>> {
-        self.get(b'c', block)
+        let Some(record) = self.get(FORMATTED_STORAGE_CHANGES, block) else {
+            return self.get(b'c', block);
+        };
+        match decode_storage_changes(block, record) {
+            Ok(changes) => Some(changes),
+            Err(err) => {
+                log::warn!("{err:?}");
+                None
+            }
+        }
     }
 
     pub fn put_storage_changes(&self, block: BlockNumber, value: &[u8]) -> Result<()> {
-        self.put(b'c', block, value)
+        let mut batch = self.batch(true);
+        batch.put_storage_changes(block, value)?;
+        batch.commit()
+    }
+
+    pub fn get_justification(&self, block: BlockNumber) -> Option<Vec<u8>> {
+        self.get(b'j', block)
+    }
+
+    pub fn put_justification(&self, block: BlockNumber, value: &[u8]) -> Result<()> {
+        self.put(b'j', block, value)
     }
 
     pub fn get_genesis(&self, block: BlockNumber) -> Option<Vec<u8>> {
@@ -110,10 +303,108 @@ impl CacheDB {
         self.put(b'g', block_number, value)
     }
 
+    /// Store an externally produced genesis blob at `block`, registering it in the metadata.
+    ///
+    /// The blob must decode as the genesis info of that block, or nothing is written.
+    pub fn import_genesis(&self, block: BlockNumber, value: &[u8]) -> Result<()> {
+        let info = GenesisBlockInfo::decode(&mut &value[..])
+            .context("Failed to decode the genesis data")?;
+        if info.block_header.number != block {
+            bail!(
+                "The genesis data is at block {}, expected {block}",
+                info.block_header.number
+            );
+        }
+        let mut metadata = self.get_metadata()?.unwrap_or_default();
+        metadata.put_genesis(block);
+        let mut batch = self.batch(true);
+        batch.put_genesis(block, value)?;
+        batch.put_metadata(&metadata)?;
+        batch.commit()
+    }
+
+    /// Stream the records of `start..end`, stopping after the first missing one.
+    ///
+    /// If `read_ahead` is not zero, the records are read on the blocking thread pool of the
+    /// runtime, which keeps up to `read_ahead` records buffered in memory while the caller is
+    /// consuming the previous ones. The reading stops once the stream is dropped.
+    pub fn read_range(
+        &self,
+        getter: RecordGetter,
+        start: BlockNumber,
+        end: BlockNumber,
+        read_ahead: usize,
+    ) -> BoxStream<'static, (BlockNumber, Option<Vec<u8>>)> {
+        let read_ahead = read_ahead.min(MAX_READ_AHEAD);
+        let db = self.clone();
+        let records = (start..end)
+            .map(move |block| (block, getter(&db, block)))
+            .scan(false, |stop, item| {
+                if *stop {
+                    return None;
+                }
+                *stop = item.1.is_none();
+                Some(item)
+            });
+        if read_ahead == 0 {
+            return stream::iter(records).boxed();
+        }
+        let (tx, rx) = mpsc::channel(read_ahead);
+        tokio::task::spawn_blocking(move || {
+            for item in records {
+                if tx.blocking_send(item).is_err() {
+                    // The reader has been dropped
+                    break;
+                }
+            }
+        });
+        stream::unfold(rx, |mut rx| async move {
+            rx.recv().await.map(|item| (item, rx))
+        })
+        .boxed()
+    }
+
+    /// Delete the records below the given block of each kind.
+    ///
+    /// The relaychain headers and justifications at the genesis blocks in the metadata are kept.
+    /// Returns the updated metadata, with the lowest available markers raised. The metadata is
+    /// written in the same batch as the deletions, so it never claims records that are gone.
+    pub fn prune_headers_below(&self, below: &Counters) -> Result<Metadata> {
+        let mut metadata = self.get_metadata()?.unwrap_or_default();
+        let mut genesis = metadata.genesis.clone();
+        genesis.sort_unstable();
+        let mut batch = WriteBatch::default();
+        let lowest = &mut metadata.lowest;
+        prune_below(
+            &mut batch,
+            b"hHj",
+            &mut lowest.header,
+            below.header,
+            &genesis,
+        );
+        prune_below(
+            &mut batch,
+            b"p",
+            &mut lowest.para_header,
+            below.para_header,
+            &[],
+        );
+        prune_below(
+            &mut batch,
+            b"cC",
+            &mut lowest.storage_changes,
+            below.storage_changes,
+            &[],
+        );
+        batch.put(METADATA_KEY, serde_json::to_vec(&metadata)?);
+        self.db.write(batch)?;
+        Ok(metadata)
+    }
+
     pub fn get_metadata(&self) -> Result<Option<Metadata>> {
         let metadata = self
-            .0
-            .get(b"m-metadata")?
+            .db
+            .get(METADATA_KEY)?
             .map(|encoded| {
                 let result: Result<Metadata> = serde_json::from_slice(&encoded).map_err(Into::into);
                 result
@@ -124,6 +415,226 @@ impl CacheDB {
 
     pub fn put_metadata(&self, metadata: &Metadata) -> Result<()> {
         let encoded = serde_json::to_vec(metadata)?;
-        self.0.put(b"m-metadata", encoded).map_err(Into::into)
+        self.db.put(METADATA_KEY, encoded).map_err(Into::into)
+    }
+
+    /// Start a group of writes. If `atomic` is false, the writes go to the DB immediately.
+    pub fn batch(&self, atomic: bool) -> Batch {
+        Batch {
+            cache: self.clone(),
+            batch: atomic.then(WriteBatch::default),
+        }
+    }
+}
+
+/// A group of writes committed to the DB at once.
+pub struct Batch {
+    cache: CacheDB,
+    batch: Option<WriteBatch>,
+}
+
+impl Batch {
+    fn put(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        match &mut self.batch {
+            Some(batch) => batch.put(key, value),
+            None => self.cache.db.put(key, value)?,
+        }
+        Ok(())
+    }
+
+    fn delete(&mut self, key: &[u8]) -> Result<()> {
+        match &mut self.batch {
+            Some(batch) => batch.delete(key),
+            None => self.cache.db.delete(key)?,
+        }
+        Ok(())
+    }
+
+    pub fn put_header(&mut self, block: BlockNumber, value: &[u8]) -> Result<()> {
+        self.put(&mk_key(CHECKSUMMED_HEADER, block), &with_checksum(value))?;
+        // Drop the legacy record if any, so it never shadows a newer one.
+        self.delete(&mk_key(b'h', block))
+    }
+
+    pub fn put_para_header(&mut self, block: BlockNumber, value: &[u8]) -> Result<()> {
+        self.put(&mk_key(b'p', block), value)
+    }
+
+    pub fn put_storage_changes(&mut self, block: BlockNumber, value: &[u8]) -> Result<()> {
+        let record = encode_storage_changes(value, self.cache.compression)?;
+        self.put(&mk_key(FORMATTED_STORAGE_CHANGES, block), &record)?;
+        // Drop the legacy record if any, so it never shadows a newer one.
+        self.delete(&mk_key(b'c', block))
+    }
+
+    pub fn put_justification(&mut self, block: BlockNumber, value: &[u8]) -> Result<()> {
+        self.put(&mk_key(b'j', block), value)
+    }
+
+    pub fn put_genesis(&mut self, block: BlockNumber, value: &[u8]) -> Result<()> {
+        self.put(&mk_key(b'g', block), value)
+    }
+
+    pub fn put_metadata(&mut self, metadata: &Metadata) -> Result<()> {
+        let encoded = serde_json::to_vec(metadata)?;
+        self.put(METADATA_KEY, &encoded)
+    }
+
+    pub fn commit(self) -> Result<()> {
+        if let Some(batch) = self.batch {
+            self.cache.db.write(batch)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// A DB in a fresh temporary directory, removed on drop.
+    pub(crate) struct TestDb {
+        pub(crate) db: CacheDB,
+        path: PathBuf,
+    }
+
+    impl TestDb {
+        pub(crate) fn new(name: &str) -> Self {
+            let path =
+                std::env::temp_dir().join(format!("headers-cache-{name}-{}", std::process::id()));
+            let _ = std::fs::remove_dir_all(&path);
+            let db = CacheDB::open(path.to_str().unwrap()).unwrap();
+            Self { db, path }
+        }
+    }
+
+    impl Drop for TestDb {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.path);
+        }
+    }
+
+    fn put_para_headers(db: &CacheDB, blocks: impl Iterator<Item = BlockNumber>) {
+        for block in blocks {
+            db.put_para_header(block, &block.to_be_bytes()).unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn range_is_read_in_order_up_to_the_first_gap() {
+        let test = TestDb::new("range-order");
+        put_para_headers(&test.db, (0..100).filter(|&b| b != 60));
+        for read_ahead in [0, 1, 16] {
+            let records: Vec<_> = test
+                .db
+                .read_range(CacheDB::get_para_header, 0, 100, read_ahead)
+                .collect()
+                .await;
+            assert_eq!(records.len(), 61, "read_ahead = {read_ahead}");
+            for (i, (block, data)) in records.iter().enumerate() {
+                assert_eq!(*block, i as BlockNumber);
+                if *block == 60 {
+                    assert_eq!(data, &None);
+                } else {
+                    assert_eq!(data.as_deref(), Some(&block.to_be_bytes()[..]));
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn long_range_streams_through_a_small_read_ahead() {
+        let test = TestDb::new("range-throughput");
+        put_para_headers(&test.db, 0..20_000);
+        let mut records = test.db.read_range(CacheDB::get_para_header, 0, 20_000, 4);
+        let mut next = 0;
+        while let Some((block, data)) = records.next().await {
+            assert_eq!(block, next);
+            assert!(data.is_some());
+            next += 1;
+        }
+        assert_eq!(next, 20_000);
+    }
+
+    #[test]
+    fn pruning_keeps_the_genesis_and_raises_the_lowest_markers() {
+        let test = TestDb::new("prune");
+        let db = &test.db;
+        for block in 0..10 {
+            db.put_header(block, b"header").unwrap();
+            db.put_storage_changes(block, b"changes").unwrap();
+        }
+        put_para_headers(db, 0..10);
+        let mut metadata = Metadata::default();
+        metadata.put_genesis(2);
+        db.put_metadata(&metadata).unwrap();
+
+        let below = Counters {
+            header: Some(5),
+            para_header: Some(3),
+            storage_changes: None,
+        };
+        let pruned = db.prune_headers_below(&below).unwrap();
+        assert_eq!(pruned.lowest.header, Some(5));
+        assert_eq!(pruned.lowest.para_header, Some(3));
+        assert_eq!(pruned.lowest.storage_changes, None);
+        let stored = db.get_metadata().unwrap().unwrap();
+        assert_eq!(stored.lowest.header, Some(5));
+        assert_eq!(stored.lowest.para_header, Some(3));
+
+        for block in 0..10 {
+            let kept = block >= 5 || block == 2;
+            assert_eq!(db.get_header(block).is_some(), kept, "header {block}");
+            assert_eq!(db.get_para_header(block).is_some(), block >= 3);
+            assert!(db.get_storage_changes(block).is_some());
+        }
+
+        // Pruning below the markers again is a no-op.
+        let again = db.prune_headers_below(&below).unwrap();
+        assert_eq!(again.lowest.header, Some(5));
+        assert!(db.get_header(2).is_some());
+    }
+
+    #[test]
+    fn justifications_are_stored_apart_from_the_headers() {
+        let test = TestDb::new("justifications");
+        let db = &test.db;
+        db.put_header(7, b"header").unwrap();
+        assert_eq!(db.get_justification(7), None);
+        db.put_justification(7, b"justification").unwrap();
+        assert_eq!(
+            db.get_justification(7).as_deref(),
+            Some(&b"justification"[..])
+        );
+        assert_eq!(db.get_header(7).as_deref(), Some(&b"header"[..]));
+
+        // They go away along with the headers.
+        let below = Counters {
+            header: Some(8),
+            ..Default::default()
+        };
+        db.prune_headers_below(&below).unwrap();
+        assert_eq!(db.get_justification(7), None);
+    }
+
+    static READS: AtomicUsize = AtomicUsize::new(0);
+
+    fn counting_getter(db: &CacheDB, block: BlockNumber) -> Option<Vec<u8>> {
+        READS.fetch_add(1, Ordering::SeqCst);
+        db.get_para_header(block)
+    }
+
+    #[tokio::test]
+    async fn read_ahead_is_bounded_while_the_consumer_waits() {
+        let test = TestDb::new("range-backpressure");
+        put_para_headers(&test.db, 0..1000);
+        let mut records = test.db.read_range(counting_getter, 0, 1000, 8);
+        assert_eq!(records.next().await.map(|(block, _)| block), Some(0));
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+        // The one consumed, the buffered ones and the one waiting for room in the channel.
+        assert!(READS.load(Ordering::SeqCst) <= 1 + 8 + 1);
+        drop(records);
     }
 }
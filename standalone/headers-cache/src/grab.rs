@@ -1,8 +1,18 @@
-use std::sync::atomic::{AtomicU32, Ordering};
+use std::{
+    collections::VecDeque,
+    fmt,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
 
 use anyhow::{anyhow, bail, Context as _, Result};
 use log::{error, info, warn};
+use rand::Rng as _;
 use scale::{Decode, Encode};
+use serde::Serialize;
 
 use pherry::{
     headers_cache as cache,
@@ -10,12 +20,41 @@ use pherry::{
 };
 
 use crate::{
-    db::{CacheDB, Metadata},
+    db::{Batch, CacheDB, Metadata, RecordGetter},
+    metrics::metrics,
     BlockNumber, Serve,
 };
 
+/// Move the checked markers back to the blocks given by `--recheck-from` and
+/// `--recheck-para-from`, so the checker walks the headers from there again.
+fn rewind_checked(db: &CacheDB, config: &Serve, metadata: &mut Metadata) -> Result<()> {
+    let mut rewound = false;
+    let mut rewind = |marker: &mut Option<BlockNumber>, from: Option<BlockNumber>, name| {
+        let (Some(checked), Some(from)) = (*marker, from) else {
+            return;
+        };
+        if from < checked {
+            info!("Rechecking {name} headers from {from}, were checked up to {checked}");
+            *marker = Some(from);
+            rewound = true;
+        }
+    };
+    rewind(&mut metadata.checked.header, config.recheck_from, "relay");
+    rewind(
+        &mut metadata.checked.para_header,
+        config.recheck_para_from,
+        "para",
+    );
+    if rewound {
+        db.put_metadata(metadata)
+            .context("Failed to save the rewound checked markers")?;
+    }
+    Ok(())
+}
+
 pub(crate) async fn run(db: CacheDB, config: Serve) -> Result<()> {
     let mut metadata = db.get_metadata()?.unwrap_or_default();
+    rewind_checked(&db, &config, &mut metadata)?;
     let mut next_header = match metadata.higest.header {
         Some(highest) => highest + 1,
         None => config.genesis_block,
@@ -33,10 +72,24 @@ pub(crate) async fn run(db: CacheDB, config: Serve) -> Result<()> {
 
     GENESIS.store(config.genesis_block, Ordering::Relaxed);
 
+    let mut backoff = Backoff::new(&config);
+    let mut relay = Endpoints::new("relaychain", &config.node_uri, &config);
+    let mut para = Endpoints::new("parachain", &config.para_node_uri, &config);
+    let cadence = Mutex::new(JustificationCadence::new(&config));
+    // Kept across the reconnections, so that the learned batch size is not lost on failures.
+    let storage_changes_batch = Mutex::new(AdaptiveBatch::new(
+        config.grab_storage_changes_batch,
+        config.grab_storage_changes_batch_bytes,
+    ));
     loop {
         if let Err(err) = Crawler::grab(
             &config,
             &db,
+            &cadence,
+            &storage_changes_batch,
+            &mut backoff,
+            &mut relay,
+            &mut para,
             &mut metadata,
             &mut next_header,
             &mut next_para_header,
@@ -53,31 +106,30 @@ pub(crate) async fn run(db: CacheDB, config: Serve) -> Result<()> {
 struct Crawler<'c> {
     config: &'c Serve,
     db: &'c CacheDB,
-    metadata: &'c mut Metadata,
+    // Shared by the concurrently running grab stages. Never held across an await point.
+    metadata: Mutex<&'c mut Metadata>,
+    cadence: &'c Mutex<JustificationCadence>,
+    storage_changes_batch: &'c Mutex<AdaptiveBatch>,
     api: ChainApi,
     para_api: ChainApi,
-    next_header: Option<&'c mut BlockNumber>,
-    next_para_header: Option<&'c mut BlockNumber>,
-    next_delta: Option<&'c mut BlockNumber>,
 }
 
 impl<'c> Crawler<'c> {
     async fn grab<'p>(
         config: &'c Serve,
         db: &'c CacheDB,
+        cadence: &'c Mutex<JustificationCadence>,
+        storage_changes_batch: &'c Mutex<AdaptiveBatch>,
+        backoff: &'c mut Backoff,
+        relay: &'c mut Endpoints,
+        para: &'c mut Endpoints,
         metadata: &'c mut Metadata,
         next_header: &'c mut BlockNumber,
         next_para_header: &'c mut BlockNumber,
         next_delta: &'c mut BlockNumber,
     ) -> Result<()> {
-        info!("Connecting to {}...", config.node_uri);
-        let api = pherry::subxt_connect(&config.node_uri)
-            .await
-            .context(format!("Failed to connect to {}", config.node_uri))?;
-        info!("Connecting to {}...", config.para_node_uri);
-        let para_api = pherry::subxt_connect(&config.para_node_uri)
-            .await
-            .context(format!("Failed to connect to {}", config.para_node_uri))?;
+        let api = connect_with_backoff(relay, backoff, None).await?;
+        let para_api = connect_with_backoff(para, backoff, None).await?;
         if !metadata.genesis.contains(&config.genesis_block) {
             info!("Fetching genesis at {}", config.genesis_block);
             let genesis = cache::fetch_genesis_info(&api, config.genesis_block)
@@ -91,32 +143,54 @@ impl<'c> Crawler<'c> {
         Self {
             config,
             db,
-            metadata,
+            metadata: Mutex::new(metadata),
+            cadence,
+            storage_changes_batch,
             api,
             para_api,
-            next_header: config.grab_headers.then_some(next_header),
-            next_para_header: config.grab_para_headers.then_some(next_para_header),
-            next_delta: config.grab_storage_changes.then_some(next_delta),
         }
-        .run()
+        .run(
+            backoff,
+            relay,
+            para,
+            config.grab_headers.then_some(next_header),
+            config.grab_para_headers.then_some(next_para_header),
+            config.grab_storage_changes.then_some(next_delta),
+        )
         .await
     }
 
     async fn finalized_header_number(&self, para: bool) -> Result<BlockNumber> {
         let api = if para { &self.para_api } else { &self.api };
-        let hash = api.rpc().finalized_head().await?;
-        let header = api.rpc().header(Some(hash)).await?;
-        let header_number = header.map(|h| h.number).unwrap_or_default();
-        Ok(header_number)
+        finalized_number(api).await
+    }
+
+    fn new_batch(&self) -> Batch {
+        self.db.batch(!self.config.unbatched_writes)
+    }
+
+    /// Commit the records in `batch` along with the metadata changes made by `f` to the DB.
+    fn commit(&self, mut batch: Batch, f: impl FnOnce(&mut Metadata)) -> Result<()> {
+        // Keep the lock until committed, so that the metadata written never goes backwards.
+        let mut metadata = self.metadata.lock().unwrap();
+        f(&mut metadata);
+        batch.put_metadata(&metadata)?;
+        batch.commit().context("Failed to update metadata")
+    }
+
+    /// Commit the metadata changes made by `f` to the DB.
+    fn update_metadata(&self, f: impl FnOnce(&mut Metadata)) -> Result<()> {
+        self.commit(self.new_batch(), f)
     }
 
-    async fn grab_headers(&mut self) -> Result<()> {
+    async fn grab_headers(&self, next_header: Option<&mut BlockNumber>) -> Result<()> {
         let latest_finalized = self.finalized_header_number(false).await?;
-        let Some(next_header) = self.next_header.as_deref_mut() else {
+        let Some(next_header) = next_header else {
             return Ok(());
         };
-        info!("Relaychain finalized: {latest_finalized}");
-        if latest_finalized < *next_header + self.config.justification_interval {
+        let interval = self.cadence.lock().unwrap().interval();
+        info!("Relaychain finalized: {latest_finalized}, justification interval: {interval}");
+        if latest_finalized < *next_header + interval {
             info!("No enough relaychain headers in node");
             return Ok(());
         }
@@ -129,18 +203,27 @@ impl<'c> Crawler<'c> {
             u32::MAX,
             self.config.justification_interval,
             |info| {
-                if info.justification.is_some() {
-                    info!("Got justification at {}", info.header.number);
-                    LATEST_JUSTFICATION.store(info.header.number as _, Ordering::Relaxed);
+                let number = info.header.number;
+                let mut batch = self.new_batch();
+                if let Some(justification) = &info.justification {
+                    info!("Got justification at {number}");
+                    self.cadence.lock().unwrap().observe(number);
+                    if self.config.store_justifications {
+                        batch
+                            .put_justification(number, justification)
+                            .context("Failed to put justification to DB")?;
+                    }
                 }
-                self.db
-                    .put_header(info.header.number, &info.encode())
+                batch
+                    .put_header(number, &info.encode())
                     .context("Failed to put record to DB")?;
-                self.metadata.update_header(info.header.number);
-                self.db
-                    .put_metadata(self.metadata)
-                    .context("Failed to update metadata")?;
-                *next_header = info.header.number + 1;
+                self.commit(batch, |m| m.update_header(number))?;
+                emit(self.config, || GrabEvent::HeaderCommitted { number });
+                if info.justification.is_some() {
+                    LATEST_JUSTFICATION.store(number as _, Ordering::Relaxed);
+                    emit(self.config, || GrabEvent::JustificationFound { number });
+                }
+                *next_header = number + 1;
                 Ok(())
             },
         )
@@ -149,9 +232,9 @@ impl<'c> Crawler<'c> {
         Ok(())
     }
 
-    async fn grab_para_headers(&mut self) -> Result<()> {
+    async fn grab_para_headers(&self, next_para_header: Option<&mut BlockNumber>) -> Result<()> {
         let latest_finalized = self.finalized_header_number(true).await?;
-        let Some(next_para_header) = self.next_para_header.as_deref_mut() else {
+        let Some(next_para_header) = next_para_header else {
             return Ok(());
         };
         if latest_finalized < *next_para_header {
@@ -160,13 +243,14 @@ impl<'c> Crawler<'c> {
         let count = latest_finalized - *next_para_header + 1;
         info!("Grabbing {count} parachain headers start from {next_para_header}...");
         cache::grab_para_headers(&self.para_api, *next_para_header, count, |info| {
-            self.db
+            let mut batch = self.new_batch();
+            batch
                 .put_para_header(info.number, &info.encode())
                 .context("Failed to put record to DB")?;
-            self.metadata.update_para_header(info.number);
-            self.db
-                .put_metadata(self.metadata)
-                .context("Failed to update metadata")?;
+            self.commit(batch, |m| m.update_para_header(info.number))?;
+            emit(self.config, || GrabEvent::ParaHeaderCommitted {
+                number: info.number,
+            });
             *next_para_header = info.number + 1;
             Ok(())
         })
@@ -175,9 +259,9 @@ impl<'c> Crawler<'c> {
         Ok(())
     }
 
-    async fn grab_storage_changes(&mut self) -> Result<()> {
+    async fn grab_storage_changes(&self, next_delta: Option<&mut BlockNumber>) -> Result<()> {
         let latest_finalized = self.finalized_header_number(true).await?;
-        let Some(next_delta) = self.next_delta.as_deref_mut() else {
+        let Some(next_delta) = next_delta else {
             return Ok(());
         };
         if latest_finalized < *next_delta {
@@ -185,34 +269,71 @@ impl<'c> Crawler<'c> {
         }
         let count = latest_finalized - *next_delta + 1;
         info!("Grabbing {count} storage changes start from {next_delta}...",);
-        cache::grab_storage_changes(
-            &self.para_api,
-            *next_delta,
-            count,
-            self.config.grab_storage_changes_batch,
-            !self.config.no_state_root,
-            |info| {
-                self.db
-                    .put_storage_changes(info.block_header.number, &info.encode())
+        while *next_delta <= latest_finalized {
+            let from = *next_delta;
+            let count = self.storage_changes_batch.lock().unwrap().next_count();
+            let to = latest_finalized.min(from.saturating_add(count - 1));
+            let result = pherry::fetch_storage_changes_with_root_or_not(
+                &self.para_api,
+                None,
+                from,
+                to,
+                !self.config.no_state_root,
+            )
+            .await;
+            let changes = match result {
+                Ok(changes) => changes,
+                Err(err) => {
+                    let blocks = to - from + 1;
+                    if !self
+                        .storage_changes_batch
+                        .lock()
+                        .unwrap()
+                        .record_failure(blocks)
+                    {
+                        return Err(err).context("Failed to grab storage changes from node");
+                    }
+                    warn!("Failed to grab storage changes {from}-{to}, retrying smaller: {err}");
+                    continue;
+                }
+            };
+            if changes.is_empty() {
+                bail!("No storage changes returned for {from}-{to}");
+            }
+            let mut batch_bytes = 0;
+            let mut writes = self.new_batch();
+            for info in &changes {
+                let encoded = info.encode();
+                batch_bytes += encoded.len();
+                writes
+                    .put_storage_changes(info.block_header.number, &encoded)
                     .context("Failed to put record to DB")?;
-                self.metadata
-                    .update_storage_changes(info.block_header.number);
-                self.db
-                    .put_metadata(self.metadata)
-                    .context("Failed to update metadata")?;
-                *next_delta = info.block_header.number + 1;
-                Ok(())
-            },
-        )
-        .await
-        .context("Failed to grab storage changes from node")?;
+            }
+            self.commit(writes, |m| {
+                for info in &changes {
+                    m.update_storage_changes(info.block_header.number);
+                }
+            })?;
+            for info in &changes {
+                emit(self.config, || GrabEvent::StorageChangeCommitted {
+                    number: info.block_header.number,
+                });
+            }
+            if let Some(last) = changes.last() {
+                *next_delta = last.block_header.number + 1;
+            }
+            self.storage_changes_batch
+                .lock()
+                .unwrap()
+                .record(changes.len(), batch_bytes);
+        }
         Ok(())
     }
 
-    async fn continue_check_headers(&mut self) -> Result<()> {
+    async fn continue_check_headers(&self) -> Result<()> {
         let db = self.db;
         let config = self.config;
-        let metadata = &mut *self.metadata;
+        let metadata = self.metadata.lock().unwrap().clone();
 
         {
             let relay_start = metadata.checked.header.unwrap_or(config.genesis_block);
@@ -222,12 +343,10 @@ impl<'c> Crawler<'c> {
                 .unwrap_or(0)
                 .min(relay_start + config.check_batch);
             if relay_start < relay_end {
-                check_and_fix_headers(db, config, "relay", relay_start, Some(relay_end), None)
+                check_and_fix_headers(db, config, false, relay_start, Some(relay_end), None)
                     .await
                     .context("Failed to check relay headers")?;
-                metadata.checked.header = Some(relay_end);
-                db.put_metadata(metadata)
-                    .context("Failed to update metadata")?;
+                self.update_metadata(|m| m.checked.header = Some(relay_end))?;
             }
         }
 
@@ -239,18 +358,22 @@ impl<'c> Crawler<'c> {
                 .unwrap_or(0)
                 .min(para_start + config.check_batch);
             if para_start < para_end {
-                check_and_fix_headers(db, config, "para", para_start, Some(para_end), None)
+                check_and_fix_headers(db, config, true, para_start, Some(para_end), None)
                     .await
                     .context("Failed to check para headers")?;
-                metadata.checked.para_header = Some(para_end);
-                db.put_metadata(metadata)
-                    .context("Failed to update metadata")?;
+                self.update_metadata(|m| m.checked.para_header = Some(para_end))?;
             }
         }
 
         if !config.no_state_root {
             let changes_start = metadata.checked.storage_changes.unwrap_or(1);
-            let max_checked_header = metadata.checked.para_header.unwrap_or_default();
+            let max_checked_header = self
+                .metadata
+                .lock()
+                .unwrap()
+                .checked
+                .para_header
+                .unwrap_or_default();
             let changes_end = metadata
                 .recent_imported
                 .storage_changes
@@ -269,28 +392,577 @@ impl<'c> Crawler<'c> {
                 )
                 .await
                 .context("Failed to check storage changes")?;
-                metadata.checked.storage_changes = Some(changes_end);
-                db.put_metadata(metadata)
-                    .context("Failed to update metadata")?;
+                self.update_metadata(|m| m.checked.storage_changes = Some(changes_end))?;
             }
         }
         Ok(())
     }
 
-    async fn run(&mut self) -> Result<()> {
+    async fn run(
+        &self,
+        backoff: &mut Backoff,
+        relay: &mut Endpoints,
+        para: &mut Endpoints,
+        mut next_header: Option<&mut BlockNumber>,
+        mut next_para_header: Option<&mut BlockNumber>,
+        mut next_delta: Option<&mut BlockNumber>,
+    ) -> Result<()> {
         loop {
-            self.grab_headers().await?;
-            self.grab_para_headers().await?;
-            self.grab_storage_changes().await?;
+            if self.config.sequential_grab {
+                blame(relay, self.grab_headers(next_header.as_deref_mut()).await)?;
+                blame(
+                    para,
+                    self.grab_para_headers(next_para_header.as_deref_mut())
+                        .await,
+                )?;
+                blame(
+                    para,
+                    self.grab_storage_changes(next_delta.as_deref_mut()).await,
+                )?;
+            } else {
+                // Let every stage run to the end even if another one fails, so that a stalled
+                // relaychain doesn't hold back the parachain progress.
+                let results = tokio::join!(
+                    self.grab_headers(next_header.as_deref_mut()),
+                    self.grab_para_headers(next_para_header.as_deref_mut()),
+                    self.grab_storage_changes(next_delta.as_deref_mut()),
+                );
+                let mut failed = None;
+                let (mut relay_failed, mut para_failed) = (false, false);
+                for (stage, result, on_para) in [
+                    ("headers", results.0, false),
+                    ("para headers", results.1, true),
+                    ("storage changes", results.2, true),
+                ] {
+                    if let Err(err) = result {
+                        error!("Failed to grab {stage}: {err:?}");
+                        failed.get_or_insert(err);
+                        if on_para {
+                            para_failed = true;
+                        } else {
+                            relay_failed = true;
+                        }
+                    }
+                }
+                if relay_failed {
+                    relay.on_failure();
+                }
+                if para_failed {
+                    para.on_failure();
+                }
+                if let Some(err) = failed {
+                    return Err(err);
+                }
+            }
             if let Err(err) = self.continue_check_headers().await {
                 error!("Error fixing headers: {err:?}");
             }
+            backoff.reset();
+            relay.on_success();
+            para.on_success();
             sleep(self.config.interval).await;
         }
     }
 }
 
+/// Count a failed stage against the endpoint it was served by.
+fn blame(endpoints: &mut Endpoints, result: Result<()>) -> Result<()> {
+    if result.is_err() {
+        endpoints.on_failure();
+    }
+    result
+}
+
+async fn finalized_number(api: &ChainApi) -> Result<BlockNumber> {
+    let hash = api.rpc().finalized_head().await?;
+    let header = api.rpc().header(Some(hash)).await?;
+    Ok(header.map(|h| h.number).unwrap_or_default())
+}
+
+/// Returns the range to grab to fill the records missing in `from..=to`, or None if complete.
+fn missing_range(
+    db: &CacheDB,
+    what: &str,
+    getter: RecordGetter,
+    from: BlockNumber,
+    to: BlockNumber,
+    tip: BlockNumber,
+) -> Option<(BlockNumber, BlockNumber)> {
+    let end = if to > tip {
+        warn!("Backfill {what}: {to} is beyond the finalized tip, grabbing up to {tip}");
+        tip
+    } else {
+        to
+    };
+    let first_missing = (from..=end).find(|&block| getter(db, block).is_none());
+    match first_missing {
+        Some(start) => Some((start, end)),
+        None => {
+            info!("Backfill {what}: {from}-{end} already complete");
+            None
+        }
+    }
+}
+
+/// Grab the records of `from..=to` missing in the DB, and return once they are committed.
+pub(crate) async fn backfill(
+    db: CacheDB,
+    config: Serve,
+    from: BlockNumber,
+    to: BlockNumber,
+) -> Result<()> {
+    if to < from {
+        bail!("Invalid range {from}-{to}");
+    }
+    let mut backoff = Backoff::new(&config);
+    let mut relay = Endpoints::new("relaychain", &config.node_uri, &config);
+    let mut para = Endpoints::new("parachain", &config.para_node_uri, &config);
+    let max_attempts = Some(config.connect_attempts);
+    let api = connect_with_backoff(&mut relay, &mut backoff, max_attempts).await?;
+    let para_api = connect_with_backoff(&mut para, &mut backoff, max_attempts).await?;
+    let mut metadata = db.get_metadata()?.unwrap_or_default();
+
+    if config.grab_headers {
+        let tip = finalized_number(&api).await?;
+        let range = missing_range(&db, "headers", CacheDB::get_header, from.max(1), to, tip);
+        if let Some((start, end)) = range {
+            info!("Backfilling headers {start}-{end}...");
+            cache::grab_headers(
+                &api,
+                &para_api,
+                start,
+                end - start + 1,
+                config.justification_interval,
+                |info| {
+                    let number = info.header.number;
+                    if let Some(justification) = &info.justification {
+                        if config.store_justifications {
+                            db.put_justification(number, justification)
+                                .context("Failed to put justification to DB")?;
+                        }
+                    }
+                    db.put_header(number, &info.encode())
+                        .context("Failed to put record to DB")?;
+                    if metadata.higest.header < Some(number) {
+                        metadata.update_header(number);
+                    }
+                    Ok(())
+                },
+            )
+            .await
+            .context("Failed to grab headers from node")?;
+        }
+    }
+
+    if config.grab_para_headers {
+        let tip = finalized_number(&para_api).await?;
+        let range = missing_range(&db, "para headers", CacheDB::get_para_header, from, to, tip);
+        if let Some((start, end)) = range {
+            info!("Backfilling para headers {start}-{end}...");
+            cache::grab_para_headers(&para_api, start, end - start + 1, |header| {
+                db.put_para_header(header.number, &header.encode())
+                    .context("Failed to put record to DB")?;
+                if metadata.higest.para_header < Some(header.number) {
+                    metadata.update_para_header(header.number);
+                }
+                Ok(())
+            })
+            .await
+            .context("Failed to grab para headers from node")?;
+        }
+    }
+
+    if config.grab_storage_changes {
+        let tip = finalized_number(&para_api).await?;
+        let range = missing_range(
+            &db,
+            "storage changes",
+            CacheDB::get_storage_changes,
+            from,
+            to,
+            tip,
+        );
+        if let Some((start, end)) = range {
+            info!("Backfilling storage changes {start}-{end}...");
+            cache::grab_storage_changes(
+                &para_api,
+                start,
+                end - start + 1,
+                config.grab_storage_changes_batch.max(1),
+                !config.no_state_root,
+                |info| {
+                    let number = info.block_header.number;
+                    db.put_storage_changes(number, &info.encode())
+                        .context("Failed to put record to DB")?;
+                    if metadata.higest.storage_changes < Some(number) {
+                        metadata.update_storage_changes(number);
+                    }
+                    Ok(())
+                },
+            )
+            .await
+            .context("Failed to grab storage changes from node")?;
+        }
+    }
+
+    db.put_metadata(&metadata)
+        .context("Failed to update metadata")?;
+    db.flush()?;
+    info!("Backfill {from}-{to} done");
+    Ok(())
+}
+
+/// Extend the relaychain headers downward from the lowest stored one until `target`.
+///
+/// Each grabbed header must be the parent of the one above it, so the new headers are chained to
+/// the existing ones. Stops early at the first block after the chain genesis.
+pub(crate) async fn grab_backward(db: CacheDB, config: Serve, target: BlockNumber) -> Result<()> {
+    let mut metadata = db.get_metadata()?.unwrap_or_default();
+    let floor = metadata.lowest.header.unwrap_or(config.genesis_block);
+    let target = target.max(1);
+    if target >= floor {
+        info!("Headers are already available from {floor}");
+        return Ok(());
+    }
+    let lowest = db
+        .get_header(floor)
+        .ok_or(anyhow!("Lowest header {floor} not found"))?;
+    let mut parent_hash = decode_header(&lowest)?.parent_hash;
+
+    let mut backoff = Backoff::new(&config);
+    let mut relay = Endpoints::new("relaychain", &config.node_uri, &config);
+    let mut para = Endpoints::new("parachain", &config.para_node_uri, &config);
+    let max_attempts = Some(config.connect_attempts);
+    let api = connect_with_backoff(&mut relay, &mut backoff, max_attempts).await?;
+    let para_api = connect_with_backoff(&mut para, &mut backoff, max_attempts).await?;
+
+    info!(
+        "Grabbing headers backward from {} to {target}...",
+        floor - 1
+    );
+    for block in (target..floor).rev() {
+        let mut batch = db.batch(!config.unbatched_writes);
+        cache::grab_headers(
+            &api,
+            &para_api,
+            block,
+            1,
+            config.justification_interval,
+            |info| {
+                if info.header.hash() != parent_hash {
+                    bail!("Header {block} is not the parent of the header above it");
+                }
+                if let Some(justification) = &info.justification {
+                    if config.store_justifications {
+                        batch
+                            .put_justification(block, justification)
+                            .context("Failed to put justification to DB")?;
+                    }
+                }
+                batch
+                    .put_header(block, &info.encode())
+                    .context("Failed to put record to DB")?;
+                parent_hash = info.header.parent_hash;
+                Ok(())
+            },
+        )
+        .await
+        .context("Failed to grab headers from node")?;
+        metadata.lowest.header = Some(block);
+        batch.put_metadata(&metadata)?;
+        batch.commit().context("Failed to update metadata")?;
+        emit(&config, || GrabEvent::HeaderCommitted { number: block });
+        if block % 1000 == 0 {
+            info!("Grabbed backward to {block}");
+        }
+    }
+    db.flush()?;
+    info!("Headers are available from {target} now");
+    Ok(())
+}
+
+/// Exponential backoff with jitter between reconnection attempts.
+pub(crate) struct Backoff {
+    base: Duration,
+    max: Duration,
+    jitter: Duration,
+    attempts: u32,
+}
+
+impl Backoff {
+    pub(crate) fn new(config: &Serve) -> Self {
+        Self {
+            base: Duration::from_millis(config.reconnect_base_delay_ms),
+            max: Duration::from_millis(config.reconnect_max_delay_ms),
+            jitter: Duration::from_millis(config.reconnect_jitter_ms),
+            attempts: 0,
+        }
+    }
+
+    /// The delay to wait before the next attempt.
+    pub(crate) fn next_delay(&mut self) -> Duration {
+        let exp = self
+            .base
+            .saturating_mul(1 << self.attempts.min(16))
+            .min(self.max);
+        self.attempts = self.attempts.saturating_add(1);
+        let jitter = rand::thread_rng().gen_range(0..=self.jitter.as_millis() as u64);
+        exp + Duration::from_millis(jitter)
+    }
+
+    pub(crate) fn attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    pub(crate) fn reset(&mut self) {
+        self.attempts = 0;
+    }
+}
+
+/// The RPC endpoints of a chain, in the order of preference.
+///
+/// Sticks to the active endpoint until it fails for a number of consecutive times, to connect or
+/// to serve a round of grabbing, then rotates to the next one.
+pub(crate) struct Endpoints {
+    chain: &'static str,
+    uris: Vec<String>,
+    active: usize,
+    failures: u32,
+    rotate_after: u32,
+}
+
+impl Endpoints {
+    pub(crate) fn new(chain: &'static str, uris: &[String], config: &Serve) -> Self {
+        Self {
+            chain,
+            uris: uris.to_vec(),
+            active: 0,
+            failures: 0,
+            rotate_after: config.node_failover_attempts.max(1),
+        }
+    }
+
+    fn active(&self) -> &str {
+        self.uris.get(self.active).map(|s| s.as_str()).unwrap_or("")
+    }
+
+    fn on_success(&mut self) {
+        self.failures = 0;
+    }
+
+    fn on_failure(&mut self) {
+        self.failures += 1;
+        if self.failures < self.rotate_after || self.uris.len() < 2 {
+            return;
+        }
+        self.failures = 0;
+        self.active = (self.active + 1) % self.uris.len();
+        warn!("Switched {} endpoint to {}", self.chain, self.active());
+    }
+}
+
+/// Connect to the active endpoint, retrying with backoff until it succeeds, or up to
+/// `max_attempts` times if given.
+///
+/// Connecting doesn't clear the failures of the endpoint, only serving a round of grabbing does.
+async fn connect_with_backoff(
+    endpoints: &mut Endpoints,
+    backoff: &mut Backoff,
+    max_attempts: Option<u32>,
+) -> Result<ChainApi> {
+    loop {
+        let uri = endpoints.active().to_string();
+        info!("Connecting to {} node {uri}...", endpoints.chain);
+        match pherry::subxt_connect(&uri).await {
+            Ok(api) => return Ok(api),
+            Err(err) if max_attempts.is_some_and(|max| backoff.attempts() + 1 >= max) => {
+                let attempts = backoff.attempts() + 1;
+                return Err(err.context(format!(
+                    "Failed to connect to {} node after {attempts} attempts",
+                    endpoints.chain
+                )));
+            }
+            Err(err) => {
+                let delay = backoff.next_delay();
+                warn!(
+                    "Failed to connect to {uri} (attempt {}), retrying in {delay:?}: {err:?}",
+                    backoff.attempts()
+                );
+                endpoints.on_failure();
+                tokio::time::sleep(delay).await;
+            }
+        }
+    }
+}
+
+/// Connect to the first reachable one of the given endpoints.
+async fn connect_any(uris: &[String]) -> Result<ChainApi> {
+    let mut last_err = anyhow!("No node endpoint given");
+    for uri in uris {
+        match pherry::subxt_connect(uri).await {
+            Ok(api) => {
+                info!("Connected to {uri}");
+                return Ok(api);
+            }
+            Err(err) => {
+                warn!("Failed to connect to {uri}: {err:?}");
+                last_err = err.context(format!("Failed to connect to {uri}"));
+            }
+        }
+    }
+    Err(last_err)
+}
+
+/// Learns the spacing of the justifications from the recently grabbed headers.
+///
+/// The grabber waits until the node has finalized enough blocks to likely include a new
+/// justification. The expected spacing is the median of the recent observations, within the
+/// operator-set bounds, and falls back to the configured interval until enough are observed.
+pub(crate) struct JustificationCadence {
+    fallback: BlockNumber,
+    min: BlockNumber,
+    max: BlockNumber,
+    last: Option<BlockNumber>,
+    spacings: VecDeque<BlockNumber>,
+}
+
+impl JustificationCadence {
+    const WINDOW: usize = 8;
+    const MIN_SAMPLES: usize = 3;
+
+    pub(crate) fn new(config: &Serve) -> Self {
+        let max = config
+            .justification_interval_max
+            .unwrap_or(config.justification_interval)
+            .max(1);
+        let min = config.justification_interval_min.clamp(1, max);
+        Self {
+            fallback: config.justification_interval.clamp(min, max),
+            min,
+            max,
+            last: None,
+            spacings: VecDeque::with_capacity(Self::WINDOW),
+        }
+    }
+
+    pub(crate) fn observe(&mut self, block: BlockNumber) {
+        if let Some(last) = self.last {
+            if block > last {
+                if self.spacings.len() == Self::WINDOW {
+                    self.spacings.pop_front();
+                }
+                self.spacings.push_back(block - last);
+            }
+        }
+        self.last = Some(block);
+    }
+
+    /// The number of blocks expected between two justifications.
+    pub(crate) fn interval(&self) -> BlockNumber {
+        if self.spacings.len() < Self::MIN_SAMPLES {
+            return self.fallback;
+        }
+        let mut spacings: Vec<_> = self.spacings.iter().copied().collect();
+        spacings.sort_unstable();
+        spacings[spacings.len() / 2].clamp(self.min, self.max)
+    }
+}
+
+/// Chooses the number of blocks per storage changes request to keep a batch within a byte budget.
+///
+/// Storage changes vary a lot in size, so the count is derived from the size of recently grabbed
+/// blocks: it grows while the blocks are small and shrinks as soon as a large one shows up.
+pub(crate) struct AdaptiveBatch {
+    max_count: BlockNumber,
+    // Current ceiling of the count, halved on failed or oversized batches, regrown on good ones.
+    limit: BlockNumber,
+    budget_bytes: usize,
+    // Estimated size of a single block, 0 if unknown yet.
+    block_bytes: usize,
+}
+
+impl AdaptiveBatch {
+    pub(crate) fn new(max_count: BlockNumber, budget_bytes: usize) -> Self {
+        let max_count = max_count.max(1);
+        let batch = Self {
+            max_count,
+            limit: max_count,
+            budget_bytes,
+            block_bytes: 0,
+        };
+        batch.report();
+        batch
+    }
+
+    pub(crate) fn next_count(&self) -> BlockNumber {
+        if self.budget_bytes == 0 {
+            return self.limit;
+        }
+        if self.block_bytes == 0 {
+            // Probe with a single block before we know anything about the sizes.
+            return 1;
+        }
+        let count = self.budget_bytes / self.block_bytes;
+        count.clamp(1, self.limit as usize) as BlockNumber
+    }
+
+    pub(crate) fn record(&mut self, blocks: usize, bytes: usize) {
+        if blocks == 0 {
+            return;
+        }
+        let avg = bytes.div_ceil(blocks).max(1);
+        // Shrink immediately on large blocks, grow slowly on small ones.
+        self.block_bytes = if avg >= self.block_bytes {
+            avg
+        } else {
+            (self.block_bytes + avg) / 2
+        };
+        if self.budget_bytes != 0 && bytes > self.budget_bytes {
+            self.limit = (blocks as BlockNumber / 2).max(1);
+        } else {
+            self.limit = (self.limit + (self.limit / 4).max(1)).min(self.max_count);
+        }
+        self.report();
+    }
+
+    /// Record a batch of `blocks` failed to be grabbed. Returns false if it can not go smaller.
+    pub(crate) fn record_failure(&mut self, blocks: BlockNumber) -> bool {
+        if blocks <= 1 {
+            return false;
+        }
+        self.limit = (blocks / 2).max(1);
+        self.report();
+        true
+    }
+
+    fn report(&self) {
+        metrics().storage_changes_batch.set(self.next_count() as _);
+    }
+}
+
 static GENESIS: AtomicU32 = AtomicU32::new(u32::MAX);
+
+/// Progress of the grab loop, for external monitoring.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "event")]
+pub(crate) enum GrabEvent {
+    HeaderCommitted { number: BlockNumber },
+    JustificationFound { number: BlockNumber },
+    ParaHeaderCommitted { number: BlockNumber },
+    StorageChangeCommitted { number: BlockNumber },
+    MismatchFixed { block: BlockNumber },
+}
+
+pub(crate) type GrabEventSender = tokio::sync::mpsc::UnboundedSender<GrabEvent>;
+
+/// Send the event to the subscriber if any. The event is not even built if there is none.
+fn emit(config: &Serve, event: impl FnOnce() -> GrabEvent) {
+    if let Some(events) = &config.events {
+        // The receiver being gone only means nobody is watching anymore
+        let _ = events.send(event());
+    }
+}
+
 static LATEST_JUSTFICATION: AtomicU32 = AtomicU32::new(u32::MAX);
 
 pub(crate) fn genesis_block() -> BlockNumber {
@@ -310,49 +982,106 @@ async fn sleep(secs: u64) {
     tokio::time::sleep(std::time::Duration::from_secs(secs)).await;
 }
 
+/// Why a range of headers failed to be checked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum CheckError {
+    /// The header is missing in the DB and could not be regrabbed.
+    HeaderNotFound(BlockNumber),
+    /// The header in the DB is undecodable and could not be regrabbed.
+    DecodeFailed(BlockNumber),
+    /// The header still doesn't link to its parent after regrabbing both of them.
+    UnfixableMismatch(BlockNumber),
+    /// The end of the range is below its start.
+    InvalidRange,
+}
+
+impl fmt::Display for CheckError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::HeaderNotFound(block) => write!(f, "Header {block} not found"),
+            Self::DecodeFailed(block) => write!(f, "Failed to decode header {block}"),
+            Self::UnfixableMismatch(block) => write!(f, "Cannot fix mismatch at {block}"),
+            Self::InvalidRange => write!(f, "Invalid range"),
+        }
+    }
+}
+
+impl std::error::Error for CheckError {}
+
+/// The outcome of a successful check of a range of headers.
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct CheckReport {
+    pub from: BlockNumber,
+    pub to: BlockNumber,
+    /// Number of headers checked against their parent.
+    pub checked: BlockNumber,
+    /// Number of headers regrabbed because they were missing or undecodable.
+    pub regrabbed: BlockNumber,
+    /// Number of parent hash mismatches fixed by regrabbing.
+    pub mismatches_fixed: BlockNumber,
+}
+
+impl fmt::Display for CheckReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Self { from, to, .. } = self;
+        match self.mismatches_fixed {
+            0 => write!(f, "Checked blocks from {from} to {to}, All OK"),
+            n => write!(f, "Checked blocks from {from} to {to}, {n} mismatches"),
+        }
+    }
+}
+
 pub(crate) async fn check_and_fix_headers(
     db: &CacheDB,
     config: &Serve,
-    chain: &str,
+    parachain: bool,
     from: BlockNumber,
     to: Option<BlockNumber>,
     count: Option<BlockNumber>,
-) -> Result<String> {
-    let parachain = match chain {
-        "relay" => false,
-        "para" => true,
-        _ => bail!("Unknown check type {chain}"),
-    };
+) -> Result<CheckReport, CheckError> {
+    let chain = if parachain { "para" } else { "relay" };
     let to = to.unwrap_or(from + count.unwrap_or(1));
     info!("Checking {chain} headers from {from} to {to}");
     if to < from {
-        bail!("Invalid range");
+        return Err(CheckError::InvalidRange);
     }
+    let mut report = CheckReport {
+        from,
+        to,
+        checked: 0,
+        regrabbed: 0,
+        mismatches_fixed: 0,
+    };
     let from = from.saturating_sub(1);
-    let mut prev = load_header_or_regrab(db, config, parachain, from).await?;
-    let mut mismatches = 0;
+    let mut prev = load_header_or_regrab(db, config, parachain, from, &mut report).await?;
     for block in (from + 1)..=to {
-        let cur_header = load_header_or_regrab(db, config, parachain, block).await?;
+        let cur_header = load_header_or_regrab(db, config, parachain, block, &mut report).await?;
+        report.checked += 1;
         if prev.hash() != cur_header.parent_hash {
-            mismatches += 1;
+            // Both headers are there, so failing to regrab them leaves the mismatch unfixed.
+            let unfixable = CheckError::UnfixableMismatch(block);
             let prev = regrab_header(db, config, prev.number, parachain)
                 .await
-                .context("Failed to regrab header")?;
-            let cur_header = regrab_header(db, config, cur_header.number, parachain).await?;
+                .map_err(|err| regrab_failed(err, unfixable))?;
+            let cur_header = regrab_header(db, config, cur_header.number, parachain)
+                .await
+                .map_err(|err| regrab_failed(err, unfixable))?;
             if prev.hash() != cur_header.parent_hash {
-                bail!("Cannot fix mismatch at {block}");
+                return Err(CheckError::UnfixableMismatch(block));
             }
+            report.mismatches_fixed += 1;
+            metrics().mismatches_fixed.inc();
+            emit(config, || GrabEvent::MismatchFixed { block });
         }
         prev = cur_header;
     }
-    let from = from + 1;
-    let response = if mismatches > 0 {
-        format!("Checked blocks from {from} to {to}, {mismatches} mismatches")
-    } else {
-        format!("Checked blocks from {from} to {to}, All OK")
-    };
-    info!("{}", response);
-    Ok(response)
+    info!("{report}");
+    Ok(report)
+}
+
+fn regrab_failed(err: anyhow::Error, reported: CheckError) -> CheckError {
+    warn!("{reported}: failed to regrab: {err:?}");
+    reported
 }
 
 pub(crate) async fn check_and_fix_storages_changes(
@@ -366,9 +1095,7 @@ pub(crate) async fn check_and_fix_storages_changes(
 ) -> Result<u32> {
     let api = match api {
         Some(api) => api,
-        None => pherry::subxt_connect(&config.para_node_uri)
-            .await
-            .context(format!("Failed to connect to {}", config.para_node_uri))?,
+        None => connect_any(&config.para_node_uri).await?,
     };
     let to = to.unwrap_or(from + count.unwrap_or(1));
     info!("Checking storage changes from {from} to {to}");
@@ -413,21 +1140,50 @@ async fn load_header_or_regrab(
     config: &Serve,
     parachain: bool,
     block: BlockNumber,
-) -> Result<Header> {
+    report: &mut CheckReport,
+) -> Result<Header, CheckError> {
     let header = if parachain {
-        db.get_para_header(block)
+        Ok(db.get_para_header(block))
     } else {
-        db.get_header(block)
-    }
-    .and_then(|header| decode_header(&header).ok());
-
+        db.get_header_checked(block)
+    };
     let header = match header {
-        Some(header) => header,
+        Ok(header) => header,
+        Err(err) => {
+            warn!("{err}");
+            metrics().codec_errors.inc();
+            return regrab_undecodable(db, config, parachain, block, report).await;
+        }
+    };
+    match header.map(|header| decode_header(&header)) {
+        Some(Ok(header)) => Ok(header),
+        Some(Err(_)) => {
+            metrics().codec_errors.inc();
+            regrab_undecodable(db, config, parachain, block, report).await
+        }
         None => {
             warn!("Header {block} not found, trying to regrab");
-            regrab_header(db, config, block, parachain).await?
+            let header = regrab_header(db, config, block, parachain)
+                .await
+                .map_err(|err| regrab_failed(err, CheckError::HeaderNotFound(block)))?;
+            report.regrabbed += 1;
+            Ok(header)
         }
-    };
+    }
+}
+
+async fn regrab_undecodable(
+    db: &CacheDB,
+    config: &Serve,
+    parachain: bool,
+    block: BlockNumber,
+    report: &mut CheckReport,
+) -> Result<Header, CheckError> {
+    warn!("Header {block} is undecodable, trying to regrab");
+    let header = regrab_header(db, config, block, parachain)
+        .await
+        .map_err(|err| regrab_failed(err, CheckError::DecodeFailed(block)))?;
+    report.regrabbed += 1;
     Ok(header)
 }
 
@@ -448,9 +1204,7 @@ async fn regrab_header(
         bail!("Grab {chain} headers disabled");
     }
     info!("Regrabbing {chain}chain header {}", number);
-    let para_api = pherry::subxt_connect(&config.para_node_uri)
-        .await
-        .context(format!("Failed to connect to {}", config.para_node_uri))?;
+    let para_api = connect_any(&config.para_node_uri).await?;
     let mut grabed = None;
     if parachain {
         cache::grab_para_headers(&para_api, number, 1, |header| {
@@ -461,9 +1215,7 @@ async fn regrab_header(
         })
         .await?;
     } else {
-        let api = pherry::subxt_connect(&config.node_uri)
-            .await
-            .context(format!("Failed to connect to {}", config.node_uri))?;
+        let api = connect_any(&config.node_uri).await?;
         cache::grab_headers(&api, &para_api, number, 1, 1, |info| {
             db.put_header(info.header.number, &info.encode())
                 .context("Failed to put record to DB")?;
@@ -474,3 +1226,185 @@ async fn regrab_header(
     };
     grabed.ok_or(anyhow!("Failed to grab {chain}chain header {number}"))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::TestDb;
+    use clap::Parser;
+
+    fn serve_config(args: &[&str]) -> Serve {
+        let args = ["headers-cache", "serve"].iter().chain(args);
+        match crate::AppArgs::parse_from(args).action {
+            crate::Action::Serve(config) => config,
+            _ => unreachable!(),
+        }
+    }
+
+    fn observed(cadence: &mut JustificationCadence, blocks: &[BlockNumber]) -> BlockNumber {
+        for &block in blocks {
+            cadence.observe(block);
+        }
+        cadence.interval()
+    }
+
+    #[test]
+    fn justification_cadence_follows_the_median_spacing() {
+        let mut cadence = JustificationCadence::new(&serve_config(&[]));
+        // Falls back to the configured interval until enough spacings are observed.
+        assert_eq!(observed(&mut cadence, &[100, 150, 210]), 1000);
+        assert_eq!(observed(&mut cadence, &[260]), 50);
+        // Blocks seen again don't count as a spacing.
+        assert_eq!(observed(&mut cadence, &[260, 200]), 50);
+        // Old spacings leave the window.
+        let blocks: Vec<_> = (1..=8).map(|i| 200 + i * 400).collect();
+        assert_eq!(observed(&mut cadence, &blocks), 400);
+    }
+
+    #[test]
+    fn justification_cadence_stays_within_the_bounds() {
+        let config = serve_config(&[
+            "--justification-interval=5000",
+            "--justification-interval-min=100",
+            "--justification-interval-max=2000",
+        ]);
+        let mut cadence = JustificationCadence::new(&config);
+        assert_eq!(cadence.interval(), 2000);
+        assert_eq!(observed(&mut cadence, &[0, 10, 20, 30]), 100);
+        assert_eq!(observed(&mut cadence, &[5030, 10030, 15030, 20030]), 2000);
+
+        // A lower bound above the upper one is lowered to it.
+        let config = serve_config(&["--justification-interval-min=2000"]);
+        let mut cadence = JustificationCadence::new(&config);
+        assert_eq!(observed(&mut cadence, &[0, 1, 2, 3]), 1000);
+    }
+
+    #[test]
+    fn storage_changes_batch_follows_the_byte_budget() {
+        let mut batch = AdaptiveBatch::new(100, 1000);
+        // A single block is probed first.
+        assert_eq!(batch.next_count(), 1);
+        batch.record(1, 10);
+        assert_eq!(batch.next_count(), 100);
+        batch.record(2, 1000);
+        assert_eq!(batch.next_count(), 2);
+
+        // An oversized batch shrinks the count at once.
+        batch.record(2, 4000);
+        assert_eq!(batch.next_count(), 1);
+        // Then small blocks bring it back up gradually.
+        let counts: Vec<_> = (0..4)
+            .map(|_| {
+                batch.record(1, 10);
+                batch.next_count()
+            })
+            .collect();
+        assert_eq!(counts, [1, 1, 3, 5]);
+    }
+
+    #[test]
+    fn storage_changes_batch_without_budget_halves_on_failures() {
+        let mut batch = AdaptiveBatch::new(8, 0);
+        assert_eq!(batch.next_count(), 8);
+        assert!(batch.record_failure(8));
+        assert_eq!(batch.next_count(), 4);
+        batch.record(4, 1 << 30);
+        assert_eq!(batch.next_count(), 5);
+        assert!(!batch.record_failure(1));
+    }
+
+    fn linked_para_headers(db: &CacheDB, blocks: std::ops::RangeInclusive<BlockNumber>) {
+        let mut parent_hash = Default::default();
+        for number in blocks {
+            let header = Header {
+                parent_hash,
+                number,
+                state_root: Default::default(),
+                extrinsics_root: Default::default(),
+                digest: Default::default(),
+            };
+            db.put_para_header(number, &header.encode()).unwrap();
+            parent_hash = header.hash();
+        }
+    }
+
+    #[tokio::test]
+    async fn header_check_reports_why_it_failed() {
+        let test_db = TestDb::new("check-headers");
+        let db = &test_db.db;
+        // Regrabbing is disabled, so nothing is fixed from the node.
+        let config = serve_config(&[]);
+        let check = |from, to| check_and_fix_headers(db, &config, true, from, Some(to), None);
+        linked_para_headers(db, 0..=3);
+
+        let report = check(1, 3).await.unwrap();
+        assert_eq!(
+            (report.checked, report.regrabbed, report.mismatches_fixed),
+            (3, 0, 0)
+        );
+        assert_eq!(report.to_string(), "Checked blocks from 1 to 3, All OK");
+
+        assert_eq!(check(3, 1).await.unwrap_err(), CheckError::InvalidRange);
+        assert_eq!(
+            check(1, 4).await.unwrap_err(),
+            CheckError::HeaderNotFound(4)
+        );
+        db.put_para_header(4, b"garbage").unwrap();
+        let err = check(1, 4).await.unwrap_err();
+        assert_eq!(err, CheckError::DecodeFailed(4));
+        assert_eq!(err.to_string(), "Failed to decode header 4");
+
+        // Header 2 doesn't link to the rewritten header 1, which can't be regrabbed.
+        linked_para_headers(db, 1..=1);
+        assert_eq!(
+            check(2, 2).await.unwrap_err(),
+            CheckError::UnfixableMismatch(2)
+        );
+    }
+
+    #[test]
+    fn endpoints_rotate_after_consecutive_failures() {
+        let config = serve_config(&[
+            "--node-uri",
+            "ws://a,ws://b",
+            "--node-failover-attempts",
+            "2",
+        ]);
+        let mut endpoints = Endpoints::new("relaychain", &config.node_uri, &config);
+        endpoints.on_failure();
+        endpoints.on_success();
+        endpoints.on_failure();
+        assert_eq!(endpoints.active(), "ws://a");
+        endpoints.on_failure();
+        assert_eq!(endpoints.active(), "ws://b");
+        endpoints.on_failure();
+        endpoints.on_failure();
+        assert_eq!(endpoints.active(), "ws://a");
+    }
+
+    #[tokio::test]
+    async fn connecting_gives_up_after_the_max_attempts() {
+        let config = serve_config(&[
+            "--node-uri",
+            "ws://127.0.0.1:1,ws://127.0.0.1:2",
+            "--node-failover-attempts",
+            "2",
+            "--reconnect-base-delay-ms",
+            "0",
+            "--reconnect-jitter-ms",
+            "0",
+        ]);
+        let mut endpoints = Endpoints::new("relaychain", &config.node_uri, &config);
+        let mut backoff = Backoff::new(&config);
+        let err = connect_with_backoff(&mut endpoints, &mut backoff, Some(3))
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "Failed to connect to relaychain node after 3 attempts"
+        );
+        // Rotated to the second endpoint after the first two failed.
+        assert_eq!(endpoints.active(), "ws://127.0.0.1:2");
+    }
+}
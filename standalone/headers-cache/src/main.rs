@@ -1,15 +1,17 @@
 use std::fs::File;
 use std::io::Write;
 
-use anyhow::Context;
-use log::{error, info};
+use anyhow::{bail, Context};
+use log::{error, info, warn};
 use scale::{Decode, Encode};
+use serde::Serialize;
 
 use clap::{Args, Parser, Subcommand};
-use pherry::headers_cache as cache;
+use pherry::{headers_cache as cache, types::Header};
 
 mod db;
 mod grab;
+mod metrics;
 mod web_api;
 
 type BlockNumber = u32;
@@ -142,30 +144,92 @@ struct Serve {
     #[clap(long)]
     #[clap(default_value_t = 1)]
     grab_storage_changes_batch: BlockNumber,
-    /// The relaychain RPC endpoint
-    #[clap(long, default_value = "ws://localhost:9945")]
-    node_uri: String,
-    /// The parachain RPC endpoint
-    #[clap(long, default_value = "ws://localhost:9944")]
-    para_node_uri: String,
+    /// Max bytes of storage changes fetched in a single batch (0 for no limit).
+    ///
+    /// The batch size is adapted to the size of the recently grabbed storage changes, and never
+    /// exceeds `--grab-storage-changes-batch`. It is halved when a batch fails or exceeds this
+    /// limit, and grows back gradually on the following good batches.
+    #[clap(long, default_value_t = 64 * 1024 * 1024)]
+    grab_storage_changes_batch_bytes: usize,
+    /// The relaychain RPC endpoints, comma separated or repeated. Tried in order.
+    #[clap(long, value_delimiter = ',', default_value = "ws://localhost:9945")]
+    node_uri: Vec<String>,
+    /// The parachain RPC endpoints, comma separated or repeated. Tried in order.
+    #[clap(long, value_delimiter = ',', default_value = "ws://localhost:9944")]
+    para_node_uri: Vec<String>,
+    /// Number of consecutive failures, to connect or to grab, before switching to the next node
+    /// endpoint
+    #[clap(long, default_value_t = 3)]
+    node_failover_attempts: u32,
+    /// Number of attempts to connect to a node before `backfill` and `grab-backward` give up.
+    /// The server retries forever
+    #[clap(long, default_value_t = 10)]
+    connect_attempts: u32,
     /// Interval that start a batch of grab
     #[clap(long, default_value_t = 30)]
     interval: u64,
     /// Prefered minimum number of blocks between justification
     #[arg(long, default_value_t = 1000)]
     justification_interval: BlockNumber,
+    /// Lower bound of the justification spacing learned from the recently grabbed headers
+    #[arg(long, default_value_t = 1)]
+    justification_interval_min: BlockNumber,
+    /// Upper bound of the learned justification spacing. Defaults to `--justification-interval`
+    #[arg(long)]
+    justification_interval_max: Option<BlockNumber>,
     /// Token for uploading APIs.
     #[arg(long)]
     token: Option<String>,
     /// The max batch size to check headers
     #[clap(long, default_value_t = 100000)]
     check_batch: BlockNumber,
+    /// Check the relaychain headers again from the given block on startup, e.g. after a suspected
+    /// corruption
+    #[clap(long)]
+    recheck_from: Option<BlockNumber>,
+    /// Check the parachain headers again from the given block on startup
+    #[clap(long)]
+    recheck_para_from: Option<BlockNumber>,
     /// Don't check state root for each storage changes
     #[clap(long)]
     no_state_root: bool,
     /// Skip blocks with empty state root while checking storage changes
     #[clap(long)]
     allow_empty_state_root: bool,
+    /// Number of records to prefetch from the DB while serving a range (0 to disable, max 4096)
+    #[clap(long, default_value_t = 16)]
+    read_ahead: usize,
+    /// Also store the justifications as separate records, fetchable via /justification/<block>
+    #[clap(long)]
+    store_justifications: bool,
+    /// Run the grab stages one after another instead of concurrently
+    #[clap(long)]
+    sequential_grab: bool,
+    /// Write the grabbed records and the metadata separately instead of in a single atomic batch
+    #[clap(long)]
+    unbatched_writes: bool,
+    /// Verify the checksums of the stored headers when reading them
+    #[clap(long, default_value_t = true, action = clap::ArgAction::Set)]
+    verify_checksums: bool,
+    /// Compress the storage changes written at the given zstd level (1-22). The records stored
+    /// either way are read back transparently
+    #[clap(long)]
+    compress_storage_changes: Option<i32>,
+    /// Print the progress events of the grab loop to stdout, one JSON object per line
+    #[clap(long)]
+    print_events: bool,
+    /// Receives the progress events of the grab loop if set
+    #[clap(skip)]
+    events: Option<grab::GrabEventSender>,
+    /// Initial delay in milliseconds before reconnecting to a node
+    #[clap(long, default_value_t = 1000)]
+    reconnect_base_delay_ms: u64,
+    /// Max delay in milliseconds between reconnection attempts
+    #[clap(long, default_value_t = 60_000)]
+    reconnect_max_delay_ms: u64,
+    /// Max random jitter in milliseconds added to each reconnection delay
+    #[clap(long, default_value_t = 1000)]
+    reconnect_jitter_ms: u64,
 }
 
 #[derive(Subcommand)]
@@ -185,8 +249,39 @@ enum Action {
         #[command(subcommand)]
         what: Import,
     },
+    /// Store a genesis produced elsewhere, e.g. by `grab genesis`, so that the server doesn't need
+    /// to fetch it from the node
+    ImportGenesis {
+        /// The database file to use
+        #[arg(long, default_value = "cache.db")]
+        db: String,
+        /// The block number of the genesis
+        #[arg(long)]
+        block: BlockNumber,
+        /// The genesis file to read from
+        file: String,
+    },
     /// Run the cache server
     Serve(Serve),
+    /// Grab the records missing in the given range into the database, then exit
+    Backfill {
+        /// The first block to fill
+        #[arg(long)]
+        from: BlockNumber,
+        /// The last block to fill, inclusive
+        #[arg(long)]
+        to: BlockNumber,
+        #[command(flatten)]
+        config: Serve,
+    },
+    /// Grab the relaychain headers below the lowest stored one, down to the given block
+    GrabBackward {
+        /// The lowest block to grab
+        #[arg(long)]
+        to: BlockNumber,
+        #[command(flatten)]
+        config: Serve,
+    },
     /// Split given grabbed headers file into chunks
     Split {
         /// Size in MB of each chunk
@@ -207,6 +302,21 @@ enum Action {
         #[arg(long, default_value = "cache.db")]
         db: String,
     },
+    /// Measure how much zstd compressing the stored storage changes of a range would save
+    MeasureCompression {
+        /// The database file to use
+        #[arg(long, default_value = "cache.db")]
+        db: String,
+        /// The first block to measure. Defaults to the lowest stored one
+        #[arg(long)]
+        from: Option<BlockNumber>,
+        /// The last block to measure, inclusive. Defaults to the highest stored one
+        #[arg(long)]
+        to: Option<BlockNumber>,
+        /// The zstd level to compress at
+        #[arg(long, default_value_t = 3)]
+        level: i32,
+    },
     /// Merge given chunks into a single file
     Merge {
         /// Appending to existing file
@@ -217,6 +327,24 @@ enum Action {
         /// The header chunk files to merge.
         files: Vec<String>,
     },
+    /// Delete the records older than the given number of blocks from the database
+    Prune {
+        /// The database file to use
+        #[arg(long, default_value = "cache.db")]
+        db: String,
+        /// Number of the most recent blocks of each kind to keep
+        #[arg(long)]
+        keep_blocks: BlockNumber,
+    },
+    /// Verify the parent hash linkage of every stored header without touching the database
+    Verify {
+        /// The database file to use
+        #[arg(long, default_value = "cache.db")]
+        db: String,
+        /// Which headers to verify
+        #[arg(value_enum)]
+        what: Chain,
+    },
     /// Reset cursors
     Reset {
         /// The database file to use
@@ -241,7 +369,26 @@ async fn main() -> anyhow::Result<()> {
     match args.action {
         Action::Grab { what } => grab(what).await?,
         Action::Import { db, what } => import(db, what).await?,
+        Action::ImportGenesis { db, block, file } => {
+            let data = std::fs::read(&file).with_context(|| format!("Failed to read {file}"))?;
+            let cache = db::CacheDB::open(&db)?;
+            cache.import_genesis(block, &data)?;
+            cache.flush()?;
+            info!("Imported genesis at {block}");
+        }
         Action::Serve(config) => serve(config).await?,
+        Action::Backfill { from, to, config } => {
+            let db = db::CacheDB::open(&config.db)?
+                .verify_checksums(config.verify_checksums)
+                .compress_storage_changes(config.compress_storage_changes);
+            grab::backfill(db, config, from, to).await?;
+        }
+        Action::GrabBackward { to, config } => {
+            let db = db::CacheDB::open(&config.db)?
+                .verify_checksums(config.verify_checksums)
+                .compress_storage_changes(config.compress_storage_changes);
+            grab::grab_backward(db, config, to).await?;
+        }
         Action::Split { size, file } => split(size, file)?,
         Action::Merge {
             append,
@@ -250,6 +397,14 @@ async fn main() -> anyhow::Result<()> {
         } => merge(append, dest_file, files)?,
         Action::Inspect { files } => inspect(files)?,
         Action::InspectDb { db } => inspect_db(db)?,
+        Action::MeasureCompression {
+            db,
+            from,
+            to,
+            level,
+        } => measure_compression(db, from, to, level)?,
+        Action::Prune { db, keep_blocks } => prune(db, keep_blocks)?,
+        Action::Verify { db, what } => verify(db, what)?,
         Action::Reset {
             db,
             header,
@@ -261,10 +416,25 @@ async fn main() -> anyhow::Result<()> {
     Ok(())
 }
 
-async fn serve(config: Serve) -> anyhow::Result<()> {
-    let db = db::CacheDB::open(&config.db)?;
+async fn serve(mut config: Serve) -> anyhow::Result<()> {
+    let db = db::CacheDB::open(&config.db)?
+        .verify_checksums(config.verify_checksums)
+        .compress_storage_changes(config.compress_storage_changes);
     let token = config.token.clone();
 
+    if config.print_events {
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        config.events = Some(tx);
+        tokio::spawn(async move {
+            while let Some(event) = rx.recv().await {
+                match serde_json::to_string(&event) {
+                    Ok(line) => println!("{line}"),
+                    Err(err) => error!("Failed to encode grab event: {err}"),
+                }
+            }
+        });
+    }
+
     if let Some(upstream) = config.mirror.clone() {
         if config.grab_headers {
             error!("ignored --grab since --mirror is turned on");
@@ -378,6 +548,168 @@ fn inspect_db(db: String) -> anyhow::Result<()> {
     Ok(())
 }
 
+/// The outcome of a compression measurement of the stored storage changes.
+#[derive(Serialize)]
+struct CompressionReport {
+    from: BlockNumber,
+    to: BlockNumber,
+    level: i32,
+    /// Number of storage changes found.
+    records: u64,
+    raw_bytes: u64,
+    compressed_bytes: u64,
+    /// `compressed_bytes / raw_bytes`
+    ratio: f64,
+}
+
+fn measure_compression(
+    db: String,
+    from: Option<BlockNumber>,
+    to: Option<BlockNumber>,
+    level: i32,
+) -> anyhow::Result<()> {
+    let cache = db::CacheDB::open_read_only(&db)?;
+    let metadata = cache.get_metadata()?.unwrap_or_default();
+    let Some(to) = to.or(metadata.higest.storage_changes) else {
+        bail!("No storage changes in the database");
+    };
+    let from = from.or(metadata.lowest.storage_changes).unwrap_or_default();
+    let mut report = CompressionReport {
+        from,
+        to,
+        level,
+        records: 0,
+        raw_bytes: 0,
+        compressed_bytes: 0,
+        ratio: 1.0,
+    };
+    for block in from..=to {
+        let Some(changes) = cache.get_storage_changes(block) else {
+            continue;
+        };
+        let record = db::encode_storage_changes(&changes, Some(level))?;
+        report.records += 1;
+        report.raw_bytes += changes.len() as u64 + 1;
+        report.compressed_bytes += record.len() as u64;
+    }
+    if report.raw_bytes > 0 {
+        report.ratio = report.compressed_bytes as f64 / report.raw_bytes as f64;
+    }
+    serde_json::to_writer_pretty(std::io::stdout(), &report)?;
+    Ok(())
+}
+
+fn prune(db: String, keep_blocks: BlockNumber) -> anyhow::Result<()> {
+    let cache = db::CacheDB::open(&db)?;
+    let metadata = cache.get_metadata()?.unwrap_or_default();
+    let below = |highest: Option<BlockNumber>| highest.map(|n| n.saturating_sub(keep_blocks));
+    let below = db::Counters {
+        header: below(metadata.higest.header),
+        para_header: below(metadata.higest.para_header),
+        storage_changes: below(metadata.higest.storage_changes),
+    };
+    let metadata = cache.prune_headers_below(&below)?;
+    cache.flush()?;
+    info!("Pruned, lowest available: {:?}", metadata.lowest);
+    Ok(())
+}
+
+#[derive(Clone, Copy, clap::ValueEnum)]
+enum Chain {
+    Relay,
+    Para,
+}
+
+/// The outcome of a full verification of the stored headers.
+#[derive(Serialize)]
+struct VerifyReport {
+    from: BlockNumber,
+    to: BlockNumber,
+    /// Number of headers found readable.
+    total: BlockNumber,
+    /// Inclusive ranges of the headers missing or unreadable.
+    gaps: Vec<(BlockNumber, BlockNumber)>,
+    /// Headers whose parent hash doesn't match the header before them.
+    forks: Vec<BlockNumber>,
+    first_break: Option<BlockNumber>,
+}
+
+fn verify(db: String, chain: Chain) -> anyhow::Result<()> {
+    const PROGRESS_INTERVAL: BlockNumber = 100_000;
+
+    let cache = db::CacheDB::open_read_only(&db)?;
+    let metadata = cache.get_metadata()?.unwrap_or_default();
+    let (from, to, name) = match chain {
+        Chain::Relay => {
+            let genesis = metadata.genesis.iter().min().copied();
+            (
+                metadata.lowest.header.or(genesis),
+                metadata.higest.header,
+                "relay",
+            )
+        }
+        Chain::Para => (
+            metadata.lowest.para_header,
+            metadata.higest.para_header,
+            "para",
+        ),
+    };
+    let Some(to) = to else {
+        bail!("No {name} headers in the database");
+    };
+    let from = from.unwrap_or_default();
+    info!("Verifying {name} headers from {from} to {to}");
+    let mut report = VerifyReport {
+        from,
+        to,
+        total: 0,
+        gaps: vec![],
+        forks: vec![],
+        first_break: None,
+    };
+    let mut prev: Option<Header> = None;
+    for block in from..=to {
+        let record = match chain {
+            Chain::Relay => cache.get_header_checked(block).unwrap_or_else(|err| {
+                warn!("{err}");
+                None
+            }),
+            Chain::Para => cache.get_para_header(block),
+        };
+        match record.and_then(|data| Header::decode(&mut &data[..]).ok()) {
+            None => {
+                match report.gaps.last_mut() {
+                    Some((_, end)) if *end + 1 == block => *end = block,
+                    _ => {
+                        warn!("Gap starts at {block}");
+                        report.gaps.push((block, block));
+                    }
+                }
+                report.first_break.get_or_insert(block);
+                prev = None;
+            }
+            Some(header) => {
+                report.total += 1;
+                if matches!(&prev, Some(prev) if prev.hash() != header.parent_hash) {
+                    warn!("Fork point at {block}, the parent hash mismatches");
+                    report.forks.push(block);
+                    report.first_break.get_or_insert(block);
+                }
+                prev = Some(header);
+            }
+        }
+        if block % PROGRESS_INTERVAL == 0 {
+            info!("Verified {name} headers up to {block}/{to}");
+        }
+    }
+    serde_json::to_writer_pretty(std::io::stdout(), &report)?;
+    println!();
+    if let Some(block) = report.first_break {
+        bail!("The {name} headers break at {block}");
+    }
+    Ok(())
+}
+
 fn reset(
     db: String,
     header: Option<u32>,
@@ -535,10 +867,7 @@ async fn import(db: String, what: Import) -> anyhow::Result<()> {
             let data = std::fs::read(input)?;
             let info = cache::GenesisBlockInfo::decode(&mut &data[..])
                 .context("Failed to decode the genesis data")?;
-            cache.put_genesis(info.block_header.number, &data)?;
-            let mut metadata = cache.get_metadata()?.unwrap_or_default();
-            metadata.put_genesis(info.block_header.number);
-            cache.put_metadata(&metadata)?;
+            cache.import_genesis(info.block_header.number, &data)?;
             println!("genesis at {} put", info.block_header.number);
         }
     }
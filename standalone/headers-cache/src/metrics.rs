@@ -0,0 +1,124 @@
+use std::sync::OnceLock;
+
+use prometheus::{Encoder, IntCounter, IntGauge, Registry, TextEncoder};
+
+use crate::db::CacheDB;
+
+pub(crate) struct Metrics {
+    registry: Registry,
+    highest_header: IntGauge,
+    highest_para_header: IntGauge,
+    highest_storage_changes: IntGauge,
+    latest_justification: IntGauge,
+    pub(crate) mismatches_fixed: IntCounter,
+    pub(crate) codec_errors: IntCounter,
+    pub(crate) storage_changes_batch: IntGauge,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new_custom(Some("headers_cache".into()), None)
+            .expect("Failed to create metrics registry");
+        macro_rules! register {
+            ($kind: ident, $name: expr, $help: expr) => {{
+                let metric = $kind::new($name, $help).expect("Invalid metric");
+                registry
+                    .register(Box::new(metric.clone()))
+                    .expect("Failed to register metric");
+                metric
+            }};
+        }
+        Self {
+            highest_header: register!(IntGauge, "highest_header", "Highest relaychain header"),
+            highest_para_header: register!(
+                IntGauge,
+                "highest_para_header",
+                "Highest parachain header"
+            ),
+            highest_storage_changes: register!(
+                IntGauge,
+                "highest_storage_changes",
+                "Highest storage changes"
+            ),
+            latest_justification: register!(
+                IntGauge,
+                "latest_justification",
+                "Block number of the latest justification"
+            ),
+            mismatches_fixed: register!(
+                IntCounter,
+                "mismatches_fixed_total",
+                "Number of header mismatches fixed while checking"
+            ),
+            codec_errors: register!(
+                IntCounter,
+                "codec_errors_total",
+                "Number of undecodable records met while checking"
+            ),
+            storage_changes_batch: register!(
+                IntGauge,
+                "storage_changes_batch",
+                "Number of blocks grabbed in the next storage changes batch"
+            ),
+            registry,
+        }
+    }
+}
+
+pub(crate) fn metrics() -> &'static Metrics {
+    static METRICS: OnceLock<Metrics> = OnceLock::new();
+    METRICS.get_or_init(Metrics::new)
+}
+
+/// Render the metrics in the prometheus text format.
+///
+/// The gauges are refreshed from the DB metadata on each call.
+pub(crate) fn render(db: &CacheDB) -> String {
+    let metrics = metrics();
+    let metadata = db.get_metadata().ok().flatten().unwrap_or_default();
+    let highest = &metadata.higest;
+    metrics
+        .highest_header
+        .set(highest.header.unwrap_or_default() as _);
+    metrics
+        .highest_para_header
+        .set(highest.para_header.unwrap_or_default() as _);
+    metrics
+        .highest_storage_changes
+        .set(highest.storage_changes.unwrap_or_default() as _);
+    let latest_justification = crate::grab::latest_justification();
+    if latest_justification != u32::MAX {
+        metrics.latest_justification.set(latest_justification as _);
+    }
+    let mut buffer = vec![];
+    if let Err(err) = TextEncoder::new().encode(&metrics.registry.gather(), &mut buffer) {
+        log::error!("Failed to encode metrics: {err}");
+    }
+    String::from_utf8(buffer).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{tests::TestDb, Metadata};
+
+    #[test]
+    fn rendered_gauges_follow_the_metadata() {
+        let test = TestDb::new("metrics");
+        let mut metadata = Metadata::default();
+        metadata.update_header(42);
+        metadata.update_storage_changes(7);
+        test.db.put_metadata(&metadata).unwrap();
+        metrics().mismatches_fixed.inc();
+
+        let text = render(&test.db);
+        let lines: Vec<_> = text.lines().collect();
+        assert!(lines.contains(&"headers_cache_highest_header 42"));
+        assert!(lines.contains(&"headers_cache_highest_para_header 0"));
+        assert!(lines.contains(&"headers_cache_highest_storage_changes 7"));
+        assert!(lines
+            .iter()
+            .any(|line| line.starts_with("headers_cache_mismatches_fixed_total ")));
+        assert!(lines.contains(&"# TYPE headers_cache_mismatches_fixed_total counter"));
+    }
+}
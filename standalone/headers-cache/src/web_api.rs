@@ -10,15 +10,24 @@ use rand::Rng;
 use rocket::{
     data::ToByteUnit,
     futures::StreamExt,
-    get, put,
-    response::status::{BadRequest, NotFound},
-    routes, Data, State,
+    get,
+    http::Status,
+    post, put,
+    response::status::{BadRequest, Custom, NotFound},
+    routes,
+    serde::json::Json,
+    Data, State,
 };
+use serde::{Deserialize, Serialize};
 
-use scale::{Decode, Encode};
+use scale::{Compact, Decode, Encode};
 
 use super::Serve as ServeConfig;
-use crate::{db::CacheDB, BlockNumber};
+use crate::{
+    db::{CacheDB, Counters},
+    grab::CheckError,
+    BlockNumber,
+};
 use auth::Authorized;
 
 mod auth;
@@ -28,10 +37,92 @@ struct App {
     config: ServeConfig,
 }
 
+impl App {
+    /// Whether the records of `block` have been pruned from the DB.
+    fn is_pruned(&self, block: BlockNumber, lowest: fn(&Counters) -> Option<BlockNumber>) -> bool {
+        let Some(metadata) = self.db.get_metadata().ok().flatten() else {
+            return false;
+        };
+        match lowest(&metadata.lowest) {
+            Some(lowest) => block < lowest && !metadata.genesis.contains(&block),
+            None => false,
+        }
+    }
+}
+
+const PRUNED: &str = "block pruned";
+
+/// A SCALE encoded `Vec` built one item at a time, so the decoded items are never all held.
+#[derive(Default)]
+struct EncodedVec {
+    len: u32,
+    items: Vec<u8>,
+}
+
+impl EncodedVec {
+    fn push(&mut self, item: &impl Encode) {
+        self.len += 1;
+        item.encode_to(&mut self.items);
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        let mut encoded = Compact(self.len).encode();
+        encoded.extend_from_slice(&self.items);
+        encoded
+    }
+}
+
+/// The blocks of one kind stored in the DB. None if there are none.
+#[derive(Serialize)]
+struct StoredRange {
+    lowest: Option<BlockNumber>,
+    highest: Option<BlockNumber>,
+}
+
+impl StoredRange {
+    /// The lowest marker is unset until the blocks are pruned or grabbed backward.
+    fn new(lowest: Option<BlockNumber>, highest: Option<BlockNumber>) -> Self {
+        Self {
+            lowest: highest.map(|_| lowest.unwrap_or_default()),
+            highest,
+        }
+    }
+}
+
+/// What the cache can serve, for the clients to decide where to start syncing from.
+#[derive(Serialize)]
+struct CacheState {
+    headers: StoredRange,
+    para_headers: StoredRange,
+    storage_changes: StoredRange,
+    latest_justification: Option<BlockNumber>,
+    genesis: Vec<BlockNumber>,
+    /// How far the background checker has verified each kind of record.
+    checked: Counters,
+}
+
 #[get("/state")]
-fn state(app: &State<App>) -> String {
+fn state(app: &State<App>) -> Json<CacheState> {
     let metadata = app.db.get_metadata().ok().flatten().unwrap_or_default();
-    serde_json::to_string_pretty(&metadata).unwrap_or("{}".into())
+    let mut genesis = metadata.genesis;
+    genesis.sort_unstable();
+    let (lowest, highest) = (&metadata.lowest, &metadata.higest);
+    // The relaychain headers start at the first genesis block until grabbed backward or pruned.
+    let lowest_header = lowest.header.or(genesis.first().copied());
+    let latest_justification = crate::grab::latest_justification();
+    Json(CacheState {
+        headers: StoredRange::new(lowest_header, highest.header),
+        para_headers: StoredRange::new(lowest.para_header, highest.para_header),
+        storage_changes: StoredRange::new(lowest.storage_changes, highest.storage_changes),
+        latest_justification: (latest_justification != u32::MAX).then_some(latest_justification),
+        genesis,
+        checked: metadata.checked,
+    })
+}
+
+#[get("/metrics")]
+fn metrics(app: &State<App>) -> String {
+    crate::metrics::render(&app.db)
 }
 
 #[get("/genesis/<block_number>")]
@@ -43,28 +134,61 @@ fn get_genesis(app: &State<App>, block_number: BlockNumber) -> Result<Vec<u8>, N
 
 #[get("/header/<block_number>")]
 fn get_header(app: &State<App>, block_number: BlockNumber) -> Result<Vec<u8>, NotFound<String>> {
+    if app.is_pruned(block_number, |c| c.header) {
+        return Err(NotFound(PRUNED.into()));
+    }
     app.db
         .get_header(block_number)
         .ok_or_else(|| NotFound("header not found".into()))
 }
 
+/// Get the GRANDPA justification of the given block, without the header payload around it.
+#[get("/justification/<block_number>")]
+fn get_justification(
+    app: &State<App>,
+    block_number: BlockNumber,
+) -> Result<Vec<u8>, NotFound<String>> {
+    if app.is_pruned(block_number, |c| c.header) {
+        return Err(NotFound(PRUNED.into()));
+    }
+    if let Some(justification) = app.db.get_justification(block_number) {
+        return Ok(justification);
+    }
+    // Fallback to the justification embedded in the header record
+    app.db
+        .get_header(block_number)
+        .and_then(|data| BlockInfo::decode(&mut &data[..]).ok())
+        .and_then(|info| info.justification)
+        .ok_or_else(|| NotFound("justification not found".into()))
+}
+
 #[get("/headers/<start>")]
-fn get_headers(app: &State<App>, start: BlockNumber) -> Result<Vec<u8>, NotFound<()>> {
+async fn get_headers(app: &State<App>, start: BlockNumber) -> Result<Vec<u8>, NotFound<()>> {
     let latest_just = crate::grab::latest_justification();
     if start > latest_just {
         log::debug!("No more justification yet");
         return Err(NotFound(()));
     }
-    let mut headers = vec![];
-    for block in start..start + 10000 {
-        match app.db.get_header(block) {
+    if app.is_pruned(start, |c| c.header) {
+        log::debug!("Headers from {start} have been pruned");
+        return Err(NotFound(()));
+    }
+    let mut headers = EncodedVec::default();
+    let mut records = app.db.read_range(
+        CacheDB::get_header,
+        start,
+        start.saturating_add(10000),
+        app.config.read_ahead,
+    );
+    while let Some((block, data)) = records.next().await {
+        match data {
             Some(data) => {
                 let info = crate::cache::BlockInfo::decode(&mut &data[..]).map_err(|_| {
                     log::error!("Failed to decode block fetched from db");
                     NotFound(())
                 })?;
                 let end = info.justification.is_some();
-                headers.push(info);
+                headers.push(&info);
                 if end {
                     break;
                 }
@@ -83,23 +207,32 @@ fn get_headers(app: &State<App>, start: BlockNumber) -> Result<Vec<u8>, NotFound
             }
         }
     }
-    log::info!("Got {} headers", headers.len());
-    Ok(headers.encode())
+    log::info!("Got {} headers", headers.len);
+    Ok(headers.into_bytes())
 }
 
 #[get("/parachain-headers/<start>/<count>")]
-fn get_parachain_headers(
+async fn get_parachain_headers(
     app: &State<App>,
     start: BlockNumber,
     count: BlockNumber,
 ) -> Result<Vec<u8>, NotFound<String>> {
-    let mut headers = vec![];
-    for block in start..start + count {
-        match app.db.get_para_header(block) {
+    if app.is_pruned(start, |c| c.para_header) {
+        return Err(NotFound(PRUNED.into()));
+    }
+    let mut headers = EncodedVec::default();
+    let mut records = app.db.read_range(
+        CacheDB::get_para_header,
+        start,
+        start.saturating_add(count),
+        app.config.read_ahead,
+    );
+    while let Some((block, data)) = records.next().await {
+        match data {
             Some(data) => {
                 let header =
                     Header::decode(&mut &data[..]).map_err(|_| NotFound("Codec error".into()))?;
-                headers.push(header);
+                headers.push(&header);
             }
             None => {
                 log::warn!("Header at {} not found", block);
@@ -107,23 +240,32 @@ fn get_parachain_headers(
             }
         }
     }
-    log::info!("Got {} parachain headers", headers.len());
-    Ok(headers.encode())
+    log::info!("Got {} parachain headers", headers.len);
+    Ok(headers.into_bytes())
 }
 
 #[get("/storage-changes/<start>/<count>")]
-fn get_storage_changes(
+async fn get_storage_changes(
     app: &State<App>,
     start: BlockNumber,
     count: BlockNumber,
 ) -> Result<Vec<u8>, NotFound<String>> {
-    let mut changes = vec![];
-    for block in start..start + count {
-        match app.db.get_storage_changes(block) {
+    if app.is_pruned(start, |c| c.storage_changes) {
+        return Err(NotFound(PRUNED.into()));
+    }
+    let mut changes = EncodedVec::default();
+    let mut records = app.db.read_range(
+        CacheDB::get_storage_changes,
+        start,
+        start.saturating_add(count),
+        app.config.read_ahead,
+    );
+    while let Some((block, data)) = records.next().await {
+        match data {
             Some(data) => {
                 let header = crate::cache::BlockHeaderWithChanges::decode(&mut &data[..])
                     .map_err(|_| NotFound("Codec error".into()))?;
-                changes.push(header);
+                changes.push(&header);
             }
             None => {
                 log::warn!("Changes at {} not found", block);
@@ -131,8 +273,8 @@ fn get_storage_changes(
             }
         }
     }
-    log::info!("Got {} storage changes", changes.len());
-    Ok(changes.encode())
+    log::info!("Got {} storage changes", changes.len);
+    Ok(changes.into_bytes())
 }
 
 async fn process_items(
@@ -168,6 +310,16 @@ async fn put_headers(
         app.db
             .put_header(number, data)
             .expect("Failed to put headers into DB");
+        if app.config.store_justifications {
+            let justification = BlockInfo::decode(&mut &data[..])
+                .ok()
+                .and_then(|info| info.justification);
+            if let Some(justification) = justification {
+                app.db
+                    .put_justification(number, &justification)
+                    .expect("Failed to put justification into DB");
+            }
+        }
     })
     .await
 }
@@ -229,12 +381,63 @@ async fn api_check_blocks(
             Ok(format!("Mismatches: {:?}", mismatches))
         }
     } else {
-        crate::grab::check_and_fix_headers(&app.db, &app.config, chain, from, to, count)
+        let parachain = match chain {
+            "relay" => false,
+            "para" => true,
+            _ => return Err(format!("Unknown check type {chain}")),
+        };
+        crate::grab::check_and_fix_headers(&app.db, &app.config, parachain, from, to, count)
             .await
+            .map(|report| report.to_string())
             .map_err(|e| e.to_string())
     }
 }
 
+#[derive(Deserialize)]
+struct CheckRange {
+    from: BlockNumber,
+    to: BlockNumber,
+}
+
+/// Check and fix the relaychain or parachain headers in the given range right away.
+#[post("/check/<what>", data = "<range>")]
+async fn api_check_range(
+    _auth: Authorized,
+    app: &State<App>,
+    what: &str,
+    range: Json<CheckRange>,
+) -> Result<Json<String>, Custom<String>> {
+    let parachain = match what {
+        "relay" => false,
+        "para" => true,
+        _ => {
+            return Err(Custom(
+                Status::BadRequest,
+                format!("Unknown check type {what}, expected relay or para"),
+            ))
+        }
+    };
+    let CheckRange { from, to } = range.into_inner();
+    if to <= from {
+        return Err(Custom(
+            Status::BadRequest,
+            format!("Invalid range {from}-{to}"),
+        ));
+    }
+    crate::grab::check_and_fix_headers(&app.db, &app.config, parachain, from, Some(to), None)
+        .await
+        .map(|report| Json(report.to_string()))
+        .map_err(|err| {
+            let status = match err {
+                CheckError::InvalidRange => Status::BadRequest,
+                CheckError::HeaderNotFound(_) => Status::NotFound,
+                CheckError::UnfixableMismatch(_) => Status::Conflict,
+                CheckError::DecodeFailed(_) => Status::InternalServerError,
+            };
+            Custom(status, err.to_string())
+        })
+}
+
 pub(crate) async fn serve(db: CacheDB, config: ServeConfig, token: Option<String>) -> Result<()> {
     let token = token.unwrap_or_else(|| {
         let token: [u8; 16] = rand::thread_rng().gen();
@@ -252,8 +455,10 @@ pub(crate) async fn serve(db: CacheDB, config: ServeConfig, token: Option<String
             "/",
             routes![
                 state,
+                metrics,
                 get_genesis,
                 get_header,
+                get_justification,
                 get_headers,
                 get_parachain_headers,
                 get_storage_changes,
@@ -261,6 +466,7 @@ pub(crate) async fn serve(db: CacheDB, config: ServeConfig, token: Option<String
                 put_parachain_headers,
                 put_storage_changes,
                 api_check_blocks,
+                api_check_range,
             ],
         )
         .attach(phala_rocket_middleware::TimeMeter)
@@ -357,3 +563,37 @@ pub(crate) async fn sync_from(
         tokio::time::sleep(std::time::Duration::from_secs(check_interval)).await;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encoded_vec_matches_the_encoding_of_a_vec() {
+        for len in [0u32, 1, 63, 64, 20_000] {
+            let items: Vec<_> = (0..len).map(|i| (i, vec![i as u8; 3])).collect();
+            let mut encoded = EncodedVec::default();
+            for item in &items {
+                encoded.push(item);
+            }
+            assert_eq!(encoded.into_bytes(), items.encode(), "len = {len}");
+        }
+    }
+
+    #[test]
+    fn stored_range_defaults_the_lowest_block_to_zero() {
+        let range = |lowest, highest| serde_json::to_string(&StoredRange::new(lowest, highest));
+        assert_eq!(
+            range(None, Some(100)).unwrap(),
+            r#"{"lowest":0,"highest":100}"#
+        );
+        assert_eq!(
+            range(Some(40), Some(100)).unwrap(),
+            r#"{"lowest":40,"highest":100}"#
+        );
+        assert_eq!(
+            range(Some(40), None).unwrap(),
+            r#"{"lowest":null,"highest":null}"#
+        );
+    }
+}
@@ -1,31 +1,54 @@
-use crate::api::ApiError::{InconsistentData, LifecycleManagerNotInitialized, WorkerNotFound};
+use crate::api::ApiError::{LifecycleManagerNotInitialized, WorkerNotFound};
+use crate::auth::{auth_middleware, Auth};
 use crate::cli::{ConfigCommands, WorkerManagerCliArgs};
-use crate::configurator::api_handler;
+use crate::configurator::{
+    api_handler, export_inventory, import_inventory, ImportSummary, InventoryBundle,
+};
 use crate::db::Worker;
-use crate::tx::Transaction;
+use crate::ratelimit::{rate_limit_middleware, RateLimiter};
+use crate::session::{SessionDelta, SessionSummary};
+use crate::tx::{Transaction, TxManagerError};
 use crate::wm::WorkerManagerMessage::ShouldResetLifecycleManager;
 use crate::wm::{send_to_main_channel, WrappedWorkerManagerContext};
-use crate::worker::{WorkerLifecycleCommand, WorkerLifecycleState, WrappedWorkerContext};
-use anyhow::anyhow;
-use axum::extract::State;
-use axum::http::StatusCode;
+use crate::worker::{
+    WorkerContext, WorkerLifecycleCommand, WorkerLifecycleState, WrappedWorkerContext,
+};
+use anyhow::{anyhow, Context};
+use axum::extract::rejection::{JsonRejection, PathRejection, QueryRejection};
+use axum::extract::{FromRequest, FromRequestParts, Path, Query, State};
+use axum::http::{HeaderName, HeaderValue, Method, StatusCode, Uri};
+use axum::middleware;
+use axum::response::sse::{Event, KeepAlive, Sse};
 use axum::response::{IntoResponse, Response};
 use axum::routing::*;
 use axum::{Json, Router};
+use axum_server::tls_rustls::RustlsConfig;
+use chrono::{DateTime, Utc};
 use futures::future::try_join_all;
-use log::{error, info};
+use futures::{FutureExt, Stream, StreamExt, TryFutureExt};
+use log::{error, info, warn};
 use phactory_api::prpc::PhactoryInfo;
 use phala_git_revision::git_revision_with_ts;
 use phala_pallets::pallet_computation::SessionInfo;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::fmt;
 use std::net::SocketAddr;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use tokio::sync::{watch, Mutex};
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+use tokio_stream::wrappers::BroadcastStream;
+use tower_http::cors::{AllowOrigin, CorsLayer};
 
 type AppContext = State<WrappedWorkerManagerContext>;
 
+/// Max number of transactions returned by a page of `/tx/history`.
+const MAX_TX_HISTORY_LIMIT: usize = 1000;
+
 #[derive(thiserror::Error, Debug)]
 pub enum ApiError {
     #[error("Server error")]
@@ -45,10 +68,64 @@ pub enum ApiError {
 
     #[error("met inconsistent data, this is a bug, please report with full backtrace")]
     InconsistentData,
+
+    #[error("unauthorized: {0}")]
+    Unauthorized(String),
+
+    #[error("forbidden: {0}")]
+    Forbidden(String),
+
+    #[error("invalid request: {0}")]
+    InvalidRequest(String),
+
+    #[error("too many requests, retry after {0} seconds")]
+    RateLimited(u64),
+
+    #[error("transaction not found: {0}")]
+    TxNotFound(usize),
+
+    #[error("transaction can't be cancelled: {0}")]
+    TxNotCancellable(TxManagerError),
+
+    #[error("no route for {0}")]
+    RouteNotFound(String),
 }
 
 type ApiResult<T> = Result<T, ApiError>;
 
+/// Like `Json`, but rejecting malformed bodies with an `ApiError`, i.e. a regular error body.
+#[derive(FromRequest)]
+#[from_request(via(Json), rejection(ApiError))]
+pub struct ApiJson<T>(pub T);
+
+/// Like `Path`, but rejecting unparsable parameters with an `ApiError`.
+#[derive(FromRequestParts)]
+#[from_request(via(Path), rejection(ApiError))]
+pub struct ApiPath<T>(pub T);
+
+/// Like `Query`, but rejecting unparsable queries with an `ApiError`.
+#[derive(FromRequestParts)]
+#[from_request(via(Query), rejection(ApiError))]
+pub struct ApiQuery<T>(pub T);
+
+impl From<JsonRejection> for ApiError {
+    fn from(rejection: JsonRejection) -> Self {
+        Self::InvalidRequest(rejection.body_text())
+    }
+}
+
+impl From<PathRejection> for ApiError {
+    fn from(rejection: PathRejection) -> Self {
+        Self::InvalidRequest(rejection.body_text())
+    }
+}
+
+impl From<QueryRejection> for ApiError {
+    fn from(rejection: QueryRejection) -> Self {
+        Self::InvalidRequest(rejection.body_text())
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct WorkerStatus {
     pub worker: Worker,
@@ -56,11 +133,33 @@ pub struct WorkerStatus {
     pub phactory_info: Option<PhactoryInfo>,
     pub last_message: String,
     pub session_info: Option<SessionInfo>,
+    /// How the session changed since the previous poll
+    pub session_delta: Option<SessionDelta>,
+    /// Derived from the session and the chain height
+    #[serde(default)]
+    pub session_summary: Option<SessionSummary>,
+    /// Retries left for the force registration in progress, if any
+    pub force_register_retries_left: Option<u32>,
+    /// Discovered over mDNS rather than configured in the inventory
+    #[serde(default)]
+    pub discovered: bool,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct WorkerStatusResponse {
     workers: Vec<WorkerStatus>,
+    /// Number of the workers matching the filter, across all the pages
+    total: usize,
+}
+
+/// Query of `/workers/status`. Every worker is returned if empty.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct WorkerStatusQuery {
+    pub offset: usize,
+    pub limit: Option<usize>,
+    /// Only return the workers in this state, e.g. `Working` or `HasError`
+    pub state: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -71,6 +170,38 @@ pub struct TxStatusResponse {
     pub past_txs: Vec<Transaction>,
 }
 
+/// Query of `/tx/history`, the times are in RFC 3339.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TxHistoryQuery {
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub offset: usize,
+    #[serde(default = "default_tx_history_limit")]
+    pub limit: usize,
+}
+
+fn default_tx_history_limit() -> usize {
+    100
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TxHistoryResponse {
+    /// Oldest first
+    pub txs: Vec<Transaction>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct CancelTxRequest {
+    pub id: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct CancelTxResponse {
+    pub id: usize,
+    pub cancelled: bool,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct WmStatusResponse {
     pub git_revision: String,
@@ -86,41 +217,151 @@ impl Default for OkResponse {
     }
 }
 
+/// Selects the workers with the given ids, along with the ones with any of the tags.
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct IdsRequest {
+    #[serde(default)]
     pub ids: Vec<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+/// Whether the backtraces of the server errors are sent to the clients.
+static DEBUG_ERRORS: AtomicBool = AtomicBool::new(false);
+
+/// Send the backtraces of the server errors in their `details`, they are only logged otherwise.
+pub fn set_debug_errors(enabled: bool) {
+    DEBUG_ERRORS.store(enabled, Ordering::Relaxed);
+}
+
+/// Identifies the kind of an error, serialized as a short snake case string such as
+/// `worker_not_found`. Unlike the messages, the codes never change once released.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    ServerError,
+    LifecycleManagerNotInitialized,
+    WorkerNotFound,
+    PoolNotFound,
+    WriteFailed,
+    InconsistentData,
+    Unauthorized,
+    Forbidden,
+    InvalidRequest,
+    RateLimited,
+    TxNotFound,
+    TxNotCancellable,
+    RouteNotFound,
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Same as the serialized form.
+        match serde_json::to_value(self) {
+            Ok(serde_json::Value::String(code)) => f.write_str(&code),
+            _ => write!(f, "{self:?}"),
+        }
+    }
+}
+
+/// The error body returned by every route.
+///
+/// `code` is a stable identifier clients can match on, `message` is meant for humans and may
+/// change between versions. `request_id` is also logged with the error on the server side.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ErrorBody {
+    pub error: bool,
+    pub code: ErrorCode,
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub details: Option<serde_json::Value>,
+    pub request_id: String,
+}
+
+impl ApiError {
+    /// The stable error code exposed to clients.
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            ApiError::ServerError(_) => ErrorCode::ServerError,
+            ApiError::LifecycleManagerNotInitialized => ErrorCode::LifecycleManagerNotInitialized,
+            ApiError::WorkerNotFound(_) => ErrorCode::WorkerNotFound,
+            ApiError::PoolNotFound(_) => ErrorCode::PoolNotFound,
+            ApiError::WriteFailed => ErrorCode::WriteFailed,
+            ApiError::InconsistentData => ErrorCode::InconsistentData,
+            ApiError::Unauthorized(_) => ErrorCode::Unauthorized,
+            ApiError::Forbidden(_) => ErrorCode::Forbidden,
+            ApiError::InvalidRequest(_) => ErrorCode::InvalidRequest,
+            ApiError::RateLimited(_) => ErrorCode::RateLimited,
+            ApiError::TxNotFound(_) => ErrorCode::TxNotFound,
+            ApiError::TxNotCancellable(_) => ErrorCode::TxNotCancellable,
+            ApiError::RouteNotFound(_) => ErrorCode::RouteNotFound,
+        }
+    }
+
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            ApiError::ServerError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            ApiError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            ApiError::Forbidden(_) => StatusCode::FORBIDDEN,
+            ApiError::WorkerNotFound(_) | ApiError::TxNotFound(_) | ApiError::RouteNotFound(_) => {
+                StatusCode::NOT_FOUND
+            }
+            ApiError::TxNotCancellable(_) => StatusCode::CONFLICT,
+            ApiError::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+            _ => StatusCode::BAD_REQUEST,
+        }
+    }
+
+    fn details(&self) -> Option<serde_json::Value> {
+        match self {
+            ApiError::ServerError(e) if DEBUG_ERRORS.load(Ordering::Relaxed) => {
+                Some(json!({ "backtrace": e.backtrace().to_string() }))
+            }
+            ApiError::WorkerNotFound(id) => Some(json!({ "worker": id })),
+            ApiError::PoolNotFound(pid) => Some(json!({ "pid": pid })),
+            ApiError::TxNotFound(id) => Some(json!({ "tx": id })),
+            ApiError::RateLimited(secs) => Some(json!({ "retry_after": secs })),
+            _ => None,
+        }
+    }
+
+    pub fn to_body(&self, request_id: String) -> ErrorBody {
+        let message = match self {
+            ApiError::ServerError(e) => format!("{e}"),
+            _ => format!("{self}"),
+        };
+        ErrorBody {
+            error: true,
+            code: self.code(),
+            message,
+            details: self.details(),
+            request_id,
+        }
+    }
 }
 
 impl IntoResponse for ApiError {
     fn into_response(self) -> Response {
-        match self {
+        let request_id = uuid::Uuid::new_v4().to_string();
+        let body = self.to_body(request_id);
+        match &self {
             ApiError::ServerError(e) => {
-                let backtrace = e.backtrace().to_string();
-                error!("{}:\n{}", &e, &backtrace);
-                (
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    Json(json!({
-                        "error": true,
-                        "code": "ServerError",
-                        "message": format!("{}", &e),
-                        "backtrace": backtrace
-                    })),
-                )
-            }
-            .into_response(),
-            _ => {
-                error!("{}", &self);
-                (
-                    StatusCode::BAD_REQUEST,
-                    Json(json!({
-                        "error": true,
-                        "code": format!("{:?}", &self),
-                        "message": format!("{self}"),
-                    })),
-                )
-                    .into_response()
+                error!(
+                    "[{}] {}: {}\n{}",
+                    &body.request_id,
+                    body.code,
+                    e,
+                    e.backtrace()
+                );
             }
+            _ => error!("[{}] {}: {}", &body.request_id, body.code, &body.message),
         }
+        (
+            self.status_code(),
+            [("x-request-id", body.request_id.clone())],
+            Json(body),
+        )
+            .into_response()
     }
 }
 
@@ -136,32 +377,100 @@ pub type WrappedWorkerContexts = Arc<Mutex<WorkerContexts>>;
 pub async fn start_api_server(
     ctx: WrappedWorkerManagerContext,
     args: WorkerManagerCliArgs,
+    shutdown: watch::Receiver<bool>,
 ) -> anyhow::Result<()> {
-    // todo: mdns
+    if args.enable_mdns {
+        let ctx = ctx.clone();
+        let args = args.clone();
+        tokio::spawn(async move {
+            if let Err(e) = crate::mdns::run(ctx, args).await {
+                error!("mDNS: {e:#}");
+            }
+        });
+    }
 
+    set_debug_errors(args.debug_errors);
+    let auth = Arc::new(Auth::from_args(&args)?);
+    let limiter = Arc::new(RateLimiter::from_args(&args));
     let app = Router::new()
         .route("/", get(handle_get_root))
+        .route("/healthz", get(handle_get_healthz))
+        .route("/readyz", get(handle_get_readyz))
         .route("/wm/status", get(handle_get_wm_status))
         .route("/wm/restart", put(handle_restart_wm))
         .route("/wm/config", post(handle_config_wm))
+        .route("/export", get(handle_export))
+        .route("/import", post(handle_import))
         .route("/workers/status", get(handle_get_worker_status))
+        .route("/workers/events", get(handle_worker_events))
+        .route("/workers/:id", get(handle_get_worker))
         .route("/workers/restart", put(handle_restart_specific_workers))
         .route(
             "/workers/force_register",
             put(handle_force_register_workers),
         )
         .route("/workers/update_endpoints", put(handle_update_endpoints))
+        .route(
+            "/workers/update_endpoints/bulk",
+            post(handle_update_endpoints_bulk),
+        )
+        .route("/workers/stop", post(handle_stop_workers))
+        .route("/workers/start", post(handle_start_workers))
         .route("/tx/status", get(handle_get_tx_status))
-        .fallback(handle_get_root)
+        .route("/tx/history", get(handle_get_tx_history))
+        .route("/tx/cancel", post(handle_cancel_tx))
+        .route("/metrics", get(handle_get_metrics))
+        .fallback(handle_fallback)
+        // The requests rejected by the auth don't count against the limits.
+        .route_layer(middleware::from_fn_with_state(
+            limiter,
+            rate_limit_middleware,
+        ))
+        .route_layer(middleware::from_fn_with_state(auth, auth_middleware))
+        // Outermost, so the preflight requests are answered without a token.
+        .layer(cors_layer(&args)?)
         .with_state(ctx);
 
+    let tls = match (&args.mgmt_tls_cert, &args.mgmt_tls_key) {
+        (Some(cert), Some(key)) => Some(
+            RustlsConfig::from_pem_file(cert, key)
+                .await
+                .context("Failed to load the TLS certificate of the management interface")?,
+        ),
+        _ => None,
+    };
+
     let fut_vec = args
         .mgmt_listen_addresses
         .into_iter()
         .map(|addr| {
-            info!("Listening on {} for management interface.", &addr);
             let addr = SocketAddr::from_str(&addr).unwrap();
-            axum::Server::bind(&addr).serve(app.clone().into_make_service())
+            let app = app.clone().into_make_service();
+            let mut shutdown = shutdown.clone();
+            let Some(tls) = tls.clone() else {
+                info!("Listening on {} for management interface.", &addr);
+                return axum::Server::bind(&addr)
+                    .serve(app)
+                    .with_graceful_shutdown(async move {
+                        let _ = shutdown.wait_for(|shutdown| *shutdown).await;
+                    })
+                    .map_err(anyhow::Error::from)
+                    .boxed();
+            };
+            info!("Listening on {} for management interface over TLS.", &addr);
+            let handle = axum_server::Handle::new();
+            tokio::spawn({
+                let handle = handle.clone();
+                async move {
+                    let _ = shutdown.wait_for(|shutdown| *shutdown).await;
+                    handle.graceful_shutdown(None);
+                }
+            });
+            axum_server::bind_rustls(addr, tls)
+                .handle(handle)
+                .serve(app)
+                .map_err(anyhow::Error::from)
+                .boxed()
         })
         .collect::<Vec<_>>();
 
@@ -169,10 +478,72 @@ pub async fn start_api_server(
     Ok(())
 }
 
+fn cors_layer(args: &WorkerManagerCliArgs) -> anyhow::Result<CorsLayer> {
+    // Without any allowed origin, the browsers only allow the same origin.
+    let mut cors = CorsLayer::new();
+    if args.cors_allowed_origins.is_empty() {
+        return Ok(cors);
+    }
+    cors = if args.cors_allowed_origins.iter().any(|o| o == "*") {
+        cors.allow_origin(AllowOrigin::any())
+    } else {
+        let origins = args
+            .cors_allowed_origins
+            .iter()
+            .map(|o| HeaderValue::from_str(o).with_context(|| format!("Invalid origin: {o}")))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        cors.allow_origin(origins)
+    };
+    let methods = args
+        .cors_allowed_methods
+        .iter()
+        .map(|m| Method::from_str(m).with_context(|| format!("Invalid method: {m}")))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let headers = args
+        .cors_allowed_headers
+        .iter()
+        .map(|h| HeaderName::from_str(h).with_context(|| format!("Invalid header: {h}")))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    Ok(cors.allow_methods(methods).allow_headers(headers))
+}
+
 async fn handle_get_root() -> (StatusCode, ()) {
     (StatusCode::IM_A_TEAPOT, ())
 }
 
+async fn handle_fallback(method: Method, uri: Uri) -> ApiError {
+    ApiError::RouteNotFound(format!("{method} {}", uri.path()))
+}
+
+/// 200 once the lifecycle manager is initialized, 503 before.
+async fn handle_get_healthz(State(ctx): AppContext) -> (StatusCode, Json<OkResponse>) {
+    let ok = ctx.current_lifecycle_tx.lock().await.is_some();
+    health_response(ok)
+}
+
+/// Like `/healthz`, but also requires at least one worker to be working.
+async fn handle_get_readyz(State(ctx): AppContext) -> (StatusCode, Json<OkResponse>) {
+    if ctx.current_lifecycle_tx.lock().await.is_none() {
+        return health_response(false);
+    }
+    let workers = ctx.workers.lock().await.clone();
+    for w in workers {
+        if let WorkerLifecycleState::Working = w.read().await.state {
+            return health_response(true);
+        }
+    }
+    health_response(false)
+}
+
+fn health_response(ok: bool) -> (StatusCode, Json<OkResponse>) {
+    let status = if ok {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(OkResponse { ok }))
+}
+
 async fn handle_get_wm_status() -> Json<WmStatusResponse> {
     Json(WmStatusResponse {
         git_revision: git_revision_with_ts().to_string(),
@@ -190,22 +561,95 @@ async fn handle_restart_wm(State(ctx): AppContext) -> ApiResult<(StatusCode, Jso
 
 async fn handle_get_worker_status(
     State(ctx): AppContext,
+    ApiQuery(query): ApiQuery<WorkerStatusQuery>,
 ) -> ApiResult<(StatusCode, Json<WorkerStatusResponse>)> {
-    let w = ctx.workers.clone();
-    let w = w.lock().await;
+    // Only the handles are copied under the lock, so the lifecycles aren't held up meanwhile.
+    let all = ctx.workers.lock().await.clone();
+    let end = query
+        .limit
+        .map_or(usize::MAX, |limit| query.offset.saturating_add(limit));
     let mut workers = Vec::new();
-    for w in w.iter() {
-        let w = w.clone();
+    let mut total = 0;
+    for w in all.iter() {
         let w = w.read().await;
-        workers.push(WorkerStatus {
-            worker: w.worker.clone(),
-            state: w.state.clone(),
-            phactory_info: w.info.clone(),
-            last_message: w.last_message.clone(),
-            session_info: w.session_info.clone(),
-        })
+        if let Some(state) = &query.state {
+            if w.state.name() != state {
+                continue;
+            }
+        }
+        let index = total;
+        total += 1;
+        if index < query.offset || index >= end {
+            continue;
+        }
+        workers.push(worker_status(&w))
+    }
+    Ok((
+        StatusCode::OK,
+        Json(WorkerStatusResponse { workers, total }),
+    ))
+}
+
+/// Stream the status of the workers as Server-Sent Events, each named `status` and carrying a
+/// `WorkerStatus`.
+///
+/// The current status of every worker is sent first, so a client reconnecting with a
+/// `Last-Event-ID` catches up on what it missed. A client lagging too far behind misses the
+/// events it couldn't keep up with.
+async fn handle_worker_events(
+    State(ctx): AppContext,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    // Subscribed before taking the snapshot, so no change falls in between.
+    let rx = ctx.events.subscribe();
+    let last_id = ctx.events.last_id();
+    let all = ctx.workers.lock().await.clone();
+    let mut snapshot = Vec::new();
+    for w in all.iter() {
+        snapshot.push(status_event(last_id, &worker_status(&*w.read().await)));
+    }
+    let live = BroadcastStream::new(rx).filter_map(|event| async move {
+        match event {
+            Ok(event) => Some(status_event(event.id, &event.status)),
+            Err(BroadcastStreamRecvError::Lagged(n)) => {
+                warn!("A subscriber of the worker events missed {n} events");
+                None
+            }
+        }
+    });
+    Sse::new(futures::stream::iter(snapshot).chain(live)).keep_alive(KeepAlive::default())
+}
+
+fn status_event(id: u64, status: &WorkerStatus) -> Result<Event, Infallible> {
+    let event = Event::default().event("status").id(id.to_string());
+    Ok(event
+        .json_data(status)
+        .unwrap_or_else(|e| Event::default().comment(format!("unserializable status: {e}"))))
+}
+
+async fn handle_get_worker(
+    State(ctx): AppContext,
+    ApiPath(id): ApiPath<String>,
+) -> ApiResult<(StatusCode, Json<WorkerStatus>)> {
+    let c = get_workers_by_id_vec(&ctx, [id]).await?.remove(0);
+    let c = c.read().await;
+    Ok((StatusCode::OK, Json(worker_status(&c))))
+}
+
+pub fn worker_status(w: &WorkerContext) -> WorkerStatus {
+    WorkerStatus {
+        worker: w.worker.clone(),
+        state: w.state.clone(),
+        phactory_info: w.info.clone(),
+        last_message: w.last_message.clone(),
+        session_info: w.session_info.clone(),
+        session_delta: w.session_delta.clone(),
+        session_summary: w
+            .session_info
+            .as_ref()
+            .and_then(|session| SessionSummary::of(session, w.chain_clock.as_ref())),
+        force_register_retries_left: w.force_register_retries_left,
+        discovered: w.discovered,
     }
-    Ok((StatusCode::OK, Json(WorkerStatusResponse { workers })))
 }
 
 async fn get_workers_by_id_vec<S: Into<String>>(
@@ -222,11 +666,37 @@ async fn get_workers_by_id_vec<S: Into<String>>(
     Ok(c)
 }
 
+async fn get_workers_by_ids_request(
+    ctx: &WrappedWorkerManagerContext,
+    request: &IdsRequest,
+) -> ApiResult<Vec<WrappedWorkerContext>> {
+    let mut c = get_workers_by_id_vec(ctx, &request.ids).await?;
+    if request.tags.is_empty() {
+        return Ok(c);
+    }
+    let worker_map = ctx.worker_map.lock().await.clone();
+    for (id, w) in worker_map {
+        if request.ids.contains(&id) {
+            continue;
+        }
+        if w.read()
+            .await
+            .worker
+            .tags
+            .iter()
+            .any(|t| request.tags.contains(t))
+        {
+            c.push(w);
+        }
+    }
+    Ok(c)
+}
+
 async fn handle_restart_specific_workers(
     State(ctx): State<WrappedWorkerManagerContext>,
-    Json(payload): Json<IdsRequest>,
+    ApiJson(payload): ApiJson<IdsRequest>,
 ) -> ApiResult<(StatusCode, Json<OkResponse>)> {
-    for c in get_workers_by_id_vec(&ctx, &payload.ids).await? {
+    for c in get_workers_by_ids_request(&ctx, &payload).await? {
         let c = c.read().await;
         match &c.state {
             WorkerLifecycleState::Restarting => drop(c),
@@ -244,9 +714,9 @@ async fn handle_restart_specific_workers(
 
 async fn handle_force_register_workers(
     State(ctx): State<WrappedWorkerManagerContext>,
-    Json(payload): Json<IdsRequest>,
+    ApiJson(payload): ApiJson<IdsRequest>,
 ) -> ApiResult<(StatusCode, Json<OkResponse>)> {
-    for c in get_workers_by_id_vec(&ctx, &payload.ids).await? {
+    for c in get_workers_by_ids_request(&ctx, &payload).await? {
         let c = c.read().await;
         let tx = c.tx.clone();
         drop(c);
@@ -256,6 +726,44 @@ async fn handle_force_register_workers(
     Ok((StatusCode::OK, Json(OkResponse::default())))
 }
 
+async fn handle_stop_workers(
+    State(ctx): State<WrappedWorkerManagerContext>,
+    ApiJson(payload): ApiJson<IdsRequest>,
+) -> ApiResult<(StatusCode, Json<OkResponse>)> {
+    for c in get_workers_by_ids_request(&ctx, &payload).await? {
+        let c = c.read().await;
+        match &c.state {
+            WorkerLifecycleState::Restarting | WorkerLifecycleState::Stopped => drop(c),
+            _ => {
+                let tx = c.tx.clone();
+                drop(c);
+                tx.send(WorkerLifecycleCommand::ShouldStop)
+                    .map_err(|e| anyhow!(e.to_string()))?;
+            }
+        }
+    }
+    Ok((StatusCode::OK, Json(OkResponse::default())))
+}
+
+async fn handle_start_workers(
+    State(ctx): State<WrappedWorkerManagerContext>,
+    ApiJson(payload): ApiJson<IdsRequest>,
+) -> ApiResult<(StatusCode, Json<OkResponse>)> {
+    for c in get_workers_by_ids_request(&ctx, &payload).await? {
+        let c = c.read().await;
+        match &c.state {
+            WorkerLifecycleState::Stopped => {
+                let tx = c.tx.clone();
+                drop(c);
+                tx.send(WorkerLifecycleCommand::ShouldStart)
+                    .map_err(|e| anyhow!(e.to_string()))?;
+            }
+            _ => drop(c),
+        }
+    }
+    Ok((StatusCode::OK, Json(OkResponse::default())))
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct UpdateEndpointsRequest {
     pub requests: Vec<UpdateEndpointRequest>,
@@ -267,33 +775,143 @@ pub struct UpdateEndpointRequest {
     pub endpoints: Vec<String>,
 }
 
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct DryRunQuery {
+    /// Only tell what would be done, without doing it
+    pub dry_run: bool,
+}
+
+/// The real runs answer as they always did, only the dry runs tell what would be done.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum UpdateEndpointsResponse {
+    DryRun(BulkUpdateEndpointsResponse),
+    Ok(OkResponse),
+}
+
 async fn handle_update_endpoints(
     State(ctx): State<WrappedWorkerManagerContext>,
-    Json(payload): Json<UpdateEndpointsRequest>,
-) -> ApiResult<(StatusCode, Json<OkResponse>)> {
-    for (idx, c) in get_workers_by_id_vec(&ctx, payload.requests.iter().map(|i| i.id.as_str()))
-        .await?
-        .iter()
-        .enumerate()
-    {
-        let c = c.read().await;
-        match &c.state {
-            WorkerLifecycleState::Working | WorkerLifecycleState::GatekeeperWorking => {
-                let tx = c.tx.clone();
-                drop(c);
-                tx.send(WorkerLifecycleCommand::ShouldUpdateEndpoint(
-                    payload
-                        .requests
-                        .get(idx)
-                        .map(|i| i.endpoints.clone())
-                        .ok_or(InconsistentData)?,
-                ))
-                .map_err(|e| anyhow!(e.to_string()))?;
-            }
-            _ => drop(c),
+    ApiQuery(query): ApiQuery<DryRunQuery>,
+    ApiJson(payload): ApiJson<UpdateEndpointsRequest>,
+) -> ApiResult<(StatusCode, Json<UpdateEndpointsResponse>)> {
+    // Unlike the bulk updates, an unknown worker fails the whole request.
+    get_workers_by_id_vec(&ctx, payload.requests.iter().map(|i| i.id.as_str())).await?;
+    let mut endpoints = EndpointsById::new();
+    for i in payload.requests {
+        if endpoints.contains_key(&i.id) {
+            return Err(ApiError::InvalidRequest(format!(
+                "duplicate worker id {}",
+                i.id
+            )));
         }
+        endpoints.insert(i.id, i.endpoints);
     }
-    Ok((StatusCode::OK, Json(OkResponse::default())))
+    let ret = update_endpoints_bulk(&ctx, endpoints, query.dry_run).await;
+    if query.dry_run {
+        return Ok((StatusCode::OK, Json(UpdateEndpointsResponse::DryRun(ret))));
+    }
+    let failed = ret.results.into_values().find_map(|result| match result {
+        EndpointUpdateResult::Failed { message } => Some(message),
+        _ => None,
+    });
+    if let Some(message) = failed {
+        return Err(anyhow!(message).into());
+    }
+    Ok((
+        StatusCode::OK,
+        Json(UpdateEndpointsResponse::Ok(OkResponse::default())),
+    ))
+}
+
+/// The endpoints of the workers by id, as in the files of bulk updates.
+pub type EndpointsById = BTreeMap<String, Vec<String>>;
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(tag = "result", rename_all = "snake_case")]
+pub enum EndpointUpdateResult {
+    /// Sent to the worker, or would be in a dry run. Its `last_message` tells if the update
+    /// failed later
+    Queued,
+    /// Only the working workers can update their endpoints
+    Skipped {
+        state: WorkerLifecycleState,
+    },
+    Failed {
+        message: String,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct BulkUpdateEndpointsResponse {
+    /// Nothing was sent to the workers, the results are what would have been done
+    #[serde(default)]
+    pub dry_run: bool,
+    /// By worker id
+    pub results: BTreeMap<String, EndpointUpdateResult>,
+}
+
+/// Parse a YAML or JSON document mapping worker ids to their endpoints.
+pub fn parse_endpoints_by_id(content: &str) -> ApiResult<EndpointsById> {
+    serde_yaml::from_str(content)
+        .map_err(|e| ApiError::InvalidRequest(format!("invalid endpoints file: {e}")))
+}
+
+/// Update the endpoints of many workers at once, through the same path as
+/// `/workers/update_endpoints`. Every worker gets a result, an unknown one fails by itself.
+///
+/// With `dry_run`, the workers are selected the same way but nothing is sent to them.
+pub async fn update_endpoints_bulk(
+    ctx: &WrappedWorkerManagerContext,
+    endpoints: EndpointsById,
+    dry_run: bool,
+) -> BulkUpdateEndpointsResponse {
+    let worker_map = ctx.worker_map.lock().await.clone();
+    let mut results = BTreeMap::new();
+    for (id, endpoints) in endpoints {
+        let result = match worker_map.get(&id) {
+            None => EndpointUpdateResult::Failed {
+                message: WorkerNotFound(id.clone()).to_string(),
+            },
+            Some(c) => {
+                let c = c.read().await;
+                match &c.state {
+                    WorkerLifecycleState::Working | WorkerLifecycleState::GatekeeperWorking
+                        if dry_run =>
+                    {
+                        EndpointUpdateResult::Queued
+                    }
+                    WorkerLifecycleState::Working | WorkerLifecycleState::GatekeeperWorking => {
+                        match c
+                            .tx
+                            .send(WorkerLifecycleCommand::ShouldUpdateEndpoint(endpoints))
+                        {
+                            Ok(_) => EndpointUpdateResult::Queued,
+                            Err(e) => EndpointUpdateResult::Failed {
+                                message: e.to_string(),
+                            },
+                        }
+                    }
+                    state => EndpointUpdateResult::Skipped {
+                        state: state.clone(),
+                    },
+                }
+            }
+        };
+        results.insert(id, result);
+    }
+    BulkUpdateEndpointsResponse { dry_run, results }
+}
+
+/// Takes the content of an endpoints file as the body.
+async fn handle_update_endpoints_bulk(
+    State(ctx): AppContext,
+    ApiQuery(query): ApiQuery<DryRunQuery>,
+    body: String,
+) -> ApiResult<(StatusCode, Json<BulkUpdateEndpointsResponse>)> {
+    let endpoints = parse_endpoints_by_id(&body)?;
+    let ret = update_endpoints_bulk(&ctx, endpoints, query.dry_run).await;
+    Ok((StatusCode::OK, Json(ret)))
 }
 
 async fn handle_get_tx_status(
@@ -303,12 +921,307 @@ async fn handle_get_tx_status(
     Ok((StatusCode::OK, Json(txm.dump().await?)))
 }
 
+async fn handle_get_tx_history(
+    State(ctx): AppContext,
+    ApiQuery(query): ApiQuery<TxHistoryQuery>,
+) -> ApiResult<(StatusCode, Json<TxHistoryResponse>)> {
+    let limit = query.limit.min(MAX_TX_HISTORY_LIMIT);
+    let txs = ctx
+        .txm
+        .history(query.from, query.to, query.offset, limit)?
+        .ok_or(ApiError::InvalidRequest(
+            "the tx history is disabled".into(),
+        ))?;
+    Ok((StatusCode::OK, Json(TxHistoryResponse { txs })))
+}
+
+async fn handle_cancel_tx(
+    State(ctx): AppContext,
+    ApiJson(payload): ApiJson<CancelTxRequest>,
+) -> ApiResult<(StatusCode, Json<CancelTxResponse>)> {
+    match ctx.txm.cancel(payload.id).await {
+        Ok(_) => Ok((
+            StatusCode::OK,
+            Json(CancelTxResponse {
+                id: payload.id,
+                cancelled: true,
+            }),
+        )),
+        Err(TxManagerError::TxNotFound(id)) => Err(ApiError::TxNotFound(id)),
+        Err(e) => Err(ApiError::TxNotCancellable(e)),
+    }
+}
+
+async fn handle_get_metrics(State(ctx): AppContext) -> String {
+    crate::metrics::render(&ctx).await
+}
+
 async fn handle_config_wm(
     State(ctx): State<WrappedWorkerManagerContext>,
-    Json(payload): Json<ConfigCommands>,
+    ApiJson(payload): ApiJson<ConfigCommands>,
 ) -> ApiResult<String> {
+    // The endpoints come inline, the manager never opens a path given by the client.
+    if let ConfigCommands::UpdateEndpoints { endpoints, dry_run } = payload {
+        let ret = update_endpoints_bulk(&ctx, endpoints, dry_run).await;
+        return Ok(serde_json::to_string_pretty(&ret).map_err(anyhow::Error::from)?);
+    }
     let po_db = ctx.txm.db.clone();
     let inv_db = ctx.inv_db.clone();
     let ret = api_handler(inv_db, po_db, payload).await?;
     Ok(ret)
 }
+
+async fn handle_export(State(ctx): AppContext) -> ApiResult<Json<InventoryBundle>> {
+    let bundle = export_inventory(ctx.inv_db.clone(), ctx.txm.db.clone())?;
+    Ok(Json(bundle))
+}
+
+async fn handle_import(
+    State(ctx): AppContext,
+    ApiJson(bundle): ApiJson<InventoryBundle>,
+) -> ApiResult<Json<ImportSummary>> {
+    let summary = import_inventory(ctx.inv_db.clone(), ctx.txm.db.clone(), bundle)?;
+    info!(
+        "Imported {} pools and {} workers",
+        summary.pools, summary.workers
+    );
+    Ok(Json(summary))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Serve the extractors of the API behind a router with the fallback of the API.
+    async fn serve() -> String {
+        async fn worker(ApiPath(id): ApiPath<u32>) -> String {
+            id.to_string()
+        }
+        async fn history(ApiQuery(query): ApiQuery<TxHistoryQuery>) -> String {
+            query.limit.to_string()
+        }
+        async fn cancel(ApiJson(payload): ApiJson<CancelTxRequest>) -> String {
+            payload.id.to_string()
+        }
+        async fn error(ApiPath(i): ApiPath<usize>) -> ApiError {
+            every_error().swap_remove(i).0
+        }
+        let app = Router::new()
+            .route("/workers/:id", get(worker))
+            .route("/tx/history", get(history))
+            .route("/tx/cancel", post(cancel))
+            .route("/errors/:i", get(error))
+            .fallback(handle_fallback);
+        let server = axum::Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0)))
+            .serve(app.into_make_service());
+        let url = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+        url
+    }
+
+    /// One error of each variant, with its status and its code as serialized.
+    fn every_error() -> Vec<(ApiError, StatusCode, &'static str)> {
+        vec![
+            (
+                ApiError::ServerError(anyhow!("disk on fire")),
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "server_error",
+            ),
+            (
+                LifecycleManagerNotInitialized,
+                StatusCode::BAD_REQUEST,
+                "lifecycle_manager_not_initialized",
+            ),
+            (
+                WorkerNotFound("w0".into()),
+                StatusCode::NOT_FOUND,
+                "worker_not_found",
+            ),
+            (
+                ApiError::PoolNotFound(1),
+                StatusCode::BAD_REQUEST,
+                "pool_not_found",
+            ),
+            (
+                ApiError::WriteFailed,
+                StatusCode::BAD_REQUEST,
+                "write_failed",
+            ),
+            (
+                ApiError::InconsistentData,
+                StatusCode::BAD_REQUEST,
+                "inconsistent_data",
+            ),
+            (
+                ApiError::Unauthorized("no token".into()),
+                StatusCode::UNAUTHORIZED,
+                "unauthorized",
+            ),
+            (
+                ApiError::Forbidden("read only".into()),
+                StatusCode::FORBIDDEN,
+                "forbidden",
+            ),
+            (
+                ApiError::InvalidRequest("bad".into()),
+                StatusCode::BAD_REQUEST,
+                "invalid_request",
+            ),
+            (
+                ApiError::RateLimited(5),
+                StatusCode::TOO_MANY_REQUESTS,
+                "rate_limited",
+            ),
+            (
+                ApiError::TxNotFound(2),
+                StatusCode::NOT_FOUND,
+                "tx_not_found",
+            ),
+            (
+                ApiError::TxNotCancellable(TxManagerError::TxAlreadyFinished(3)),
+                StatusCode::CONFLICT,
+                "tx_not_cancellable",
+            ),
+            (
+                ApiError::RouteNotFound("GET /".into()),
+                StatusCode::NOT_FOUND,
+                "route_not_found",
+            ),
+        ]
+    }
+
+    /// The messages logged by the process.
+    static LOGGED: std::sync::Mutex<Vec<String>> = std::sync::Mutex::new(Vec::new());
+
+    struct CapturingLogger;
+
+    impl log::Log for CapturingLogger {
+        fn enabled(&self, _metadata: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            LOGGED.lock().unwrap().push(record.args().to_string());
+        }
+
+        fn flush(&self) {}
+    }
+
+    fn capture_logs() {
+        static LOGGER: CapturingLogger = CapturingLogger;
+        // Already set by another test otherwise.
+        let _ = log::set_logger(&LOGGER);
+        log::set_max_level(log::LevelFilter::Error);
+    }
+
+    async fn expect_error(
+        request: reqwest::RequestBuilder,
+        status: StatusCode,
+        code: ErrorCode,
+    ) -> ErrorBody {
+        let response = request.send().await.unwrap();
+        assert_eq!(response.status().as_u16(), status.as_u16());
+        let request_id = response.headers()["x-request-id"]
+            .to_str()
+            .unwrap()
+            .to_owned();
+        let body: ErrorBody = response.json().await.unwrap();
+        assert!(body.error);
+        assert_eq!(body.code, code);
+        assert_eq!(body.request_id, request_id);
+        body
+    }
+
+    #[tokio::test]
+    async fn rejected_extractions_use_the_error_body() {
+        let url = serve().await;
+        let client = reqwest::Client::new();
+
+        let body = expect_error(
+            client.get(format!("{url}/workers/abc")),
+            StatusCode::BAD_REQUEST,
+            ErrorCode::InvalidRequest,
+        )
+        .await;
+        assert!(body.message.starts_with("invalid request: "));
+        expect_error(
+            client.get(format!("{url}/tx/history?from=yesterday")),
+            StatusCode::BAD_REQUEST,
+            ErrorCode::InvalidRequest,
+        )
+        .await;
+        expect_error(
+            client
+                .post(format!("{url}/tx/cancel"))
+                .header("content-type", "application/json")
+                .body("{"),
+            StatusCode::BAD_REQUEST,
+            ErrorCode::InvalidRequest,
+        )
+        .await;
+        expect_error(
+            client.post(format!("{url}/tx/cancel")).body("{\"id\": 1}"),
+            StatusCode::BAD_REQUEST,
+            ErrorCode::InvalidRequest,
+        )
+        .await;
+
+        let accepted = client
+            .post(format!("{url}/tx/cancel"))
+            .json(&CancelTxRequest { id: 7 })
+            .send()
+            .await
+            .unwrap();
+        assert!(accepted.status().is_success());
+        assert_eq!(accepted.text().await.unwrap(), "7");
+    }
+
+    #[tokio::test]
+    async fn unknown_routes_use_the_error_body() {
+        let url = serve().await;
+        let client = reqwest::Client::new();
+        let body = expect_error(
+            client.delete(format!("{url}/workers")),
+            StatusCode::NOT_FOUND,
+            ErrorCode::RouteNotFound,
+        )
+        .await;
+        assert_eq!(body.message, "no route for DELETE /workers");
+        let value: serde_json::Value = client
+            .get(format!("{url}/nope?x=1"))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(value["code"], "route_not_found");
+        assert!(value.get("details").is_none());
+    }
+
+    #[tokio::test]
+    async fn every_error_has_its_status_and_stable_code() {
+        capture_logs();
+        let url = serve().await;
+        let client = reqwest::Client::new();
+        for (i, (error, status, code)) in every_error().into_iter().enumerate() {
+            let body = expect_error(
+                client.get(format!("{url}/errors/{i}")),
+                status,
+                error.code(),
+            )
+            .await;
+            assert_eq!(serde_json::to_value(body.code).unwrap(), code);
+            assert_eq!(body.code.to_string(), code);
+            let logged = format!("[{}] {code}: ", body.request_id);
+            assert!(
+                LOGGED
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .any(|line| line.starts_with(&logged)),
+                "{code} is not logged with the request id"
+            );
+        }
+    }
+}
@@ -1,3 +1,4 @@
+use crate::api::{parse_endpoints_by_id, EndpointsById};
 use crate::configurator;
 use crate::wm::wm;
 use clap::{Parser, Subcommand, ValueEnum};
@@ -18,10 +19,42 @@ pub struct WorkerManagerCliArgs {
     #[arg(short = 'm', long, env, default_values_t = vec!["0.0.0.0:3001".to_string(), "[::]:3001".to_string()])]
     pub mgmt_listen_addresses: Vec<String>,
 
+    /// Path to the PEM certificate chain to serve the management interface over HTTPS with,
+    /// plain HTTP is served if not set
+    #[arg(long, env, requires = "mgmt_tls_key")]
+    pub mgmt_tls_cert: Option<String>,
+
+    /// Path to the PEM private key of the TLS certificate
+    #[arg(long, env, requires = "mgmt_tls_cert")]
+    pub mgmt_tls_key: Option<String>,
+
+    /// Origins allowed to call the management interface from a browser, comma separated, `*` for
+    /// any. Only the same origin is allowed if not set
+    #[arg(long, env, value_delimiter = ',')]
+    pub cors_allowed_origins: Vec<String>,
+
+    /// Methods allowed in the cross-origin requests, comma separated
+    #[arg(long, env, value_delimiter = ',', default_value = "GET,POST,PUT")]
+    pub cors_allowed_methods: Vec<String>,
+
+    /// Headers allowed in the cross-origin requests, comma separated
+    #[arg(
+        long,
+        env,
+        value_delimiter = ',',
+        default_value = "authorization,content-type,x-api-key"
+    )]
+    pub cors_allowed_headers: Vec<String>,
+
     /// Enable mDNS broadcast of management interface information
     #[arg(long, env)]
     pub mgmt_disable_mdns: bool,
 
+    /// Discover the workers on the LAN over mDNS, and advertise the management interface unless
+    /// `--mgmt-disable-mdns` is given
+    #[arg(long, env)]
+    pub enable_mdns: bool,
+
     /// Disable fast-sync feature
     #[arg(long, env)]
     pub disable_fast_sync: bool,
@@ -41,6 +74,95 @@ pub struct WorkerManagerCliArgs {
     /// Timeout in seconds of PCCS server to get collateral
     #[arg(long, env, default_value = "10")]
     pub pccs_timeout: u64,
+
+    /// Times to retry a failed force registration of a worker
+    #[arg(long, env, default_value_t = 3)]
+    pub force_register_retries: u32,
+
+    /// Initial delay in seconds before retrying a failed force registration, doubled on each retry
+    #[arg(long, env, default_value_t = 6)]
+    pub force_register_retry_delay: u64,
+
+    /// Static token required by the management interface, as a bearer token or in `X-API-Key`
+    #[arg(long, env)]
+    pub mgmt_token: Option<String>,
+
+    /// Issuer of the OIDC tokens accepted by the management interface, instead of a static token
+    #[arg(long, env)]
+    pub oidc_issuer: Option<String>,
+
+    /// Audience the OIDC tokens must be issued for
+    #[arg(long, env)]
+    pub oidc_audience: Option<String>,
+
+    /// URL of the JWKS to verify the OIDC tokens with, discovered from the issuer if not set
+    #[arg(long, env)]
+    pub oidc_jwks_url: Option<String>,
+
+    /// Claim of the OIDC tokens identifying the actor in the audit log
+    #[arg(long, env, default_value = "sub")]
+    pub oidc_actor_claim: String,
+
+    /// Send the backtraces of the server errors to the clients of the management interface
+    #[arg(long, env)]
+    pub debug_errors: bool,
+
+    /// Path to the YAML file mapping actors to roles, every actor can do anything if not set
+    #[arg(long, env)]
+    pub rbac_config_path: Option<String>,
+
+    /// Number of workers allowed to sync concurrently after startup, all at once if not set
+    #[arg(long, env)]
+    pub sync_slow_start_initial: Option<usize>,
+
+    /// Number of workers allowed to sync concurrently more after each slow start interval
+    #[arg(long, env, default_value_t = 1)]
+    pub sync_slow_start_step: usize,
+
+    /// Interval in seconds between the ramp up steps of the slow start
+    #[arg(long, env, default_value_t = 30)]
+    pub sync_slow_start_interval: u64,
+
+    /// Requests allowed to the mutating management routes per rate limit interval, no limit if not
+    /// set
+    #[arg(long, env)]
+    pub rate_limit_requests: Option<u32>,
+
+    /// Requests allowed to the read-only management routes per rate limit interval, no limit if
+    /// not set
+    #[arg(long, env)]
+    pub rate_limit_read_requests: Option<u32>,
+
+    /// Interval in seconds the rate limits are counted over
+    #[arg(long, env, default_value_t = 60)]
+    pub rate_limit_interval: u64,
+
+    /// Persist the finished transactions, to be queried from `/tx/history` across restarts
+    #[arg(long, env)]
+    pub tx_history: bool,
+
+    /// Max number of transactions kept in the history, no limit if not set
+    #[arg(long, env)]
+    pub tx_history_max_count: Option<usize>,
+
+    /// Max age in seconds of the transactions kept in the history, no limit if not set
+    #[arg(long, env)]
+    pub tx_history_max_age: Option<u64>,
+
+    /// Seconds to wait for the running transactions to finish on SIGTERM or SIGINT
+    #[arg(long, env, default_value_t = 60)]
+    pub shutdown_timeout: u64,
+}
+
+impl WorkerManagerCliArgs {
+    /// Whether the management interface is advertised over mDNS, the only place the two mDNS
+    /// flags are combined.
+    ///
+    /// Nothing is sent over mDNS without `--enable-mdns`. `--mgmt-disable-mdns` only ever turns
+    /// the advertisement off, the workers are discovered either way.
+    pub fn mdns_advertises_mgmt(&self) -> bool {
+        self.enable_mdns && !self.mgmt_disable_mdns
+    }
 }
 
 pub async fn start_wm() {
@@ -203,6 +325,30 @@ pub enum ConfigCommands {
         name: String,
     },
 
+    /// Set the tags of a worker, so the operations can target the workers by group
+    SetWorkerTags {
+        /// Name of the worker
+        #[arg(short, long)]
+        name: String,
+
+        /// Tags of the worker, comma separated, cleared if not set
+        #[arg(short, long, value_delimiter = ',')]
+        tags: Vec<String>,
+    },
+
+    /// Update the endpoints of the workers in bulk, from a map of worker ids to their endpoints.
+    /// Only applies to a running manager, through `/wm/config`.
+    UpdateEndpoints {
+        /// Read from a YAML or JSON file on the command line, sent inline to the manager
+        #[arg(short, long = "path", value_name = "PATH", value_parser = read_endpoints_file)]
+        endpoints: EndpointsById,
+
+        /// Only tell which workers would be updated or skipped
+        #[arg(long, default_value_t = false)]
+        #[serde(default)]
+        dry_run: bool,
+    },
+
     /// Get all pool operators
     GetAllPoolOperators,
 
@@ -265,3 +411,9 @@ pub async fn start_config() {
         }
     }
 }
+
+fn read_endpoints_file(path: &str) -> Result<EndpointsById, String> {
+    let content =
+        std::fs::read_to_string(path).map_err(|e| format!("failed to read {path}: {e}"))?;
+    parse_endpoints_by_id(&content).map_err(|e| e.to_string())
+}
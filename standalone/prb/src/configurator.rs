@@ -4,12 +4,14 @@ use crate::cli::{AccountType, ConfigCliArgs, ConfigCommands};
 use crate::db;
 use crate::db::{
     add_worker, get_all_pools, get_all_pools_with_workers, get_pool_by_pid,
-    get_pool_by_pid_with_workers, get_worker_by_name, remove_worker, setup_inventory_db,
-    update_worker, WrappedDb,
+    get_pool_by_pid_with_workers, get_worker_by_name, remove_worker, set_worker_tags,
+    setup_inventory_db, update_worker, Pool, WrappedDb,
 };
 use crate::tx::{get_options, PoolOperator, PoolOperatorAccess, PoolOperatorForSerialize, DB};
 use anyhow::{anyhow, Context, Result};
+use log::warn;
 use schnorrkel::SecretKey;
+use serde::{Deserialize, Serialize};
 use sp_core::crypto::{AccountId32, Ss58Codec};
 use sp_core::sr25519::Pair as Sr22519Pair;
 use sp_core::Pair;
@@ -92,6 +94,15 @@ pub async fn cli_main(args: ConfigCliArgs) -> Result<()> {
         ConfigCommands::RemoveWorker { name } => {
             remove_worker(db, name.clone())?;
         }
+        ConfigCommands::SetWorkerTags { name, tags } => {
+            set_worker_tags(db.clone(), name.clone(), tags.clone())?;
+            let v = get_worker_by_name(db, name.clone())?.context("Worker not found!")?;
+            let v = serde_json::to_string_pretty(&v)?;
+            println!("{v}");
+        }
+        ConfigCommands::UpdateEndpoints { .. } => {
+            anyhow::bail!("UpdateEndpoints needs a running manager, send it to `/wm/config`");
+        }
         ConfigCommands::GetAllPoolOperators => {
             let l = po_db.get_all_po()?;
             let l = l
@@ -221,6 +232,16 @@ pub async fn api_handler(db: WrappedDb, po_db: Arc<DB>, command: ConfigCommands)
             remove_worker(db, name)?;
             Ok(serde_json::to_string_pretty(&ok)?)
         }
+        ConfigCommands::SetWorkerTags { name, tags } => {
+            set_worker_tags(db.clone(), name.clone(), tags)?;
+            let v = get_worker_by_name(db, name)?.context("Worker not found!")?;
+            let v = serde_json::to_string_pretty(&v)?;
+            Ok(v)
+        }
+        ConfigCommands::UpdateEndpoints { .. } => {
+            // Handled by the route itself, as it needs the worker contexts.
+            anyhow::bail!("UpdateEndpoints needs a running manager")
+        }
         ConfigCommands::GetAllPoolOperators => {
             let l = po_db.get_all_po()?;
             let l = l
@@ -233,7 +254,9 @@ pub async fn api_handler(db: WrappedDb, po_db: Arc<DB>, command: ConfigCommands)
         ConfigCommands::GetPoolOperator { pid } => {
             let po = po_db.get_po(pid)?;
             if let Some(po) = po {
-                Ok(serde_json::to_string_pretty::<PoolOperatorForSerialize>(&(&po).into())?)
+                Ok(serde_json::to_string_pretty::<PoolOperatorForSerialize>(
+                    &(&po).into(),
+                )?)
             } else {
                 Err(anyhow!("Record not found!"))
             }
@@ -267,3 +290,225 @@ pub async fn api_handler(db: WrappedDb, po_db: Arc<DB>, command: ConfigCommands)
         }
     }
 }
+
+/// Version of the inventory bundle format produced by `export_inventory`.
+pub const INVENTORY_BUNDLE_VERSION: u32 = 1;
+
+/// A portable snapshot of the inventory, used to migrate a manager.
+///
+/// The signing keys of the pool operators are not included, only their accounts are. After
+/// importing, supply the keys with the `SetPoolOperator` config command for each pid listed in
+/// `ImportSummary::missing_operators`.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct InventoryBundle {
+    pub version: u32,
+    pub pools: Vec<Pool>,
+    pub pool_operators: Vec<PoolOperatorForSerialize>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ImportSummary {
+    pub pools: usize,
+    pub workers: usize,
+    /// Pools without a pool operator set in this manager.
+    pub missing_operators: Vec<u64>,
+}
+
+pub fn export_inventory(db: WrappedDb, po_db: Arc<DB>) -> Result<InventoryBundle> {
+    let pools = get_all_pools_with_workers(db)?;
+    let pool_operators = po_db
+        .get_all_po()?
+        .iter()
+        .map(|po| po.into())
+        .collect::<Vec<PoolOperatorForSerialize>>();
+    Ok(InventoryBundle {
+        version: INVENTORY_BUNDLE_VERSION,
+        pools,
+        pool_operators,
+    })
+}
+
+pub fn import_inventory(
+    db: WrappedDb,
+    po_db: Arc<DB>,
+    bundle: InventoryBundle,
+) -> Result<ImportSummary> {
+    if bundle.version != INVENTORY_BUNDLE_VERSION {
+        anyhow::bail!("Unsupported inventory bundle version: {}", bundle.version);
+    }
+    // Check for conflicts first to avoid a partial import.
+    for pool in &bundle.pools {
+        if get_pool_by_pid(db.clone(), pool.pid)?.is_some() {
+            anyhow::bail!("Pool {} already exists", pool.pid);
+        }
+        for worker in pool.workers.iter().flatten() {
+            if get_worker_by_name(db.clone(), worker.name.clone())?.is_some() {
+                anyhow::bail!("Worker {} already exists", worker.name);
+            }
+        }
+    }
+    let mut summary = ImportSummary {
+        pools: 0,
+        workers: 0,
+        missing_operators: vec![],
+    };
+    for pool in bundle.pools {
+        db::add_pool(
+            db.clone(),
+            ConfigCommands::AddPool {
+                name: pool.name,
+                pid: pool.pid,
+                disabled: !pool.enabled,
+                sync_only: pool.sync_only,
+            },
+        )?;
+        summary.pools += 1;
+        for worker in pool.workers.unwrap_or_default() {
+            let name = worker.name.clone();
+            add_worker(
+                db.clone(),
+                ConfigCommands::AddWorker {
+                    name: worker.name,
+                    endpoint: worker.endpoint,
+                    stake: worker.stake,
+                    pid: pool.pid,
+                    disabled: !worker.enabled,
+                    sync_only: worker.sync_only,
+                    gatekeeper: worker.gatekeeper,
+                },
+            )?;
+            if !worker.tags.is_empty() {
+                set_worker_tags(db.clone(), name, worker.tags)?;
+            }
+            summary.workers += 1;
+        }
+        let expected = bundle
+            .pool_operators
+            .iter()
+            .find(|po| po.pid == pool.pid)
+            .map(|po| &po.operator_account_id);
+        match po_db.get_po(pool.pid)? {
+            None => summary.missing_operators.push(pool.pid),
+            Some(po) => {
+                let current = PoolOperatorForSerialize::from(&po).operator_account_id;
+                if expected.is_some_and(|expected| *expected != current) {
+                    warn!(
+                        "Pool operator of {} differs from the exported one",
+                        pool.pid
+                    );
+                }
+            }
+        }
+    }
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open_dbs(name: &str) -> (WrappedDb, Arc<DB>) {
+        let path = std::env::temp_dir().join(format!("prb-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        let db = setup_inventory_db(path.to_str().unwrap());
+        let po_db = DB::open(&get_options(None), path.join("po")).unwrap();
+        (db, Arc::new(po_db))
+    }
+
+    fn add_pool_with_worker(db: &WrappedDb, pid: u64, worker: &str) {
+        db::add_pool(
+            db.clone(),
+            ConfigCommands::AddPool {
+                name: format!("pool-{pid}"),
+                pid,
+                disabled: false,
+                sync_only: true,
+            },
+        )
+        .unwrap();
+        add_worker(
+            db.clone(),
+            ConfigCommands::AddWorker {
+                name: worker.into(),
+                endpoint: format!("http://{worker}:8000"),
+                stake: "100".into(),
+                pid,
+                disabled: true,
+                sync_only: false,
+                gatekeeper: false,
+            },
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn exported_inventory_is_imported_into_another_manager() {
+        let (src, src_po) = open_dbs("export");
+        add_pool_with_worker(&src, 1, "w1");
+        set_worker_tags(src.clone(), "w1".into(), vec!["eu".into()]).unwrap();
+        let pair = Sr22519Pair::from_seed(&[1; 32]);
+        src_po
+            .set_po(
+                1,
+                PoolOperator {
+                    pid: 1,
+                    pair,
+                    proxied: None,
+                },
+            )
+            .unwrap();
+        let bundle = export_inventory(src, src_po).unwrap();
+        assert_eq!(bundle.pool_operators.len(), 1);
+        let bundle: InventoryBundle =
+            serde_json::from_str(&serde_json::to_string(&bundle).unwrap()).unwrap();
+
+        let (dst, dst_po) = open_dbs("import");
+        let summary = import_inventory(dst.clone(), dst_po, bundle).unwrap();
+        assert_eq!((summary.pools, summary.workers), (1, 1));
+        // The signing keys aren't exported, so the operator has to be set again.
+        assert_eq!(summary.missing_operators, vec![1]);
+
+        let pool = get_pool_by_pid_with_workers(dst, 1).unwrap().unwrap();
+        assert_eq!(
+            (pool.name.as_str(), pool.enabled, pool.sync_only),
+            ("pool-1", true, true)
+        );
+        let workers = pool.workers.unwrap();
+        assert_eq!(workers.len(), 1);
+        let worker = &workers[0];
+        assert_eq!(worker.name, "w1");
+        assert_eq!(worker.endpoint, "http://w1:8000");
+        assert_eq!(worker.stake, "100");
+        assert!(!worker.enabled);
+        assert_eq!(worker.tags, vec!["eu".to_string()]);
+    }
+
+    #[test]
+    fn conflicting_import_changes_nothing() {
+        let (src, src_po) = open_dbs("conflict-src");
+        add_pool_with_worker(&src, 1, "w1");
+        add_pool_with_worker(&src, 2, "w2");
+        let bundle = export_inventory(src, src_po).unwrap();
+
+        // Only the worker of the second pool conflicts.
+        let (dst, dst_po) = open_dbs("conflict-dst");
+        add_pool_with_worker(&dst, 3, "w2");
+        let err = import_inventory(dst.clone(), dst_po.clone(), bundle.clone()).unwrap_err();
+        assert_eq!(err.to_string(), "Worker w2 already exists");
+        assert!(get_pool_by_pid(dst.clone(), 1).unwrap().is_none());
+
+        let bundle = InventoryBundle {
+            version: INVENTORY_BUNDLE_VERSION + 1,
+            ..bundle
+        };
+        let err = import_inventory(dst, dst_po, bundle).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "Unsupported inventory bundle version: {}",
+                INVENTORY_BUNDLE_VERSION + 1
+            )
+        );
+    }
+}
@@ -25,6 +25,7 @@ pub const ID_PROP_WORKER_STAKE: &str = "stake";
 pub const ID_PROP_WORKER_ENABLED: &str = "enabled";
 pub const ID_PROP_WORKER_SYNC_ONLY: &str = "sync_only";
 pub const ID_PROP_WORKER_GATEKEEPER: &str = "gatekeeper";
+pub const ID_PROP_WORKER_TAGS: &str = "tags";
 
 // Account-related settings moved to trade service
 pub const ID_PROP_POOL_NAME: &str = "name";
@@ -57,6 +58,9 @@ pub struct Worker {
     pub enabled: bool,
     pub sync_only: bool,
     pub gatekeeper: bool,
+    /// Groups of the worker, to target the operations at
+    #[serde(default)]
+    pub tags: Vec<String>,
 }
 
 impl From<VertexProperties> for Pool {
@@ -99,6 +103,7 @@ impl From<VertexProperties> for Worker {
             enabled: true,
             sync_only: false,
             gatekeeper: false,
+            tags: Vec::new(),
         };
         value.props.iter().for_each(|p| match p.name.as_str() {
             ID_PROP_WORKER_NAME => {
@@ -119,6 +124,9 @@ impl From<VertexProperties> for Worker {
             ID_PROP_WORKER_GATEKEEPER => {
                 ret.gatekeeper = p.value.as_bool().unwrap();
             }
+            ID_PROP_WORKER_TAGS => {
+                ret.tags = serde_json::from_value(p.value.clone()).unwrap_or_default();
+            }
             &_ => {}
         });
         ret
@@ -325,7 +333,6 @@ pub fn update_pool(db: WrappedDb, cmd: ConfigCommands) -> Result<Uuid> {
             let p = get_raw_pool_by_pid(db.clone(), pid)?;
             let Some(v) = p else {
                 anyhow::bail!("Pool not found!")
-
             };
             let id = v.vertex.id;
             let uq: VertexQuery = SpecificVertexQuery { ids: vec![id] }.into();
@@ -621,6 +628,23 @@ pub fn update_worker(db: WrappedDb, cmd: ConfigCommands) -> Result<Uuid> {
     }
 }
 
+/// Replace the tags of a worker, cleared if empty.
+pub fn set_worker_tags(db: WrappedDb, name: String, tags: Vec<String>) -> Result<()> {
+    let worker = get_raw_worker_by_name(db.clone(), name)?.context("Worker not found!")?;
+    let uq: VertexQuery = SpecificVertexQuery {
+        ids: vec![worker.vertex.id],
+    }
+    .into();
+    db.set_vertex_properties(
+        VertexPropertyQuery {
+            inner: uq,
+            name: Identifier::new(ID_PROP_WORKER_TAGS).unwrap(),
+        },
+        serde_json::to_value(tags)?,
+    )?;
+    Ok(())
+}
+
 pub fn remove_worker(db: WrappedDb, name: String) -> Result<()> {
     let worker = get_raw_worker_by_name(db.clone(), name)?.context("Worker not found!")?;
     let q = SpecificVertexQuery {
@@ -0,0 +1,49 @@
+//! Changes of the status of the workers, streamed to the clients of `/workers/events`.
+
+use crate::api::{worker_status, WorkerStatus};
+use crate::worker::WorkerContext;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::sync::broadcast;
+
+/// Events kept for the subscribers lagging behind. Older ones are dropped for them.
+const EVENT_BUFFER: usize = 1024;
+
+#[derive(Debug, Clone)]
+pub struct WorkerEvent {
+    /// Increasing with each event, sent as the id of the SSE event
+    pub id: u64,
+    pub status: WorkerStatus,
+}
+
+pub struct WorkerEvents {
+    tx: broadcast::Sender<WorkerEvent>,
+    last_id: AtomicU64,
+}
+
+impl Default for WorkerEvents {
+    fn default() -> Self {
+        let (tx, _) = broadcast::channel(EVENT_BUFFER);
+        Self {
+            tx,
+            last_id: AtomicU64::new(0),
+        }
+    }
+}
+
+impl WorkerEvents {
+    /// Publish the current status of the worker, dropped if no one is listening.
+    pub fn publish(&self, c: &WorkerContext) {
+        let id = self.last_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let status = worker_status(c);
+        _ = self.tx.send(WorkerEvent { id, status });
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<WorkerEvent> {
+        self.tx.subscribe()
+    }
+
+    /// Id of the latest event published.
+    pub fn last_id(&self) -> u64 {
+        self.last_id.load(Ordering::Relaxed)
+    }
+}
@@ -1,11 +1,19 @@
 pub mod api;
+pub mod auth;
 pub mod cli;
 pub mod configurator;
 pub mod datasource;
 pub mod db;
+pub mod events;
 pub mod lifecycle;
+pub mod mdns;
+pub mod metrics;
 pub mod pruntime;
+pub mod ratelimit;
+pub mod rbac;
+pub mod session;
 pub mod tx;
+pub mod tx_history;
 pub mod utils;
 pub mod wm;
 pub mod worker;
@@ -1,4 +1,4 @@
-use crate::api::WorkerStatus;
+use crate::api::worker_status;
 use crate::datasource::WrappedDataSourceManager;
 use crate::db::{get_all_workers, Worker, WrappedDb};
 use crate::tx::TxManager;
@@ -10,10 +10,12 @@ use crate::worker::{WorkerContext, WrappedWorkerContext};
 use anyhow::Result;
 use log::{debug, info, warn};
 use reqwest::Client;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
-use tokio::sync::{RwLock, Semaphore};
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, RwLock, Semaphore};
 use tokio::task::JoinSet;
+use tokio::time::sleep;
 
 pub struct WorkerLifecycleManager {
     pub main_tx: WorkerManagerCommandTx,
@@ -27,6 +29,7 @@ pub struct WorkerLifecycleManager {
     pub worker_context_map: WorkerContextMap,
     pub fast_sync_enabled: bool,
     pub fast_sync_semaphore: Arc<Semaphore>,
+    pub sync_slow_start: Option<SyncSlowStart>,
     pub webhook_url: Option<String>,
     pub reqwest: Client,
 }
@@ -34,6 +37,54 @@ pub type WrappedWorkerLifecycleManager = Arc<WorkerLifecycleManager>;
 
 pub type WorkerContextMap = HashMap<String, WrappedWorkerContext>; // HashMap<UuidString, WrappedWorkerContext>
 
+#[derive(Debug, Clone)]
+pub struct SlowStartConfig {
+    /// Number of workers allowed to sync concurrently at the beginning
+    pub initial: usize,
+    /// Number of workers allowed more after each interval
+    pub step: usize,
+    pub interval: Duration,
+}
+
+/// Ramps up the number of workers syncing concurrently, to not overload the nodes by bringing up a
+/// large fleet at once.
+pub struct SyncSlowStart {
+    semaphore: Arc<Semaphore>,
+}
+
+impl SyncSlowStart {
+    pub fn start(config: &SlowStartConfig, workers: usize) -> Self {
+        let mut allowed = config.initial.min(workers);
+        let semaphore = Arc::new(Semaphore::new(allowed));
+        let ramp = Arc::downgrade(&semaphore);
+        let step = config.step.max(1);
+        let interval = config.interval;
+        tokio::spawn(async move {
+            while allowed < workers {
+                sleep(interval).await;
+                let Some(semaphore) = ramp.upgrade() else {
+                    // The lifecycle manager has been reset
+                    return;
+                };
+                let added = step.min(workers - allowed);
+                semaphore.add_permits(added);
+                allowed += added;
+                info!("Slow start: {allowed} of {workers} workers are allowed to sync now.");
+            }
+        });
+        Self { semaphore }
+    }
+
+    /// Wait for the turn of the worker to sync, which lasts until the permit is dropped.
+    pub async fn acquire(&self) -> OwnedSemaphorePermit {
+        self.semaphore
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("The semaphore is never closed")
+    }
+}
+
 impl WorkerLifecycleManager {
     pub async fn create(
         main_tx: WorkerManagerCommandTx,
@@ -47,10 +98,17 @@ impl WorkerLifecycleManager {
         let workers =
             get_all_workers(inv_db.clone()).expect("Failed to load workers from local database");
         let count = workers.len();
-        let workers = workers
+        let mut workers = workers
             .into_iter()
             .filter(|w| w.enabled)
             .collect::<Vec<_>>();
+        let discovered = main_ctx.discovered_workers.lock().await.clone();
+        let discovered: Vec<_> = discovered
+            .into_values()
+            .filter(|d| !workers.iter().any(|w| w.endpoint == d.endpoint))
+            .collect();
+        let discovered_ids: HashSet<String> = discovered.iter().map(|w| w.id.clone()).collect();
+        workers.extend(discovered);
         let count_enabled = workers.len();
         if count_enabled == 0 {
             warn!("There are no worker enabled!");
@@ -73,7 +131,8 @@ impl WorkerLifecycleManager {
         let mut worker_context_map: WorkerContextMap = HashMap::new();
         while let Some(c) = join_set.join_next().await {
             match c {
-                Ok(Ok(c)) => {
+                Ok(Ok(mut c)) => {
+                    c.discovered = discovered_ids.contains(&c.id);
                     let cc = Arc::new(RwLock::new(c));
                     let c = cc.clone();
                     let mut c = c.write().await;
@@ -88,6 +147,10 @@ impl WorkerLifecycleManager {
         }
 
         let fast_sync_semaphore = Arc::new(Semaphore::new(2));
+        let sync_slow_start = main_ctx
+            .sync_slow_start
+            .as_ref()
+            .map(|config| SyncSlowStart::start(config, worker_context_vec.len()));
 
         let dd = dsm.clone();
         dd.clone().wait_until_rpc_avail(false).await;
@@ -104,24 +167,23 @@ impl WorkerLifecycleManager {
             worker_context_vec,
             fast_sync_enabled,
             fast_sync_semaphore,
+            sync_slow_start,
             webhook_url,
             reqwest: Client::new(),
         };
         Arc::new(lm)
     }
 
+    /// Publish the status of the worker to the subscribers of the events, and to the webhook if
+    /// configured.
     pub async fn webhook_send(self: Arc<Self>, c: WrappedWorkerContext) -> Result<()> {
+        let cc = c.read().await;
+        self.main_ctx.events.publish(&cc);
         let Some(webhook_url) = &self.webhook_url else {
             return Ok(());
         };
-        let cc = c.read().await;
-        let s = WorkerStatus {
-            worker: cc.worker.clone(),
-            state: cc.state.clone(),
-            phactory_info: cc.info.clone(),
-            last_message: cc.last_message.clone(),
-            session_info: cc.session_info.clone(),
-        };
+        let s = worker_status(&cc);
+        drop(cc);
         let body = serde_json::to_string(&s)?;
         if let Err(e) = self
             .reqwest
@@ -161,3 +223,45 @@ impl WorkerLifecycleManager {
         send_to_main_channel_and_wait_for_response(self.main_tx.clone(), message).await
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future::join;
+    use tokio::time::timeout;
+
+    #[tokio::test]
+    async fn slow_start_ramps_up_to_the_worker_count() {
+        let config = SlowStartConfig {
+            initial: 1,
+            step: 2,
+            interval: Duration::from_millis(100),
+        };
+        let slow_start = SyncSlowStart::start(&config, 4);
+        let _first = slow_start.acquire().await;
+        assert_eq!(slow_start.semaphore.available_permits(), 0);
+
+        let next_two = join(slow_start.acquire(), slow_start.acquire());
+        let _next_two = timeout(Duration::from_secs(5), next_two)
+            .await
+            .expect("ramped up in time");
+        // The last step only adds the one left.
+        let _last = timeout(Duration::from_secs(5), slow_start.acquire())
+            .await
+            .expect("ramped up in time");
+        sleep(config.interval * 3).await;
+        assert_eq!(slow_start.semaphore.available_permits(), 0);
+    }
+
+    #[tokio::test]
+    async fn slow_start_never_exceeds_the_worker_count() {
+        let config = SlowStartConfig {
+            initial: 10,
+            step: 0,
+            interval: Duration::ZERO,
+        };
+        let slow_start = SyncSlowStart::start(&config, 3);
+        sleep(Duration::from_millis(10)).await;
+        assert_eq!(slow_start.semaphore.available_permits(), 3);
+    }
+}
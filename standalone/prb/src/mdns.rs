@@ -0,0 +1,138 @@
+//! mDNS (DNS-SD) advertisement of the management interface and discovery of the workers.
+//!
+//! The workers announce themselves as `_pruntime._tcp.local.`, with the pool they belong to in
+//! the `pid` TXT record, and optionally `name`, `sync_only` and `gatekeeper`. The ones whose
+//! endpoint isn't configured already are lifecycled as the configured ones, but are never
+//! written to the inventory, and are marked as discovered in their status.
+
+use crate::cli::WorkerManagerCliArgs;
+use crate::db::Worker;
+use crate::wm::WrappedWorkerManagerContext;
+use crate::worker::WorkerContext;
+use anyhow::{anyhow, Context, Result};
+use log::{info, warn};
+use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+
+/// Service type the management interface is advertised as.
+pub const MGMT_SERVICE_TYPE: &str = "_prb-mgmt._tcp.local.";
+/// Service type the workers are discovered as.
+pub const WORKER_SERVICE_TYPE: &str = "_pruntime._tcp.local.";
+
+/// Advertise the management interface, then feed the discovered workers into the worker map
+/// until the daemon shuts down.
+pub async fn run(ctx: WrappedWorkerManagerContext, args: WorkerManagerCliArgs) -> Result<()> {
+    let daemon = ServiceDaemon::new().context("Failed to start the mDNS daemon")?;
+    if args.mdns_advertises_mgmt() {
+        advertise(&daemon, &args.mgmt_listen_addresses)?;
+    }
+    let events = daemon
+        .browse(WORKER_SERVICE_TYPE)
+        .map_err(|e| anyhow!("Failed to browse {WORKER_SERVICE_TYPE}: {e}"))?;
+    info!("Discovering workers as {WORKER_SERVICE_TYPE} over mDNS.");
+    while let Ok(event) = events.recv_async().await {
+        let ServiceEvent::ServiceResolved(service) = event else {
+            continue;
+        };
+        match discovered_worker(&service) {
+            Ok(worker) => add_discovered_worker(ctx.clone(), worker).await,
+            Err(e) => warn!("Ignoring {}: {e}", service.get_fullname()),
+        }
+    }
+    Ok(())
+}
+
+fn advertise(daemon: &ServiceDaemon, listen_addresses: &[String]) -> Result<()> {
+    let mut ports: Vec<u16> = listen_addresses
+        .iter()
+        .filter_map(|addr| SocketAddr::from_str(addr).ok())
+        .map(|addr| addr.port())
+        .collect();
+    ports.sort();
+    ports.dedup();
+    for port in ports {
+        let service = ServiceInfo::new(
+            MGMT_SERVICE_TYPE,
+            &format!("prb-{port}"),
+            "prb.local.",
+            "",
+            port,
+            None,
+        )
+        .map_err(|e| anyhow!("Invalid mDNS service: {e}"))?
+        .enable_addr_auto();
+        daemon
+            .register(service)
+            .map_err(|e| anyhow!("Failed to advertise the management interface: {e}"))?;
+        info!("Advertising the management interface on port {port} over mDNS.");
+    }
+    Ok(())
+}
+
+fn discovered_worker(service: &ServiceInfo) -> Result<Worker> {
+    let addr = service
+        .get_addresses()
+        .iter()
+        .next()
+        .ok_or(anyhow!("no address"))?;
+    let endpoint = format!("http://{}:{}", addr, service.get_port());
+    let pid = service
+        .get_property_val_str("pid")
+        .ok_or(anyhow!("no pid"))?
+        .parse()
+        .context("invalid pid")?;
+    let flag = |key| service.get_property_val_str(key) == Some("true");
+    let name = match service.get_property_val_str("name") {
+        Some(name) => name.to_string(),
+        None => service
+            .get_fullname()
+            .trim_end_matches(WORKER_SERVICE_TYPE)
+            .trim_end_matches('.')
+            .to_string(),
+    };
+    Ok(Worker {
+        id: uuid::Uuid::new_v5(&uuid::Uuid::NAMESPACE_URL, endpoint.as_bytes()).to_string(),
+        name,
+        endpoint,
+        stake: "0".to_string(),
+        pid: Some(pid),
+        enabled: true,
+        sync_only: flag("sync_only"),
+        gatekeeper: flag("gatekeeper"),
+        tags: Vec::new(),
+    })
+}
+
+async fn add_discovered_worker(ctx: WrappedWorkerManagerContext, worker: Worker) {
+    let contexts: Vec<_> = ctx.worker_map.lock().await.values().cloned().collect();
+    for c in contexts {
+        if c.read().await.worker.endpoint == worker.endpoint {
+            return;
+        }
+    }
+    let mut discovered = ctx.discovered_workers.lock().await;
+    if discovered.contains_key(&worker.id) {
+        return;
+    }
+    info!("Discovered worker {} at {}", &worker.name, &worker.endpoint);
+    discovered.insert(worker.id.clone(), worker.clone());
+    drop(discovered);
+
+    // A lifecycle manager created later picks the worker up by itself.
+    let running = ctx.current_lifecycle_manager.lock().unwrap().is_some();
+    if !running {
+        return;
+    }
+    match WorkerContext::create(worker, ctx.clone()).await {
+        Ok(mut c) => {
+            c.discovered = true;
+            let c = Arc::new(RwLock::new(c));
+            c.write().await.self_ref = Some(c.clone());
+            tokio::spawn(WorkerContext::start(c));
+        }
+        Err(e) => warn!("Failed to create the context of a discovered worker: {e}"),
+    }
+}
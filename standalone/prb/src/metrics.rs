@@ -0,0 +1,132 @@
+use std::sync::OnceLock;
+
+use phala_pallets::pallet_computation::WorkerState;
+use prometheus::{Encoder, GaugeVec, IntGaugeVec, Opts, Registry, TextEncoder};
+
+use crate::wm::WorkerManagerContext;
+
+/// Names of the lifecycle states, so the states without any worker are reported as 0.
+const LIFECYCLE_STATES: &[&str] = &[
+    "Starting",
+    "Synchronizing",
+    "Preparing",
+    "Working",
+    "GatekeeperWorking",
+    "HasError",
+    "Restarting",
+    "Stopped",
+];
+
+struct Metrics {
+    registry: Registry,
+    workers: IntGaugeVec,
+    txs: IntGaugeVec,
+    session_v: GaugeVec,
+    session_state: IntGaugeVec,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry =
+            Registry::new_custom(Some("prb".into()), None).expect("Failed to create registry");
+        macro_rules! register {
+            ($kind: ident, $name: expr, $help: expr, $labels: expr) => {{
+                let metric = $kind::new(Opts::new($name, $help), $labels).expect("Invalid metric");
+                registry
+                    .register(Box::new(metric.clone()))
+                    .expect("Failed to register metric");
+                metric
+            }};
+        }
+        Self {
+            workers: register!(
+                IntGaugeVec,
+                "workers",
+                "Number of workers in each lifecycle state",
+                &["state"]
+            ),
+            txs: register!(
+                IntGaugeVec,
+                "txs",
+                "Number of running, pending and past transactions",
+                &["status"]
+            ),
+            session_v: register!(
+                GaugeVec,
+                "worker_session_v",
+                "The last updated V of the computing session of each worker",
+                &["worker", "name"]
+            ),
+            session_state: register!(
+                IntGaugeVec,
+                "worker_session_state",
+                "The state of the computing session of each worker, always 1",
+                &["worker", "name", "state"]
+            ),
+            registry,
+        }
+    }
+}
+
+fn metrics() -> &'static Metrics {
+    static METRICS: OnceLock<Metrics> = OnceLock::new();
+    METRICS.get_or_init(Metrics::new)
+}
+
+fn session_state_name(state: &WorkerState) -> &'static str {
+    match state {
+        WorkerState::Ready => "Ready",
+        WorkerState::WorkerIdle => "WorkerIdle",
+        WorkerState::_Unused => "Unused",
+        WorkerState::WorkerUnresponsive => "WorkerUnresponsive",
+        WorkerState::WorkerCoolingDown => "WorkerCoolingDown",
+    }
+}
+
+/// Render the metrics in the prometheus text format.
+///
+/// The gauges are refreshed from the workers and the transactions on each call. The ones of the
+/// workers that are gone are dropped.
+pub async fn render(ctx: &WorkerManagerContext) -> String {
+    let metrics = metrics();
+
+    let (running, pending, past) = ctx.txm.counts().await;
+    metrics
+        .txs
+        .with_label_values(&["running"])
+        .set(running as _);
+    metrics
+        .txs
+        .with_label_values(&["pending"])
+        .set(pending as _);
+    metrics.txs.with_label_values(&["past"]).set(past as _);
+
+    for state in LIFECYCLE_STATES {
+        metrics.workers.with_label_values(&[*state]).set(0);
+    }
+    metrics.session_v.reset();
+    metrics.session_state.reset();
+    let workers = ctx.workers.lock().await.clone();
+    for w in workers.iter() {
+        let w = w.read().await;
+        metrics.workers.with_label_values(&[w.state.name()]).inc();
+        let Some(session) = &w.session_info else {
+            continue;
+        };
+        let labels = [w.worker.id.as_str(), w.worker.name.as_str()];
+        // V is in `U64F64` bits.
+        let v = session.v as f64 / (1u128 << 64) as f64;
+        metrics.session_v.with_label_values(&labels).set(v);
+        let state = session_state_name(&session.state);
+        metrics
+            .session_state
+            .with_label_values(&[labels[0], labels[1], state])
+            .set(1);
+    }
+
+    let mut buffer = vec![];
+    if let Err(err) = TextEncoder::new().encode(&metrics.registry.gather(), &mut buffer) {
+        log::error!("Failed to encode metrics: {err}");
+    }
+    String::from_utf8(buffer).unwrap_or_default()
+}
@@ -0,0 +1,101 @@
+//! Token bucket rate limiting of the management interface.
+//!
+//! The mutating routes share one bucket, so a buggy script can't cause a restart storm across
+//! the fleet. The read-only ones share another, looser one.
+
+use crate::api::ApiError;
+use crate::cli::WorkerManagerCliArgs;
+use axum::extract::State;
+use axum::http::{header, Method, Request};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use log::warn;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+pub struct TokenBucket {
+    capacity: f64,
+    /// Tokens added per second
+    refill_rate: f64,
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl TokenBucket {
+    /// A full bucket allowing `requests` per `interval`, and bursts of as many.
+    pub fn new(requests: u32, interval: Duration) -> Self {
+        let capacity = requests as f64;
+        Self {
+            capacity,
+            refill_rate: capacity / interval.as_secs_f64().max(f64::EPSILON),
+            tokens: capacity,
+            updated_at: Instant::now(),
+        }
+    }
+
+    /// Take a token, or return how long to wait until one is available.
+    pub fn try_take(&mut self) -> Result<(), Duration> {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.updated_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.refill_rate).min(self.capacity);
+        self.updated_at = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return Ok(());
+        }
+        if self.refill_rate <= 0.0 {
+            return Err(Duration::MAX);
+        }
+        Err(Duration::from_secs_f64(
+            (1.0 - self.tokens) / self.refill_rate,
+        ))
+    }
+}
+
+/// The buckets of the management interface. No limit if None.
+pub struct RateLimiter {
+    mutating: Option<Mutex<TokenBucket>>,
+    read_only: Option<Mutex<TokenBucket>>,
+}
+
+pub type WrappedRateLimiter = Arc<RateLimiter>;
+
+impl RateLimiter {
+    pub fn from_args(args: &WorkerManagerCliArgs) -> Self {
+        let interval = Duration::from_secs(args.rate_limit_interval);
+        let bucket = |requests| Mutex::new(TokenBucket::new(requests, interval));
+        Self {
+            mutating: args.rate_limit_requests.map(bucket),
+            read_only: args.rate_limit_read_requests.map(bucket),
+        }
+    }
+
+    fn check(&self, method: &Method) -> Result<(), Duration> {
+        let bucket = if method == Method::GET {
+            &self.read_only
+        } else {
+            &self.mutating
+        };
+        match bucket {
+            Some(bucket) => bucket.lock().unwrap().try_take(),
+            None => Ok(()),
+        }
+    }
+}
+
+pub async fn rate_limit_middleware<B>(
+    State(limiter): State<WrappedRateLimiter>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    if let Err(wait) = limiter.check(req.method()) {
+        let retry_after = wait.as_secs_f64().ceil().min(u32::MAX as f64) as u64;
+        warn!("rate limited {} {}", req.method(), req.uri().path());
+        let mut response = ApiError::RateLimited(retry_after).into_response();
+        response
+            .headers_mut()
+            .insert(header::RETRY_AFTER, retry_after.into());
+        return response;
+    }
+    next.run(req).await
+}
@@ -0,0 +1,251 @@
+use crate::auth::Actor;
+use anyhow::{anyhow, Context, Result};
+use axum::http::Method;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::File;
+
+/// What a request to the management interface is about to do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Permission {
+    /// Query the status of the manager, the workers and the transactions.
+    Read,
+    /// Act on the running workers, e.g. restarting or registering them.
+    Operate,
+    /// Change the inventory and the configuration of the manager.
+    Configure,
+}
+
+impl Permission {
+    /// The permission required by a request to the given route.
+    pub fn required_by(method: &Method, path: &str) -> Self {
+        if method == Method::GET {
+            return Self::Read;
+        }
+        match path {
+            "/wm/restart"
+            | "/workers/restart"
+            | "/workers/force_register"
+            | "/workers/update_endpoints"
+            | "/workers/update_endpoints/bulk"
+            | "/workers/stop"
+            | "/workers/start"
+            | "/tx/cancel" => Self::Operate,
+            // Mutating routes not listed above are the most sensitive by default
+            _ => Self::Configure,
+        }
+    }
+}
+
+/// The roles allowed to each actor, loaded from a YAML file like:
+///
+/// ```yaml
+/// roles:
+///   read_only: [read]
+///   operator: [read, operate]
+///   admin: [read, operate, configure]
+/// actors:
+///   alice@example.com: admin
+///   bob@example.com: operator
+/// default_role: read_only
+/// ```
+///
+/// `roles` defaults to the three roles above. Actors not listed get `default_role`, or nothing if
+/// it is not set.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RbacConfig {
+    #[serde(default = "default_roles")]
+    pub roles: HashMap<String, Vec<Permission>>,
+    #[serde(default)]
+    pub actors: HashMap<String, String>,
+    #[serde(default)]
+    pub default_role: Option<String>,
+}
+
+fn default_roles() -> HashMap<String, Vec<Permission>> {
+    use Permission::*;
+    HashMap::from([
+        ("read_only".into(), vec![Read]),
+        ("operator".into(), vec![Read, Operate]),
+        ("admin".into(), vec![Read, Operate, Configure]),
+    ])
+}
+
+pub struct Rbac {
+    config: RbacConfig,
+}
+
+impl Rbac {
+    pub fn new(config: RbacConfig) -> Result<Self> {
+        let undefined = config
+            .actors
+            .values()
+            .chain(config.default_role.iter())
+            .find(|role| !config.roles.contains_key(*role));
+        if let Some(role) = undefined {
+            return Err(anyhow!("role `{role}` is not defined"));
+        }
+        Ok(Self { config })
+    }
+
+    pub fn load(path: &str) -> Result<Self> {
+        let file = File::open(path).with_context(|| format!("Failed to open {path}"))?;
+        let config = serde_yaml::from_reader(file).context("Failed to parse the RBAC config")?;
+        Self::new(config)
+    }
+
+    /// Check whether the actor is allowed to do what's required, returning the reason if not.
+    pub fn check(&self, actor: &Actor, required: Permission) -> Result<(), String> {
+        let role = self
+            .config
+            .actors
+            .get(&actor.0)
+            .or(self.config.default_role.as_ref())
+            .ok_or_else(|| format!("{actor} has no role"))?;
+        let allowed = self
+            .config
+            .roles
+            .get(role)
+            .map_or(false, |permissions| permissions.contains(&required));
+        if allowed {
+            Ok(())
+        } else {
+            Err(format!(
+                "role `{role}` of {actor} lacks the {required:?} permission"
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rbac(yaml: &str) -> Result<Rbac> {
+        Rbac::new(serde_yaml::from_str(yaml)?)
+    }
+
+    fn actor(name: &str) -> Actor {
+        Actor(name.into())
+    }
+
+    #[test]
+    fn mutating_routes_require_more_than_read() {
+        use Permission::*;
+        assert_eq!(
+            Permission::required_by(&Method::GET, "/workers/restart"),
+            Read
+        );
+        assert_eq!(
+            Permission::required_by(&Method::POST, "/workers/restart"),
+            Operate
+        );
+        assert_eq!(
+            Permission::required_by(&Method::POST, "/wm/config"),
+            Configure
+        );
+        assert_eq!(
+            Permission::required_by(&Method::DELETE, "/unknown"),
+            Configure
+        );
+    }
+
+    /// The routes registered by `start_api_server`, read from its source.
+    fn served_routes() -> Vec<(Method, String)> {
+        let source = include_str!("api.rs");
+        let start = source.find("let app = Router::new()").unwrap();
+        let end = start + source[start..].find(".fallback(").unwrap();
+        source[start..end]
+            .split(".route(")
+            .skip(1)
+            .map(|route| {
+                let mut quoted = route.split('"');
+                let path = quoted.nth(1).unwrap().to_string();
+                let method = quoted
+                    .next()
+                    .unwrap()
+                    .trim_start_matches(|c: char| c == ',' || c.is_whitespace());
+                let method = method[..method.find('(').unwrap()].to_uppercase();
+                (Method::from_bytes(method.as_bytes()).unwrap(), path)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn every_route_has_its_permission() {
+        use Permission::*;
+        let table = [
+            (Method::GET, "/", Read),
+            (Method::GET, "/healthz", Read),
+            (Method::GET, "/readyz", Read),
+            (Method::GET, "/wm/status", Read),
+            (Method::PUT, "/wm/restart", Operate),
+            (Method::POST, "/wm/config", Configure),
+            (Method::GET, "/export", Read),
+            (Method::POST, "/import", Configure),
+            (Method::GET, "/workers/status", Read),
+            (Method::GET, "/workers/events", Read),
+            (Method::GET, "/workers/:id", Read),
+            (Method::PUT, "/workers/restart", Operate),
+            (Method::PUT, "/workers/force_register", Operate),
+            (Method::PUT, "/workers/update_endpoints", Operate),
+            (Method::POST, "/workers/update_endpoints/bulk", Operate),
+            (Method::POST, "/workers/stop", Operate),
+            (Method::POST, "/workers/start", Operate),
+            (Method::GET, "/tx/status", Read),
+            (Method::GET, "/tx/history", Read),
+            (Method::POST, "/tx/cancel", Operate),
+            (Method::GET, "/metrics", Read),
+        ];
+        let listed: Vec<_> = table
+            .iter()
+            .map(|(method, path, _)| (method.clone(), path.to_string()))
+            .collect();
+        assert_eq!(
+            served_routes(),
+            listed,
+            "the routes changed, decide the permission of the new ones here"
+        );
+        for (method, path, permission) in table {
+            assert_eq!(
+                Permission::required_by(&method, path),
+                permission,
+                "{method} {path}"
+            );
+        }
+    }
+
+    #[test]
+    fn actors_get_the_permissions_of_their_role() {
+        let roles = rbac(
+            "actors: { alice: admin, bob: operator }\n\
+             default_role: read_only",
+        )
+        .unwrap();
+        assert!(roles.check(&actor("alice"), Permission::Configure).is_ok());
+        assert!(roles.check(&actor("bob"), Permission::Operate).is_ok());
+        assert_eq!(
+            roles.check(&actor("bob"), Permission::Configure),
+            Err("role `operator` of bob lacks the Configure permission".into())
+        );
+        assert!(roles.check(&actor("carol"), Permission::Read).is_ok());
+        assert!(roles.check(&actor("carol"), Permission::Operate).is_err());
+
+        let roles = rbac("actors: { alice: admin }").unwrap();
+        assert_eq!(
+            roles.check(&actor("carol"), Permission::Read),
+            Err("carol has no role".into())
+        );
+    }
+
+    #[test]
+    fn undefined_roles_are_rejected() {
+        let err = rbac("roles: { viewer: [read] }\nactors: { alice: admin }")
+            .err()
+            .unwrap();
+        assert_eq!(err.to_string(), "role `admin` is not defined");
+        assert!(rbac("default_role: root").is_err());
+        assert!(rbac("roles: { viewer: [read] }\ndefault_role: viewer").is_ok());
+    }
+}
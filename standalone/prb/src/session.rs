@@ -0,0 +1,205 @@
+use crate::utils::fetch_storage_bytes;
+use anyhow::Result;
+use parity_scale_codec::{Decode, Encode};
+use phala_pallets::pallet_computation::{SessionInfo, WorkerState};
+use phaxt::ChainApi;
+use serde::{Deserialize, Serialize};
+use subxt::dynamic::{storage, Value};
+
+/// `ExpectedBlockTimeSec` of the parachain, to estimate the timings in blocks.
+const EXPECTED_BLOCK_TIME_SECS: u64 = 12;
+
+/// How the computing session of a worker changed between two successive polls.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionDelta {
+    /// The state before this poll, if it changed.
+    pub previous_state: Option<WorkerState>,
+    /// Seconds the last heartbeat challenge time moved forward.
+    pub heartbeat_advanced_secs: u64,
+    /// Number of consecutive polls without any heartbeat progress.
+    pub polls_since_heartbeat: u32,
+    /// Benchmark iterations reported since the previous poll.
+    pub iterations: u64,
+    /// Change of V, in `U64F64` bits.
+    pub v: i128,
+    /// Reward received since the previous poll, in `U32F32` bits.
+    pub total_reward: u128,
+}
+
+/// The fields of [`SessionInfo`] that are not public, decoded from its SCALE encoding.
+///
+/// Must be kept in the same layout as the struct in the computation pallet.
+#[derive(Decode)]
+struct SessionFields {
+    state: WorkerState,
+    _ve: u128,
+    v: u128,
+    _v_updated_at: u64,
+    benchmark: BenchmarkFields,
+    cool_down_start: u64,
+    total_reward: u128,
+}
+
+#[derive(Decode)]
+struct BenchmarkFields {
+    _p_init: u32,
+    _p_instant: u32,
+    iterations: u64,
+    _working_start_time: u64,
+    challenge_time_last: u64,
+}
+
+impl SessionFields {
+    fn of(session: &SessionInfo) -> Option<Self> {
+        Self::decode(&mut &session.encode()[..]).ok()
+    }
+}
+
+impl SessionDelta {
+    /// Diff two snapshots of a session, given the delta computed at the previous poll if any.
+    pub fn between(
+        prev: &SessionInfo,
+        cur: &SessionInfo,
+        prev_delta: Option<&SessionDelta>,
+    ) -> Option<Self> {
+        let (prev, cur) = (SessionFields::of(prev)?, SessionFields::of(cur)?);
+        let heartbeat_advanced_secs = cur
+            .benchmark
+            .challenge_time_last
+            .saturating_sub(prev.benchmark.challenge_time_last);
+        let polls_since_heartbeat = if heartbeat_advanced_secs > 0 {
+            0
+        } else {
+            prev_delta.map_or(0, |d| d.polls_since_heartbeat) + 1
+        };
+        Some(Self {
+            previous_state: (prev.state != cur.state).then_some(prev.state),
+            heartbeat_advanced_secs,
+            polls_since_heartbeat,
+            iterations: cur
+                .benchmark
+                .iterations
+                .saturating_sub(prev.benchmark.iterations),
+            v: (cur.v as i128).wrapping_sub(prev.v as i128),
+            total_reward: cur.total_reward.saturating_sub(prev.total_reward),
+        })
+    }
+}
+
+/// The state of the chain when a session is fetched, to derive its timings from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChainClock {
+    /// Number of the latest parachain block
+    pub height: u32,
+    /// Unix timestamp of the block, in seconds
+    pub now: u64,
+    /// `CoolDownPeriod` of the computation pallet, in seconds
+    pub cool_down_period: u64,
+}
+
+impl ChainClock {
+    pub async fn fetch(api: &ChainApi) -> Result<Option<Self>> {
+        let plain = |pallet, entry| storage(pallet, entry, Vec::<Value>::new());
+        let height: Option<u32> = fetch_storage_bytes(api, &plain("System", "Number")).await?;
+        let now: Option<u64> = fetch_storage_bytes(api, &plain("Timestamp", "Now")).await?;
+        let cool_down_period: Option<u64> =
+            fetch_storage_bytes(api, &plain("PhalaComputation", "CoolDownPeriod")).await?;
+        let (Some(height), Some(now)) = (height, now) else {
+            return Ok(None);
+        };
+        Ok(Some(Self {
+            height,
+            now: now / 1000,
+            cool_down_period: cool_down_period.unwrap_or_default(),
+        }))
+    }
+}
+
+/// Fields derived from a [`SessionInfo`] as the computation pallet would, so its logic isn't
+/// reimplemented by the clients. The timings are None if the chain height is unknown.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionSummary {
+    pub unresponsive: bool,
+    pub cooling_down: bool,
+    /// The chain height the timings are estimated at
+    pub chain_height: Option<u32>,
+    /// Estimated blocks since the last heartbeat, None before the first one.
+    pub blocks_since_heartbeat: Option<u64>,
+    /// Estimated blocks until the cool down ends and the stake can be reclaimed, None if not
+    /// cooling down.
+    pub blocks_until_cool_down_end: Option<u64>,
+}
+
+impl SessionSummary {
+    pub fn of(session: &SessionInfo, clock: Option<&ChainClock>) -> Option<Self> {
+        let fields = SessionFields::of(session)?;
+        let cooling_down = fields.state == WorkerState::WorkerCoolingDown;
+        let blocks_since_heartbeat = clock
+            .filter(|_| fields.benchmark.challenge_time_last > 0)
+            .map(|clock| {
+                clock
+                    .now
+                    .saturating_sub(fields.benchmark.challenge_time_last)
+                    / EXPECTED_BLOCK_TIME_SECS
+            });
+        let blocks_until_cool_down_end = clock.filter(|_| cooling_down).map(|clock| {
+            let end = fields
+                .cool_down_start
+                .saturating_add(clock.cool_down_period);
+            end.saturating_sub(clock.now)
+                .div_ceil(EXPECTED_BLOCK_TIME_SECS)
+        });
+        Some(Self {
+            unresponsive: fields.state == WorkerState::WorkerUnresponsive,
+            cooling_down,
+            chain_height: clock.map(|clock| clock.height),
+            blocks_since_heartbeat,
+            blocks_until_cool_down_end,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A session with the given fields, encoded in the layout of the computation pallet.
+    fn session(
+        state: WorkerState,
+        v: u128,
+        iterations: u64,
+        challenge_time_last: u64,
+        total_reward: u128,
+    ) -> SessionInfo {
+        let benchmark = (0u32, 0u32, iterations, 0u64, challenge_time_last);
+        let encoded = (state, 0u128, v, 0u64, benchmark, 0u64, total_reward).encode();
+        SessionInfo::decode(&mut &encoded[..]).unwrap()
+    }
+
+    #[test]
+    fn session_delta_diffs_the_successive_polls() {
+        let prev = session(WorkerState::WorkerIdle, 100, 1000, 600, 5);
+        let cur = session(WorkerState::WorkerIdle, 90, 1500, 660, 12);
+        let delta = SessionDelta::between(&prev, &cur, None).unwrap();
+        assert_eq!(
+            delta,
+            SessionDelta {
+                previous_state: None,
+                heartbeat_advanced_secs: 60,
+                polls_since_heartbeat: 0,
+                iterations: 500,
+                v: -10,
+                total_reward: 7,
+            }
+        );
+
+        let stalled = session(WorkerState::WorkerUnresponsive, 90, 1500, 660, 12);
+        let delta = SessionDelta::between(&cur, &stalled, Some(&delta)).unwrap();
+        assert_eq!(delta.previous_state, Some(WorkerState::WorkerIdle));
+        assert_eq!(delta.heartbeat_advanced_secs, 0);
+        assert_eq!(delta.polls_since_heartbeat, 1);
+        let delta = SessionDelta::between(&stalled, &stalled, Some(&delta)).unwrap();
+        assert_eq!(delta.previous_state, None);
+        assert_eq!(delta.polls_since_heartbeat, 2);
+    }
+}
@@ -4,6 +4,7 @@ pub use crate::khala;
 use crate::khala::runtime_types::khala_parachain_runtime::ProxyType;
 use crate::khala::utility::events::ItemFailed;
 use crate::tx::TxManagerError::*;
+use crate::tx_history::{TxHistoryAccess, TxHistoryRetention};
 use crate::use_parachain_api;
 use anyhow::{anyhow, Error, Result};
 use chrono::{DateTime, Utc};
@@ -15,6 +16,7 @@ use moka_cht::HashMap;
 use parity_scale_codec::{Decode, Encode};
 use phactory_api::prpc::GetEndpointResponse;
 use phala_types::messaging::SignedMessage;
+use phala_types::WorkerEndpointPayload;
 use phaxt::dynamic::tx::EncodedPayload;
 use pherry::mk_params;
 use rocksdb::{DBCompactionStyle, DBWithThreadMode, MultiThreaded, Options};
@@ -26,7 +28,7 @@ use sp_core::Pair;
 use std::collections::{HashMap as StdHashMap, VecDeque};
 use std::fmt::{Debug, Display, Formatter};
 use std::path::Path;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
 use subxt::error::DispatchError as SubxtDispatchError;
@@ -83,6 +85,7 @@ pub enum TransactionState {
     Running,
     Success(TransactionSuccess),
     Error(TransactionErrorMessage),
+    Cancelled(TransactionSuccess),
 }
 
 #[derive(thiserror::Error, Clone, Debug, Serialize)]
@@ -98,6 +101,16 @@ pub enum TxManagerError {
 
     #[error("Invalid pool operator")]
     InvalidPoolOperator,
+    #[error("The transaction manager is shutting down")]
+    ShuttingDown,
+    #[error("Transaction #{0} not found")]
+    TxNotFound(usize),
+    #[error("Transaction #{0} is already running")]
+    TxAlreadyRunning(usize),
+    #[error("Transaction #{0} is already finished")]
+    TxAlreadyFinished(usize),
+    #[error("Transaction #{0} was cancelled")]
+    TxCancelled(usize),
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -119,6 +132,31 @@ pub struct TransactionErrorMessage {
     pub message: String,
 }
 
+/// An error shared by all the requests coalesced into one transaction.
+///
+/// Displays as the original error, which [`SharedError::get`] gives access to, e.g. to downcast
+/// it to a [`TxManagerError`].
+#[derive(Debug, Clone)]
+pub struct SharedError(Arc<Error>);
+
+impl SharedError {
+    pub fn get(&self) -> &Error {
+        &self.0
+    }
+}
+
+impl Display for SharedError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        Display::fmt(&*self.0, f)
+    }
+}
+
+impl std::error::Error for SharedError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.0.source()
+    }
+}
+
 impl From<&Error> for TransactionErrorMessage {
     fn from(e: &Error) -> Self {
         Self {
@@ -161,6 +199,14 @@ pub struct Transaction {
     pub tx_payload: Option<EncodedPayload>,
     #[serde(skip)]
     pub shot: Option<oneshot::Sender<Result<()>>>,
+    /// Semantic identity of the operation, used to coalesce identical pending transactions.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub dedup_key: Option<String>,
+    /// Number of later requests coalesced into this transaction.
+    #[serde(default)]
+    pub coalesced: usize,
+    #[serde(skip)]
+    pub followers: Vec<oneshot::Sender<Result<()>>>,
 }
 
 impl Transaction {
@@ -170,6 +216,7 @@ impl Transaction {
         tx_payload: EncodedPayload,
         desc: String,
         shot: oneshot::Sender<Result<()>>,
+        dedup_key: Option<String>,
     ) -> Self {
         Self {
             id,
@@ -179,8 +226,27 @@ impl Transaction {
             created_at: Utc::now(),
             tx_payload: Some(tx_payload),
             shot: Some(shot),
+            dedup_key,
+            coalesced: 0,
+            followers: Vec::new(),
         }
     }
+    fn notify(&mut self, r: Result<()>) -> Result<()> {
+        let r = if self.followers.is_empty() {
+            r
+        } else {
+            let r = r.map_err(|e| SharedError(Arc::new(e)));
+            for follower in self.followers.drain(..) {
+                let _ = follower.send(r.clone().map_err(Into::into));
+            }
+            r.map_err(Into::into)
+        };
+        let shot = self.shot.take().ok_or(UnknownDataMismatch)?;
+        if shot.send(r).is_err() {
+            return Err(anyhow!("shot can't be sent"));
+        }
+        Ok(())
+    }
     pub fn clone_for_serialize(&self) -> Self {
         Self {
             id: self.id,
@@ -190,6 +256,9 @@ impl Transaction {
             created_at: self.created_at,
             tx_payload: None,
             shot: None,
+            dedup_key: self.dedup_key.clone(),
+            coalesced: self.coalesced,
+            followers: Vec::new(),
         }
     }
 }
@@ -206,9 +275,13 @@ pub struct TxManager {
     tx_count: AtomicUsize,
     tx_map: HashMap<usize, Arc<Mutex<Transaction>>>,
     pending_txs: Mutex<VecDeque<usize>>,
+    pending_dedup_keys: Mutex<StdHashMap<String, usize>>,
     running_txs: Mutex<Vec<usize>>,
     past_txs: Mutex<VecDeque<usize>>,
     channel_tx: mpsc::UnboundedSender<usize>,
+    shutting_down: AtomicBool,
+    /// Where the finished transactions are persisted, not persisted if None
+    history: Option<TxHistoryRetention>,
 }
 
 impl TxManager {
@@ -247,12 +320,101 @@ impl TxManager {
             past_txs,
         })
     }
+
+    /// Numbers of the running, pending and past transactions, as in [`TxManager::dump`].
+    pub async fn counts(&self) -> (usize, usize, usize) {
+        let running = self.running_txs.lock().await.len();
+        let pending = self.pending_txs.lock().await.len();
+        let past = self.past_txs.lock().await.len();
+        (running, pending, past)
+    }
+
+    /// Stop accepting transactions, then wait for the running ones to finish, up to `timeout`.
+    ///
+    /// The pending ones not sent yet are failed. Returns false if some were still running when
+    /// timed out.
+    pub async fn shutdown(&self, timeout: Duration) -> bool {
+        self.shutting_down.store(true, Ordering::SeqCst);
+        let drained = async {
+            while !self.running_txs.lock().await.is_empty() {
+                tokio::time::sleep(Duration::from_millis(200)).await;
+            }
+        };
+        tokio::time::timeout(timeout, drained).await.is_ok()
+    }
+
+    /// Persist the finished transactions if the history is enabled, then prune the old ones.
+    async fn archive(&self, ids: &[usize]) {
+        let Some(retention) = &self.history else {
+            return;
+        };
+        if ids.is_empty() {
+            return;
+        }
+        for id in ids {
+            let Some(tx) = self.tx_map.get(id) else {
+                continue;
+            };
+            let tx = tx.lock().await;
+            if let Err(e) = self.db.put_tx_history(&tx) {
+                error!("Failed to persist tx #{id}: {e}");
+            }
+        }
+        if let Err(e) = self.db.prune_tx_history(retention) {
+            error!("Failed to prune the tx history: {e}");
+        }
+    }
+
+    /// The persisted transactions, see [`TxHistoryAccess::get_tx_history`].
+    pub fn history(
+        &self,
+        from: Option<DateTime<Utc>>,
+        to: Option<DateTime<Utc>>,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Option<Vec<Transaction>>> {
+        if self.history.is_none() {
+            return Ok(None);
+        }
+        Ok(Some(self.db.get_tx_history(from, to, offset, limit)?))
+    }
+
+    /// Cancel a pending transaction, so it is never submitted.
+    ///
+    /// The requester waiting for it gets [`TxManagerError::TxCancelled`]. A transaction can't be
+    /// cancelled any more once taken out of the pending queue to be submitted.
+    pub async fn cancel(&self, id: usize) -> Result<(), TxManagerError> {
+        let mut pending_txs = self.pending_txs.lock().await;
+        let running_txs = self.running_txs.lock().await;
+        let mut pending_dedup_keys = self.pending_dedup_keys.lock().await;
+
+        let tx = self.tx_map.get(&id).ok_or(TxNotFound(id))?;
+        let mut tx = tx.lock().await;
+        match tx.state {
+            TransactionState::Pending if !running_txs.contains(&id) => {}
+            TransactionState::Pending | TransactionState::Running => {
+                return Err(TxAlreadyRunning(id));
+            }
+            _ => return Err(TxAlreadyFinished(id)),
+        }
+        pending_txs.retain(|i| *i != id);
+        if let Some(key) = &tx.dedup_key {
+            pending_dedup_keys.remove(key);
+        }
+        debug!("cancel: {:?}", &id);
+        tx.state = TransactionState::Cancelled(TransactionSuccess::default());
+        tx.tx_payload = None;
+        // The requester may be gone already.
+        let _ = tx.notify(Err(TxCancelled(id).into()));
+        Ok(())
+    }
 }
 
 impl TxManager {
     pub fn new(
         path_base: &str,
         dsm: WrappedDataSourceManager,
+        history: Option<TxHistoryRetention>,
     ) -> Result<(Arc<Self>, BoxFuture<'static, Result<()>>)> {
         let opts = get_options(None);
         let path = Path::new(path_base).join("po");
@@ -266,9 +428,12 @@ impl TxManager {
             tx_count: AtomicUsize::new(0),
             tx_map: HashMap::new(),
             pending_txs: Mutex::new(VecDeque::new()),
+            pending_dedup_keys: Mutex::new(StdHashMap::new()),
             running_txs: Mutex::new(Vec::new()),
             past_txs: Mutex::new(VecDeque::new()),
             channel_tx: tx,
+            shutting_down: AtomicBool::new(false),
+            history,
         });
         let handle = Box::pin(txm.clone().start_trader(rx));
 
@@ -286,12 +451,42 @@ impl TxManager {
             let mut running_txs = self.running_txs.lock().await;
             let mut past_txs = self.past_txs.lock().await;
 
+            // The cancelled ones are already out of the pending queue.
+            let mut kept_txs = Vec::with_capacity(current_txs.len());
+            let mut cancelled_txs = Vec::new();
+            for i in current_txs {
+                let tx = self.tx_map.get(&i).ok_or(UnknownDataMismatch)?;
+                if let TransactionState::Cancelled(_) = tx.lock().await.state {
+                    past_txs.push_front(i);
+                    cancelled_txs.push(i);
+                } else {
+                    kept_txs.push(i);
+                }
+            }
+            self.archive(&cancelled_txs).await;
+            let current_txs = kept_txs;
+            self.take_pending(&mut pending_txs, &current_txs).await?;
+            if current_txs.is_empty() {
+                continue;
+            }
+
+            if self.shutting_down.load(Ordering::SeqCst) {
+                for &i in current_txs.iter() {
+                    let tx = self.tx_map.get(&i).ok_or(UnknownDataMismatch)?;
+                    let mut tx = tx.lock().await;
+                    let e = Error::from(ShuttingDown);
+                    tx.state = TransactionState::Error((&e).into());
+                    tx.notify(Err(e))?;
+                    drop(tx);
+                    past_txs.push_front(i);
+                }
+                self.archive(&current_txs).await;
+                continue;
+            }
+
             let ct_clone = current_txs.clone();
             let last_running_txs = std::mem::replace(&mut *running_txs, ct_clone);
 
-            for _ in current_txs.iter() {
-                let _ = pending_txs.pop_front();
-            }
             for i in last_running_txs {
                 past_txs.push_front(i);
             }
@@ -324,15 +519,34 @@ impl TxManager {
 
             let last_running_txs = std::mem::take(&mut *running_txs);
 
-            for i in last_running_txs {
+            for &i in last_running_txs.iter() {
                 past_txs.push_front(i);
             }
             drop(running_txs);
             drop(past_txs);
+            self.archive(&last_running_txs).await;
         }
         error!("Unexpected exit of start_trader!");
         std::process::exit(255);
     }
+    /// Take the transactions out of the pending queue to be sent.
+    ///
+    /// Their dedup keys go along, under the lock of the queue, so that no new request coalesces
+    /// into a transaction being sent.
+    async fn take_pending(&self, pending_txs: &mut VecDeque<usize>, ids: &[usize]) -> Result<()> {
+        let mut pending_dedup_keys = self.pending_dedup_keys.lock().await;
+        pending_txs.retain(|i| !ids.contains(i));
+        for i in ids {
+            let tx = self.tx_map.get(i).ok_or(UnknownDataMismatch)?;
+            if let Some(key) = &tx.lock().await.dedup_key {
+                if pending_dedup_keys.get(key) == Some(i) {
+                    pending_dedup_keys.remove(key);
+                }
+            }
+        }
+        Ok(())
+    }
+
     async fn wrap_send_tx_group(self: Arc<Self>, pid: u64, ids: Vec<usize>) -> Result<()> {
         if ids.is_empty() {
             anyhow::bail!("TxGroup can't be empty!");
@@ -351,27 +565,22 @@ impl TxManager {
                     let id = ids.get(idx).ok_or(UnknownDataMismatch)?;
                     let tx = self.clone().tx_map.get(id).ok_or(UnknownDataMismatch)?;
                     let mut tx = tx.lock().await;
-                    let shot = tx.shot.take().ok_or(UnknownDataMismatch)?;
                     tx.state = match &r {
                         Ok(_) => TransactionState::Success(TransactionSuccess::default()),
                         Err(e) => TransactionState::Error(e.into()),
                     };
-                    if shot.send(r).is_err() {
-                        return Err(anyhow!("shot can't be sent"));
-                    }
+                    tx.notify(r)?;
                     drop(tx);
                 }
             }
             Err(e) => {
                 error!("send_tx_group: {}", &e);
+                let e = SharedError(Arc::new(e));
                 for id in ids {
                     let tx = self.clone().tx_map.get(&id).ok_or(UnknownDataMismatch)?;
                     let mut tx = tx.lock().await;
-                    let shot = tx.shot.take().ok_or(UnknownDataMismatch)?;
-                    tx.state = TransactionState::Error((&e).into());
-                    if shot.send(Err(anyhow!(e.to_string()))).is_err() {
-                        return Err(anyhow!("shot can't be sent"));
-                    }
+                    tx.state = TransactionState::Error(e.get().into());
+                    tx.notify(Err(e.clone().into()))?;
                     drop(tx);
                 }
             }
@@ -431,7 +640,11 @@ impl TxManager {
 
         let mut encoded = Vec::new();
         call.encode_call_data_to(&metadata, &mut encoded)?;
-        debug!("sending tx: 0x{}, with nonce={}", hex::encode(&encoded), api.tx().account_nonce(signer.account_id()).await?);
+        debug!(
+            "sending tx: 0x{}, with nonce={}",
+            hex::encode(&encoded),
+            api.tx().account_nonce(signer.account_id()).await?
+        );
 
         // In pRBv3, transactions are queued, there is always only 1 running transaction for each pool,
         // and a dedicate account is always required for each pRB/pherry instance,
@@ -528,23 +741,63 @@ impl TxManager {
         tx_payload: EncodedPayload,
         desc: String,
     ) -> Result<()> {
+        self.send_to_queue_dedup(pid, tx_payload, desc, None).await
+    }
+
+    /// Queue a transaction, coalescing it into a pending one with the same `dedup_key` if any.
+    ///
+    /// The key identifies the operation semantically (e.g. the worker and the call), so that
+    /// repeated requests with different encoded payloads are still considered identical. The
+    /// pending transaction is then sent with the payload of the latest request, and all the
+    /// requesters get its result.
+    pub async fn send_to_queue_dedup(
+        &self,
+        pid: u64,
+        tx_payload: EncodedPayload,
+        desc: String,
+        dedup_key: Option<String>,
+    ) -> Result<()> {
+        if self.shutting_down.load(Ordering::SeqCst) {
+            return Err(ShuttingDown.into());
+        }
         let (shot, rx) = oneshot::channel();
         tokio::pin!(rx);
 
         let mut pending_txs = self.pending_txs.lock().await;
+        let mut pending_dedup_keys = self.pending_dedup_keys.lock().await;
+
+        if let Some(key) = &dedup_key {
+            if let Some(id) = pending_dedup_keys.get(key) {
+                let tx = self.tx_map.get(id).ok_or(UnknownDataMismatch)?;
+                let mut tx = tx.lock().await;
+                debug!("send_to_queue: coalesced {key} into #{}", tx.id);
+                tx.tx_payload = Some(tx_payload);
+                tx.desc = desc;
+                tx.coalesced += 1;
+                tx.followers.push(shot);
+                drop(tx);
+                drop(pending_dedup_keys);
+                drop(pending_txs);
+                return rx.await?;
+            }
+        }
 
         let id = self.tx_count.fetch_add(1, Ordering::SeqCst);
         debug!("send_to_queue: {:?}", &id);
 
         pending_txs.push_back(id);
-        drop(pending_txs);
+        if let Some(key) = &dedup_key {
+            pending_dedup_keys.insert(key.clone(), id);
+        }
 
         self.tx_map.insert(
             id,
             Arc::new(Mutex::new(Transaction::new(
-                id, pid, tx_payload, desc, shot,
+                id, pid, tx_payload, desc, shot, dedup_key,
             ))),
         );
+        drop(pending_dedup_keys);
+        drop(pending_txs);
         self.channel_tx.clone().send(id)?;
         rx.await?
     }
@@ -577,23 +830,34 @@ impl TxManager {
             .encoded_endpoint_payload
             .ok_or(anyhow!("Missing field endpoint_payload"))?;
         let signature = signed.signature.ok_or(anyhow!("Missing field signature"))?;
+        let dedup_key = WorkerEndpointPayload::decode(&mut &endpoint_payload[..])
+            .ok()
+            .map(|p| format!("update_worker_endpoint:{pid}:0x{}", hex::encode(p.pubkey)));
         let tx_payload = EncodedPayload::new(
             "PhalaRegistry",
             "update_worker_endpoint",
             (Encoded(endpoint_payload), signature).encode(),
         );
         let desc = "Update endpoint of worker.".to_string();
-        self.clone().send_to_queue(pid, tx_payload, desc).await
+        self.clone()
+            .send_to_queue_dedup(pid, tx_payload, desc, dedup_key)
+            .await
     }
     pub async fn sync_offchain_message(
         self: Arc<Self>,
         pid: u64,
         signed_message: SignedMessage,
     ) -> Result<()> {
+        let dedup_key = format!(
+            "sync_offchain_message:{:?}:{}",
+            signed_message.message.sender, signed_message.sequence
+        );
         let encoded = signed_message.encode();
         let tx_payload = EncodedPayload::new("PhalaMq", "sync_offchain_message", encoded);
         let desc = format!("Sync offchain message to chain for pool #{pid}.");
-        self.clone().send_to_queue(pid, tx_payload, desc).await
+        self.clone()
+            .send_to_queue_dedup(pid, tx_payload, desc, Some(dedup_key))
+            .await
     }
     pub async fn add_worker(self: Arc<Self>, pid: u64, pubkey: Sr25519Public) -> Result<()> {
         let desc = format!(
@@ -605,7 +869,10 @@ impl TxManager {
             "add_worker",
             (pid, Encoded(pubkey.encode())).encode(),
         );
-        self.clone().send_to_queue(pid, tx_payload, desc).await
+        let dedup_key = format!("add_worker:{pid}:0x{}", pubkey.encode_hex::<String>());
+        self.clone()
+            .send_to_queue_dedup(pid, tx_payload, desc, Some(dedup_key))
+            .await
     }
     pub async fn start_computing(
         self: Arc<Self>,
@@ -618,12 +885,19 @@ impl TxManager {
             worker.encode_hex::<String>(),
             &stake
         );
+        let stake = stake.parse::<u128>()?;
         let tx_payload = EncodedPayload::new(
             "PhalaStakePoolv2",
             "start_computing",
-            (pid, Encoded(worker.encode()), stake.parse::<u128>()?).encode(),
+            (pid, Encoded(worker.encode()), stake).encode(),
         );
-        self.clone().send_to_queue(pid, tx_payload, desc).await
+        let dedup_key = format!(
+            "start_computing:{pid}:0x{}:{stake}",
+            worker.encode_hex::<String>()
+        );
+        self.clone()
+            .send_to_queue_dedup(pid, tx_payload, desc, Some(dedup_key))
+            .await
     }
     pub async fn stop_computing(self: Arc<Self>, pid: u64, worker: Sr25519Public) -> Result<()> {
         let desc = format!(
@@ -635,7 +909,10 @@ impl TxManager {
             "stop_computing",
             (pid, Encoded(worker.encode())).encode(),
         );
-        self.clone().send_to_queue(pid, tx_payload, desc).await
+        let dedup_key = format!("stop_computing:{pid}:0x{}", worker.encode_hex::<String>());
+        self.clone()
+            .send_to_queue_dedup(pid, tx_payload, desc, Some(dedup_key))
+            .await
     }
 }
 
@@ -757,3 +1034,111 @@ impl PoolOperatorAccess for DB {
         Ok(r.unwrap())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datasource::{DataSourceConfig, DataSourceManager};
+
+    /// A manager without data sources. Its trader isn't run, so the transactions stay pending.
+    async fn pending_only_txm(name: &str) -> (Arc<TxManager>, BoxFuture<'static, Result<()>>) {
+        let config: DataSourceConfig = serde_yaml::from_str(
+            "relaychain: { select_policy: Failover, data_sources: [] }\n\
+             parachain: { select_policy: Failover, data_sources: [] }",
+        )
+        .unwrap();
+        let (dsm, _) = DataSourceManager::from_config(config, 1024).await.unwrap();
+        let path = std::env::temp_dir().join(format!("prb-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        TxManager::new(path.to_str().unwrap(), dsm, None).unwrap()
+    }
+
+    async fn wait_until_coalesced(txm: &TxManager, id: usize, coalesced: usize) {
+        loop {
+            if let Some(tx) = txm.tx_map.get(&id) {
+                if tx.lock().await.coalesced == coalesced {
+                    return;
+                }
+            }
+            tokio::task::yield_now().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn coalesced_requests_take_the_latest_payload_and_share_the_error() {
+        let (txm, _trader) = pending_only_txm("coalesce").await;
+        let request = |desc: &'static str| {
+            let txm = txm.clone();
+            let payload = EncodedPayload::new("PhalaRegistry", "update_worker_endpoint", vec![]);
+            let key = Some("update_worker_endpoint:0:0x00".to_string());
+            tokio::spawn(async move { txm.send_to_queue_dedup(0, payload, desc.into(), key).await })
+        };
+
+        let first = request("first");
+        wait_until_coalesced(&txm, 0, 0).await;
+        let second = request("second");
+        wait_until_coalesced(&txm, 0, 1).await;
+        assert_eq!(txm.tx_count.load(Ordering::SeqCst), 1);
+        assert_eq!(txm.tx_map.get(&0).unwrap().lock().await.desc, "second");
+
+        txm.cancel(0).await.unwrap();
+        for request in [first, second] {
+            let err = request.await.unwrap().unwrap_err();
+            assert_eq!(err.to_string(), "Transaction #0 was cancelled");
+            let shared = err.downcast_ref::<SharedError>().unwrap();
+            assert!(matches!(
+                shared.get().downcast_ref::<TxManagerError>(),
+                Some(TxCancelled(0))
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn requests_are_not_coalesced_into_a_transaction_being_sent() {
+        let (txm, _trader) = pending_only_txm("sending").await;
+        let request = || {
+            let txm = txm.clone();
+            let payload = EncodedPayload::new("PhalaRegistry", "update_worker_endpoint", vec![]);
+            let key = Some("update_worker_endpoint:0:0x00".to_string());
+            tokio::spawn(async move { txm.send_to_queue_dedup(0, payload, "".into(), key).await })
+        };
+
+        let _first = request();
+        wait_until_coalesced(&txm, 0, 0).await;
+        // As the trader does before sending it.
+        let mut pending_txs = txm.pending_txs.lock().await;
+        txm.take_pending(&mut pending_txs, &[0]).await.unwrap();
+        drop(pending_txs);
+
+        let _second = request();
+        wait_until_coalesced(&txm, 1, 0).await;
+        assert_eq!(txm.tx_map.get(&0).unwrap().lock().await.coalesced, 0);
+        assert_eq!(*txm.pending_txs.lock().await, [1]);
+        assert_eq!(
+            txm.pending_dedup_keys
+                .lock()
+                .await
+                .values()
+                .collect::<Vec<_>>(),
+            [&1]
+        );
+    }
+
+    #[tokio::test]
+    async fn start_computing_with_another_stake_is_not_coalesced() {
+        let (txm, _trader) = pending_only_txm("stake").await;
+        let worker = Sr25519Public::from_raw([1; 32]);
+        let start = |stake: &'static str| {
+            let txm = txm.clone();
+            tokio::spawn(txm.start_computing(0, worker, stake.into()))
+        };
+
+        let _first = start("100");
+        wait_until_coalesced(&txm, 0, 0).await;
+        let _same = start("100");
+        wait_until_coalesced(&txm, 0, 1).await;
+        let _other = start("200");
+        wait_until_coalesced(&txm, 1, 0).await;
+        assert_eq!(txm.tx_count.load(Ordering::SeqCst), 2);
+    }
+}
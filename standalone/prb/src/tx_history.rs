@@ -0,0 +1,108 @@
+//! Persistent history of the finished transactions, kept in the database of the tx manager so
+//! it survives restarts.
+//!
+//! The records are keyed by the time they finished then their id, so they are iterated in the
+//! order they finished and the oldest ones are pruned by a range deletion.
+
+use crate::tx::{Transaction, DB};
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use rocksdb::{Direction, IteratorMode, WriteBatch};
+
+static TX_HISTORY_PREFIX: &[u8] = b"tx_history:";
+
+/// How many of the finished transactions are kept. Everything is kept if both are None.
+#[derive(Debug, Clone, Default)]
+pub struct TxHistoryRetention {
+    pub max_count: Option<usize>,
+    /// In seconds
+    pub max_age: Option<u64>,
+}
+
+fn history_key(finished_at_ms: u64, id: u64) -> Vec<u8> {
+    let mut key = TX_HISTORY_PREFIX.to_vec();
+    key.extend(finished_at_ms.to_be_bytes());
+    key.extend(id.to_be_bytes());
+    key
+}
+
+fn finished_at_of(key: &[u8]) -> Option<u64> {
+    let ts = key.strip_prefix(TX_HISTORY_PREFIX)?.get(..8)?;
+    Some(u64::from_be_bytes(ts.try_into().ok()?))
+}
+
+fn timestamp_ms(t: &DateTime<Utc>) -> u64 {
+    t.timestamp_millis().max(0) as u64
+}
+
+pub trait TxHistoryAccess {
+    fn put_tx_history(&self, tx: &Transaction) -> Result<()>;
+    /// The transactions finished between `from` and `to` included, oldest first.
+    fn get_tx_history(
+        &self,
+        from: Option<DateTime<Utc>>,
+        to: Option<DateTime<Utc>>,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Vec<Transaction>>;
+    fn prune_tx_history(&self, retention: &TxHistoryRetention) -> Result<()>;
+}
+
+impl TxHistoryAccess for DB {
+    fn put_tx_history(&self, tx: &Transaction) -> Result<()> {
+        let key = history_key(timestamp_ms(&Utc::now()), tx.id as u64);
+        self.put(key, serde_json::to_vec(&tx.clone_for_serialize())?)?;
+        Ok(())
+    }
+
+    fn get_tx_history(
+        &self,
+        from: Option<DateTime<Utc>>,
+        to: Option<DateTime<Utc>>,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Vec<Transaction>> {
+        let start = history_key(from.as_ref().map_or(0, timestamp_ms), 0);
+        let to = to.as_ref().map_or(u64::MAX, timestamp_ms);
+        let mut ret = Vec::new();
+        let mut skipped = 0;
+        for item in self.iterator(IteratorMode::From(&start, Direction::Forward)) {
+            let (key, value) = item?;
+            match finished_at_of(&key) {
+                Some(finished_at) if finished_at <= to => {}
+                _ => break,
+            }
+            if skipped < offset {
+                skipped += 1;
+                continue;
+            }
+            if ret.len() >= limit {
+                break;
+            }
+            ret.push(serde_json::from_slice(&value)?);
+        }
+        Ok(ret)
+    }
+
+    fn prune_tx_history(&self, retention: &TxHistoryRetention) -> Result<()> {
+        let mut batch = WriteBatch::default();
+        if let Some(max_age) = retention.max_age {
+            let cutoff = timestamp_ms(&Utc::now()).saturating_sub(max_age.saturating_mul(1000));
+            batch.delete_range(history_key(0, 0), history_key(cutoff, 0));
+        }
+        if let Some(max_count) = retention.max_count {
+            let start = history_key(0, 0);
+            let keys = self
+                .iterator(IteratorMode::From(&start, Direction::Forward))
+                .map_while(|item| item.ok().map(|(key, _)| key))
+                .take_while(|key| key.starts_with(TX_HISTORY_PREFIX))
+                .collect::<Vec<_>>();
+            let excess = keys.len().saturating_sub(max_count);
+            for key in keys.into_iter().take(excess) {
+                batch.delete(key);
+            }
+        }
+        self.write(batch)?;
+        Ok(())
+    }
+}
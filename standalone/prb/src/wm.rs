@@ -1,20 +1,25 @@
 use crate::api::{start_api_server, WrappedWorkerContexts};
 use crate::cli::WorkerManagerCliArgs;
 use crate::datasource::{setup_data_source_manager, WrappedDataSourceManager};
-use crate::db::{setup_inventory_db, WrappedDb};
-use crate::lifecycle::{WorkerContextMap, WorkerLifecycleManager, WrappedWorkerLifecycleManager};
+use crate::db::{setup_inventory_db, Worker, WrappedDb};
+use crate::events::WorkerEvents;
+use crate::lifecycle::{
+    SlowStartConfig, WorkerContextMap, WorkerLifecycleManager, WrappedWorkerLifecycleManager,
+};
 use crate::tx::TxManager;
+use crate::tx_history::TxHistoryRetention;
 use crate::use_parachain_api;
 use crate::wm::WorkerManagerMessage::*;
 use crate::worker::{WorkerLifecycleState, WrappedWorkerContext};
 use anyhow::{anyhow, Result};
 use futures::future::{try_join, try_join3, try_join_all};
-use log::{debug, info};
+use log::{debug, info, warn};
 use std::collections::HashMap;
 use std::sync::atomic::AtomicBool;
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
-use tokio::sync::{mpsc, oneshot, Mutex as TokioMutex};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{mpsc, oneshot, watch, Mutex as TokioMutex};
 use tokio::time::sleep;
 
 pub type GlobalWorkerManagerCommandChannelPair = (
@@ -44,6 +49,12 @@ pub struct WorkerManagerContext {
     pub txm: Arc<TxManager>,
     pub pccs_url: String,
     pub pccs_timeout_secs: u64,
+    pub force_register_retries: u32,
+    pub force_register_retry_delay_secs: u64,
+    pub sync_slow_start: Option<SlowStartConfig>,
+    /// The workers discovered over mDNS, by id
+    pub discovered_workers: Arc<TokioMutex<HashMap<String, Worker>>>,
+    pub events: WorkerEvents,
 }
 
 pub type WrappedWorkerManagerContext = Arc<WorkerManagerContext>;
@@ -106,7 +117,12 @@ pub async fn wm(args: WorkerManagerCliArgs) {
     dsm.clone().wait_until_rpc_avail(false).await;
     let _api = use_parachain_api!(dsm, false).unwrap();
 
-    let (txm, txm_handle) = TxManager::new(&args.db_path, dsm.clone()).expect("TxManager");
+    let tx_history = args.tx_history.then(|| TxHistoryRetention {
+        max_count: args.tx_history_max_count,
+        max_age: args.tx_history_max_age,
+    });
+    let (txm, txm_handle) =
+        TxManager::new(&args.db_path, dsm.clone(), tx_history).expect("TxManager");
 
     let ctx = Arc::new(WorkerManagerContext {
         initialized: false.into(),
@@ -119,10 +135,20 @@ pub async fn wm(args: WorkerManagerCliArgs) {
         worker_map: Arc::new(TokioMutex::new(HashMap::new())),
         pccs_url: args.pccs_url.clone(),
         pccs_timeout_secs: args.pccs_timeout,
+        force_register_retries: args.force_register_retries,
+        force_register_retry_delay_secs: args.force_register_retry_delay,
+        sync_slow_start: args.sync_slow_start_initial.map(|initial| SlowStartConfig {
+            initial,
+            step: args.sync_slow_start_step,
+            interval: Duration::from_secs(args.sync_slow_start_interval),
+        }),
+        discovered_workers: Default::default(),
+        events: Default::default(),
     });
 
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
     let join_handle = try_join3(
-        tokio::spawn(start_api_server(ctx.clone(), args.clone())),
+        tokio::spawn(start_api_server(ctx.clone(), args.clone(), shutdown_rx)),
         tokio::spawn(txm_handle),
         try_join_all(ds_handles),
     );
@@ -131,6 +157,17 @@ pub async fn wm(args: WorkerManagerCliArgs) {
         ret = join_handle => {
             info!("wm.join_handle: {:?}", ret);
         }
+        _ = shutdown_signal() => {
+            info!("Shutting down, no more management requests are accepted.");
+            let _ = shutdown_tx.send(true);
+            let timeout = Duration::from_secs(args.shutdown_timeout);
+            if txm.shutdown(timeout).await {
+                info!("All running transactions finished, exiting!");
+            } else {
+                warn!("Transactions still running after {timeout:?}, exiting anyway!");
+            }
+            std::process::exit(0);
+        }
         _ = async {
             loop {
                 let (reload_tx, mut reload_rx) = mpsc::channel::<()>(1);
@@ -158,6 +195,15 @@ pub async fn wm(args: WorkerManagerCliArgs) {
     }
 }
 
+/// Resolves on SIGTERM or SIGINT.
+async fn shutdown_signal() {
+    let mut sigterm = signal(SignalKind::terminate()).expect("Failed to listen for SIGTERM");
+    tokio::select! {
+        _ = sigterm.recv() => {}
+        _ = tokio::signal::ctrl_c() => {}
+    }
+}
+
 #[allow(clippy::await_holding_lock)]
 pub async fn set_lifecycle_manager(
     ctx: WrappedWorkerManagerContext,
@@ -233,7 +279,8 @@ async fn message_loop(
                     let ii = ii.read().await;
                     let sm_tx = ii.sm_tx.as_ref().unwrap().clone();
                     drop(ii);
-                    sm_tx.send(WorkerLifecycleState::HasError("WM reloaded!".to_string()))?;
+                    // The lifecycle of a stopped worker is already gone.
+                    _ = sm_tx.send(WorkerLifecycleState::HasError("WM reloaded!".to_string()));
                 }
                 *workers = vec![];
                 *worker_map = HashMap::new();
@@ -2,6 +2,7 @@ use crate::datasource::WrappedDataSourceManager;
 use crate::db::{get_pool_by_pid, Worker};
 use crate::lifecycle::WrappedWorkerLifecycleManager;
 use crate::pruntime::{PRuntimeClient, PRuntimeClientWithSemaphore};
+use crate::session::{ChainClock, SessionDelta};
 use crate::tx::PoolOperatorAccess;
 use crate::utils::fetch_storage_bytes;
 use crate::wm::{WorkerManagerMessage, WrappedWorkerManagerContext};
@@ -43,6 +44,10 @@ pub enum WorkerLifecycleCommand {
     ShouldRestart,
     ShouldForceRegister,
     ShouldUpdateEndpoint(Vec<String>),
+    /// Stop the lifecycle and keep the worker stopped until it is started again.
+    ShouldStop,
+    /// Start the lifecycle of a stopped worker again.
+    ShouldStart,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -55,6 +60,24 @@ pub enum WorkerLifecycleState {
 
     HasError(String),
     Restarting,
+    /// Stopped on purpose, not restarted until asked to.
+    Stopped,
+}
+
+impl WorkerLifecycleState {
+    /// The name of the state, without the error message of `HasError`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            WorkerLifecycleState::Starting => "Starting",
+            WorkerLifecycleState::Synchronizing => "Synchronizing",
+            WorkerLifecycleState::Preparing => "Preparing",
+            WorkerLifecycleState::Working => "Working",
+            WorkerLifecycleState::GatekeeperWorking => "GatekeeperWorking",
+            WorkerLifecycleState::HasError(_) => "HasError",
+            WorkerLifecycleState::Restarting => "Restarting",
+            WorkerLifecycleState::Stopped => "Stopped",
+        }
+    }
 }
 
 pub type WrappedWorkerContext = Arc<RwLock<WorkerContext>>;
@@ -111,7 +134,7 @@ macro_rules! return_if_error_or_restarting {
             WorkerLifecycleState::HasError(_) => {
                 return $r;
             }
-            WorkerLifecycleState::Restarting => {
+            WorkerLifecycleState::Restarting | WorkerLifecycleState::Stopped => {
                 return $r;
             }
             _ => {}
@@ -125,7 +148,7 @@ macro_rules! return_if_error_or_restarting {
             WorkerLifecycleState::HasError(_) => {
                 return;
             }
-            WorkerLifecycleState::Restarting => {
+            WorkerLifecycleState::Restarting | WorkerLifecycleState::Stopped => {
                 return;
             }
             _ => {}
@@ -147,6 +170,13 @@ pub struct WorkerContext {
     pub info: Option<PhactoryInfo>,
     pub last_message: String,
     pub session_info: Option<SessionInfo>,
+    /// How the session changed since the previous poll
+    pub session_delta: Option<SessionDelta>,
+    /// The chain when the session was fetched, None if unknown
+    pub chain_clock: Option<ChainClock>,
+    pub force_register_retries_left: Option<u32>,
+    /// Discovered over mDNS rather than configured in the inventory
+    pub discovered: bool,
 }
 
 impl WorkerContext {
@@ -168,6 +198,10 @@ impl WorkerContext {
             info: None,
             last_message: String::new(),
             session_info: None,
+            session_delta: None,
+            chain_clock: None,
+            force_register_retries_left: None,
+            discovered: false,
         };
         ret.set_last_message("Starting lifecycle...");
         Ok(ret)
@@ -248,6 +282,12 @@ impl WorkerContext {
                     &cc.worker.name, &cc.worker.endpoint, &cc.worker.id
                 );
             }
+            WorkerLifecycleState::Stopped => {
+                // The lifecycle loop is gone, so the state is set directly.
+                drop(cc);
+                c.write().await.state = WorkerLifecycleState::Restarting;
+                return Ok(());
+            }
             _ => {
                 let sm_tx = cc.sm_tx.as_ref().unwrap().clone();
                 sm_tx.send(WorkerLifecycleState::Restarting)?;
@@ -257,6 +297,23 @@ impl WorkerContext {
         Ok(())
     }
 
+    async fn stop(c: WrappedWorkerContext) -> Result<()> {
+        let cc = c.read().await;
+        match cc.state {
+            WorkerLifecycleState::Restarting | WorkerLifecycleState::Stopped => {
+                warn!(
+                    "Attempting to stop a worker which is not running!({}, {}, {})",
+                    &cc.worker.name, &cc.worker.endpoint, &cc.worker.id
+                );
+            }
+            _ => {
+                let sm_tx = cc.sm_tx.as_ref().unwrap().clone();
+                sm_tx.send(WorkerLifecycleState::Stopped)?;
+            }
+        }
+        Ok(())
+    }
+
     async fn do_start(c: WrappedWorkerContext) {
         let (tx, rx) = mpsc::unbounded_channel::<WorkerLifecycleState>();
         let mut cc = c.write().await;
@@ -272,7 +329,7 @@ impl WorkerContext {
     async fn set_state(c: WrappedWorkerContext, state: WorkerLifecycleState) {
         let c = c.read().await;
         match &c.state {
-            WorkerLifecycleState::Restarting => {}
+            WorkerLifecycleState::Restarting | WorkerLifecycleState::Stopped => {}
             _ => {
                 let sm_tx = c.sm_tx.as_ref().unwrap().clone();
                 sm_tx.send(state).expect("should update sm state");
@@ -340,6 +397,10 @@ impl WorkerContext {
                     );
                     return;
                 }
+                WorkerLifecycleState::Stopped => {
+                    set_worker_message!(c, "Stopped, waiting to be started.");
+                    return;
+                }
             }
 
             tokio::spawn(lm.clone().webhook_send(c.clone()));
@@ -565,6 +626,7 @@ impl WorkerContext {
                     let cc = c.clone();
                     let mut cc = cc.write().await;
                     cc.info = Some(p);
+                    cc.ctx.events.publish(&cc);
                     drop(cc);
                 }
                 Err(e) => {
@@ -641,9 +703,19 @@ impl WorkerContext {
                 if session.state == WorkerState::WorkerUnresponsive {
                     set_worker_message!(c, "Worker unresponsive!")
                 }
+                let chain_clock = ChainClock::fetch(&api).await.unwrap_or_else(|e| {
+                    debug!("Failed to fetch the chain clock: {e}");
+                    None
+                });
                 let cc = c.clone();
                 let mut cc = cc.write().await;
+                let delta = cc.session_info.as_ref().and_then(|prev| {
+                    SessionDelta::between(prev, &session, cc.session_delta.as_ref())
+                });
+                cc.session_delta = delta;
                 cc.session_info = Some(session);
+                cc.chain_clock = chain_clock;
+                cc.ctx.events.publish(&cc);
                 drop(cc);
             }
             sleep(Duration::from_secs(6)).await;
@@ -671,10 +743,26 @@ impl WorkerContext {
                     }
                 }
                 ShouldForceRegister => {
-                    if let Err(e) = Self::register_worker(c.clone(), true).await {
+                    if let Err(e) = Self::force_register_worker(c.clone()).await {
                         set_worker_message!(c, format!("ShouldForceRegister: {}", e));
                     }
                 }
+                ShouldStop => {
+                    if let Err(e) = Self::stop(c.clone()).await {
+                        set_worker_message!(c, format!("ShouldStop: {}", e));
+                    }
+                }
+                ShouldStart => {
+                    let stopped = matches!(c.read().await.state, WorkerLifecycleState::Stopped);
+                    if stopped {
+                        if let Err(e) = Self::restart(c.clone()).await {
+                            error!("ShouldStart: {}", e);
+                            std::process::exit(255);
+                        }
+                        return;
+                    }
+                    set_worker_message!(c, "ShouldStart: the worker is not stopped.");
+                }
             }
         }
         drop(rx);
@@ -693,6 +781,32 @@ impl WorkerContext {
         Ok(())
     }
 
+    async fn set_force_register_retries_left(c: &WrappedWorkerContext, left: Option<u32>) {
+        let mut cc = c.write().await;
+        cc.force_register_retries_left = left;
+    }
+
+    async fn force_register_worker(c: WrappedWorkerContext) -> Result<()> {
+        let (lm, _worker, pr) = extract_essential_values!(c);
+        let pubkey = pr
+            .get_info(())
+            .await?
+            .public_key
+            .ok_or(anyhow!("public key not found!"))?;
+        let registration = WorkerRegistration {
+            c: c.clone(),
+            lm: lm.clone(),
+            pubkey: hex::decode(pubkey)?,
+        };
+        let mut backoff = RetryBackoff::new(
+            lm.main_ctx.force_register_retries,
+            Duration::from_secs(lm.main_ctx.force_register_retry_delay_secs),
+        );
+        let result = force_register(&registration, &mut backoff).await;
+        Self::set_force_register_retries_left(&c, None).await;
+        result
+    }
+
     async fn register_worker(c: WrappedWorkerContext, force_ra: bool) -> Result<()> {
         let (lm, worker, pr) = extract_essential_values!(c);
         let txm = lm.txm.clone();
@@ -822,8 +936,16 @@ impl WorkerContext {
 
 impl WorkerContext {
     async fn sync_loop(c: WrappedWorkerContext) {
-        set_worker_message!(c, "Now start synchronizing!");
         let (lm, worker, pr) = extract_essential_values!(c);
+        // Held until caught up with the chain
+        let mut slow_start_permit = match &lm.sync_slow_start {
+            Some(slow_start) => {
+                set_worker_message!(c, "Waiting for the turn to synchronize...");
+                Some(slow_start.acquire().await)
+            }
+            None => None,
+        };
+        set_worker_message!(c, "Now start synchronizing!");
         let dsm = lm.dsm.clone();
         // let pid = worker.pid.expect("PID not found!");
 
@@ -848,6 +970,7 @@ impl WorkerContext {
                 Ok((dont_wait, s)) => {
                     sync_state = s;
                     if !dont_wait {
+                        slow_start_permit.take();
                         sync_state.authory_set_state = None;
                         sync_state.blocks.clear();
                         // match Self::mq_sync_loop_round(c.clone(), pid).await {
@@ -905,7 +1028,8 @@ impl WorkerContext {
             sync_state.authory_set_state = None;
             sync_state.blocks.clear();
             debug!("ready to use headers-cache: {:?}", i.public_key);
-            let not_done_with_hc = Self::sync_with_cached_headers(pr.clone(), dsm.clone(), &i).await?;
+            let not_done_with_hc =
+                Self::sync_with_cached_headers(pr.clone(), dsm.clone(), &i).await?;
             if not_done_with_hc {
                 return Ok((true, sync_state));
             }
@@ -1188,20 +1312,19 @@ impl WorkerContext {
                 .sync_header(HeadersToSync::new(header_batch, authrotiy_change))
                 .await?;
             next_headernum = r.synced_to + 1;
-            let hdr_synced_to =
-                match dsm.clone().get_finalized_header(last_header_hash).await? {
-                    Some((fin_header, proof)) => {
-                        Self::sync_parachain_header(
-                            pr.clone(),
-                            dsm.clone(),
-                            next_para_headernum,
-                            fin_header.number,
-                            proof,
-                        )
-                        .await?
-                    }
-                    None => 0,
-                };
+            let hdr_synced_to = match dsm.clone().get_finalized_header(last_header_hash).await? {
+                Some((fin_header, proof)) => {
+                    Self::sync_parachain_header(
+                        pr.clone(),
+                        dsm.clone(),
+                        next_para_headernum,
+                        fin_header.number,
+                        proof,
+                    )
+                    .await?
+                }
+                None => 0,
+            };
             next_para_headernum = hdr_synced_to + 1;
 
             if next_blocknum < hdr_synced_to {
@@ -1225,3 +1348,263 @@ impl WorkerContext {
         Ok(())
     }
 }
+
+/// The steps of force registering a worker.
+#[async_trait::async_trait]
+trait ForceRegistration {
+    /// Submit the registration and wait for it to take effect.
+    async fn submit(&self) -> Result<()>;
+    /// The `last_updated` of the worker in the on-chain registry, None if not registered.
+    async fn registry_last_updated(&self) -> Result<Option<u64>>;
+    async fn set_retries_left(&self, left: u32);
+    async fn set_message(&self, message: String);
+}
+
+struct WorkerRegistration {
+    c: WrappedWorkerContext,
+    lm: WrappedWorkerLifecycleManager,
+    pubkey: Vec<u8>,
+}
+
+#[async_trait::async_trait]
+impl ForceRegistration for WorkerRegistration {
+    async fn submit(&self) -> Result<()> {
+        WorkerContext::register_worker(self.c.clone(), true).await
+    }
+
+    async fn registry_last_updated(&self) -> Result<Option<u64>> {
+        let api =
+            use_parachain_api!(self.lm.dsm, false).ok_or(anyhow!("no online substrate session"))?;
+        let registry_query = storage(
+            "PhalaRegistry",
+            "Workers",
+            vec![Value::from_bytes(&self.pubkey)],
+        );
+        let registry_info: Option<WorkerInfoV2<subxt::utils::AccountId32>> =
+            fetch_storage_bytes(&api, &registry_query).await?;
+        Ok(registry_info.map(|info| info.last_updated))
+    }
+
+    async fn set_retries_left(&self, left: u32) {
+        WorkerContext::set_force_register_retries_left(&self.c, Some(left)).await;
+    }
+
+    async fn set_message(&self, message: String) {
+        set_worker_message!(self.c, message);
+    }
+}
+
+/// Force register the worker, retrying with backoff on failures.
+///
+/// Before each retry the on-chain registry is checked, so that a registration which has
+/// landed but whose result was not observed is not submitted again.
+async fn force_register(
+    registration: &impl ForceRegistration,
+    backoff: &mut RetryBackoff,
+) -> Result<()> {
+    let registered_at = registration.registry_last_updated().await?;
+    registration.set_retries_left(backoff.retries_left()).await;
+    loop {
+        let err = match registration.submit().await {
+            Ok(()) => return Ok(()),
+            Err(err) => err,
+        };
+        let Some(delay) = backoff.next_retry() else {
+            return Err(err);
+        };
+        registration.set_retries_left(backoff.retries_left()).await;
+        registration
+            .set_message(format!(
+                "Force register failed: {err}, retrying in {}s ({} retries left)",
+                delay.as_secs(),
+                backoff.retries_left()
+            ))
+            .await;
+        sleep(delay).await;
+        match registration.registry_last_updated().await {
+            Ok(last_updated) if last_updated != registered_at => {
+                registration
+                    .set_message("Registration found on chain, not resubmitting.".into())
+                    .await;
+                return Ok(());
+            }
+            Ok(_) => {}
+            Err(e) => {
+                // The previous attempt may have landed, do not risk resubmitting.
+                return Err(e.context("Failed to check the registration state"));
+            }
+        }
+    }
+}
+
+/// Retries with an exponentially growing delay, up to a number of times.
+struct RetryBackoff {
+    retries_left: u32,
+    delay: Duration,
+}
+
+impl RetryBackoff {
+    fn new(retries: u32, initial_delay: Duration) -> Self {
+        Self {
+            retries_left: retries,
+            delay: initial_delay,
+        }
+    }
+
+    fn retries_left(&self) -> u32 {
+        self.retries_left
+    }
+
+    /// Takes a retry, returning the delay to wait before it, or None if they are used up.
+    fn next_retry(&mut self) -> Option<Duration> {
+        self.retries_left = self.retries_left.checked_sub(1)?;
+        let delay = self.delay;
+        self.delay = delay.saturating_mul(2);
+        Some(delay)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn force_register_retries_back_off_until_used_up() {
+        let mut backoff = RetryBackoff::new(3, Duration::from_secs(6));
+        assert_eq!(backoff.retries_left(), 3);
+        let delays: Vec<_> = std::iter::from_fn(|| backoff.next_retry()).collect();
+        assert_eq!(delays, [6, 12, 24].map(Duration::from_secs));
+        assert_eq!(backoff.retries_left(), 0);
+
+        assert_eq!(
+            RetryBackoff::new(0, Duration::from_secs(6)).next_retry(),
+            None
+        );
+        let mut backoff = RetryBackoff::new(2, Duration::MAX);
+        assert_eq!(backoff.next_retry(), Some(Duration::MAX));
+        assert_eq!(backoff.next_retry(), Some(Duration::MAX));
+    }
+
+    /// Replays the given results of the submissions and the registry lookups.
+    #[derive(Default)]
+    struct StubRegistration {
+        submissions: std::sync::Mutex<Vec<Result<()>>>,
+        registry: std::sync::Mutex<Vec<Result<Option<u64>>>>,
+        submitted: std::sync::Mutex<u32>,
+        retries_left: std::sync::Mutex<Vec<u32>>,
+        messages: std::sync::Mutex<Vec<String>>,
+    }
+
+    impl StubRegistration {
+        fn new(submissions: Vec<Result<()>>, registry: Vec<Result<Option<u64>>>) -> Self {
+            Self {
+                submissions: submissions.into_iter().rev().collect::<Vec<_>>().into(),
+                registry: registry.into_iter().rev().collect::<Vec<_>>().into(),
+                ..Default::default()
+            }
+        }
+
+        fn submitted(&self) -> u32 {
+            *self.submitted.lock().unwrap()
+        }
+
+        fn registry_lookups_left(&self) -> usize {
+            self.registry.lock().unwrap().len()
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl ForceRegistration for StubRegistration {
+        async fn submit(&self) -> Result<()> {
+            *self.submitted.lock().unwrap() += 1;
+            self.submissions
+                .lock()
+                .unwrap()
+                .pop()
+                .expect("unexpected submission")
+        }
+
+        async fn registry_last_updated(&self) -> Result<Option<u64>> {
+            self.registry
+                .lock()
+                .unwrap()
+                .pop()
+                .expect("unexpected registry lookup")
+        }
+
+        async fn set_retries_left(&self, left: u32) {
+            self.retries_left.lock().unwrap().push(left);
+        }
+
+        async fn set_message(&self, message: String) {
+            self.messages.lock().unwrap().push(message);
+        }
+    }
+
+    #[tokio::test]
+    async fn force_register_retries_a_transient_failure() {
+        let registration = StubRegistration::new(
+            vec![Err(anyhow!("connection reset")), Ok(())],
+            vec![Ok(Some(1)), Ok(Some(1))],
+        );
+        let mut backoff = RetryBackoff::new(3, Duration::ZERO);
+        force_register(&registration, &mut backoff).await.unwrap();
+        assert_eq!(registration.submitted(), 2);
+        // The registry was checked before resubmitting.
+        assert_eq!(registration.registry_lookups_left(), 0);
+        assert_eq!(*registration.retries_left.lock().unwrap(), [3, 2]);
+        assert_eq!(
+            *registration.messages.lock().unwrap(),
+            ["Force register failed: connection reset, retrying in 0s (2 retries left)"]
+        );
+    }
+
+    #[tokio::test]
+    async fn force_register_does_not_resubmit_a_landed_registration() {
+        let registration = StubRegistration::new(
+            vec![Err(anyhow!("timed out waiting for the result"))],
+            vec![Ok(Some(1)), Ok(Some(2))],
+        );
+        let mut backoff = RetryBackoff::new(3, Duration::ZERO);
+        force_register(&registration, &mut backoff).await.unwrap();
+        assert_eq!(registration.submitted(), 1);
+        assert_eq!(
+            registration.messages.lock().unwrap().last().unwrap(),
+            "Registration found on chain, not resubmitting."
+        );
+
+        // A first registration is noticed as well.
+        let registration = StubRegistration::new(
+            vec![Err(anyhow!("timed out waiting for the result"))],
+            vec![Ok(None), Ok(Some(2))],
+        );
+        force_register(&registration, &mut RetryBackoff::new(3, Duration::ZERO))
+            .await
+            .unwrap();
+        assert_eq!(registration.submitted(), 1);
+    }
+
+    #[tokio::test]
+    async fn force_register_stops_when_the_registry_is_unknown_or_retries_are_used_up() {
+        let registration = StubRegistration::new(
+            vec![Err(anyhow!("timed out waiting for the result"))],
+            vec![Ok(None), Err(anyhow!("no online substrate session"))],
+        );
+        let err = force_register(&registration, &mut RetryBackoff::new(3, Duration::ZERO))
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Failed to check the registration state");
+        assert_eq!(registration.submitted(), 1);
+
+        let registration = StubRegistration::new(
+            vec![Err(anyhow!("1")), Err(anyhow!("2")), Err(anyhow!("3"))],
+            vec![Ok(None), Ok(None), Ok(None)],
+        );
+        let err = force_register(&registration, &mut RetryBackoff::new(2, Duration::ZERO))
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "3");
+        assert_eq!(registration.submitted(), 3);
+        assert_eq!(*registration.retries_left.lock().unwrap(), [2, 1, 0]);
+    }
+}
@@ -349,12 +349,12 @@ async fn prpc_proxy_get(id: TraceId, method: String) -> Custom<Vec<u8>> {
 }
 
 #[post("/sidevm/<id>/<path..>", data = "<body>")]
-async fn connect_sidevm_post(
+async fn connect_sidevm_post<'r>(
     head: RequestInfo,
     id: String,
     path: PathBuf,
-    body: Data<'_>,
-) -> Result<StreamResponse, (Status, String)> {
+    body: Data<'r>,
+) -> Result<StreamResponse<'r>, (Status, String)> {
     ecall_connect_sidevm(head, id, path, Some(body)).await
 }
 
@@ -363,7 +363,7 @@ async fn connect_sidevm_get(
     head: RequestInfo,
     id: String,
     path: PathBuf,
-) -> Result<StreamResponse, (Status, String)> {
+) -> Result<StreamResponse<'static>, (Status, String)> {
     ecall_connect_sidevm(head, id, path, None).await
 }
 
@@ -96,6 +96,11 @@ struct Args {
     /// The max retry times of getting the attestation report.
     #[arg(long, default_value = "1")]
     ra_max_retries: u32,
+
+    /// The max total bytes of the headers returned to the contracts with an HTTP response.
+    /// The headers beyond it are dropped.
+    #[arg(long, default_value_t = 64 * 1024)]
+    max_http_response_header_bytes: u64,
 }
 
 impl Args {
@@ -138,6 +143,7 @@ impl Args {
             no_rcu: self.no_rcu,
             ra_timeout: self.ra_timeout,
             ra_max_retries: self.ra_max_retries,
+            max_http_response_header_bytes: Some(self.max_http_response_header_bytes),
         }
     }
 }
@@ -1,13 +1,13 @@
 use crate::pal_gramine::GraminePlatform;
 
 use anyhow::Result;
+use clap::Parser;
 use core::sync::atomic::{AtomicU32, Ordering};
 use phactory::{benchmark, Phactory, RpcService};
 use rocket::http::Status;
 use sidevm_host_runtime::rocket_stream::{connect, RequestInfo, StreamResponse};
 use std::path::PathBuf;
 use tracing::info;
-use clap::Parser;
 
 lazy_static::lazy_static! {
     static ref APPLICATION: RpcService<GraminePlatform> = RpcService::new(GraminePlatform, crate::Args::parse().to_init_args());
@@ -82,12 +82,12 @@ pub async fn ecall_prpc_request(
     (code, data)
 }
 
-pub(crate) async fn ecall_connect_sidevm(
+pub(crate) async fn ecall_connect_sidevm<'r>(
     head: RequestInfo,
     id: String,
     path: PathBuf,
-    body: Option<rocket::Data<'_>>,
-) -> Result<StreamResponse, (Status, String)> {
+    body: Option<rocket::Data<'r>>,
+) -> Result<StreamResponse<'r>, (Status, String)> {
     let contract_id = hex::decode(id.trim_start_matches("0x"))
         .map_err(|err| (Status::BadRequest, err.to_string()))?;
     let Some(command_tx) = APPLICATION